@@ -0,0 +1,407 @@
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use shared::protocol::DEFAULT_SERVER_URL;
+use shared::signing::Verifier;
+use shared::tcp::TcpOptions;
+use url::Url;
+
+/// Connects to a markdown mirror server and keeps a local copy of the watched file
+#[derive(Debug, Parser)]
+#[command(name = "client", version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Client identifier, used in the output file name (same as --client-id)
+    #[arg(value_name = "CLIENT_ID", conflicts_with = "client_id")]
+    id: Option<String>,
+
+    /// Client identifier, used in the output file name
+    #[arg(long, value_name = "ID")]
+    client_id: Option<String>,
+
+    /// Directory the mirrored file is written to
+    #[arg(short, long, value_name = "DIR", env = "OUTPUT_DIR", default_value = "client")]
+    pub output_dir: String,
+
+    /// Permissions of the output directory when the client creates it, in octal (e.g. `750`);
+    /// Unix only, the umask does not apply
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    pub output_dir_mode: Option<u32>,
+
+    /// Name of each mirrored file's output file in the output directory;
+    /// `{client_id}` and `{file_id}` are filled in, so a server watching several
+    /// files can be mirrored with e.g. `{file_id}`
+    #[arg(long, value_name = "TEMPLATE", default_value = "client{client_id}_README.md")]
+    pub output_template: String,
+
+    /// WebSocket URL of the server
+    #[arg(short, long, value_name = "URL", env = "SERVER_URL", default_value = DEFAULT_SERVER_URL)]
+    pub server_url: Url,
+
+    /// HTTP long-poll endpoint of the server (e.g. `http://localhost:3031`), used when
+    /// the WebSocket connection cannot be established
+    #[arg(long, value_name = "URL", env = "LONG_POLL_URL")]
+    pub long_poll_url: Option<Url>,
+
+    /// Always long-poll instead of trying WebSocket first
+    #[arg(long, requires = "long_poll_url")]
+    pub long_poll: bool,
+
+    /// Number of runtime worker threads [default: available parallelism]
+    #[arg(long, value_name = "N", env = "WORKER_THREADS")]
+    pub worker_threads: Option<NonZeroUsize>,
+
+    /// Only mirror chars START..END of a file, e.g. `README.md:0-2000`
+    #[arg(long, value_name = "FILE:START-END", value_parser = RangeSpec::parse)]
+    pub range: Option<RangeSpec>,
+
+    /// Only mirror a file from char OFFSET to its end, e.g. `README.md:5000` for a viewer
+    /// that already shows the first 5000 chars
+    #[arg(long, value_name = "FILE:OFFSET", value_parser = FromSpec::parse, conflicts_with = "range")]
+    pub from: Option<FromSpec>,
+
+    /// Only mirror this one of the server's files, can be repeated; the server sends
+    /// nothing for the others, so a busy file does not hold the client up
+    #[arg(long, value_name = "FILE")]
+    pub follow: Vec<String>,
+
+    /// Where to write the content, can be repeated: `file` (the usual output file),
+    /// `file:PATH`, `stdout` or an `http://` URL to POST it to [default: file]
+    #[arg(long = "sink", value_name = "SINK")]
+    pub sinks: Vec<String>,
+
+    /// Print to stdout like `tail -f`: only the appended text of a diff that adds to
+    /// the end of the file, the whole content for any other change
+    #[arg(long, conflicts_with = "sinks")]
+    pub tail: bool,
+
+    /// Write the file with CRLF line endings
+    #[arg(long)]
+    pub crlf: bool,
+
+    /// Start the file with a UTF-8 byte order mark
+    #[arg(long)]
+    pub bom: bool,
+
+    /// Shell command run after the file was rewritten from full content (e.g. after reconnecting),
+    /// with MARKDOWN_OP_OUTPUT and MARKDOWN_OP_FILE_ID set
+    #[arg(long, value_name = "COMMAND")]
+    pub on_resync: Option<String>,
+
+    /// Write every update of a file to a new file named after when it was written, e.g.
+    /// `client1_README.2024-06-01T12-00-00.123Z.md`, instead of rewriting the output
+    /// file, and keep only the last N of them
+    #[arg(long, value_name = "N", conflicts_with_all = ["tail", "snapshot", "git_commit"])]
+    pub snapshot_history: Option<NonZeroUsize>,
+
+    /// Read every output file back after writing it and compare it with the mirrored
+    /// content; a mismatch is written again once, then reported as a failed write
+    #[arg(long)]
+    pub verify_writes: bool,
+
+    /// Commit every change to the git repository the output directory is in, with the file id
+    /// and seq in the message; only the mirrored files are committed
+    #[arg(long, conflicts_with = "tail")]
+    pub git_commit: bool,
+
+    /// Write the first full content the server sends and exit, without reconnecting or
+    /// streaming diffs, e.g. `client --snapshot > out.md`; writes to stdout unless --sink is given
+    #[arg(long, conflicts_with_all = ["tail", "git_commit", "range", "from", "long_poll", "mirror_delete_on_exit"])]
+    pub snapshot: bool,
+
+    /// How long --snapshot waits for content before giving up with an error
+    #[arg(long, value_name = "MS", default_value_t = 10000)]
+    pub snapshot_timeout_ms: u64,
+
+    /// Print how long after the server detected each change the client had mirrored it
+    #[arg(long)]
+    pub report_latency: bool,
+
+    /// Give the output files the modification time of the source file whenever it is sent in full
+    #[arg(long)]
+    pub preserve_mtime: bool,
+
+    /// On Ctrl+C, remove the files this client wrote, unless they were modified since
+    #[arg(long)]
+    pub mirror_delete_on_exit: bool,
+
+    /// Ask the server to compress messages; send SIGUSR1 to switch compression on or off at runtime
+    #[arg(long)]
+    pub compress: bool,
+
+    /// How long to wait for the server to accept the connection
+    #[arg(long, value_name = "MS", env = "CONNECT_TIMEOUT_MS", default_value_t = 5000)]
+    pub connect_timeout_ms: u64,
+
+    /// Let the kernel batch small messages to the server (Nagle's algorithm) instead
+    /// of sending them right away
+    #[arg(long)]
+    pub no_tcp_nodelay: bool,
+
+    /// Idle seconds before TCP keepalive probes check the server is still there (0 disables)
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    pub tcp_keepalive_secs: u64,
+
+    /// Seconds between unanswered TCP keepalive probes
+    #[arg(long, value_name = "SECS", default_value = "10")]
+    pub tcp_keepalive_interval_secs: NonZeroU64,
+
+    /// Largest message accepted from the server; a larger one, e.g. the full content of a
+    /// huge file, ends the client with an error instead of being read
+    #[arg(long, value_name = "BYTES", default_value_t = 64 << 20)]
+    pub max_message_bytes: usize,
+
+    /// For this long after a connection fails, connects that fail straight away (refused,
+    /// DNS hiccup) are retried quickly without counting against the reconnect attempts
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    pub grace_ms: u64,
+
+    /// How reconnect delays are randomized, so clients dropped together, e.g. by a server
+    /// restart, don't all reconnect at the same moment
+    #[arg(long, value_enum, value_name = "STRATEGY", default_value_t = Jitter::Fixed)]
+    pub jitter: Jitter,
+
+    /// What to do when a diff does not fit the client's copy of its file, e.g. because
+    /// the copy was changed behind the client's back
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = ApplyErrorPolicy::Resync)]
+    pub on_apply_error: ApplyErrorPolicy,
+
+    /// PEM certificate of the CA a `wss://` server's certificate must be signed by
+    #[arg(long, value_name = "PATH", env = "MARKDOWN_OP_TLS_CA")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// PEM client certificate presented to a `wss://` server that requires one
+    /// (mutual TLS); its common name is the id the server knows the client by
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Token presented to the server when connecting
+    #[arg(long, value_name = "TOKEN", env = "MARKDOWN_OP_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// Only accept messages signed with the secret key of this Ed25519 public key (base64),
+    /// as printed by the server
+    #[arg(long, value_name = "KEY", env = "MARKDOWN_OP_VERIFY_KEY", value_parser = Verifier::from_base64)]
+    pub verify_key: Option<Verifier>,
+}
+
+impl Cli {
+    /// The client id, from either the positional argument or `--client-id`
+    pub fn client_id(&self) -> String {
+        self.client_id
+            .clone()
+            .or_else(|| self.id.clone())
+            .unwrap_or_else(|| "1".to_string())
+    }
+
+    /// Socket options of the connection to the server
+    pub fn tcp_options(&self) -> TcpOptions {
+        TcpOptions {
+            nodelay: !self.no_tcp_nodelay,
+            keepalive: (self.tcp_keepalive_secs > 0).then(|| Duration::from_secs(self.tcp_keepalive_secs)),
+            keepalive_interval: Duration::from_secs(self.tcp_keepalive_interval_secs.get()),
+        }
+    }
+}
+
+/// Part of a watched file to mirror instead of the whole file
+#[derive(Debug, Clone)]
+pub struct RangeSpec {
+    pub file_id: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl RangeSpec {
+    fn parse(spec: &str) -> Result<Self, String> {
+        let (file_id, range) = spec.rsplit_once(':').ok_or("expected FILE:START-END")?;
+        let (start, end) = range.split_once('-').ok_or("expected START-END after the file")?;
+        let start = start.parse().map_err(|e| format!("invalid start {start:?}: {e}"))?;
+        let end = end.parse().map_err(|e| format!("invalid end {end:?}: {e}"))?;
+        if end < start {
+            return Err(format!("range end {end} is before its start {start}"));
+        }
+        Ok(Self { file_id: file_id.to_string(), start, end })
+    }
+}
+
+/// Where in a watched file to start mirroring instead of its beginning
+#[derive(Debug, Clone)]
+pub struct FromSpec {
+    pub file_id: String,
+    pub offset: usize,
+}
+
+impl FromSpec {
+    fn parse(spec: &str) -> Result<Self, String> {
+        let (file_id, offset) = spec.rsplit_once(':').ok_or("expected FILE:OFFSET")?;
+        let offset = offset.parse().map_err(|e| format!("invalid offset {offset:?}: {e}"))?;
+        Ok(Self { file_id: file_id.to_string(), offset })
+    }
+}
+
+/// Permission bits in octal, with or without a leading `0o`
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    match u32::from_str_radix(digits, 8) {
+        Ok(bits) if bits <= 0o7777 => Ok(bits),
+        Ok(_) => Err(format!("{mode} is more than permission bits (at most 7777)")),
+        Err(e) => Err(format!("invalid octal mode {mode:?}: {e}")),
+    }
+}
+
+/// Randomization of the reconnect backoff, after
+/// <https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/>
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Jitter {
+    /// The exponential delay as it is
+    None,
+    /// The exponential delay plus up to 100ms
+    Fixed,
+    /// Anywhere from 0 up to the exponential delay
+    Full,
+    /// Between the initial delay and three times the previous delay, capped
+    Decorrelated,
+}
+
+/// How a diff that does not fit the copy of its file is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ApplyErrorPolicy {
+    /// Skip the diff and keep mirroring onto the copy as it is
+    Ignore,
+    /// Ask the server for the full content of the file
+    Resync,
+    /// Exit with an error, e.g. to fail a CI job on a desync
+    Fail,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Rebuild a file offline by applying a recorded change log
+    ApplyLog(ApplyLogArgs),
+    /// Send a command to the server and exit
+    Control(ControlArgs),
+    /// Print the server's watched files, connections and content digests as JSON and exit
+    Status,
+    /// Check the output directory can be written and the server is reachable, print
+    /// what is wrong and how to fix it, and exit
+    Doctor,
+}
+
+#[derive(Debug, Args)]
+pub struct ControlArgs {
+    pub action: ControlAction,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ControlAction {
+    /// Stop broadcasting changes, e.g. before a bulk operation on the watched files
+    Pause,
+    /// Send the current content of files changed while paused, then broadcast as usual
+    Resume,
+}
+
+impl From<ControlAction> for shared::Control {
+    fn from(action: ControlAction) -> Self {
+        match action {
+            ControlAction::Pause => shared::Control::Pause,
+            ControlAction::Resume => shared::Control::Resume,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ApplyLogArgs {
+    /// JSON Lines file with one change per line
+    #[arg(short, long, value_name = "PATH")]
+    pub log: PathBuf,
+
+    /// Starting content; an empty file is assumed when omitted
+    #[arg(short, long, value_name = "PATH")]
+    pub input: Option<PathBuf>,
+
+    /// Where to write the result; stdout when omitted
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+
+    /// Only apply changes for this file id
+    #[arg(long, value_name = "ID")]
+    pub file_id: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::error::ErrorKind;
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from([&["client"], args].concat())
+    }
+
+    fn error(args: &[&str]) -> ErrorKind {
+        parse(args).expect_err("invalid arguments").kind()
+    }
+
+    #[test]
+    fn defaults() {
+        let cli = parse(&[]).expect("parse");
+        assert!(cli.command.is_none());
+        assert_eq!(cli.client_id(), "1");
+        assert_eq!(cli.output_dir, "client");
+        assert_eq!(cli.server_url.as_str(), "ws://localhost:3030/");
+        assert_eq!(cli.jitter, Jitter::Fixed);
+        assert_eq!(cli.on_apply_error, ApplyErrorPolicy::Resync);
+        assert_eq!(cli.tcp_options(), TcpOptions::default());
+        assert!(cli.sinks.is_empty() && !cli.tail && !cli.snapshot);
+    }
+
+    #[test]
+    fn client_id_comes_from_the_argument_or_client_id() {
+        assert_eq!(parse(&["7"]).expect("parse").client_id(), "7");
+        assert_eq!(parse(&["--client-id", "8"]).expect("parse").client_id(), "8");
+        assert_eq!(error(&["7", "--client-id", "8"]), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn conflicting_options_are_rejected() {
+        assert_eq!(error(&["--range", "a.md:0-10", "--from", "a.md:5"]), ErrorKind::ArgumentConflict);
+        assert_eq!(error(&["--tail", "--sink", "stdout"]), ErrorKind::ArgumentConflict);
+        assert_eq!(error(&["--snapshot", "--tail"]), ErrorKind::ArgumentConflict);
+        assert_eq!(error(&["--snapshot-history", "3", "--git-commit"]), ErrorKind::ArgumentConflict);
+        assert_eq!(error(&["--long-poll"]), ErrorKind::MissingRequiredArgument);
+        assert_eq!(error(&["--tls-cert", "cert.pem"]), ErrorKind::MissingRequiredArgument);
+        // options belong to mirroring, not to the subcommands
+        assert_eq!(error(&["status", "--tail"]), ErrorKind::UnknownArgument);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert_eq!(error(&["--server-url", "not a url"]), ErrorKind::ValueValidation);
+        assert_eq!(error(&["--unknown"]), ErrorKind::UnknownArgument);
+        assert_eq!(error(&["--worker-threads", "0"]), ErrorKind::ValueValidation);
+        assert_eq!(error(&["--tcp-keepalive-interval-secs", "0"]), ErrorKind::ValueValidation);
+        assert_eq!(error(&["--range", "a.md:10-5"]), ErrorKind::ValueValidation);
+        assert_eq!(error(&["--from", "a.md"]), ErrorKind::ValueValidation);
+        assert_eq!(error(&["--output-dir-mode", "8"]), ErrorKind::ValueValidation);
+        assert_eq!(error(&["--output-dir-mode", "17777"]), ErrorKind::ValueValidation);
+        assert_eq!(error(&["--jitter", "some"]), ErrorKind::InvalidValue);
+        assert_eq!(error(&["--verify-key", "not base64"]), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn specs_are_parsed() {
+        let cli = parse(&["--range", "docs/a.md:10-20", "--output-dir-mode", "0o750"]).expect("parse");
+        let range = cli.range.expect("range");
+        assert_eq!((range.file_id.as_str(), range.start, range.end), ("docs/a.md", 10, 20));
+        assert_eq!(cli.output_dir_mode, Some(0o750));
+        let from = parse(&["--from", "C:/a.md:5"]).expect("parse").from.expect("from");
+        assert_eq!((from.file_id.as_str(), from.offset), ("C:/a.md", 5));
+    }
+}
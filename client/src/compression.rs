@@ -0,0 +1,26 @@
+/// Whether the server should compress the messages it sends, switched at
+/// runtime with SIGUSR1, e.g. after moving to a metered connection
+pub struct CompressionToggle {
+    pub enabled: bool,
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl CompressionToggle {
+    pub fn new(enabled: bool) -> std::io::Result<Self> {
+        Ok(Self {
+            enabled,
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?,
+        })
+    }
+
+    /// Waits until compression is switched, then flips `enabled`
+    pub async fn toggled(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+        self.enabled = !self.enabled;
+    }
+}
@@ -0,0 +1,45 @@
+use std::borrow::Cow;
+use futures_util::{SinkExt, StreamExt};
+use tokio::time::Duration;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use shared::{ClientMessage, Control, ControlReply, ServerStatus};
+use crate::cli::Cli;
+
+/// How long to wait for the server to answer a status request
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connects to the server only to send one control command
+pub async fn send(cli: &Cli, control: Control) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = crate::connect(cli, &cli.server_url).await?;
+    let message = serde_json::to_string(&ClientMessage::Control(control))?;
+    stream.send(Message::Text(message)).await?;
+    stream.close(None).await?;
+    println!("Sent {:?} to {}", control, cli.server_url);
+    Ok(())
+}
+
+/// Asks the server for its status. The initial content the server sends every
+/// connection arrives first and is skipped.
+pub async fn status(cli: &Cli) -> Result<ServerStatus, Box<dyn std::error::Error>> {
+    let mut stream = crate::connect(cli, &cli.server_url).await?;
+    let message = serde_json::to_string(&ClientMessage::Control(Control::Status))?;
+    stream.send(Message::Text(message)).await?;
+    let reply = tokio::time::timeout(REPLY_TIMEOUT, async {
+        while let Some(message) = stream.next().await {
+            if let Message::Text(text) = message? {
+                let text = match &cli.verify_key {
+                    Some(verifier) => Cow::Owned(verifier.verify(&text)?),
+                    None => shared::signing::strip(&text),
+                };
+                if let Ok(ControlReply::Status(status)) = serde_json::from_str(&text) {
+                    return Ok(status);
+                }
+            }
+        }
+        Err("server closed the connection without answering".into())
+    })
+    .await
+    .map_err(|_| "timed out waiting for the server's status")?;
+    let _ = stream.close(None).await;
+    reply
+}
@@ -0,0 +1,84 @@
+use std::{fmt::Display, io, path::Path};
+use tokio_tungstenite::tungstenite;
+use crate::cli::Cli;
+use crate::error::ConnectError;
+
+/// File written to the output directory to check it is writable
+const PROBE_FILE: &str = ".markdown-op-doctor";
+
+/// Results of the checks, printed as they run
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn ok(&self, check: &str, detail: impl Display) {
+        println!("ok    {check}: {detail}");
+    }
+
+    fn fail(&mut self, check: &str, problem: impl Display, hint: impl Display) {
+        self.failures += 1;
+        println!("FAIL  {check}: {problem}");
+        println!("      {hint}");
+    }
+}
+
+/// Checks the output directory can be written and the server accepts a
+/// connection, returning whether every check passed
+pub async fn run(cli: &Cli) -> bool {
+    let mut report = Report::default();
+    check_output_dir(&mut report, Path::new(&cli.output_dir));
+    match crate::connect(cli, &cli.server_url).await {
+        Ok(mut stream) => {
+            let _ = stream.close(None).await;
+            report.ok("server", format!("{} accepted the connection", cli.server_url));
+        }
+        Err(e) => {
+            let hint = connect_hint(&e, cli);
+            report.fail("server", format!("{} is not reachable: {e}", cli.server_url), hint);
+        }
+    }
+    match report.failures {
+        0 => println!("All checks passed"),
+        1 => println!("1 check failed"),
+        n => println!("{n} checks failed"),
+    }
+    report.failures == 0
+}
+
+fn check_output_dir(report: &mut Report, dir: &Path) {
+    let check = "output";
+    let probe = dir.join(PROBE_FILE);
+    let written = std::fs::create_dir_all(dir).and_then(|()| std::fs::write(&probe, ""));
+    let _ = std::fs::remove_file(&probe);
+    match written {
+        Ok(()) => report.ok(check, format!("{} is writable", dir.display())),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => report.fail(
+            check,
+            format!("{} is not writable", dir.display()),
+            "give the user running the client write access, or pick another directory with OUTPUT_DIR",
+        ),
+        Err(e) => report.fail(check, format!("can't write to {}: {e}", dir.display()), "pick another directory with OUTPUT_DIR"),
+    }
+}
+
+/// What to try when connecting to the server failed with `error`
+fn connect_hint(error: &ConnectError, cli: &Cli) -> String {
+    match error {
+        ConnectError::Rejected(status) if status.as_u16() == 401 || status.as_u16() == 403 => {
+            "the server wants a token; set the one it was started with in MARKDOWN_OP_TOKEN".to_string()
+        }
+        ConnectError::Rejected(_) | ConnectError::Protocol(_) => {
+            "check SERVER_URL points at the server's WebSocket address, e.g. ws://127.0.0.1:3030".to_string()
+        }
+        ConnectError::Timeout => format!(
+            "nothing answered within {}ms; check a firewall isn't dropping the connection, or raise CONNECT_TIMEOUT_MS",
+            cli.connect_timeout_ms
+        ),
+        ConnectError::WebSocket(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            "nothing is listening there; start the server, or check the host and port in SERVER_URL".to_string()
+        }
+        _ => "check the host in SERVER_URL resolves and the network allows the connection".to_string(),
+    }
+}
@@ -0,0 +1,95 @@
+use shared::ApplyError;
+use tokio_tungstenite::tungstenite::{self, error::{CapacityError, ProtocolError}};
+
+/// Why a connection to the server ended with an error
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    #[error("server rejected the connection with {0}")]
+    Rejected(tungstenite::http::StatusCode),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("{0} corrupt messages in a row")]
+    Corrupt(u32),
+    #[error("connection timeout")]
+    Timeout,
+    #[error("TLS: {0}")]
+    Tls(String),
+    #[error("server sent a message of {size} bytes, over the --max-message-bytes limit of {max}")]
+    TooLarge { size: usize, max: usize },
+    #[error("cannot apply diff to {file_id}: {error}")]
+    ApplyFailed { file_id: String, error: ApplyError },
+    #[error(transparent)]
+    WebSocket(tungstenite::Error),
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error>),
+}
+
+impl ConnectError {
+    /// Errors that will not go away by retrying, such as a wrong token
+    pub fn is_fatal(&self) -> bool {
+        // the server would send the same message again after reconnecting, and
+        // --on-apply-error fail asks not to carry on after a desync
+        matches!(
+            self,
+            ConnectError::Rejected(_) | ConnectError::Protocol(_) | ConnectError::TooLarge { .. } | ConnectError::Tls(_) | ConnectError::ApplyFailed { .. }
+        )
+    }
+}
+
+impl From<tungstenite::Error> for ConnectError {
+    fn from(error: tungstenite::Error) -> Self {
+        match error {
+            tungstenite::Error::Http(response) if response.status().is_client_error() => {
+                ConnectError::Rejected(response.status())
+            }
+            tungstenite::Error::Url(e) => ConnectError::Protocol(e.to_string()),
+            tungstenite::Error::Capacity(CapacityError::MessageTooLong { size, max_size }) => {
+                ConnectError::TooLarge { size, max: max_size }
+            }
+            // the server going away mid-handshake or mid-stream is worth retrying
+            tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake | ProtocolError::HandshakeIncomplete) => {
+                ConnectError::WebSocket(error)
+            }
+            tungstenite::Error::Protocol(e) => ConnectError::Protocol(e.to_string()),
+            error => ConnectError::WebSocket(error),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConnectError {
+    fn from(error: std::io::Error) -> Self {
+        ConnectError::Other(Box::new(error))
+    }
+}
+
+impl From<serde_json::Error> for ConnectError {
+    fn from(error: serde_json::Error) -> Self {
+        ConnectError::Other(Box::new(error))
+    }
+}
+
+impl From<tungstenite::http::header::InvalidHeaderValue> for ConnectError {
+    fn from(error: tungstenite::http::header::InvalidHeaderValue) -> Self {
+        ConnectError::Other(Box::new(error))
+    }
+}
+
+/// Why a message from the server was not applied
+#[derive(Debug, thiserror::Error)]
+pub enum MessageError {
+    /// A well-formed change of a kind this client does not know, e.g. from a newer server
+    #[error("unknown kind of change {kind}")]
+    Unknown { kind: String, seq: Option<u64> },
+    /// A message that could not be read, with the file it was for when that is still recognizable
+    #[error("{error}")]
+    Corrupt { file_id: Option<String>, error: Box<dyn std::error::Error> },
+    /// A diff that does not fit the copy of its file, with `--on-apply-error fail`
+    #[error("cannot apply diff to {file_id}: {error}")]
+    ApplyFailed { file_id: String, error: ApplyError },
+}
+
+impl MessageError {
+    pub fn corrupt(error: impl Into<Box<dyn std::error::Error>>) -> Self {
+        MessageError::Corrupt { file_id: None, error: error.into() }
+    }
+}
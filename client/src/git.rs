@@ -0,0 +1,136 @@
+use std::path::{Component, Path, PathBuf};
+use git2::{Index, IndexEntry, IndexTime, Repository, Signature};
+
+/// Commits the mirrored files to the git repository the output directory is
+/// in, one commit per change, see `--git-commit`. Only the mirrored files go
+/// into a commit: whatever else is changed or staged in the working tree is
+/// left as it is.
+#[derive(Debug, Clone)]
+pub struct GitMirror {
+    workdir: PathBuf,
+}
+
+impl GitMirror {
+    /// Finds the repository `output_dir` is in
+    pub fn discover(output_dir: &Path) -> Result<Self, git2::Error> {
+        let repo = Repository::discover(output_dir)?;
+        let workdir = repo.workdir().ok_or_else(|| git2::Error::from_str("a bare repository has no working tree to mirror into"))?;
+        let workdir = workdir.canonicalize().map_err(|e| git2::Error::from_str(&e.to_string()))?;
+        Ok(Self { workdir })
+    }
+
+    /// Commits `paths` as they are on disk on top of HEAD, with a message naming
+    /// the file and seq, and stages them so they don't show up as modified.
+    /// Paths outside the working tree are skipped; returns whether anything was committed.
+    pub fn commit(&self, paths: &[PathBuf], file_id: &str, seq: u64) -> Result<bool, git2::Error> {
+        let repo = Repository::open(&self.workdir)?;
+        let head = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        // built from HEAD rather than the repository's index, which may hold
+        // changes staged by someone else
+        let mut index = Index::new()?;
+        if let Some(head) = &head {
+            index.read_tree(&head.tree()?)?;
+        }
+        let mut relative_paths = Vec::new();
+        for path in paths {
+            let Some(relative) = self.relative(path) else {
+                continue;
+            };
+            let content = std::fs::read(path).map_err(|e| git2::Error::from_str(&format!("{}: {}", path.display(), e)))?;
+            index.add(&entry(&relative, content.len(), repo.blob(&content)?))?;
+            relative_paths.push(relative);
+        }
+        let tree = repo.find_tree(index.write_tree_to(&repo)?)?;
+        if head.as_ref().is_some_and(|head| head.tree_id() == tree.id()) {
+            return Ok(false);
+        }
+        let signature = repo.signature().or_else(|_| Signature::now("markdown-op", "markdown-op@localhost"))?;
+        let message = format!("Mirror {} at seq {}", file_id, seq);
+        let parents: Vec<_> = head.iter().collect();
+        repo.commit(Some("HEAD"), &signature, &signature, &message, &tree, &parents)?;
+        let mut staged = repo.index()?;
+        for relative in &relative_paths {
+            staged.add_path(Path::new(relative))?;
+        }
+        staged.write()?;
+        Ok(true)
+    }
+
+    /// `path` relative to the working tree, with `/` separators as git stores it
+    fn relative(&self, path: &Path) -> Option<String> {
+        let absolute = path.canonicalize().ok()?;
+        let relative = absolute.strip_prefix(&self.workdir).ok()?;
+        let names: Vec<_> = relative
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+        Some(names.join("/"))
+    }
+}
+
+/// An index entry for a regular file whose content is the blob `id`
+fn entry(path: &str, size: usize, id: git2::Oid) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode: 0o100644,
+        uid: 0,
+        gid: 0,
+        file_size: size as u32,
+        id,
+        flags: 0,
+        flags_extended: 0,
+        path: path.as_bytes().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit_messages(repo: &Repository) -> Vec<String> {
+        let mut walk = repo.revwalk().expect("revwalk");
+        walk.push_head().expect("head");
+        walk.map(|oid| repo.find_commit(oid.expect("oid")).expect("commit").message().unwrap_or_default().to_string()).collect()
+    }
+
+    #[test]
+    fn every_change_is_one_commit_of_only_the_mirrored_file() {
+        let dir = std::env::temp_dir().join(format!("markdown-op-git-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).expect("init");
+        let output_dir = dir.join("mirror");
+        std::fs::create_dir_all(&output_dir).expect("create dir");
+        // someone else's work in progress, which must stay out of the commits
+        std::fs::write(dir.join("notes.txt"), "draft\n").expect("write");
+        let mut staged = repo.index().expect("index");
+        staged.add_path(Path::new("notes.txt")).expect("stage");
+        staged.write().expect("write index");
+
+        let git = GitMirror::discover(&output_dir).expect("discover");
+        let file = output_dir.join("doc.md");
+        std::fs::write(&file, "# Title\n").expect("write");
+        assert!(git.commit(std::slice::from_ref(&file), "doc.md", 1).expect("commit"));
+        std::fs::write(&file, "# Title\n\nMore.\n").expect("write");
+        assert!(git.commit(std::slice::from_ref(&file), "doc.md", 2).expect("commit"));
+        // nothing changed, e.g. full content sent again
+        assert!(!git.commit(std::slice::from_ref(&file), "doc.md", 3).expect("commit"));
+
+        assert_eq!(commit_messages(&repo), ["Mirror doc.md at seq 2", "Mirror doc.md at seq 1"]);
+        let tree = repo.head().and_then(|head| head.peel_to_tree()).expect("tree");
+        let blob = tree.get_path(Path::new("mirror/doc.md")).and_then(|entry| entry.to_object(&repo)).expect("mirrored file");
+        assert_eq!(blob.as_blob().map(git2::Blob::content), Some(&b"# Title\n\nMore.\n"[..]));
+        assert!(tree.get_path(Path::new("notes.txt")).is_err());
+        // still staged, and the mirrored file is not shown as modified
+        let statuses = repo.statuses(None).expect("status");
+        let status_of = |path: &str| statuses.iter().find(|entry| entry.path() == Some(path)).map(|entry| entry.status());
+        assert_eq!(status_of("notes.txt"), Some(git2::Status::INDEX_NEW));
+        assert_eq!(status_of("mirror/doc.md"), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
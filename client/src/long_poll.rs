@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, time::Duration};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use url::Url;
+use shared::{seq::SeqTracker, ClientMessage, Received};
+use crate::cli::Cli;
+use crate::error::{ConnectError, MessageError};
+use crate::output::Output;
+use crate::MirroredFile;
+use crate::shutdown::Shutdown;
+
+/// How long to wait for a poll to be answered; the server holds it for 25s at most
+const POLL_TIMEOUT: Duration = Duration::from_secs(40);
+
+/// Mirrors the files by polling `GET /changes?since=N` on the server's
+/// long-poll endpoint, for networks where WebSocket connections are blocked
+pub async fn poll_and_process(
+    cli: &Cli,
+    base_url: &Url,
+    output: &Output,
+    file_contents: &mut HashMap<String, MirroredFile>,
+    last_seq: &mut SeqTracker,
+    shutdown: &mut Shutdown,
+) -> Result<(), ConnectError> {
+    if base_url.scheme() != "http" {
+        return Err(ConnectError::Protocol(format!("long-poll URL {} must start with http://", base_url)));
+    }
+    let changes_url = base_url.join("changes").map_err(|e| ConnectError::Protocol(e.to_string()))?;
+    if cli.range.is_some() || cli.from.is_some() {
+        eprintln!("Range subscriptions are not supported when long-polling, mirroring whole files");
+    }
+    if !cli.follow.is_empty() {
+        eprintln!("--follow is not supported when long-polling, mirroring every file");
+    }
+    println!("Long-polling {}", changes_url);
+    loop {
+        let mut url = changes_url.clone();
+        if let Some(seq) = last_seq.last() {
+            url.query_pairs_mut().append_pair("since", &seq.to_string());
+        }
+        let poll = tokio::time::timeout(POLL_TIMEOUT, get(&url, cli.token.as_deref()));
+        let body = tokio::select! {
+            body = poll => match body {
+                Ok(body) => body?,
+                Err(_) => return Err(ConnectError::Timeout),
+            },
+            _ = shutdown.requested() => return Ok(()),
+        };
+        let changes: Vec<serde_json::Value> = serde_json::from_str(&body)?;
+        for change in changes {
+            let change = match &cli.verify_key {
+                // the signature covers the message text, which survives the round trip through a `Value`
+                Some(verifier) => match verifier.verify(&change.to_string()) {
+                    Ok(message) => serde_json::from_str(&message)?,
+                    Err(e) => {
+                        eprintln!("Error processing message: {}", e);
+                        // a change was lost, so the next poll starts over with full content
+                        last_seq.reset();
+                        break;
+                    }
+                },
+                None => strip(change)?,
+            };
+            let message = match Received::from_value(change) {
+                Ok(Received::Change(message)) => message,
+                // only sent over WebSocket
+                Ok(Received::Sync(_)) => continue,
+                Ok(Received::Unknown { kind, seq }) => {
+                    eprintln!("Ignoring a change of unknown kind {}, the server may be newer than this client", kind);
+                    if let Some(seq) = seq {
+                        last_seq.observe(seq);
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Error processing message: {}", e);
+                    continue;
+                }
+            };
+            match crate::process_change(message, cli, output, file_contents).await {
+                Ok(ClientMessage::Ack { seq }) => {
+                    last_seq.observe(seq);
+                }
+                // the next poll without `since` answers with full content
+                Ok(_) => {
+                    last_seq.reset();
+                    break;
+                }
+                Err(MessageError::ApplyFailed { file_id, error }) => return Err(ConnectError::ApplyFailed { file_id, error }),
+                Err(e) => eprintln!("Error processing message: {}", e),
+            }
+        }
+    }
+}
+
+/// The change in a signed envelope, without checking the signature; for
+/// clients that were not given a key
+fn strip(change: serde_json::Value) -> Result<serde_json::Value, serde_json::Error> {
+    if change.get("Signed").is_none() {
+        return Ok(change);
+    }
+    serde_json::from_str(&shared::signing::strip(&change.to_string()))
+}
+
+/// A minimal HTTP/1.1 GET returning the body of a 2xx response
+async fn get(url: &Url, token: Option<&str>) -> Result<String, ConnectError> {
+    let host = url.host_str().ok_or_else(|| ConnectError::Protocol(format!("URL {} has no host", url)))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target = format!("{target}?{query}");
+    }
+    let mut stream = TcpStream::connect((host, port)).await?;
+    let mut request = format!("GET {target} HTTP/1.1\r\nHost: {host}:{port}\r\nAccept: application/json\r\nConnection: close\r\n");
+    if let Some(token) = token {
+        request.push_str(&format!("Authorization: Bearer {token}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| ConnectError::Protocol("malformed HTTP response".to_string()))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .ok_or_else(|| ConnectError::Protocol(format!("malformed HTTP status line {:?}", head.lines().next())))?;
+    if status.is_client_error() {
+        return Err(ConnectError::Rejected(status));
+    }
+    if !status.is_success() {
+        return Err(ConnectError::Other(format!("server answered {}", status).into()));
+    }
+    Ok(body.to_string())
+}
@@ -1,69 +1,425 @@
-use std::{collections::HashMap, env, path::Path};
-use futures_util::StreamExt;
-use tokio::{fs, io::{AsyncWriteExt, BufWriter}, time::{sleep, Duration}};
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
-use shared::FileChange;
-use shared::protocol::DEFAULT_SERVER_URL;
-use url::Url;
+mod cli;
+mod compression;
+mod control;
+mod doctor;
+mod error;
+mod git;
+mod long_poll;
+mod output;
+mod replay;
+mod shutdown;
+mod sink;
+mod snapshot;
+mod tls;
+
+use std::{borrow::Cow, collections::HashMap, path::Path, time::SystemTime};
+use clap::Parser;
+use rand::Rng;
+use futures_util::{SinkExt, StreamExt};
+use tokio::{net::TcpStream, time::{sleep, Duration, Instant}};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{client_async_with_config, WebSocketStream};
+use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest, http::header::AUTHORIZATION, protocol::{Message, WebSocketConfig}};
+use url::{Host, Url};
+use shared::{chunks, seq::SeqTracker, ApplyError, ChecksumAlgorithm, ClientMessage, FileChange, Received, Sequenced, SyncProgress};
+use crate::cli::{ApplyErrorPolicy, Cli, Command, Jitter};
+use crate::compression::CompressionToggle;
+use crate::error::{ConnectError, MessageError};
+use crate::output::Output;
+use crate::shutdown::Shutdown;
 
 const MAX_RECONNECT_ATTEMPTS: u32 = 15;
 const INITIAL_RECONNECT_DELAY_MS: u64 = 100;
 const MAX_RECONNECT_DELAY_MS: u64 = 2000;
+/// Most random delay `Jitter::Fixed` adds to a reconnect
+const MAX_JITTER_MS: u64 = 100;
+/// Consecutive failed attempts after which the client backs off for a long cool-down
+const CIRCUIT_BREAKER_THRESHOLD: u32 = 5;
+const CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+/// A failed attempt that took less than this never reached the server, e.g. a refused connect
+const FAST_FAILURE: Duration = Duration::from_millis(200);
+/// Delay before retrying a fast failure within the grace period
+const GRACE_RETRY_DELAY: Duration = Duration::from_millis(50);
+/// Unreadable messages in a row after which the client reconnects for a fresh copy of every file
+const MAX_CORRUPT_MESSAGES: u32 = 3;
+/// How long to wait for the server to answer our close frame on shutdown
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    if let Some(Command::ApplyLog(args)) = &cli.command {
+        if let Err(e) = replay::apply_log(args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(Command::Control(args)) = &cli.command {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        if let Err(e) = runtime.block_on(control::send(&cli, args.action.into())) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(Command::Status) = &cli.command {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        match runtime.block_on(control::status(&cli)) {
+            Ok(status) => println!("{}", serde_json::to_string_pretty(&status)?),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if let Some(Command::Doctor) = &cli.command {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        if !runtime.block_on(doctor::run(&cli)) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if cli.snapshot {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        if let Err(e) = runtime.block_on(snapshot::run(&cli)) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let runtime = shared::runtime::multi_thread(cli.worker_threads)?;
+    if let Err(e) = runtime.block_on(run(cli)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
     println!("Starting Markdown Mirror Client");
-    let client_id = env::args().nth(1).unwrap_or_else(|| "1".to_string());
-    let output_dir = env::var("OUTPUT_DIR").unwrap_or_else(|_| "client".to_string());
+    let client_id = cli.client_id();
+    let output_dir = cli.output_dir.clone();
     println!("Client ID: {}", client_id);
     println!("Output directory: {}", output_dir);
-    fs::create_dir_all(&output_dir).await?;
+    println!("Worker threads: {}", tokio::runtime::Handle::current().metrics().num_workers());
+    output::prepare_dir(Path::new(&output_dir), cli.output_dir_mode)?;
+    let output = Output::from_cli(&cli, &client_id)?;
+    let mut shutdown = Shutdown::on_ctrl_c();
+    let result = mirror(&cli, &output, &mut shutdown).await;
+    if shutdown.is_requested() && cli.mirror_delete_on_exit {
+        output.remove_written().await;
+    }
+    result
+}
+
+/// The client's copy of a file
+struct MirroredFile {
+    content: String,
+    /// Seq of the full content the copy was rebuilt from; earlier diffs are
+    /// already part of it, while diffs with the same seq were made from it
+    seq: u64,
+    /// Set while the copy waits for the chunks that bring it up to date
+    awaiting: Option<AwaitingChunks>,
+}
+
+/// A hash list the client requested chunks for, see [`shared::chunks`]
+struct AwaitingChunks {
+    chunk_size: usize,
+    chunk_count: usize,
+    algorithm: ChecksumAlgorithm,
+    /// Checksum the rebuilt content must have
+    digest: String,
+    last_modified: Option<SystemTime>,
+    /// Diffs that arrived meanwhile, applied once the file is rebuilt
+    diffs: Vec<FileChange>,
+}
+
+/// Mirrors the files until the server closes the connection or the client is
+/// interrupted, reconnecting on errors
+async fn mirror(cli: &Cli, output: &Output, shutdown: &mut Shutdown) -> Result<(), Box<dyn std::error::Error>> {
     let mut file_contents = HashMap::new();
-    let mut attempt = 0;
+    let mut last_seq = SeqTracker::new();
+    let mut compression = CompressionToggle::new(cli.compress)?;
+    let mut failures = Failures::default();
     let mut reconnect_delay = INITIAL_RECONNECT_DELAY_MS;
+    let mut rng = rand::thread_rng();
+    // when the current run of failures started
+    let mut failing_since = None;
     loop {
-        match connect_and_process(&client_id, &output_dir, &mut file_contents).await {
+        let seq_before = last_seq;
+        let started = Instant::now();
+        let result = connect_and_process(cli, output, &mut file_contents, &mut last_seq, &mut compression, shutdown).await;
+        if shutdown.is_requested() {
+            break;
+        }
+        match result {
             Ok(_) => {
                 println!("Connection closed normally");
                 break;
             }
+            Err(e) if e.is_fatal() => {
+                eprintln!("Giving up, retrying cannot fix this error");
+                return Err(e.into());
+            }
             Err(e) => {
-                attempt += 1;
-                if attempt >= MAX_RECONNECT_ATTEMPTS {
-                    eprintln!("Max reconnection attempts reached. Exiting.");
-                    return Err(e);
+                // a connection that delivered changes was healthy, only count failures in a row
+                if last_seq != seq_before {
+                    failures.in_a_row = 0;
+                    reconnect_delay = INITIAL_RECONNECT_DELAY_MS;
+                    failing_since = None;
+                }
+                let failing_for = failing_since.get_or_insert(started).elapsed();
+                if within_grace(started.elapsed(), failing_for, Duration::from_millis(cli.grace_ms)) {
+                    eprintln!("Connection error: {}. Retrying in {}ms (grace period)", e, GRACE_RETRY_DELAY.as_millis());
+                    tokio::select! {
+                        _ = sleep(GRACE_RETRY_DELAY) => {}
+                        _ = shutdown.requested() => break,
+                    }
+                    continue;
                 }
-                let jitter = (rand::random::<u64>() % 100) as u64;
-                let delay = (reconnect_delay + jitter).min(MAX_RECONNECT_DELAY_MS);
-                eprintln!("Connection error: {}. Reconnecting in {}ms (attempt {}/{})", e, delay, attempt, MAX_RECONNECT_ATTEMPTS);
-                sleep(Duration::from_millis(delay)).await;
-                reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY_MS);
+                match failures.record() {
+                    Retry::GiveUp => {
+                        eprintln!("Max reconnection attempts reached. Exiting.");
+                        return Err(e.into());
+                    }
+                    Retry::CoolDown => {
+                        eprintln!(
+                            "Connection error: {}. {} failures in a row, cooling down for {}s (attempt {}/{})",
+                            e, CIRCUIT_BREAKER_THRESHOLD, CIRCUIT_BREAKER_COOLDOWN.as_secs(), failures.attempts, MAX_RECONNECT_ATTEMPTS
+                        );
+                        tokio::select! {
+                            _ = sleep(CIRCUIT_BREAKER_COOLDOWN) => {}
+                            _ = shutdown.requested() => break,
+                        }
+                        reconnect_delay = INITIAL_RECONNECT_DELAY_MS;
+                        continue;
+                    }
+                    Retry::Backoff => {}
+                }
+                let (delay, next_delay) = jittered_backoff(cli.jitter, reconnect_delay, &mut rng);
+                eprintln!("Connection error: {}. Reconnecting in {}ms (attempt {}/{})", e, delay, failures.attempts, MAX_RECONNECT_ATTEMPTS);
+                tokio::select! {
+                    _ = sleep(Duration::from_millis(delay)) => {}
+                    _ = shutdown.requested() => break,
+                }
+                reconnect_delay = next_delay;
             }
         }
     }
     Ok(())
 }
 
+/// Failed connections, counted against the reconnect limits
+#[derive(Debug, Default)]
+struct Failures {
+    /// Every counted failure since the client started
+    attempts: u32,
+    /// Failures since the last connection that delivered changes, or the last cool-down
+    in_a_row: u32,
+}
+
+/// What to do about a failed connection
+#[derive(Debug, PartialEq, Eq)]
+enum Retry {
+    /// Reconnect after the usual backoff
+    Backoff,
+    /// Too many failures in a row: wait `CIRCUIT_BREAKER_COOLDOWN` first
+    CoolDown,
+    /// Out of attempts
+    GiveUp,
+}
+
+impl Failures {
+    /// Counts a failure and says how to go on after it
+    fn record(&mut self) -> Retry {
+        self.attempts += 1;
+        if self.attempts >= MAX_RECONNECT_ATTEMPTS {
+            return Retry::GiveUp;
+        }
+        self.in_a_row += 1;
+        if self.in_a_row >= CIRCUIT_BREAKER_THRESHOLD {
+            self.in_a_row = 0;
+            return Retry::CoolDown;
+        }
+        Retry::Backoff
+    }
+}
+
+/// Whether a failed attempt that took `attempt_took` is retried without
+/// counting it: only fast failures, and only until the failures in a row have
+/// gone on for `grace`, so a blip costs no attempts but a server that is down does
+fn within_grace(attempt_took: Duration, failing_for: Duration, grace: Duration) -> bool {
+    attempt_took < FAST_FAILURE && failing_for < grace
+}
+
+/// The delay before the next reconnect, `current` plus `jitter` but at most
+/// `MAX_RECONNECT_DELAY_MS`, and the `current` for the reconnect after it,
+/// doubled up to the same cap
+fn next_backoff(current: u64, jitter: u64) -> (u64, u64) {
+    let delay = current.saturating_add(jitter).min(MAX_RECONNECT_DELAY_MS);
+    let next = current.saturating_mul(2).min(MAX_RECONNECT_DELAY_MS);
+    (delay, next)
+}
+
+/// Like [`next_backoff`], with the jitter drawn from `rng` the way `jitter`
+/// says. With `Jitter::Decorrelated` the next `current` is the delay itself,
+/// which the one after it is drawn around, rather than the doubled delay.
+fn jittered_backoff(jitter: Jitter, current: u64, rng: &mut impl Rng) -> (u64, u64) {
+    match jitter {
+        Jitter::None => next_backoff(current, 0),
+        Jitter::Fixed => next_backoff(current, rng.gen_range(0..MAX_JITTER_MS)),
+        Jitter::Full => {
+            let (delay, next) = next_backoff(current, 0);
+            (rng.gen_range(0..=delay), next)
+        }
+        Jitter::Decorrelated => {
+            let upper = current.saturating_mul(3).max(INITIAL_RECONNECT_DELAY_MS);
+            let delay = rng.gen_range(INITIAL_RECONNECT_DELAY_MS..=upper).min(MAX_RECONNECT_DELAY_MS);
+            (delay, delay)
+        }
+    }
+}
+
 async fn connect_and_process(
-    client_id: &str,
-    output_dir: &str,
-    file_contents: &mut HashMap<String, String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let url = Url::parse(DEFAULT_SERVER_URL)?;
-    let connect_result = tokio::time::timeout(Duration::from_secs(5), connect_async(url)).await;
-    let (ws_stream, _) = match connect_result {
-        Ok(Ok(stream)) => stream,
-        Ok(Err(e)) => return Err(Box::new(e)),
-        Err(_) => return Err("Connection timeout".into()),
+    cli: &Cli,
+    output: &Output,
+    file_contents: &mut HashMap<String, MirroredFile>,
+    last_seq: &mut SeqTracker,
+    compression: &mut CompressionToggle,
+    shutdown: &mut Shutdown,
+) -> Result<(), ConnectError> {
+    if let (true, Some(long_poll_url)) = (cli.long_poll, &cli.long_poll_url) {
+        return long_poll::poll_and_process(cli, long_poll_url, output, file_contents, last_seq, shutdown).await;
+    }
+    let mut url = cli.server_url.clone();
+    // lets the server replay what was missed instead of resending everything
+    if let Some(seq) = last_seq.last() {
+        url.query_pairs_mut().append_pair("since", &seq.to_string());
+    }
+    for file_id in &cli.follow {
+        url.query_pairs_mut().append_pair("file", file_id);
+    }
+    // with copies to compare, large files only need the chunks that changed
+    if !file_contents.is_empty() && cli.range.is_none() && cli.from.is_none() {
+        url.query_pairs_mut().append_pair("chunks", "1");
+        // when there is no replay from `since`, e.g. the server restarted, a
+        // copy the server had in history only needs the diffs since
+        for (file_id, file) in file_contents.iter_mut().filter(|(_, file)| file.awaiting.is_none()) {
+            url.query_pairs_mut().append_pair("digest", &format!("{}:{}", file_id, shared::digest(&file.content)));
+            // the seqs of another connection, or another server run, say nothing about this one's
+            file.seq = 0;
+        }
+    }
+    let connected = tokio::select! {
+        connected = connect(cli, &url) => connected,
+        _ = shutdown.requested() => return Ok(()),
+    };
+    let ws_stream = match (connected, &cli.long_poll_url) {
+        (Ok(ws_stream), _) => ws_stream,
+        (Err(e), Some(long_poll_url)) => {
+            eprintln!("WebSocket connection failed: {}, falling back to long-polling", e);
+            return long_poll::poll_and_process(cli, long_poll_url, output, file_contents, last_seq, shutdown).await;
+        }
+        (Err(e), None) => return Err(e),
     };
     println!("Connected to server");
-    let (_, mut read) = ws_stream.split();
-    while let Some(msg) = read.next().await {
+    let (mut write, mut read) = ws_stream.split();
+    if let Some(range) = &cli.range {
+        let subscribe = ClientMessage::SubscribeRange {
+            file_id: range.file_id.clone(),
+            start: range.start,
+            end: range.end,
+        };
+        write.send(Message::Text(serde_json::to_string(&subscribe)?)).await?;
+    }
+    if let Some(from) = &cli.from {
+        let subscribe = ClientMessage::SubscribeFrom { file_id: from.file_id.clone(), offset: from.offset };
+        write.send(Message::Text(serde_json::to_string(&subscribe)?)).await?;
+    }
+    if compression.enabled {
+        let set_compression = ClientMessage::SetCompression { enabled: true };
+        write.send(Message::Text(serde_json::to_string(&set_compression)?)).await?;
+    }
+    let mut corrupt_in_a_row = 0;
+    loop {
+        let msg = tokio::select! {
+            msg = read.next() => msg,
+            _ = compression.toggled() => {
+                println!("Compression {}", if compression.enabled { "on" } else { "off" });
+                let set_compression = ClientMessage::SetCompression { enabled: compression.enabled };
+                write.send(Message::Text(serde_json::to_string(&set_compression)?)).await?;
+                continue;
+            }
+            _ = shutdown.requested() => {
+                println!("Closing the connection");
+                write.send(Message::Close(None)).await?;
+                // the server answers with its own close frame, which ends the stream
+                let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+                    while let Some(Ok(msg)) = read.next().await {
+                        if let Message::Close(_) = msg {
+                            break;
+                        }
+                    }
+                })
+                .await;
+                return Ok(());
+            }
+        };
+        let Some(msg) = msg else {
+            break;
+        };
         match msg {
             Ok(Message::Text(text)) => {
-                if let Err(e) = process_message(&text, client_id, output_dir, file_contents).await {
-                    eprintln!("Error processing message: {}", e);
+                let reply = match process_message(&text, cli, output, file_contents).await {
+                    Ok(Some(reply)) => {
+                        corrupt_in_a_row = 0;
+                        reply
+                    }
+                    Ok(None) => {
+                        corrupt_in_a_row = 0;
+                        continue;
+                    }
+                    // a later server may send kinds of changes this client can do without
+                    Err(MessageError::Unknown { kind, seq }) => {
+                        corrupt_in_a_row = 0;
+                        // the answer of a server run with --echo to each message this client sends
+                        if kind != "Echo" {
+                            eprintln!("Ignoring a change of unknown kind {}, the server may be newer than this client", kind);
+                        }
+                        let Some(seq) = seq else {
+                            continue;
+                        };
+                        ClientMessage::Ack { seq }
+                    }
+                    Err(MessageError::Corrupt { file_id, error }) => {
+                        corrupt_in_a_row += 1;
+                        eprintln!("Error processing message: {}", error);
+                        if corrupt_in_a_row >= MAX_CORRUPT_MESSAGES {
+                            eprintln!("{} corrupt messages in a row, reconnecting for a fresh copy of every file", corrupt_in_a_row);
+                            // without `since`, the server starts over with full content
+                            file_contents.clear();
+                            last_seq.reset();
+                            let _ = write.send(Message::Close(None)).await;
+                            return Err(ConnectError::Corrupt(corrupt_in_a_row));
+                        }
+                        // the change is lost, so the copy of its file can't be trusted anymore
+                        let Some(file_id) = file_id else {
+                            continue;
+                        };
+                        eprintln!("Requesting resync of {}", file_id);
+                        file_contents.remove(&file_id);
+                        ClientMessage::Resync { file_id }
+                    }
+                    Err(MessageError::ApplyFailed { file_id, error }) => {
+                        let _ = write.send(Message::Close(None)).await;
+                        return Err(ConnectError::ApplyFailed { file_id, error });
+                    }
+                };
+                if let ClientMessage::Ack { seq } = reply {
+                    last_seq.observe(seq);
                 }
+                write.send(Message::Text(serde_json::to_string(&reply)?)).await?;
             }
             Ok(Message::Close(_)) => {
                 println!("Server closed connection");
@@ -72,7 +428,7 @@ async fn connect_and_process(
             Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => {}
             Err(e) => {
                 eprintln!("WebSocket error: {}", e);
-                return Err(Box::new(e));
+                return Err(e.into());
             }
             _ => {}
         }
@@ -80,39 +436,414 @@ async fn connect_and_process(
     Ok(())
 }
 
+/// The byte stream under the WebSocket: plain TCP, or TCP wrapped in TLS for `wss://`
+trait ByteStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ByteStream for S {}
+
+async fn connect(cli: &Cli, url: &Url) -> Result<WebSocketStream<Box<dyn ByteStream>>, ConnectError> {
+    let mut request = url.as_str().into_client_request()?;
+    if let Some(token) = &cli.token {
+        request.headers_mut().insert(AUTHORIZATION, format!("Bearer {token}").parse()?);
+    }
+    let config = WebSocketConfig {
+        max_message_size: Some(cli.max_message_bytes),
+        max_frame_size: Some(cli.max_message_bytes),
+        ..WebSocketConfig::default()
+    };
+    let connecting = async {
+        let host = match url.host() {
+            Some(Host::Domain(domain)) => domain.to_string(),
+            Some(Host::Ipv4(addr)) => addr.to_string(),
+            Some(Host::Ipv6(addr)) => addr.to_string(),
+            None => return Err(ConnectError::Protocol(format!("{url} has no host"))),
+        };
+        let port = url.port_or_known_default().unwrap_or(80);
+        let tcp = TcpStream::connect((host.as_str(), port)).await.map_err(tungstenite::Error::Io)?;
+        if let Err(e) = cli.tcp_options().apply(&tcp) {
+            eprintln!("Cannot set TCP options: {}", e);
+        }
+        let stream: Box<dyn ByteStream> = match url.scheme() {
+            "wss" => Box::new(tls::connect(cli, &host, tcp).await?),
+            _ => Box::new(tcp),
+        };
+        match client_async_with_config(request, stream, Some(config)).await {
+            Ok((stream, _)) => Ok(stream),
+            // e.g. the server turning down the client certificate, which TLS 1.3 reports after the handshake
+            Err(tungstenite::Error::Io(e)) => Err(tls::rejection(&e).unwrap_or_else(|| tungstenite::Error::Io(e).into())),
+            Err(e) => Err(e.into()),
+        }
+    };
+    match tokio::time::timeout(Duration::from_millis(cli.connect_timeout_ms), connecting).await {
+        Ok(result) => result,
+        Err(_) => Err(ConnectError::Timeout),
+    }
+}
+
+/// Handles one message from the server, returning the reply for it; progress
+/// reports get none
 async fn process_message(
     text: &str,
-    client_id: &str,
-    output_dir: &str,
-    file_contents: &mut HashMap<String, String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let change: FileChange = serde_json::from_str(text)?;
+    cli: &Cli,
+    output: &Output,
+    file_contents: &mut HashMap<String, MirroredFile>,
+) -> Result<Option<ClientMessage>, MessageError> {
+    let text = shared::compression::decompress(text).map_err(MessageError::corrupt)?;
+    let text = match &cli.verify_key {
+        Some(verifier) => Cow::Owned(verifier.verify(&text).map_err(MessageError::corrupt)?),
+        None => shared::signing::strip(&text),
+    };
+    match parse_change(&text)? {
+        Received::Sync(progress) => {
+            report_progress(&progress, cli);
+            Ok(None)
+        }
+        Received::Change(message) => process_change(message, cli, output, file_contents).await.map(Some),
+        Received::Unknown { kind, seq } => Err(MessageError::Unknown { kind, seq }),
+    }
+}
+
+/// Parses a message from the server. JSON that fails to parse only because it
+/// is a kind of change this client does not know is told apart from a corrupt message.
+fn parse_change(text: &str) -> Result<Received, MessageError> {
+    match Received::parse(text) {
+        Ok(Received::Unknown { kind, seq }) => Err(MessageError::Unknown { kind, seq }),
+        Ok(received) => Ok(received),
+        Err(error) => Err(MessageError::Corrupt { file_id: corrupt_file_id(text), error: error.into() }),
+    }
+}
+
+/// Prints how far the initial sync of a file got, see `server --sync-report`
+fn report_progress(progress: &SyncProgress, cli: &Cli) {
+    // stdout only carries the content when tailing
+    if cli.tail {
+        return;
+    }
+    match progress {
+        SyncProgress::SyncStart { file_id, total_bytes, seq } => println!("Syncing {} ({} bytes) as of seq {}", file_id, total_bytes, seq),
+        SyncProgress::SyncComplete { file_id, seq } => println!("Synced {} as of seq {}", file_id, seq),
+    }
+}
+
+/// The file a corrupt change was for, if that much of it can be read
+fn corrupt_file_id(text: &str) -> Option<String> {
+    let Ok(serde_json::Value::Object(fields)) = serde_json::from_str::<serde_json::Value>(text) else {
+        return None;
+    };
+    let change = fields.iter().find(|(key, _)| !Sequenced::ENVELOPE_FIELDS.contains(&key.as_str()));
+    change.and_then(|(_, fields)| fields.get("file_id")?.as_str()).map(str::to_string)
+}
+
+/// Applies one change from the server, returning the reply for it; with
+/// `--report-latency` prints how long after the server detected it that was
+async fn process_change(
+    message: Sequenced,
+    cli: &Cli,
+    output: &Output,
+    file_contents: &mut HashMap<String, MirroredFile>,
+) -> Result<ClientMessage, MessageError> {
+    let (seq, detected_at) = (message.seq, message.detected_at);
+    let reply = apply_change(message, cli, output, file_contents).await?;
+    if let (true, Some(detected_at)) = (cli.report_latency, detected_at) {
+        // the client's clock may be behind the server's
+        let latency = SystemTime::now().duration_since(detected_at).unwrap_or_default();
+        println!("Mirrored seq {} {}ms after the server detected it", seq, latency.as_millis());
+    }
+    Ok(reply)
+}
+
+async fn apply_change(
+    message: Sequenced,
+    cli: &Cli,
+    output: &Output,
+    file_contents: &mut HashMap<String, MirroredFile>,
+) -> Result<ClientMessage, MessageError> {
+    let Sequenced { seq, change, .. } = message;
     match &change {
-        FileChange::FullContent { file_id, content } => {
-            file_contents.insert(file_id.clone(), content.clone());
-            write_file(client_id, output_dir, content).await?;
-            println!("Updated file: client/client{}_README.md", client_id);
+        FileChange::FullContent { file_id, content, last_modified } => {
+            // a resync: the whole output is rebuilt from the new content
+            file_contents.insert(file_id.clone(), MirroredFile { content: content.clone(), seq, awaiting: None });
+            write_rebuilt(file_id, content, *last_modified, cli, output).await;
+        }
+        FileChange::Diff { file_id, .. } => {
+            // dropped after a failed diff, until the resync arrives
+            let Some(file) = file_contents.get_mut(file_id) else {
+                return Ok(ClientMessage::Ack { seq });
+            };
+            if let Some(awaiting) = &mut file.awaiting {
+                awaiting.diffs.push(change.clone());
+                return Ok(ClientMessage::Ack { seq });
+            }
+            // sent before the full content that replaced the copy, e.g. a resync
+            if seq < file.seq {
+                eprintln!("Ignoring diff {} to {}, the full content of seq {} already includes it", seq, file_id, file.seq);
+                return Ok(ClientMessage::Ack { seq });
+            }
+            let content = &mut file.content;
+            let appended = if cli.tail { change.appended_text(content.chars().count()) } else { None };
+            if let Err(error) = change.try_apply(content) {
+                return apply_failed(file_id, error, seq, cli, file_contents);
+            }
+            match appended {
+                Some(text) => output.append(text).await,
+                None => output.write(file_id, content).await,
+            }
+            if !cli.tail {
+                println!("Applied diff to file: {}", output.path(file_id).display());
+            }
+        }
+        FileChange::ChunkHashes { file_id, chunk_size, algorithm, hashes, digest, last_modified } => {
+            let content = file_contents.remove(file_id).map(|file| file.content).unwrap_or_default();
+            let indices = chunks::missing(&content, *chunk_size, hashes, *algorithm);
+            println!("Fetching {} of {} chunks of {}", indices.len(), hashes.len(), file_id);
+            let awaiting = AwaitingChunks {
+                chunk_size: *chunk_size,
+                chunk_count: hashes.len(),
+                algorithm: *algorithm,
+                digest: digest.clone(),
+                last_modified: *last_modified,
+                diffs: Vec::new(),
+            };
+            file_contents.insert(file_id.clone(), MirroredFile { content, seq, awaiting: Some(awaiting) });
+            // acked once the chunks arrive and the file is whole again
+            return Ok(ClientMessage::RequestChunks { file_id: file_id.clone(), indices });
+        }
+        FileChange::Chunks { file_id, chunks: received } => {
+            let Some(file) = file_contents.get_mut(file_id) else {
+                return Ok(ClientMessage::Ack { seq });
+            };
+            let Some(awaiting) = file.awaiting.take() else {
+                eprintln!("Ignoring chunks of {}, no hash list of it is pending", file_id);
+                return Ok(ClientMessage::Ack { seq });
+            };
+            let own = chunks::split(&file.content, awaiting.chunk_size);
+            let rebuilt: Option<String> = (0..awaiting.chunk_count)
+                .map(|index| received.get(&index).map(String::as_str).or_else(|| own.get(index).copied()))
+                .collect();
+            let mut content = match rebuilt {
+                Some(content) if awaiting.algorithm.checksum(&content) == awaiting.digest => content,
+                _ => {
+                    eprintln!("Chunks of {} don't add up to the server's copy, requesting resync", file_id);
+                    file_contents.remove(file_id);
+                    return Ok(ClientMessage::Resync { file_id: file_id.clone() });
+                }
+            };
+            for diff in &awaiting.diffs {
+                if let Err(error) = diff.try_apply(&mut content) {
+                    // skipping a queued diff still leaves the rebuilt file to write
+                    if cli.on_apply_error == ApplyErrorPolicy::Ignore {
+                        eprintln!("Cannot apply diff to {}: {}, ignoring it", file_id, error);
+                        continue;
+                    }
+                    return apply_failed(file_id, error, seq, cli, file_contents);
+                }
+            }
+            write_rebuilt(file_id, &content, awaiting.last_modified, cli, output).await;
+            *file = MirroredFile { content, seq, awaiting: None };
+        }
+        FileChange::ValidationError { file_id, message } => {
+            eprintln!("Server held back {}: {}", file_id, message);
+            return Ok(ClientMessage::Ack { seq });
         }
-        FileChange::Diff { file_id, position, delete_count, insert_text } => {
-            let content = file_contents.entry(file_id.clone()).or_insert_with(String::new);
-            if *position <= content.len() {
-                let end = (*position + *delete_count).min(content.len());
-                content.replace_range(*position..end, insert_text);
-                write_file(client_id, output_dir, content).await?;
-                println!("Applied diff to file: client/client{}_README.md", client_id);
-            } else {
-                eprintln!("Invalid diff position: {} for content length: {}", position, content.len());
+        FileChange::MetadataChanged { file_id, mode, .. } => {
+            match mode {
+                Some(mode) => println!("Metadata of {} changed, mode {:o}", file_id, mode & 0o7777),
+                None => println!("Metadata of {} changed", file_id),
             }
+            return Ok(ClientMessage::Ack { seq });
         }
     }
-    Ok(())
+    output.commit(change.file_id(), seq).await;
+    Ok(ClientMessage::Ack { seq })
 }
 
-async fn write_file(client_id: &str, output_dir: &str, content: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let output_path = Path::new(output_dir).join(format!("client{}_README.md", client_id));
-    let file = fs::File::create(&output_path).await?;
-    let mut writer = BufWriter::new(file);
-    writer.write_all(content.as_bytes()).await?;
-    writer.flush().await?;
-    Ok(())
+/// Handles a diff that does not fit the copy of its file as `--on-apply-error` says,
+/// returning the reply for it
+fn apply_failed(
+    file_id: &str,
+    error: ApplyError,
+    seq: u64,
+    cli: &Cli,
+    file_contents: &mut HashMap<String, MirroredFile>,
+) -> Result<ClientMessage, MessageError> {
+    match cli.on_apply_error {
+        ApplyErrorPolicy::Ignore => {
+            eprintln!("Cannot apply diff to {}: {}, ignoring it", file_id, error);
+            Ok(ClientMessage::Ack { seq })
+        }
+        ApplyErrorPolicy::Resync => {
+            eprintln!("Cannot apply diff to {}: {}, requesting resync", file_id, error);
+            file_contents.remove(file_id);
+            Ok(ClientMessage::Resync { file_id: file_id.to_string() })
+        }
+        ApplyErrorPolicy::Fail => Err(MessageError::ApplyFailed { file_id: file_id.to_string(), error }),
+    }
+}
+
+/// Writes a file that was rebuilt as a whole, from full content or chunks
+async fn write_rebuilt(file_id: &str, content: &str, last_modified: Option<SystemTime>, cli: &Cli, output: &Output) {
+    output.write(file_id, content).await;
+    if let (true, Some(modified)) = (cli.preserve_mtime, last_modified) {
+        output.set_modified(file_id, modified);
+    }
+    // stdout only carries the content when tailing
+    if !cli.tail {
+        println!("Updated file: {}", output.path(file_id).display());
+    }
+    if let Some(command) = &cli.on_resync {
+        output::spawn_resync_hook(command, &output.path(file_id), file_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use rand::{rngs::StdRng, SeedableRng};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+    use super::*;
+
+    #[test]
+    fn the_circuit_breaker_trips_every_threshold_failures_in_a_row() {
+        let mut failures = Failures::default();
+        let retries: Vec<Retry> = (1..MAX_RECONNECT_ATTEMPTS).map(|_| failures.record()).collect();
+        for (attempt, retry) in (1..).zip(&retries) {
+            let expected = if attempt % CIRCUIT_BREAKER_THRESHOLD == 0 { Retry::CoolDown } else { Retry::Backoff };
+            assert_eq!(*retry, expected, "attempt {attempt}");
+        }
+        assert_eq!(failures.record(), Retry::GiveUp);
+    }
+
+    #[test]
+    fn a_healthy_connection_restarts_the_run_of_failures() {
+        let mut failures = Failures::default();
+        for _ in 1..CIRCUIT_BREAKER_THRESHOLD {
+            assert_eq!(failures.record(), Retry::Backoff);
+        }
+        // what `mirror` does after a connection that delivered changes
+        failures.in_a_row = 0;
+        for _ in 1..CIRCUIT_BREAKER_THRESHOLD {
+            assert_eq!(failures.record(), Retry::Backoff);
+        }
+        assert_eq!(failures.record(), Retry::CoolDown);
+        // attempts are not given back, however healthy the connections in between
+        assert_eq!(failures.attempts, 2 * CIRCUIT_BREAKER_THRESHOLD - 1);
+    }
+
+    #[tokio::test]
+    async fn an_unauthorized_connection_is_not_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let url = format!("ws://{}", listener.local_addr().expect("address"));
+        let output_dir = std::env::temp_dir().join(format!("markdown-op-unauthorized-{}", std::process::id()));
+        let cli = Cli::try_parse_from(["client", "--server-url", &url, "--output-dir", &output_dir.to_string_lossy()]).expect("parse");
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("accept");
+            let mut request = [0; 4096];
+            let _ = socket.read(&mut request).await;
+            socket.write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n").await.expect("respond");
+            // a second attempt would be accepted here
+            tokio::time::timeout(Duration::from_millis(500), listener.accept()).await.is_err()
+        });
+        let result = tokio::time::timeout(Duration::from_secs(5), run(cli)).await.expect("the client gave up in time");
+        let _ = std::fs::remove_dir_all(&output_dir);
+        let error = result.expect_err("a rejected connection is an error");
+        assert!(error.to_string().contains("401"), "{error}");
+        assert!(server.await.expect("server task"), "the client connected again");
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut current = INITIAL_RECONNECT_DELAY_MS;
+        let mut delays = Vec::new();
+        for _ in 0..MAX_RECONNECT_ATTEMPTS {
+            let (delay, next) = next_backoff(current, 0);
+            delays.push(delay);
+            current = next;
+        }
+        assert_eq!(delays[..6], [100, 200, 400, 800, 1600, 2000]);
+        assert!(delays[5..].iter().all(|&delay| delay == MAX_RECONNECT_DELAY_MS));
+    }
+
+    #[test]
+    fn jitter_is_added_within_the_cap() {
+        for current in [INITIAL_RECONNECT_DELAY_MS, 800, 1950, MAX_RECONNECT_DELAY_MS] {
+            for jitter in [0, 1, MAX_JITTER_MS - 1] {
+                let (delay, next) = next_backoff(current, jitter);
+                assert!(delay >= current.min(MAX_RECONNECT_DELAY_MS));
+                assert!(delay <= (current + jitter).min(MAX_RECONNECT_DELAY_MS));
+                // jitter only delays this attempt, it does not compound
+                assert_eq!(next, next_backoff(current, 0).1);
+            }
+        }
+        assert_eq!(next_backoff(100, 42), (142, 200));
+        assert_eq!(next_backoff(1950, 99), (2000, 2000));
+    }
+
+    #[test]
+    fn decorrelated_jitter_stays_within_its_bounds() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut current = INITIAL_RECONNECT_DELAY_MS;
+        let mut delays = Vec::new();
+        for _ in 0..10_000 {
+            let (delay, next) = jittered_backoff(Jitter::Decorrelated, current, &mut rng);
+            assert!(delay >= INITIAL_RECONNECT_DELAY_MS, "{delay}ms after {current}ms");
+            assert!(delay <= (current * 3).min(MAX_RECONNECT_DELAY_MS), "{delay}ms after {current}ms");
+            assert_eq!(next, delay);
+            delays.push(delay);
+            current = next;
+        }
+        // spread out over the whole range rather than stuck at the cap or the floor
+        let mean = delays.iter().sum::<u64>() / delays.len() as u64;
+        assert!((800..=1800).contains(&mean), "mean delay {mean}ms");
+        assert!(delays.iter().any(|&delay| delay < 500));
+        assert!(delays.iter().filter(|&&delay| delay == MAX_RECONNECT_DELAY_MS).count() < delays.len() / 2);
+    }
+
+    #[test]
+    fn other_jitter_strategies_keep_the_exponential_schedule() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for current in [INITIAL_RECONNECT_DELAY_MS, 800, MAX_RECONNECT_DELAY_MS] {
+            let (_, next) = next_backoff(current, 0);
+            assert_eq!(jittered_backoff(Jitter::None, current, &mut rng), (current, next));
+            for _ in 0..1000 {
+                let (delay, after) = jittered_backoff(Jitter::Full, current, &mut rng);
+                assert!(delay <= current);
+                assert_eq!(after, next);
+                let (delay, after) = jittered_backoff(Jitter::Fixed, current, &mut rng);
+                assert!((current..(current + MAX_JITTER_MS).min(MAX_RECONNECT_DELAY_MS + 1)).contains(&delay));
+                assert_eq!(after, next);
+            }
+        }
+    }
+
+    #[test]
+    fn only_fast_failures_within_the_grace_period_are_free() {
+        let grace = Duration::from_millis(1000);
+        let instant = Duration::from_millis(1);
+        // a burst of refused connects right after the connection dropped
+        for failing_for in (0..1000).step_by(50).map(Duration::from_millis) {
+            assert!(within_grace(instant, failing_for, grace));
+        }
+        assert!(!within_grace(instant, grace, grace));
+        // an attempt that got as far as timing out counts straight away
+        assert!(!within_grace(FAST_FAILURE, Duration::ZERO, grace));
+        assert!(!within_grace(instant, Duration::ZERO, Duration::ZERO));
+    }
+
+    #[test]
+    fn backoff_does_not_overflow() {
+        assert_eq!(next_backoff(u64::MAX, u64::MAX), (MAX_RECONNECT_DELAY_MS, MAX_RECONNECT_DELAY_MS));
+    }
+
+    #[test]
+    fn unknown_kinds_of_change_are_told_apart_from_corrupt_messages() {
+        let unknown = parse_change(r#"{"seq":7,"Rename":{"file_id":"a.md","to":"b.md"}}"#);
+        assert!(matches!(unknown, Err(MessageError::Unknown { kind, seq: Some(7) }) if kind == "Rename"));
+        // a known kind with broken fields is corrupt, and still names its file
+        let broken = parse_change(r#"{"seq":8,"Diff":{"file_id":"a.md","position":"x"}}"#);
+        assert!(matches!(broken, Err(MessageError::Corrupt { file_id: Some(file_id), .. }) if file_id == "a.md"));
+        for corrupt in [r#"{"seq":9,"Diff":{"file_id""#, "not json", "[1,2]", r#"{"seq":10}"#] {
+            assert!(matches!(parse_change(corrupt), Err(MessageError::Corrupt { file_id: None, .. })), "{corrupt}");
+        }
+        assert!(parse_change(r#"{"seq":11,"ValidationError":{"file_id":"a.md","message":"unclosed fence"}}"#).is_ok());
+    }
 }
@@ -0,0 +1,433 @@
+use std::{borrow::Cow, collections::{hash_map::DefaultHasher, HashMap}, hash::{Hash, Hasher}, num::NonZeroUsize, path::{Component, Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Mutex}, time::SystemTime};
+use filetime::FileTime;
+use crate::cli::Cli;
+use crate::git::GitMirror;
+use crate::sink::Sink;
+
+const BOM: char = '\u{FEFF}';
+
+/// File written to the output directory at startup to check it is writable
+const PROBE_FILE: &str = ".markdown-op-probe";
+
+/// Creates the output directory, with `mode` on Unix if it did not exist yet,
+/// and checks a file can be written in it, so a directory the client can't
+/// write to fails before connecting rather than at the first change
+pub fn prepare_dir(dir: &Path, mode: Option<u32>) -> Result<(), String> {
+    let existed = dir.is_dir();
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, mode);
+    }
+    builder.create(dir).map_err(|e| format!("Cannot create output directory {}: {}", dir.display(), e))?;
+    #[cfg(unix)]
+    if let (false, Some(mode)) = (existed, mode) {
+        // mkdir masks the mode with the umask
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("Cannot set the mode of output directory {}: {}", dir.display(), e))?;
+    }
+    #[cfg(not(unix))]
+    if mode.is_some() && !existed {
+        eprintln!("--output-dir-mode only applies on Unix, creating {} as usual", dir.display());
+    }
+    let probe = dir.join(PROBE_FILE);
+    let written = std::fs::write(&probe, "");
+    let _ = std::fs::remove_file(&probe);
+    written.map_err(|e| format!("Output directory {} is not writable: {}", dir.display(), e))
+}
+
+/// How the mirrored content is encoded before it is written out.
+///
+/// Always applied to the whole content, so a resync after reconnecting produces
+/// the same file as the incremental updates would have.
+#[derive(Debug, Clone, Copy)]
+pub struct OutputEncoding {
+    pub crlf: bool,
+    pub bom: bool,
+}
+
+impl OutputEncoding {
+    pub fn from_cli(cli: &Cli) -> Self {
+        Self { crlf: cli.crlf, bom: cli.bom }
+    }
+
+    pub fn encode(&self, content: &str) -> String {
+        let content = content.strip_prefix(BOM).unwrap_or(content);
+        let mut encoded = String::with_capacity(content.len() + 3);
+        if self.bom {
+            encoded.push(BOM);
+        }
+        encoded.push_str(&self.line_endings(content));
+        encoded
+    }
+
+    /// The text with the configured line endings, for text added to the end of
+    /// content that was already encoded
+    pub fn line_endings<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.crlf {
+            Cow::Owned(text.replace("\r\n", "\n").replace('\n', "\r\n"))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+}
+
+/// Where and how the mirrored content is written
+pub struct Output {
+    output_dir: PathBuf,
+    client_id: String,
+    /// Name of each file's usual output file, see `--output-template`
+    template: String,
+    sinks: Vec<Sink>,
+    encoding: OutputEncoding,
+    /// Read every written file back, see `--verify-writes`
+    verify_writes: bool,
+    /// Commit every change to the repository the files are written into, see `--git-commit`
+    git: Option<GitMirror>,
+    /// Write every update to a new timestamped file and keep this many, see `--snapshot-history`
+    snapshot_history: Option<NonZeroUsize>,
+    /// The timestamped file each file was last written to, with `--snapshot-history`
+    latest_snapshot: Mutex<HashMap<String, PathBuf>>,
+    /// Hash of the content last written to each output file
+    written: Mutex<HashMap<PathBuf, u64>>,
+    /// The first file mirrored, to warn when another one goes to the same output file
+    first_file_id: Mutex<Option<String>>,
+    warned_shared_output: AtomicBool,
+}
+
+impl Output {
+    pub fn from_cli(cli: &Cli, client_id: &str) -> Result<Self, String> {
+        let sinks = if cli.tail || (cli.snapshot && cli.sinks.is_empty()) {
+            vec![Sink::Stdout]
+        } else if cli.sinks.is_empty() {
+            vec![Sink::Output]
+        } else {
+            cli.sinks.iter().map(|spec| Sink::parse(spec)).collect::<Result<_, _>>()?
+        };
+        let git = if cli.git_commit {
+            Some(GitMirror::discover(Path::new(&cli.output_dir)).map_err(|e| format!("--git-commit: {}", e.message()))?)
+        } else {
+            None
+        };
+        Ok(Self {
+            output_dir: PathBuf::from(&cli.output_dir),
+            client_id: client_id.to_string(),
+            template: cli.output_template.clone(),
+            sinks,
+            encoding: OutputEncoding::from_cli(cli),
+            verify_writes: cli.verify_writes,
+            git,
+            snapshot_history: cli.snapshot_history,
+            latest_snapshot: Mutex::new(HashMap::new()),
+            written: Mutex::new(HashMap::new()),
+            first_file_id: Mutex::new(None),
+            warned_shared_output: AtomicBool::new(false),
+        })
+    }
+
+    /// The usual output file of a file, also handed to the resync hook; with
+    /// `--snapshot-history` the timestamped file it was last written to
+    pub fn path(&self, file_id: &str) -> PathBuf {
+        match self.latest_snapshot.lock().expect("lock").get(file_id) {
+            Some(path) => path.clone(),
+            None => self.template_path(file_id),
+        }
+    }
+
+    /// The template with `{client_id}` and `{file_id}` filled in, inside the
+    /// output directory. Only the plain names of the file id are kept, so an
+    /// absolute id or one with `..` can't point outside it.
+    fn template_path(&self, file_id: &str) -> PathBuf {
+        let file_id: PathBuf = Path::new(file_id)
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        let name = self
+            .template
+            .replace("{client_id}", &self.client_id)
+            .replace("{file_id}", &file_id.to_string_lossy());
+        self.output_dir.join(name)
+    }
+
+    /// Writes a file's content to every sink; a failing sink is reported and skipped
+    pub async fn write(&self, file_id: &str, content: &str) {
+        self.check_shared_output(file_id);
+        let content = self.encoding.encode(content);
+        let path = match self.snapshot_history {
+            Some(_) => snapshot_path(&self.template_path(file_id), SystemTime::now()),
+            None => self.path(file_id),
+        };
+        for sink in &self.sinks {
+            let sink = match sink {
+                Sink::Output => &Sink::File(path.clone()),
+                sink => sink,
+            };
+            match self.write_to(sink, &content).await {
+                Ok(()) => {
+                    if let Sink::File(path) = sink {
+                        self.written.lock().expect("lock").insert(path.clone(), hash(content.as_bytes()));
+                    }
+                }
+                Err(e) => eprintln!("Failed to write to {}: {}", sink, e),
+            }
+        }
+        if let Some(keep) = self.snapshot_history {
+            if self.sinks.iter().any(|sink| matches!(sink, Sink::Output)) {
+                self.latest_snapshot.lock().expect("lock").insert(file_id.to_string(), path);
+                prune_snapshots(&self.template_path(file_id), keep.get()).await;
+            }
+        }
+    }
+
+    /// Writes to one sink. With `--verify-writes` a file is read back, and
+    /// written once more when it does not hold the content before giving up.
+    async fn write_to(&self, sink: &Sink, content: &str) -> Result<(), Box<dyn std::error::Error>> {
+        sink.write(content).await?;
+        if !self.verify_writes {
+            return Ok(());
+        }
+        if let Err(e) = sink.verify(content).await {
+            eprintln!("Verifying {} failed: {}, writing it again", sink, e);
+            sink.write(content).await?;
+            sink.verify(content)
+                .await
+                .map_err(|e| format!("verification failed again after rewriting: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Warns once when a second file would overwrite the first one's output file
+    fn check_shared_output(&self, file_id: &str) {
+        if self.template.contains("{file_id}") {
+            return;
+        }
+        let mut first_file_id = self.first_file_id.lock().expect("lock");
+        let first = first_file_id.get_or_insert_with(|| file_id.to_string());
+        if first != file_id && !self.warned_shared_output.swap(true, Ordering::Relaxed) {
+            eprintln!(
+                "{} and {} are both mirrored to {}, add {{file_id}} to --output-template to keep them apart",
+                first,
+                file_id,
+                self.path(file_id).display()
+            );
+        }
+    }
+
+    /// Writes only `text` added to the end of the content, to sinks that are
+    /// streamed rather than rewritten; see `--tail`
+    pub async fn append(&self, text: &str) {
+        let text = self.encoding.line_endings(text);
+        for sink in self.sinks.iter().filter(|sink| matches!(sink, Sink::Stdout)) {
+            if let Err(e) = sink.write(&text).await {
+                eprintln!("Failed to write to {}: {}", sink, e);
+            }
+        }
+    }
+
+    /// Sets the modification time of the files a file is written to, so tools
+    /// that compare mtimes see the mirror as old as its source
+    pub fn set_modified(&self, file_id: &str, modified: SystemTime) {
+        let mtime = FileTime::from_system_time(modified);
+        for path in self.file_paths(file_id) {
+            if let Err(e) = filetime::set_file_mtime(&path, mtime) {
+                eprintln!("Failed to set the modification time of {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Commits the files a file is written to after the change with `seq`,
+    /// with `--git-commit`; a failed commit is reported and mirroring goes on
+    pub async fn commit(&self, file_id: &str, seq: u64) {
+        let Some(git) = self.git.clone() else {
+            return;
+        };
+        let paths = self.file_paths(file_id);
+        let id = file_id.to_string();
+        match tokio::task::spawn_blocking(move || git.commit(&paths, &id, seq)).await {
+            Ok(Ok(true)) => println!("Committed {} at seq {}", file_id, seq),
+            Ok(Ok(false)) => {}
+            Ok(Err(e)) => eprintln!("Failed to commit {}: {}", file_id, e.message()),
+            Err(e) => eprintln!("Failed to commit {}: {}", file_id, e),
+        }
+    }
+
+    /// The files a file is written to, leaving out stdout and HTTP sinks
+    fn file_paths(&self, file_id: &str) -> Vec<PathBuf> {
+        self.sinks
+            .iter()
+            .filter_map(|sink| match sink {
+                Sink::Output => Some(self.path(file_id)),
+                Sink::File(path) => Some(path.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Removes the files this client wrote. A file whose content changed since
+    /// it was last written, or that is not a regular file (a FIFO), is kept.
+    pub async fn remove_written(&self) {
+        let written: Vec<_> = self.written.lock().expect("lock").drain().collect();
+        for (path, written_hash) in written {
+            if !tokio::fs::metadata(&path).await.is_ok_and(|metadata| metadata.is_file()) {
+                continue;
+            }
+            match tokio::fs::read(&path).await {
+                Ok(content) if hash(&content) == written_hash => match tokio::fs::remove_file(&path).await {
+                    Ok(()) => println!("Removed {}", path.display()),
+                    Err(e) => eprintln!("Failed to remove {}: {}", path.display(), e),
+                },
+                Ok(_) => eprintln!("Not removing {}: it was modified after the client wrote it", path.display()),
+                Err(e) => eprintln!("Failed to read {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+/// `path` with the time `at`, in ISO 8601 with `-` for `:` so it is a valid file
+/// name everywhere, put before its extension, e.g. `README.2024-06-01T12-00-00.123Z.md`
+fn snapshot_path(path: &Path, at: SystemTime) -> PathBuf {
+    let timestamp = humantime::format_rfc3339_millis(at).to_string().replace(':', "-");
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, timestamp, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, timestamp),
+    };
+    path.with_file_name(name)
+}
+
+/// Whether `name` is a snapshot of `path` made by [`snapshot_path`]
+fn is_snapshot_of(name: &str, path: &Path) -> bool {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+    let timestamp = name.strip_prefix(&format!("{stem}.")).and_then(|rest| rest.strip_suffix(&extension));
+    // e.g. 2024-06-01T12-00-00.123Z
+    timestamp.is_some_and(|timestamp| {
+        timestamp.len() == 24 && timestamp.ends_with('Z') && timestamp.as_bytes()[10] == b'T'
+    })
+}
+
+/// Removes all but the newest `keep` snapshots of `path`; their timestamps
+/// sort in the order they were written
+async fn prune_snapshots(path: &Path, keep: usize) {
+    let Some(dir) = path.parent() else {
+        return;
+    };
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return;
+    };
+    let mut snapshots = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_name().to_str().is_some_and(|name| is_snapshot_of(name, path)) {
+            snapshots.push(entry.path());
+        }
+    }
+    snapshots.sort();
+    let excess = snapshots.len().saturating_sub(keep);
+    for snapshot in &snapshots[..excess] {
+        if let Err(e) = tokio::fs::remove_file(snapshot).await {
+            eprintln!("Failed to remove old snapshot {}: {}", snapshot.display(), e);
+        }
+    }
+}
+
+fn hash(content: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Starts the `--on-resync` command after the client received full content for
+/// a file, e.g. after reconnecting, on its own task so a slow command does not
+/// hold up the changes that follow. The output path and file id are passed in
+/// `MARKDOWN_OP_OUTPUT` and `MARKDOWN_OP_FILE_ID`.
+pub fn spawn_resync_hook(command: &str, output_path: &Path, file_id: &str) {
+    let command = command.to_string();
+    let output_path = output_path.to_path_buf();
+    let file_id = file_id.to_string();
+    tokio::spawn(async move { run_resync_hook(&command, &output_path, &file_id).await });
+}
+
+async fn run_resync_hook(command: &str, output_path: &Path, file_id: &str) {
+    #[cfg(unix)]
+    let mut process = tokio::process::Command::new("sh");
+    #[cfg(unix)]
+    process.arg("-c");
+    #[cfg(windows)]
+    let mut process = tokio::process::Command::new("cmd");
+    #[cfg(windows)]
+    process.arg("/C");
+    let status = process
+        .arg(command)
+        .env("MARKDOWN_OP_OUTPUT", output_path)
+        .env("MARKDOWN_OP_FILE_ID", file_id)
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Resync hook exited with {}", status),
+        Err(e) => eprintln!("Failed to run resync hook: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use clap::Parser;
+    use super::*;
+
+    const BOTH: OutputEncoding = OutputEncoding { crlf: true, bom: true };
+
+    #[test]
+    fn content_is_encoded_as_a_whole() {
+        assert_eq!(BOTH.encode("# Title\n\nText.\n"), "\u{feff}# Title\r\n\r\nText.\r\n");
+        assert_eq!(OutputEncoding { crlf: false, bom: false }.encode("# Title\r\n"), "# Title\r\n");
+        assert_eq!(OutputEncoding { crlf: false, bom: true }.encode("# Title\n"), "\u{feff}# Title\n");
+    }
+
+    #[test]
+    fn encoding_again_gives_the_same_file() {
+        // a resync rebuilds the file from content that may already hold a BOM or CRLFs
+        let once = BOTH.encode("# Title\nText.\n");
+        assert_eq!(BOTH.encode(&once), once);
+        assert_eq!(OutputEncoding { crlf: false, bom: false }.encode(&once), "# Title\r\nText.\r\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_slow_resync_hook_runs_in_the_background() {
+        let output = std::env::temp_dir().join(format!("markdown-op-hook-{}.md", std::process::id()));
+        let hooked = output.with_extension("md.hooked");
+        let _ = std::fs::remove_file(&hooked);
+        let started = Instant::now();
+        spawn_resync_hook("sleep 1; echo \"$MARKDOWN_OP_FILE_ID\" > \"$MARKDOWN_OP_OUTPUT.hooked\"", &output, "doc.md");
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert!(!hooked.exists());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !hooked.exists() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let written = std::fs::read_to_string(&hooked);
+        let _ = std::fs::remove_file(&hooked);
+        assert_eq!(written.expect("the hook never ran"), "doc.md\n");
+    }
+
+    #[tokio::test]
+    async fn a_failing_sink_does_not_stop_the_others() {
+        // a port nothing listens on, so every POST is refused
+        let dead = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+            format!("http://{}/", listener.local_addr().expect("address"))
+        };
+        let copy = std::env::temp_dir().join(format!("markdown-op-sinks-{}.md", std::process::id()));
+        let copy_sink = format!("file:{}", copy.display());
+        let cli = Cli::try_parse_from(["client", "--sink", &dead, "--sink", &copy_sink]).expect("parse");
+        let output = Output::from_cli(&cli, "client1").expect("sinks");
+        output.write("doc.md", "# Title\n").await;
+        let written = std::fs::read_to_string(&copy);
+        let _ = std::fs::remove_file(&copy);
+        assert_eq!(written.expect("the file sink was skipped"), "# Title\n");
+    }
+}
@@ -0,0 +1,105 @@
+use std::{fs, io::Write};
+use shared::{FileChange, Sequenced};
+use crate::cli::ApplyLogArgs;
+
+/// Applies every change of the log in order, the way a connected client would have
+pub fn apply_log(args: &ApplyLogArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut content = match &args.input {
+        Some(path) => fs::read_to_string(path)?,
+        None => String::new(),
+    };
+    let log = fs::read_to_string(&args.log)?;
+    let mut applied = 0;
+    // without --file-id the log must hold a single file, changes to another would corrupt it
+    let mut first_file_id: Option<String> = None;
+    for (index, line) in log.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_number = index + 1;
+        // logs recorded off the wire carry a seq, hand-written ones may not
+        let change = serde_json::from_str::<Sequenced>(line)
+            .map(|message| message.change)
+            .or_else(|_| serde_json::from_str::<FileChange>(line))
+            .map_err(|e| format!("{}:{}: invalid change: {}", args.log.display(), line_number, e))?;
+        match &args.file_id {
+            Some(id) if id != change.file_id() => continue,
+            Some(_) => {}
+            None => {
+                let first = first_file_id.get_or_insert_with(|| change.file_id().to_string());
+                if first != change.file_id() {
+                    return Err(format!(
+                        "{}:{}: the log holds changes to both {} and {}, pick one with --file-id",
+                        args.log.display(),
+                        line_number,
+                        first,
+                        change.file_id()
+                    )
+                    .into());
+                }
+            }
+        }
+        change
+            .try_apply(&mut content)
+            .map_err(|e| format!("{}:{}: cannot apply change: {}", args.log.display(), line_number, e))?;
+        applied += 1;
+    }
+    match &args.output {
+        Some(path) => fs::write(path, &content)?,
+        None => std::io::stdout().write_all(content.as_bytes())?,
+    }
+    eprintln!("Applied {} changes from {}", applied, args.log.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use shared::DiffStrategyKind;
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("markdown-op-replay-{}-{name}", std::process::id()))
+    }
+
+    /// Writes the changes as a log and replays it, returning the rebuilt content
+    fn replay(name: &str, changes: &[FileChange], file_id: Option<&str>) -> Result<String, String> {
+        let log = temp_path(&format!("{name}.jsonl"));
+        let output = temp_path(&format!("{name}.md"));
+        let lines: Vec<String> = changes.iter().map(|change| serde_json::to_string(change).expect("JSON")).collect();
+        fs::write(&log, lines.join("\n")).expect("write log");
+        let args = ApplyLogArgs { log: log.clone(), input: None, output: Some(output.clone()), file_id: file_id.map(str::to_string) };
+        let result = apply_log(&args).map_err(|e| e.to_string()).map(|()| fs::read_to_string(&output).expect("read output"));
+        let _ = fs::remove_file(&log);
+        let _ = fs::remove_file(&output);
+        result
+    }
+
+    const VERSIONS: [&str; 4] = ["# Title\n", "# Title\n\nFirst line.\n", "# Title\n\nFirst line, edited.\nSecond.\n", "# New title\n\nSecond.\n"];
+
+    fn recorded(file_id: &str, kind: DiffStrategyKind) -> Vec<FileChange> {
+        let mut changes = vec![FileChange::FullContent { file_id: file_id.to_string(), content: VERSIONS[0].to_string(), last_modified: None }];
+        for pair in VERSIONS.windows(2) {
+            changes.extend(kind.strategy().diff(file_id, pair[0], pair[1]));
+        }
+        changes
+    }
+
+    #[test]
+    fn applying_a_log_rebuilds_the_last_version() {
+        for kind in [DiffStrategyKind::Char, DiffStrategyKind::Line] {
+            let rebuilt = replay(&format!("{kind:?}"), &recorded("doc.md", kind), None);
+            assert_eq!(rebuilt.as_deref(), Ok(VERSIONS[3]), "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn a_log_of_several_files_needs_a_file_id() {
+        let mut changes = recorded("doc.md", DiffStrategyKind::Char);
+        changes.insert(2, FileChange::FullContent { file_id: "other.md".to_string(), content: "Other\n".to_string(), last_modified: None });
+        let error = replay("several", &changes, None).expect_err("ambiguous log");
+        assert!(error.ends_with(":3: the log holds changes to both doc.md and other.md, pick one with --file-id"), "{error}");
+        assert_eq!(replay("several-doc", &changes, Some("doc.md")).as_deref(), Ok(VERSIONS[3]));
+        assert_eq!(replay("several-other", &changes, Some("other.md")).as_deref(), Ok("Other\n"));
+    }
+}
@@ -0,0 +1,31 @@
+use tokio::sync::watch;
+
+/// Set once the client is asked to stop with Ctrl+C. Connections only check it
+/// between messages, so an update that is being written out always completes.
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Starts listening for Ctrl+C
+    pub fn on_ctrl_c() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                println!("Interrupted, shutting down");
+                let _ = sender.send(true);
+            }
+        });
+        Self(receiver)
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once shutdown was requested
+    pub async fn requested(&mut self) {
+        // without a signal handler there is nothing to wait for
+        if self.0.wait_for(|&requested| requested).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use std::{fmt, path::{Path, PathBuf}};
+use tokio::{fs, io::{AsyncReadExt, AsyncWriteExt, BufWriter}, net::TcpStream, time::Duration};
+use url::Url;
+
+const FIFO_WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A destination the mirrored content is written to after every change
+#[derive(Debug, Clone)]
+pub enum Sink {
+    /// The usual output file of each mirrored file, see `Output::path`
+    Output,
+    /// Overwrite a file (or stream into it, if it is a FIFO)
+    File(PathBuf),
+    Stdout,
+    /// POST the content to a plain HTTP endpoint
+    Http(Url),
+}
+
+impl Sink {
+    /// Parses `file`, `file:PATH`, `stdout` or an `http://` URL; `file` alone
+    /// is the client's usual output file
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec {
+            "file" => Ok(Sink::Output),
+            "stdout" | "-" => Ok(Sink::Stdout),
+            _ if spec.starts_with("file:") => Ok(Sink::File(PathBuf::from(&spec["file:".len()..]))),
+            _ if spec.starts_with("http://") => Url::parse(spec).map(Sink::Http).map_err(|e| e.to_string()),
+            _ if spec.starts_with("https://") => Err("https sinks are not supported, use http://".to_string()),
+            _ => Err(format!("unknown sink {spec:?}, expected file, file:PATH, stdout or an http:// URL")),
+        }
+    }
+
+    pub async fn write(&self, content: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Sink::Output => Err("the output file is resolved per mirrored file by Output".into()),
+            Sink::File(path) => write_file(path, content).await,
+            Sink::Stdout => {
+                let mut stdout = tokio::io::stdout();
+                stdout.write_all(content.as_bytes()).await?;
+                stdout.flush().await?;
+                Ok(())
+            }
+            Sink::Http(url) => match tokio::time::timeout(HTTP_TIMEOUT, post(url, content)).await {
+                Ok(result) => result,
+                Err(_) => Err("request timed out".into()),
+            },
+        }
+    }
+}
+
+impl Sink {
+    /// Reads a file sink back and checks it holds `content`, for `--verify-writes`.
+    /// Other sinks, and FIFOs, whose content can't be read back, always pass.
+    pub async fn verify(&self, content: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let Sink::File(path) = self else {
+            return Ok(());
+        };
+        #[cfg(unix)]
+        if is_fifo(path).await {
+            return Ok(());
+        }
+        let read = fs::read(path).await?;
+        if read != content.as_bytes() {
+            return Err(format!("read back {} bytes that differ from the {} written", read.len(), content.len()).into());
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sink::Output => write!(f, "output file"),
+            Sink::File(path) => write!(f, "{}", path.display()),
+            Sink::Stdout => write!(f, "stdout"),
+            Sink::Http(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+async fn write_file(path: &Path, content: &str) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(unix)]
+    if is_fifo(path).await {
+        return write_fifo(path, content).await;
+    }
+    // a {file_id} in the output template can name a subdirectory
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).await?;
+    }
+    let file = fs::File::create(path).await?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(content.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// A minimal HTTP/1.1 POST, enough for webhooks and local services
+async fn post(url: &Url, content: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let host = url.host_str().ok_or("URL has no host")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path = format!("{path}?{query}");
+    }
+    let mut stream = TcpStream::connect((host, port)).await?;
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}:{port}\r\nContent-Type: text/markdown; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        content.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(content.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(format!("server answered {status_line:?}").into()),
+    }
+}
+
+#[cfg(unix)]
+async fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    fs::metadata(path).await.is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+/// Streams the content into a FIFO without truncating it; updates are
+/// skipped while no reader is attached instead of blocking the client
+#[cfg(unix)]
+async fn write_fifo(path: &Path, content: &str) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    // a non-blocking open for writing fails with ENXIO when nobody is reading
+    let probe = std::fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path);
+    let fifo = match probe {
+        Ok(fifo) => fifo,
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+            eprintln!("No reader on FIFO {}, skipping update", path.display());
+            return Ok(());
+        }
+        Err(e) => return Err(Box::new(e)),
+    };
+    // writes go through the same non-blocking descriptor, so a full pipe is
+    // waited on by the runtime rather than by a thread that outlives the timeout
+    let mut fifo = tokio::net::unix::pipe::Sender::from_file(fifo)?;
+    let write = async {
+        fifo.write_all(content.as_bytes()).await?;
+        fifo.flush().await
+    };
+    match tokio::time::timeout(FIFO_WRITE_TIMEOUT, write).await {
+        Ok(result) => Ok(result?),
+        Err(_) => {
+            eprintln!("Timed out writing to FIFO {}, skipping update", path.display());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{ffi::CString, io::Read, os::unix::{ffi::OsStrExt, fs::OpenOptionsExt}, path::PathBuf, time::Instant};
+    use super::*;
+
+    fn fifo(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("markdown-op-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let c_path = CString::new(path.as_os_str().as_bytes()).expect("path without NUL");
+        // SAFETY: `c_path` is a valid NUL-terminated path
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0, "mkfifo");
+        path
+    }
+
+    /// Opens the reading end without waiting for a writer
+    fn reader(path: &Path) -> std::fs::File {
+        std::fs::OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(path).expect("open FIFO for reading")
+    }
+
+    #[tokio::test]
+    async fn updates_are_skipped_while_no_reader_is_attached() {
+        let path = fifo("no-reader");
+        assert!(is_fifo(&path).await);
+        let started = Instant::now();
+        write_fifo(&path, "# Title\n").await.expect("skipped, not failed");
+        assert!(started.elapsed() < FIFO_WRITE_TIMEOUT);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn updates_stream_into_an_attached_reader() {
+        let path = fifo("reader");
+        let mut reader = reader(&path);
+        write_fifo(&path, "# Title\n").await.expect("write");
+        write_fifo(&path, "# Title\n\nMore.\n").await.expect("write");
+        let mut read = String::new();
+        reader.read_to_string(&mut read).expect("read FIFO");
+        // not truncated between updates
+        assert_eq!(read, "# Title\n# Title\n\nMore.\n");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn a_reader_that_stops_reading_only_costs_the_timeout() {
+        let path = fifo("stalled");
+        let mut reader = reader(&path);
+        // far more than the pipe holds, with nobody draining it
+        let started = Instant::now();
+        write_fifo(&path, &"x".repeat(4 * 1024 * 1024)).await.expect("timed out, not failed");
+        assert!(started.elapsed() < FIFO_WRITE_TIMEOUT * 2, "took {:?}", started.elapsed());
+        // the stalled write let go of the FIFO, so the next update goes through once it drains
+        let mut drained = Vec::new();
+        let _ = reader.read_to_end(&mut drained);
+        write_fifo(&path, "# Title\n").await.expect("write");
+        let mut read = String::new();
+        reader.read_to_string(&mut read).expect("read FIFO");
+        assert_eq!(read, "# Title\n");
+        let _ = std::fs::remove_file(&path);
+    }
+}
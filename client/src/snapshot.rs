@@ -0,0 +1,45 @@
+use std::borrow::Cow;
+use futures_util::StreamExt;
+use tokio::time::Duration;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use shared::{FileChange, Received, Sequenced};
+use crate::cli::Cli;
+use crate::error::MessageError;
+use crate::output::Output;
+
+/// Connects once, writes the first full content the server sends and returns,
+/// for generating golden files in CI; see `--snapshot`. There is no reconnecting:
+/// a server that can't be reached or sends nothing in time is an error.
+pub async fn run(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let output = Output::from_cli(cli, &cli.client_id())?;
+    let timeout = Duration::from_millis(cli.snapshot_timeout_ms);
+    let (file_id, content) = tokio::time::timeout(timeout, first_full_content(cli))
+        .await
+        .map_err(|_| format!("no content received from {} within {} ms", cli.server_url, cli.snapshot_timeout_ms))??;
+    output.write(&file_id, &content).await;
+    Ok(())
+}
+
+/// The file id and content of the first full content the server sends
+async fn first_full_content(cli: &Cli) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let mut stream = crate::connect(cli, &cli.server_url).await?;
+    while let Some(message) = stream.next().await {
+        let Message::Text(text) = message? else {
+            continue;
+        };
+        let text = shared::compression::decompress(&text)?;
+        let text = match &cli.verify_key {
+            Some(verifier) => Cow::Owned(verifier.verify(&text)?),
+            None => shared::signing::strip(&text),
+        };
+        match crate::parse_change(&text) {
+            Ok(Received::Change(Sequenced { change: FileChange::FullContent { file_id, content, .. }, .. })) => {
+                let _ = stream.close(None).await;
+                return Ok((file_id, content));
+            }
+            Ok(_) | Err(MessageError::Unknown { .. }) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err("server closed the connection without sending any content".into())
+}
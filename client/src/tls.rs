@@ -0,0 +1,65 @@
+use std::{fs::File, io::{self, BufReader}, path::Path, sync::Arc};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{self, pki_types::{CertificateDer, PrivateKeyDer, ServerName}, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite;
+use crate::cli::Cli;
+use crate::error::ConnectError;
+
+/// Completes the TLS handshake with a `wss://` server at `host`. Its
+/// certificate is verified against `--tls-ca`, and `--tls-cert` is presented
+/// when the server asks for a client certificate (mutual TLS).
+pub async fn connect(cli: &Cli, host: &str, stream: TcpStream) -> Result<TlsStream<TcpStream>, ConnectError> {
+    let server_name = ServerName::try_from(host.to_string()).map_err(|e| ConnectError::Tls(format!("{host}: {e}")))?;
+    let connector = TlsConnector::from(Arc::new(client_config(cli).map_err(ConnectError::Tls)?));
+    connector
+        .connect(server_name, stream)
+        .await
+        .map_err(|e| rejection(&e).unwrap_or_else(|| tungstenite::Error::Io(e).into()))
+}
+
+fn client_config(cli: &Cli) -> Result<ClientConfig, String> {
+    let Some(ca) = &cli.tls_ca else {
+        return Err("wss:// needs --tls-ca with the CA certificate to verify the server with".to_string());
+    };
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(ca)? {
+        roots.add(cert).map_err(|e| format!("invalid CA certificate in {}: {}", ca.display(), e))?;
+    }
+    let builder = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots);
+    match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(read_certs(cert)?, read_key(key)?)
+            .map_err(|e| format!("--tls-cert and --tls-key: {e}")),
+        _ => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid PEM in {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("invalid PEM in {}: {}", path.display(), e))?
+        .ok_or_else(|| format!("no private key in {}", path.display()))
+}
+
+/// A TLS failure behind an I/O error, e.g. the server turning down the client
+/// certificate, which reconnecting would only run into again
+pub fn rejection(error: &io::Error) -> Option<ConnectError> {
+    let tls_error = error.get_ref()?.downcast_ref::<rustls::Error>()?;
+    Some(ConnectError::Tls(tls_error.to_string()))
+}
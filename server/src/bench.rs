@@ -0,0 +1,150 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use shared::{DiffStrategyKind, FileChange};
+use crate::history::{History, HistoryConfig};
+
+const FILE_ID: &str = "bench.md";
+
+/// Publishes `changes` full-content changes of about `size` chars to `clients`
+/// subscribers and times handing every subscriber its message, once with the
+/// JSON shared by all of them and once serialized per client as a baseline
+pub fn broadcast(clients: usize, changes: usize, size: usize) {
+    let history = Arc::new(History::new(changes.max(1), HistoryConfig::default(), None));
+    let mut subscriptions: Vec<_> = (0..clients).map(|_| history.subscribe(None, &[FILE_ID])).collect();
+    let mut shared = Duration::ZERO;
+    let mut per_client = Duration::ZERO;
+    let mut bytes = 0;
+    for version in 0..changes {
+        let change = FileChange::FullContent { file_id: FILE_ID.to_string(), content: content(version, size), last_modified: None };
+        // publishing is when the shared JSON is serialized
+        let started = Instant::now();
+        history.publish(change);
+        let received: Vec<_> = subscriptions.iter_mut().filter_map(|subscription| subscription.receiver.try_recv().ok()).collect();
+        bytes += received.iter().map(|broadcast| broadcast.text(false).len()).sum::<usize>();
+        shared += started.elapsed();
+        // what every connection did before changes were serialized once
+        let started = Instant::now();
+        for broadcast in &received {
+            // a change is plain strings and numbers, which always serialize
+            std::hint::black_box(serde_json::to_string(&broadcast.message).expect("serialize change"));
+        }
+        per_client += started.elapsed();
+    }
+    let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    println!("{changes} changes of {size} chars to {clients} clients");
+    println!("shared:     {:.1} ms, {:.1} MB handed out", millis(shared), bytes as f64 / 1_000_000.0);
+    println!("per client: {:.1} ms", millis(per_client));
+    println!("speedup:    {:.1}x", per_client.as_secs_f64() / shared.as_secs_f64().max(f64::EPSILON));
+}
+
+/// Lines of text, at least `size` chars long and different for every version
+fn content(version: usize, size: usize) -> String {
+    let mut content = String::new();
+    let mut line = 0;
+    while content.len() < size {
+        content.push_str(&format!("Line {line} of version {version}, with \"quotes\" and a tab\tto escape.\n"));
+        line += 1;
+    }
+    content
+}
+
+/// Words of the synthetic content, with some multi-byte chars so positions
+/// that mix up chars and bytes show up as failed applies
+const WORDS: &[&str] = &[
+    "the", "mirror", "diff", "server", "client", "markdown", "change", "file", "content", "watch", "café", "naïve", "über",
+    "👋", "🚀", "言葉", "fast", "slow", "edit", "line",
+];
+
+/// What one benchmark run measured
+struct Totals {
+    diffing: Duration,
+    content_bytes: usize,
+    changes: usize,
+    json_bytes: usize,
+    full_content: usize,
+    correct: usize,
+}
+
+/// Applies `edits` random edits to about `size` chars of synthetic markdown,
+/// diffs each version against the one before with `strategy` and applies the
+/// diff back to check it makes the edited content. Prints the throughput and
+/// diff sizes; returns whether every diff applied correctly.
+pub fn diff(size: usize, edits: usize, strategy: DiffStrategyKind, seed: u64) -> bool {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let differ = strategy.strategy();
+    let mut content = synthetic_content(&mut rng, size);
+    let mut totals = Totals { diffing: Duration::ZERO, content_bytes: 0, changes: 0, json_bytes: 0, full_content: 0, correct: 0 };
+    for _ in 0..edits {
+        let edited = random_edit(&mut rng, &content);
+        let started = Instant::now();
+        let changes = differ.diff(FILE_ID, &content, &edited);
+        totals.diffing += started.elapsed();
+        totals.content_bytes += content.len() + edited.len();
+        totals.changes += changes.len();
+        // a change is plain strings and numbers, which always serialize
+        totals.json_bytes += changes.iter().map(|change| serde_json::to_string(change).expect("serialize change").len()).sum::<usize>();
+        if matches!(changes.as_slice(), [FileChange::FullContent { .. }]) {
+            totals.full_content += 1;
+        }
+        let mut applied = content.clone();
+        if changes.iter().all(|change| change.try_apply(&mut applied).is_ok()) && applied == edited {
+            totals.correct += 1;
+        }
+        content = edited;
+    }
+    report(&totals, size, edits, strategy, seed);
+    totals.correct == edits
+}
+
+fn report(totals: &Totals, size: usize, edits: usize, strategy: DiffStrategyKind, seed: u64) {
+    let seconds = totals.diffing.as_secs_f64().max(f64::EPSILON);
+    let per_edit = |total: usize| total as f64 / edits.max(1) as f64;
+    let strategy = format!("{strategy:?}").to_lowercase();
+    println!("{edits} edits of {size} chars with the {strategy} strategy (seed {seed})");
+    println!("diffing:      {:.1} ms, {:.0} diffs/s, {:.1} MB/s of content", seconds * 1000.0, edits as f64 / seconds, totals.content_bytes as f64 / seconds / 1_000_000.0);
+    println!("average diff: {:.1} changes, {:.0} bytes of JSON", per_edit(totals.changes), per_edit(totals.json_bytes));
+    println!("full content: {} of {edits} diffs", totals.full_content);
+    println!("correct:      {} of {edits} diffs", totals.correct);
+}
+
+/// Markdown of headings, paragraphs and list items, at least `size` chars long
+fn synthetic_content(rng: &mut StdRng, size: usize) -> String {
+    let mut content = String::new();
+    let mut section = 0;
+    while content.chars().count() < size {
+        match rng.gen_range(0..6) {
+            0 => {
+                section += 1;
+                content.push_str(&format!("## Section {section}\n\n"));
+            }
+            1 => {
+                for _ in 0..rng.gen_range(1..4) {
+                    content.push_str(&format!("- {}\n", words(rng, 3..8)));
+                }
+                content.push('\n');
+            }
+            _ => content.push_str(&format!("{}\n\n", words(rng, 10..40))),
+        }
+    }
+    content
+}
+
+fn words(rng: &mut StdRng, count: std::ops::Range<usize>) -> String {
+    let count = rng.gen_range(count);
+    (0..count).map(|_| *WORDS.choose(rng).expect("words")).collect::<Vec<_>>().join(" ")
+}
+
+/// `content` with a few chars at a random place inserted, deleted or replaced
+fn random_edit(rng: &mut StdRng, content: &str) -> String {
+    let boundaries: Vec<usize> = content.char_indices().map(|(offset, _)| offset).chain([content.len()]).collect();
+    let start = rng.gen_range(0..boundaries.len());
+    let end = (start + rng.gen_range(0..20)).min(boundaries.len() - 1);
+    let (start, end) = (boundaries[start], boundaries[end]);
+    let inserted = match rng.gen_range(0..3) {
+        0 => String::new(),
+        _ => format!(" {}", words(rng, 1..4)),
+    };
+    let mut edited = content.to_string();
+    edited.replace_range(start..end, &inserted);
+    edited
+}
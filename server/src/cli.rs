@@ -0,0 +1,253 @@
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use shared::DiffStrategyKind;
+use crate::config::DiffBase;
+use crate::matcher::MatchMode;
+use crate::reader::{ReadStrategy, TrailingWhitespace};
+
+/// Watches a file and mirrors its content to WebSocket clients
+#[derive(Debug, Parser)]
+#[command(name = "server", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// File to watch (same as --watch)
+    #[arg(value_name = "FILE", conflicts_with = "watch")]
+    file: Option<String>,
+
+    /// File to watch, can be repeated
+    #[arg(short, long, value_name = "FILE")]
+    watch: Vec<String>,
+
+    /// How FILE and --watch select files: `exact` paths, or `glob` / `regex` patterns such as `docs/**/README.md`
+    #[arg(long = "match", value_enum, value_name = "MODE")]
+    pub match_mode: Option<MatchMode>,
+
+    /// Mirror content piped to stdin instead of a file; each burst of output is a new version
+    #[arg(long, conflicts_with_all = ["file", "watch"])]
+    pub stdin: bool,
+
+    /// Config file (TOML, or JSON with a .json extension); defaults to ./markdown-op.toml if present
+    #[arg(short, long, value_name = "PATH", env = "MARKDOWN_OP_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address the WebSocket server listens on [default: 127.0.0.1:3030]
+    #[arg(short, long, value_name = "ADDR", env = "BIND_ADDR")]
+    pub bind: Option<String>,
+
+    /// Also serve clients on this Unix domain socket (length-prefixed JSON frames)
+    #[arg(long, value_name = "PATH")]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Also serve clients over HTTP long-polling on this address, for networks that block WebSocket
+    #[arg(long, value_name = "ADDR")]
+    pub long_poll: Option<String>,
+
+    /// Also stream changes as Server-Sent Events on this address, for browsers using `EventSource`
+    #[arg(long, value_name = "ADDR")]
+    pub sse: Option<String>,
+
+    /// Also publish every change to this NATS server (`host:port`), on `markdown-op.{file_id}`
+    #[arg(long, value_name = "ADDR", env = "NATS_ADDR")]
+    pub nats: Option<String>,
+
+    /// Diff strategy, e.g. `line` or `char,md=line` (default plus per-extension overrides)
+    #[arg(long = "diff", value_name = "SPEC", env = "DIFF_STRATEGY")]
+    pub diff_strategy: Option<String>,
+
+    /// Diff new content against what was last read (`read`) or last broadcast to clients (`broadcast`)
+    #[arg(long, value_enum, value_name = "BASE")]
+    pub diff_base: Option<DiffBase>,
+
+    /// Send every change as full content and never diff, for the simplest, most robust mirroring
+    #[arg(long)]
+    pub no_diff: bool,
+
+    /// Check every diff reproduces the new content before broadcasting it, and send full content when it does not
+    #[arg(long)]
+    pub verify_diffs: bool,
+
+    /// Hold back broken intermediate saves (unclosed fences, comments, front matter)
+    #[arg(long)]
+    pub validate: bool,
+
+    /// Only mirror content that has this line, e.g. `<!-- ready -->`; other saves are not broadcast
+    #[arg(long, value_name = "LINE")]
+    pub mirror_when_marker: Option<String>,
+
+    /// Only broadcast a change once the file was untouched for this long, hiding partial writes
+    #[arg(long, value_name = "MS")]
+    pub quiescence_ms: Option<u64>,
+
+    /// Don't read changed files while no client is connected, catch up when one connects
+    #[arg(long)]
+    pub lazy: bool,
+
+    /// Broadcast permission and modification time changes of the watched files as `MetadataChanged`
+    #[arg(long)]
+    pub watch_metadata: bool,
+
+    /// How changed files are read; `mmap` avoids copying large files that did not change
+    #[arg(long, value_enum, value_name = "STRATEGY")]
+    pub read_strategy: Option<ReadStrategy>,
+
+    /// `normalize` ends every file with exactly one newline, so saves that only add or strip trailing whitespace broadcast nothing
+    #[arg(long, value_enum, value_name = "MODE")]
+    pub trailing_whitespace: Option<TrailingWhitespace>,
+
+    /// Keep recent changes so reconnecting clients receive what they missed instead of full content
+    #[arg(long)]
+    pub history: bool,
+
+    /// With --history, a client missing more than N changes gets the content as of the last N and only those
+    #[arg(long, value_name = "N")]
+    pub max_backfill: Option<usize>,
+
+    /// Show the watched files, clients, recent changes and bytes sent in a live terminal UI; q quits
+    #[arg(long)]
+    pub tui: bool,
+
+    /// Send clients reconnecting with a copy of a large file its chunk hashes, so they fetch only the changed chunks
+    #[arg(long)]
+    pub chunks: bool,
+
+    /// Start new clients from this file's content, then send the diffs to the watched file (golden-file testing)
+    #[arg(long, value_name = "PATH")]
+    pub initial_snapshot: Option<PathBuf>,
+
+    /// Number of runtime worker threads [default: available parallelism]
+    #[arg(long, value_name = "N", env = "WORKER_THREADS")]
+    pub worker_threads: Option<NonZeroUsize>,
+
+    /// Token clients must present to connect
+    #[arg(long, value_name = "TOKEN", env = "MARKDOWN_OP_TOKEN", hide_env_values = true)]
+    pub auth_token: Option<String>,
+
+    /// PEM certificate chain to serve WebSocket clients over TLS (`wss://`) with, together with --tls-key
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Require client certificates signed by these PEM CA certificates (mutual TLS); a certificate's common name is the client's id
+    #[arg(long, value_name = "PATH")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Answer every client message with an `Echo` of it as the server applied it, to debug clients
+    #[arg(long)]
+    pub echo: bool,
+
+    /// Send `SyncStart` with the size of each file's content before a connecting client gets it, and `SyncComplete` after
+    #[arg(long)]
+    pub sync_report: bool,
+
+    /// Sign every message with this Ed25519 secret key (base64 of 32 bytes); clients verify with the public key
+    #[arg(long, value_name = "KEY", env = "MARKDOWN_OP_SIGNING_KEY", hide_env_values = true)]
+    pub signing_key: Option<String>,
+}
+
+impl Cli {
+    /// The watched files given on the command line, from either the positional argument or `--watch`
+    pub fn watched_files(&self) -> Vec<String> {
+        self.file.iter().chain(&self.watch).cloned().collect()
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Time handing broadcast changes to many clients, with the JSON shared by
+    /// all of them and serialized per client, and exit
+    BenchBroadcast {
+        /// Subscribers to hand every change to
+        #[arg(long, value_name = "N", default_value_t = 50)]
+        clients: usize,
+        /// Changes to publish, one after another
+        #[arg(long, value_name = "M", default_value_t = 200)]
+        changes: usize,
+        /// Chars of content in every change
+        #[arg(long, value_name = "CHARS", default_value_t = 10_000)]
+        size: usize,
+    },
+    /// Check the watched files, listen addresses and file watcher with the given
+    /// options, print what is wrong and how to fix it, and exit
+    Doctor,
+    /// Time the diff engine on synthetic content with random edits, without a
+    /// server or clients, check every diff applies, and exit
+    BenchDiff {
+        /// Chars of synthetic content to start from
+        #[arg(long, value_name = "N", default_value_t = 10_000)]
+        size: usize,
+        /// Random edits to diff, one after another
+        #[arg(long, value_name = "M", default_value_t = 100)]
+        edits: usize,
+        /// Diff strategy to time, e.g. `line` to compare with the default
+        #[arg(long, value_name = "STRATEGY", default_value = "char")]
+        strategy: DiffStrategyKind,
+        /// Seed of the content and edits, so runs with the same seed diff the same text
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::error::ErrorKind;
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from([&["server"], args].concat())
+    }
+
+    fn error(args: &[&str]) -> ErrorKind {
+        parse(args).expect_err("invalid arguments").kind()
+    }
+
+    #[test]
+    fn defaults_leave_everything_to_the_config() {
+        let cli = parse(&[]).expect("parse");
+        assert!(cli.command.is_none());
+        assert!(cli.watched_files().is_empty());
+        assert_eq!(cli.bind, None);
+        assert_eq!(cli.diff_strategy, None);
+        assert_eq!(cli.worker_threads, None);
+        assert!(!cli.stdin && !cli.history && !cli.no_diff);
+    }
+
+    #[test]
+    fn watched_files_come_from_the_argument_or_watch() {
+        assert_eq!(parse(&["a.md"]).expect("parse").watched_files(), ["a.md"]);
+        assert_eq!(parse(&["-w", "a.md", "--watch", "b.md"]).expect("parse").watched_files(), ["a.md", "b.md"]);
+        assert_eq!(error(&["a.md", "--watch", "b.md"]), ErrorKind::ArgumentConflict);
+        assert_eq!(error(&["--stdin", "a.md"]), ErrorKind::ArgumentConflict);
+        assert_eq!(error(&["--stdin", "--watch", "a.md"]), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn tls_needs_both_the_certificate_and_the_key() {
+        assert_eq!(error(&["--tls-cert", "cert.pem"]), ErrorKind::MissingRequiredArgument);
+        assert_eq!(error(&["--tls-key", "key.pem"]), ErrorKind::MissingRequiredArgument);
+        assert!(parse(&["--tls-cert", "cert.pem", "--tls-key", "key.pem"]).is_ok());
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert_eq!(error(&["--worker-threads", "0"]), ErrorKind::ValueValidation);
+        assert_eq!(error(&["--quiescence-ms", "soon"]), ErrorKind::ValueValidation);
+        assert_eq!(error(&["--read-strategy", "slurp"]), ErrorKind::InvalidValue);
+        assert_eq!(error(&["--diff-base", "disk"]), ErrorKind::InvalidValue);
+        assert_eq!(error(&["--match", "fuzzy"]), ErrorKind::InvalidValue);
+    }
+
+    #[test]
+    fn bench_diff_has_defaults_for_everything() {
+        let Some(Command::BenchDiff { size, edits, strategy, seed }) = parse(&["bench-diff"]).expect("parse").command else {
+            panic!("expected bench-diff");
+        };
+        assert_eq!((size, edits, strategy, seed), (10_000, 100, DiffStrategyKind::Char, 0));
+        assert_eq!(error(&["bench-diff", "--strategy", "byte"]), ErrorKind::ValueValidation);
+    }
+}
@@ -0,0 +1,60 @@
+use std::time::{Duration, Instant};
+use futures_util::future::BoxFuture;
+
+/// Source of the current time for the watcher's debounce and read throttling,
+/// so the timing logic can be driven without real sleeps
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Completes once `duration` has passed on this clock
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The real monotonic clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A clock that only moves when told to, see [`ManualClock::advance`]
+#[cfg(test)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed: tokio::sync::watch::Sender<Duration>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self { start: Instant::now(), elapsed: tokio::sync::watch::Sender::new(Duration::ZERO) }
+    }
+
+    /// Moves the clock forward, waking the sleeps that are now over
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += duration);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.borrow()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let deadline = *self.elapsed.borrow() + duration;
+        let mut elapsed = self.elapsed.subscribe();
+        Box::pin(async move {
+            // the sender lives as long as the clock, which outlives what it times
+            let _ = elapsed.wait_for(|elapsed| *elapsed >= deadline).await;
+        })
+    }
+}
@@ -0,0 +1,707 @@
+use std::{collections::HashMap, net::SocketAddr, path::{Path, PathBuf}, time::Duration};
+use serde::Deserialize;
+use shared::protocol::{DEFAULT_BIND_ADDR, DEFAULT_WATCH_FILE};
+use shared::{framing, ChecksumAlgorithm, DiffStrategyKind};
+use shared::signing::Signer;
+use shared::tcp::TcpOptions;
+use crate::cli::Cli;
+use crate::history::HistoryConfig;
+use crate::hooks::Hook;
+use crate::matcher::{MatchMode, PatternMatcher};
+use crate::reader::{ReadStrategy, TrailingWhitespace};
+use crate::tls::TlsConfig;
+use crate::validation::{MirrorWhen, ValidationConfig};
+
+/// Config file picked up from the working directory when `--config` is not given
+pub const DEFAULT_CONFIG_FILE: &str = "markdown-op.toml";
+
+/// tungstenite's own limit, which the client defaults to as well
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 << 20;
+
+/// Room left in a message for everything but the content: seq, file id,
+/// modification time and signature
+const MESSAGE_OVERHEAD: usize = 4096;
+
+/// File id of the content mirrored from stdin
+pub const STDIN_FILE_ID: &str = "stdin";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {path}: {source}")]
+    Read { path: PathBuf, source: std::io::Error },
+    #[error("invalid config file {path}: {message}")]
+    Parse { path: PathBuf, message: String },
+    #[error("invalid config: {0}")]
+    Invalid(String),
+}
+
+/// Runtime settings of the server, loaded from the config file and overridden by flags
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Files to watch and mirror, or patterns selecting them, see `match_mode`
+    pub watch: Vec<String>,
+    /// Whether `watch` holds paths (`exact`), `glob` or `regex` patterns
+    pub match_mode: MatchMode,
+    /// The files `watch` selects, mirrored under these ids; filled in when the config is loaded
+    #[serde(skip)]
+    pub files: Vec<String>,
+    /// With patterns, each of them and the files it selected; a file two
+    /// patterns select belongs to the first
+    #[serde(skip)]
+    pub matched: Vec<(PatternMatcher, Vec<String>)>,
+    /// Mirror content piped to stdin instead of the watched files
+    pub stdin: bool,
+    /// With `stdin`, how long stdin must be quiet before what was read is broadcast as a new version
+    pub stdin_interval_ms: u64,
+    /// Address the WebSocket server listens on
+    pub bind: String,
+    /// Also serve clients on this Unix domain socket (length-prefixed JSON frames)
+    pub unix_socket: Option<PathBuf>,
+    /// Also serve clients over HTTP long-polling on this address
+    pub long_poll: Option<String>,
+    /// Also stream changes as Server-Sent Events on this address
+    pub sse: Option<String>,
+    /// Window in which repeated events for the same path are ignored
+    pub debounce_ms: u64,
+    /// Minimum time between two full reads of the same file
+    pub min_read_interval_ms: u64,
+    /// Only read a changed file once its size and modification time were stable this long (0 disables)
+    pub quiescence_ms: u64,
+    /// How long to wait for a rewrite after reading an empty file before mirroring it (0 disables)
+    pub empty_settle_ms: u64,
+    /// Don't read changed files while no client is connected; they are read once one connects
+    pub lazy: bool,
+    /// Broadcast permission and modification time changes of the watched files,
+    /// which are otherwise ignored, without reading their content
+    pub watch_metadata: bool,
+    /// How changed files are read: `read` copies each version into memory,
+    /// `mmap` maps the file and skips the copy when nothing changed
+    pub read_strategy: ReadStrategy,
+    /// Whether the whitespace a file ends with is mirrored as it is or normalized
+    /// to one newline, so saves that only add or strip it broadcast nothing
+    pub trailing_whitespace: TrailingWhitespace,
+    /// Send full content instead of a diff every N changes of a file (0 disables)
+    pub full_content_every: u64,
+    /// A file changing more often than this per second is throttled (0 disables)
+    pub max_broadcasts_per_sec: u32,
+    /// How often a throttled file's latest content is broadcast
+    pub throttled_interval_ms: u64,
+    /// Clients that connect without changes to resume from start from this
+    /// file's content instead, then get the diffs to the watched file
+    pub initial_snapshot: Option<PathBuf>,
+    /// Token clients must present when connecting, if set
+    pub auth_token: Option<String>,
+    /// Base64 Ed25519 secret key to sign every message sent to clients with, if set
+    pub signing_key: Option<String>,
+    /// Answer every client message with how the server understood and applied
+    /// it, for client authors checking their messages
+    pub echo: bool,
+    /// Bracket the content sent to a connecting client for each file with
+    /// `SyncStart` (giving its size) and `SyncComplete`
+    pub sync_report: bool,
+    pub diff: DiffConfig,
+    pub limits: Limits,
+    pub validation: ValidationConfig,
+    pub mirror_when: MirrorWhen,
+    pub history: HistoryConfig,
+    pub publish: PublishConfig,
+    pub chunks: ChunkConfig,
+    pub tls: TlsConfig,
+    pub tcp: TcpConfig,
+    /// Commands run after a change is broadcast, `[[hooks]]` in the config file
+    pub hooks: Vec<Hook>,
+}
+
+/// Which diff strategy is used for which files
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiffConfig {
+    /// Strategy for files without a more specific one
+    pub default: DiffStrategyKind,
+    /// Strategy per file extension, e.g. `md = "line"`
+    pub extensions: HashMap<String, DiffStrategyKind>,
+    /// Strategy per watched file, taking precedence over its extension,
+    /// e.g. `"docs/index.md" = "frontmatter"`
+    pub files: HashMap<String, DiffStrategyKind>,
+    /// Policy per file extension, e.g. `svg = "full"` for files that diff poorly
+    pub policies: HashMap<String, DiffPolicy>,
+    /// What a new version of a file is diffed against
+    pub base: DiffBase,
+    /// Never diff: every change to every file is sent as full content, whatever
+    /// `policies` say
+    pub disabled: bool,
+    /// Apply every diff to a copy of the previous content before broadcasting
+    /// it, and send full content instead when the result is not the new content
+    pub verify: bool,
+}
+
+/// Whether changes to a file are sent as diffs at all
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffPolicy {
+    /// Diffs, except for files under `limits.full_content_threshold`
+    #[default]
+    Diff,
+    /// Always the full content, whatever the size of the file, e.g. for
+    /// generated files where a small change rewrites most of the file anyway
+    Full,
+}
+
+/// The version of a file new content is diffed against
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffBase {
+    /// The content last read from disk
+    #[default]
+    Read,
+    /// The content as of the last broadcast change, which is what clients
+    /// hold; a read that broadcast nothing, or that nobody was connected to
+    /// receive, does not move it
+    Broadcast,
+}
+
+/// Where changes are published besides the connected clients
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PublishConfig {
+    /// `host:port` of a NATS server every change is also published to
+    pub nats: Option<String>,
+    /// Changes to a file are published on the subject `{subject_prefix}.{file_id}`
+    pub subject_prefix: String,
+    /// Serve WebSocket clients; without, changes only go to the broker and the other transports
+    pub websocket: bool,
+}
+
+/// Chunk sync: a client reconnecting with a copy of a large file is sent the
+/// hashes of its chunks and fetches only the chunks that changed, see [`shared::chunks`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChunkConfig {
+    pub enabled: bool,
+    /// Chars per chunk
+    pub size: usize,
+    /// Files smaller than this are sent in full as usual
+    pub min_bytes: usize,
+    /// How chunks and the whole file are hashed
+    pub algorithm: ChecksumAlgorithm,
+}
+
+/// Socket options of WebSocket and SSE connections, see [`shared::tcp`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    /// Send small messages right away instead of batching them (`TCP_NODELAY`)
+    pub nodelay: bool,
+    /// Idle seconds before the first keepalive probe (0 disables keepalive)
+    pub keepalive_secs: u64,
+    /// Seconds between unanswered keepalive probes
+    pub keepalive_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_connections: usize,
+    /// Files smaller than this are always sent as full content
+    pub full_content_threshold: usize,
+    /// Bound on the content kept in memory to diff against, across all files;
+    /// the files changed least recently are dropped and resent as full content (0 disables)
+    pub max_cached_bytes: usize,
+    /// Largest length-prefixed frame sent or accepted on the Unix socket
+    pub max_frame_bytes: usize,
+    /// Largest WebSocket message sent or accepted; a file whose content would
+    /// not fit in one is held back like a file that failed validation
+    pub max_message_bytes: usize,
+    /// File events queued for processing per watch; past it events are dropped
+    /// and the watch's files are read again once the queue drains
+    pub event_queue_capacity: usize,
+    /// Changes queued per file for each connection; a connection that falls
+    /// further behind on a file is closed so the client resyncs
+    pub channel_capacity: usize,
+    /// Milliseconds a message may wait for a client to read it before the
+    /// connection is closed as stuck (0 waits forever)
+    pub send_timeout_ms: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            watch: vec![DEFAULT_WATCH_FILE.to_string()],
+            match_mode: MatchMode::default(),
+            files: vec![DEFAULT_WATCH_FILE.to_string()],
+            matched: Vec::new(),
+            stdin: false,
+            stdin_interval_ms: 100,
+            bind: DEFAULT_BIND_ADDR.to_string(),
+            unix_socket: None,
+            long_poll: None,
+            sse: None,
+            debounce_ms: 25,
+            min_read_interval_ms: 100,
+            quiescence_ms: 0,
+            empty_settle_ms: 50,
+            lazy: false,
+            watch_metadata: false,
+            read_strategy: ReadStrategy::default(),
+            trailing_whitespace: TrailingWhitespace::default(),
+            full_content_every: 0,
+            max_broadcasts_per_sec: 0,
+            throttled_interval_ms: 500,
+            initial_snapshot: None,
+            auth_token: None,
+            echo: false,
+            sync_report: false,
+            signing_key: None,
+            diff: DiffConfig::default(),
+            limits: Limits::default(),
+            validation: ValidationConfig::default(),
+            mirror_when: MirrorWhen::default(),
+            history: HistoryConfig::default(),
+            publish: PublishConfig::default(),
+            chunks: ChunkConfig::default(),
+            tls: TlsConfig::default(),
+            tcp: TcpConfig::default(),
+            hooks: Vec::new(),
+        }
+    }
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        Self {
+            nats: None,
+            subject_prefix: "markdown-op".to_string(),
+            websocket: true,
+        }
+    }
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            size: 64 << 10,
+            min_bytes: 1 << 20,
+            algorithm: ChecksumAlgorithm::default(),
+        }
+    }
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        let options = TcpOptions::default();
+        Self {
+            nodelay: options.nodelay,
+            keepalive_secs: options.keepalive.map_or(0, |time| time.as_secs()),
+            keepalive_interval_secs: options.keepalive_interval.as_secs(),
+        }
+    }
+}
+
+impl TcpConfig {
+    pub fn options(&self) -> TcpOptions {
+        TcpOptions {
+            nodelay: self.nodelay,
+            keepalive: (self.keepalive_secs > 0).then(|| Duration::from_secs(self.keepalive_secs)),
+            keepalive_interval: Duration::from_secs(self.keepalive_interval_secs),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_connections: 100,
+            full_content_threshold: 1024,
+            max_cached_bytes: 0,
+            max_frame_bytes: framing::DEFAULT_MAX_FRAME_LEN,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            event_queue_capacity: 500,
+            channel_capacity: 1000,
+            send_timeout_ms: 30_000,
+        }
+    }
+}
+
+impl Limits {
+    pub fn send_timeout(&self) -> Option<Duration> {
+        (self.send_timeout_ms > 0).then(|| Duration::from_millis(self.send_timeout_ms))
+    }
+}
+
+impl ServerConfig {
+    /// Loads the config file (if any) and applies the command-line overrides
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+        config.apply_cli(cli)?;
+        config.match_files()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parses a TOML config file, or JSON when the file has a `.json` extension
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let parse_error = |message: String| ConfigError::Parse { path: path.to_path_buf(), message };
+        if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&text).map_err(|e| parse_error(e.to_string()))
+        } else {
+            toml::from_str(&text).map_err(|e| parse_error(e.to_string()))
+        }
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) -> Result<(), ConfigError> {
+        let watched_files = cli.watched_files();
+        if !watched_files.is_empty() {
+            self.watch = watched_files;
+        }
+        if cli.stdin {
+            self.stdin = true;
+        }
+        if let Some(bind) = &cli.bind {
+            self.bind = bind.clone();
+        }
+        if let Some(path) = &cli.unix_socket {
+            self.unix_socket = Some(path.clone());
+        }
+        if let Some(addr) = &cli.long_poll {
+            self.long_poll = Some(addr.clone());
+        }
+        if let Some(addr) = &cli.sse {
+            self.sse = Some(addr.clone());
+        }
+        if let Some(path) = &cli.initial_snapshot {
+            self.initial_snapshot = Some(path.clone());
+        }
+        if let Some(token) = &cli.auth_token {
+            self.auth_token = Some(token.clone());
+        }
+        if let Some(key) = &cli.signing_key {
+            self.signing_key = Some(key.clone());
+        }
+        if let Some(path) = &cli.tls_cert {
+            self.tls.cert = Some(path.clone());
+        }
+        if let Some(path) = &cli.tls_key {
+            self.tls.key = Some(path.clone());
+        }
+        if let Some(path) = &cli.tls_client_ca {
+            self.tls.client_ca = Some(path.clone());
+        }
+        if cli.echo {
+            self.echo = true;
+        }
+        if cli.sync_report {
+            self.sync_report = true;
+        }
+        if cli.validate {
+            self.validation.enabled = true;
+        }
+        if let Some(marker) = &cli.mirror_when_marker {
+            self.mirror_when.marker = Some(marker.clone());
+        }
+        if let Some(quiescence_ms) = cli.quiescence_ms {
+            self.quiescence_ms = quiescence_ms;
+        }
+        if cli.lazy {
+            self.lazy = true;
+        }
+        if cli.watch_metadata {
+            self.watch_metadata = true;
+        }
+        if let Some(read_strategy) = cli.read_strategy {
+            self.read_strategy = read_strategy;
+        }
+        if let Some(trailing_whitespace) = cli.trailing_whitespace {
+            self.trailing_whitespace = trailing_whitespace;
+        }
+        if cli.history {
+            self.history.enabled = true;
+        }
+        if let Some(max_backfill) = cli.max_backfill {
+            self.history.max_backfill = max_backfill;
+        }
+        if cli.chunks {
+            self.chunks.enabled = true;
+        }
+        if let Some(spec) = &cli.diff_strategy {
+            self.diff.apply_spec(spec)?;
+        }
+        if let Some(base) = cli.diff_base {
+            self.diff.base = base;
+        }
+        if cli.no_diff {
+            self.diff.disabled = true;
+        }
+        if cli.verify_diffs {
+            self.diff.verify = true;
+        }
+        if let Some(addr) = &cli.nats {
+            self.publish.nats = Some(addr.clone());
+        }
+        if let Some(match_mode) = cli.match_mode {
+            self.match_mode = match_mode;
+        }
+        Ok(())
+    }
+
+    /// Fills in `files` from `watch`. A pattern that selects no file is an
+    /// error, since files created later are not mirrored.
+    fn match_files(&mut self) -> Result<(), ConfigError> {
+        self.matched.clear();
+        if self.match_mode == MatchMode::Exact || self.stdin {
+            self.files = self.watch.clone();
+            return Ok(());
+        }
+        let mut files = Vec::new();
+        for pattern in &self.watch {
+            let matcher = match self.match_mode {
+                MatchMode::Glob => PatternMatcher::glob(pattern),
+                _ => PatternMatcher::regex(pattern),
+            };
+            let matcher = matcher.map_err(|e| ConfigError::Invalid(format!("watch pattern {:?}: {}", pattern, e)))?;
+            let matching = matcher
+                .files()
+                .map_err(|e| ConfigError::Invalid(format!("watch pattern {:?}: {}", pattern, e)))?;
+            if matching.is_empty() {
+                return Err(ConfigError::Invalid(format!("watch pattern {:?} matches no files", pattern)));
+            }
+            let matching: Vec<String> = matching.into_iter().filter(|file| !files.contains(file)).collect();
+            files.extend(matching.iter().cloned());
+            self.matched.push((matcher, matching));
+        }
+        self.files = files;
+        Ok(())
+    }
+
+    /// Ids of the files clients are sent
+    pub fn file_ids(&self) -> Vec<&str> {
+        if self.stdin {
+            vec![STDIN_FILE_ID]
+        } else {
+            self.files.iter().map(String::as_str).collect()
+        }
+    }
+
+    /// Checks content can be mirrored: it passes validation and fits in one
+    /// message. Returns why it is held back otherwise.
+    pub fn check_content(&self, content: &str) -> Result<(), String> {
+        let max = self.limits.max_message_bytes;
+        // every char escaped as \uXXXX would still fit, so skip counting
+        let fits = content.len().saturating_mul(6).saturating_add(MESSAGE_OVERHEAD + 2) <= max;
+        if !fits && json_len(content) + MESSAGE_OVERHEAD > max {
+            return Err(format!(
+                "content of {} bytes does not fit in a message of at most {} bytes (limits.max_message_bytes)",
+                content.len(),
+                self.limits.max_message_bytes
+            ));
+        }
+        self.validation.check(content)
+    }
+
+    /// The signer for `signing_key`, which was validated when the config was loaded
+    pub fn signer(&self) -> Option<Signer> {
+        self.signing_key.as_deref().and_then(|key| Signer::from_base64(key).ok())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.watch.is_empty() && !self.stdin {
+            return Err(ConfigError::Invalid("no files to watch".to_string()));
+        }
+        if self.bind.parse::<SocketAddr>().is_err() {
+            return Err(ConfigError::Invalid(format!("bind address {:?} is not a valid socket address", self.bind)));
+        }
+        if let Some(addr) = self.long_poll.as_ref().filter(|addr| addr.parse::<SocketAddr>().is_err()) {
+            return Err(ConfigError::Invalid(format!("long_poll address {:?} is not a valid socket address", addr)));
+        }
+        if let Some(addr) = self.sse.as_ref().filter(|addr| addr.parse::<SocketAddr>().is_err()) {
+            return Err(ConfigError::Invalid(format!("sse address {:?} is not a valid socket address", addr)));
+        }
+        if self.initial_snapshot.is_some() && (self.stdin || self.files.len() != 1) {
+            return Err(ConfigError::Invalid("initial_snapshot needs exactly one watched file".to_string()));
+        }
+        if self.max_broadcasts_per_sec > 0 && self.throttled_interval_ms == 0 {
+            return Err(ConfigError::Invalid("throttled_interval_ms must be at least 1".to_string()));
+        }
+        if self.limits.max_connections == 0 {
+            return Err(ConfigError::Invalid("limits.max_connections must be at least 1".to_string()));
+        }
+        if self.limits.max_message_bytes <= MESSAGE_OVERHEAD {
+            return Err(ConfigError::Invalid(format!("limits.max_message_bytes must be over {}", MESSAGE_OVERHEAD)));
+        }
+        if self.chunks.size == 0 {
+            return Err(ConfigError::Invalid("chunks.size must be at least 1".to_string()));
+        }
+        if self.tcp.keepalive_secs > 0 && self.tcp.keepalive_interval_secs == 0 {
+            return Err(ConfigError::Invalid("tcp.keepalive_interval_secs must be at least 1".to_string()));
+        }
+        if self.limits.max_frame_bytes == 0 {
+            return Err(ConfigError::Invalid("limits.max_frame_bytes must be at least 1".to_string()));
+        }
+        if self.limits.event_queue_capacity == 0 {
+            return Err(ConfigError::Invalid("limits.event_queue_capacity must be at least 1".to_string()));
+        }
+        if self.limits.channel_capacity == 0 {
+            return Err(ConfigError::Invalid("limits.channel_capacity must be at least 1".to_string()));
+        }
+        let prefix = &self.publish.subject_prefix;
+        if prefix.is_empty() || prefix.chars().any(|c| matches!(c, '*' | '>') || c.is_whitespace()) {
+            return Err(ConfigError::Invalid(format!("publish.subject_prefix {:?} is not a valid NATS subject", prefix)));
+        }
+        if !self.publish.websocket && self.publish.nats.is_none() && self.unix_socket.is_none() && self.long_poll.is_none() && self.sse.is_none() {
+            return Err(ConfigError::Invalid("publish.websocket is off and there is no broker or other transport to send changes to".to_string()));
+        }
+        if self.auth_token.as_deref().is_some_and(str::is_empty) {
+            return Err(ConfigError::Invalid("auth_token must not be empty".to_string()));
+        }
+        if let Some(Err(e)) = self.signing_key.as_deref().map(Signer::from_base64) {
+            return Err(ConfigError::Invalid(format!("signing_key: {}", e)));
+        }
+        self.tls.validate().map_err(ConfigError::Invalid)?;
+        Ok(())
+    }
+}
+
+impl DiffConfig {
+    /// Applies a spec such as `line` or `char,md=line,json=char`
+    pub fn apply_spec(&mut self, spec: &str) -> Result<(), ConfigError> {
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.split_once('=') {
+                Some((extension, kind)) => {
+                    let extension = extension.trim().trim_start_matches('.').to_string();
+                    self.extensions.insert(extension, kind.parse().map_err(ConfigError::Invalid)?);
+                }
+                None => self.default = entry.parse().map_err(ConfigError::Invalid)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The policy for the given file
+    pub fn policy_for(&self, path: &Path) -> DiffPolicy {
+        if self.disabled {
+            return DiffPolicy::Full;
+        }
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| self.policies.get(ext))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The strategy to use for the given file
+    pub fn strategy_for(&self, path: &Path) -> DiffStrategyKind {
+        if let Some(kind) = path.to_str().and_then(|path| self.files.get(path)) {
+            return *kind;
+        }
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| self.extensions.get(ext))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Length of `content` as a JSON string, escapes included
+fn json_len(content: &str) -> usize {
+    content
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' | '\n' | '\r' | '\t' | '\u{8}' | '\u{c}' => 2,
+            c if (c as u32) < 0x20 => 6,
+            c => c.len_utf8(),
+        })
+        .sum::<usize>()
+        + 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../markdown-op.example.toml");
+
+    /// A config with one watched file and everything else at its default
+    fn watching_one_file() -> ServerConfig {
+        ServerConfig { watch: vec!["doc.md".to_string()], files: vec!["doc.md".to_string()], ..ServerConfig::default() }
+    }
+
+    fn invalid(config: &ServerConfig) -> String {
+        match config.validate() {
+            Err(ConfigError::Invalid(message)) => message,
+            other => panic!("expected the config to be invalid, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn the_example_config_loads_and_is_valid() {
+        let config = ServerConfig::from_file(Path::new(EXAMPLE)).expect("example config");
+        assert_eq!(config.watch, ["README.md"]);
+        assert_eq!(config.diff.extensions.get("md"), Some(&DiffStrategyKind::Line));
+        config.validate().expect("valid");
+    }
+
+    #[test]
+    fn json_configs_load_like_toml() {
+        let text = std::fs::read_to_string(EXAMPLE).expect("read example config");
+        let example: toml::Value = toml::from_str(&text).expect("parse example config");
+        let path = std::env::temp_dir().join(format!("markdown-op-config-{}.json", std::process::id()));
+        std::fs::write(&path, serde_json::to_string(&example).expect("JSON")).expect("write JSON config");
+        let json = ServerConfig::from_file(&path);
+        let _ = std::fs::remove_file(&path);
+        let toml = ServerConfig::from_file(Path::new(EXAMPLE)).expect("example config");
+        assert_eq!(format!("{:?}", json.expect("JSON config")), format!("{toml:?}"));
+    }
+
+    #[test]
+    fn unknown_and_mistyped_fields_are_rejected() {
+        let path = std::env::temp_dir().join(format!("markdown-op-config-{}.toml", std::process::id()));
+        for text in ["wach = [\"README.md\"]\n", "debounce_ms = \"fast\"\n", "[limits]\nmax_conections = 5\n"] {
+            std::fs::write(&path, text).expect("write config");
+            let loaded = ServerConfig::from_file(&path);
+            assert!(matches!(loaded, Err(ConfigError::Parse { .. })), "{text}: {loaded:?}");
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn validate_rejects_bad_combinations() {
+        watching_one_file().validate().expect("valid");
+        assert_eq!(invalid(&ServerConfig { watch: Vec::new(), ..watching_one_file() }), "no files to watch");
+        assert!(invalid(&ServerConfig { bind: "localhost".to_string(), ..watching_one_file() }).contains("not a valid socket address"));
+
+        let mut no_connections = watching_one_file();
+        no_connections.limits.max_connections = 0;
+        assert_eq!(invalid(&no_connections), "limits.max_connections must be at least 1");
+
+        let two_files = vec!["a.md".to_string(), "b.md".to_string()];
+        let snapshot = ServerConfig { files: two_files, initial_snapshot: Some(PathBuf::from("start.md")), ..watching_one_file() };
+        assert_eq!(invalid(&snapshot), "initial_snapshot needs exactly one watched file");
+
+        let throttled = ServerConfig { max_broadcasts_per_sec: 10, throttled_interval_ms: 0, ..watching_one_file() };
+        assert_eq!(invalid(&throttled), "throttled_interval_ms must be at least 1");
+
+        let mut nowhere = watching_one_file();
+        nowhere.publish.websocket = false;
+        assert!(invalid(&nowhere).starts_with("publish.websocket is off"));
+        nowhere.long_poll = Some("127.0.0.1:3031".to_string());
+        nowhere.validate().expect("long-poll clients get the changes");
+
+        let mut keepalive = watching_one_file();
+        keepalive.tcp.keepalive_interval_secs = 0;
+        assert_eq!(invalid(&keepalive), "tcp.keepalive_interval_secs must be at least 1");
+        keepalive.tcp.keepalive_secs = 0;
+        keepalive.validate().expect("keepalive is off");
+
+        assert_eq!(invalid(&ServerConfig { auth_token: Some(String::new()), ..watching_one_file() }), "auth_token must not be empty");
+        assert!(invalid(&ServerConfig { signing_key: Some("short".to_string()), ..watching_one_file() }).starts_with("signing_key: "));
+    }
+}
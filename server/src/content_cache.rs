@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+
+/// The last content read of every watched file, which new versions are diffed
+/// against. When the total size goes over `max_bytes`, the files changed least
+/// recently are evicted; their next change is then sent as full content.
+#[derive(Default)]
+pub struct ContentCache {
+    entries: HashMap<String, Entry>,
+    total_bytes: usize,
+    /// Bound on `total_bytes` (0 for none)
+    pub max_bytes: usize,
+    changes: u64,
+}
+
+struct Entry {
+    content: String,
+    /// Value of `changes` when the content was stored, to find the least recently changed file
+    changed: u64,
+}
+
+impl ContentCache {
+    pub fn get(&self, file_id: &str) -> Option<&String> {
+        self.entries.get(file_id).map(|entry| &entry.content)
+    }
+
+    /// Every cached file with its content
+    #[cfg(debug_assertions)]
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.entries.iter().map(|(file_id, entry)| (file_id, &entry.content))
+    }
+
+    /// Stores the content of a file, then evicts other files until the cache
+    /// is within `max_bytes`. The file just stored is kept even if it alone is
+    /// over the bound, since its next change is the likeliest.
+    pub fn insert(&mut self, file_id: String, content: String) {
+        self.changes += 1;
+        self.total_bytes += content.len();
+        let entry = Entry { content, changed: self.changes };
+        if let Some(old) = self.entries.insert(file_id.clone(), entry) {
+            self.total_bytes -= old.content.len();
+        }
+        while self.max_bytes > 0 && self.total_bytes > self.max_bytes {
+            let Some(evicted) = self
+                .entries
+                .iter()
+                .filter(|(id, _)| **id != file_id)
+                .min_by_key(|(_, entry)| entry.changed)
+                .map(|(id, _)| id.clone())
+            else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&evicted) {
+                self.total_bytes -= entry.content.len();
+                println!("Evicted cached content of {} ({} bytes), its next change is sent as full content", evicted, entry.content.len());
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::{fmt::Display, io, net::TcpListener, path::Path, sync::Arc};
+use crate::cli::Cli;
+use crate::config::ServerConfig;
+use crate::history::History;
+use crate::reader;
+use crate::watcher::FileWatcher;
+
+/// Results of the checks, printed as they run
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn ok(&self, check: &str, detail: impl Display) {
+        println!("ok    {check}: {detail}");
+    }
+
+    fn fail(&mut self, check: &str, problem: impl Display, hint: impl Display) {
+        self.failures += 1;
+        println!("FAIL  {check}: {problem}");
+        println!("      {hint}");
+    }
+}
+
+/// Checks what the server needs to start with the options in `cli`: the
+/// config, each watched file, the file watcher and the listen addresses.
+/// Returns whether every check passed.
+pub async fn run(cli: &Cli) -> bool {
+    let mut report = Report::default();
+    let config = match ServerConfig::load(cli) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            report.fail("config", e, "fix the config file or the flags it names; nothing else can be checked without it");
+            return summarize(&report);
+        }
+    };
+    report.ok("config", "loaded");
+    let history = Arc::new(History::new(config.limits.channel_capacity, config.history.clone(), None));
+    let mut watcher = FileWatcher::new(Arc::clone(&config), history);
+    for file in &config.files {
+        if check_file(&mut report, &config, file) {
+            match watcher.watch_file(file.clone(), file) {
+                Ok(()) => report.ok(&format!("watcher {file}"), "receiving change notifications"),
+                Err(e) => report.fail(
+                    &format!("watcher {file}"),
+                    format!("the file watcher could not start: {e}"),
+                    "on Linux, too many watches or instances may be in use; raise fs.inotify.max_user_watches and fs.inotify.max_user_instances",
+                ),
+            }
+        }
+    }
+    check_bind(&mut report, "bind", &config.bind, "--bind");
+    if let Some(addr) = &config.long_poll {
+        check_bind(&mut report, "long-poll", addr, "--long-poll");
+    }
+    if let Some(addr) = &config.sse {
+        check_bind(&mut report, "sse", addr, "--sse");
+    }
+    summarize(&report)
+}
+
+/// Checks the file exists and its content can be mirrored, returning whether it can be watched
+fn check_file(report: &mut Report, config: &ServerConfig, file: &str) -> bool {
+    let check = format!("watch {file}");
+    let path = Path::new(file);
+    let bytes = match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            report.fail(&check, "is a directory", "watch the markdown files inside it, each with --watch");
+            return false;
+        }
+        Ok(_) => std::fs::read(path),
+        Err(e) => Err(e),
+    };
+    let bytes = match bytes {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let hint = match std::env::current_dir() {
+                Ok(dir) if path.is_relative() => format!("check the path; relative paths are resolved against {}", dir.display()),
+                _ => "check the path".to_string(),
+            };
+            report.fail(&check, "does not exist", hint);
+            return false;
+        }
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            report.fail(&check, "is not readable", "give the user running the server read access to the file and its directory");
+            return false;
+        }
+        Err(e) => {
+            report.fail(&check, e, "check the file can be read, e.g. with cat");
+            return false;
+        }
+    };
+    match reader::decode(path, &bytes) {
+        Ok(content) => match config.check_content(&content) {
+            Ok(()) => report.ok(&check, format!("readable, {} bytes", bytes.len())),
+            Err(reason) => report.fail(&check, reason, "the file is held back from clients until it passes; fix it or adjust the config"),
+        },
+        Err(e) if reader::is_gzip(path) => report.fail(&check, format!("can't be decompressed: {e}"), "files with a .gz extension must be gzipped"),
+        Err(_) => report.fail(&check, "is not valid UTF-8", "re-save the file as UTF-8"),
+    }
+    // a file that can't be mirrored yet is still watched, so its next save is picked up
+    true
+}
+
+fn check_bind(report: &mut Report, check: &str, addr: &str, flag: &str) {
+    match TcpListener::bind(addr) {
+        Ok(_) => report.ok(check, format!("{addr} is available")),
+        Err(e) => {
+            let hint = match e.kind() {
+                io::ErrorKind::AddrInUse => format!("another server is already listening there; stop it or pick another address with {flag}"),
+                io::ErrorKind::PermissionDenied => format!("ports below 1024 need extra privileges; pick a higher port with {flag}"),
+                io::ErrorKind::AddrNotAvailable => format!("the address is not one of this machine's; use e.g. 127.0.0.1 or 0.0.0.0 with {flag}"),
+                _ => format!("pick another address with {flag}"),
+            };
+            report.fail(check, format!("can't listen on {addr}: {e}"), hint);
+        }
+    }
+}
+
+fn summarize(report: &Report) -> bool {
+    match report.failures {
+        0 => println!("All checks passed"),
+        1 => println!("1 check failed"),
+        n => println!("{n} checks failed"),
+    }
+    report.failures == 0
+}
@@ -0,0 +1,786 @@
+use std::{borrow::Cow, collections::{BTreeMap, HashMap}, future::Future, ops::Range, path::Path, sync::Arc, time::Duration};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use shared::{chunks, compression, signing::Signer, ClientMessage, Control, ControlReply, FileChange, FileStatus, PositionUnit, Sequenced, ServerStatus, SyncProgress};
+use crate::config::{DiffPolicy, ServerConfig, STDIN_FILE_ID};
+use crate::history::{History, Subscription};
+use crate::metrics;
+use crate::reader;
+use crate::watcher::WatchControl;
+use crate::transport::{Connection, Transport, TransportError};
+
+/// What a client holds of a file that was sent to it again after connecting:
+/// the part it subscribed to (all of it when `part` is `None`), as of seq `since`
+struct FileView {
+    part: Option<Part>,
+    since: u64,
+}
+
+/// The part of a file a client subscribed to, moved along as the file changes
+#[derive(Debug, Clone)]
+enum Part {
+    /// Chars `start..end`, see [`ClientMessage::SubscribeRange`]
+    Range(Range<usize>),
+    /// Chars from the offset to the end, see [`ClientMessage::SubscribeFrom`]
+    From(usize),
+}
+
+impl Part {
+    /// The change as the client holding this part applies it, see
+    /// [`FileChange::narrow_to`] and [`FileChange::narrow_from`]
+    fn narrow(&mut self, change: &FileChange) -> Option<FileChange> {
+        match self {
+            Part::Range(range) => change.narrow_to(range),
+            Part::From(offset) => change.narrow_from(offset),
+        }
+    }
+}
+
+/// A client that stopped reading: a send did not go out within `limits.send_timeout_ms`
+#[derive(Debug, thiserror::Error)]
+#[error("client is not reading, a send took over {} ms", .0.as_millis())]
+struct SendTimedOut(Duration);
+
+/// Settings and views of one connection, changed by the client's messages
+#[derive(Default)]
+struct ClientState {
+    views: HashMap<String, FileView>,
+    /// Send messages compressed, see [`shared::compression`]
+    compress: bool,
+    /// Signs every message, see [`ServerConfig::signing_key`]
+    signer: Option<Signer>,
+    /// What diff positions count, see [`shared::utf16`]
+    positions: PositionUnit,
+    /// Send large files as chunk hashes, see [`shared::chunks`]
+    chunk_sync: bool,
+    /// Seq and content each pending hash list was made from, until the client requests its chunks
+    chunked: HashMap<String, (u64, String)>,
+    /// How long a send may wait for the client to read, see [`SendTimedOut`]
+    send_timeout: Option<Duration>,
+}
+
+/// Accepts clients on a transport and streams file changes to them
+pub struct ConnectionHandler<T: Transport> {
+    transport: Arc<T>,
+    history: Arc<History>,
+    control: Arc<WatchControl>,
+    config: Arc<ServerConfig>,
+}
+
+impl<T: Transport> ConnectionHandler<T> {
+    pub fn new(transport: T, history: Arc<History>, control: Arc<WatchControl>, config: Arc<ServerConfig>) -> Self {
+        Self {
+            transport: Arc::new(transport),
+            history,
+            control,
+            config,
+        }
+    }
+
+    pub async fn start_server(&self, shutdown: CancellationToken) -> Result<(), TransportError> {
+        // connections being handled, counting the one being accepted
+        let connection_count = Arc::new(AtomicUsize::new(0));
+
+        loop {
+            tokio::select! {
+                accept_result = self.transport.accept() => {
+                    match accept_result {
+                        Ok((pending, client_addr)) => {
+                            let total = connection_count.fetch_add(1, Ordering::SeqCst) + 1;
+                            println!("New connection from: {} (total: {})", client_addr, total);
+                            if total > self.config.limits.max_connections {
+                                eprintln!("Too many connections, rejecting: {}", client_addr);
+                                connection_count.fetch_sub(1, Ordering::SeqCst);
+                                continue;
+                            }
+                            let connection_count = Arc::clone(&connection_count);
+                            let transport = Arc::clone(&self.transport);
+                            let history = Arc::clone(&self.history);
+                            let control = Arc::clone(&self.control);
+                            let config = Arc::clone(&self.config);
+                            tokio::spawn(async move {
+                                if let Err(e) = Self::handle_client(transport, pending, &client_addr, history, control, config).await {
+                                    eprintln!("Error from client {}: {}", client_addr, e);
+                                }
+                                connection_count.fetch_sub(1, Ordering::SeqCst);
+                                println!("Client {} disconnected", client_addr);
+                            });
+                        }
+                        Err(e) => eprintln!("Error accepting connection: {}", e),
+                    }
+                }
+                _ = shutdown.cancelled() => {
+                    println!("Received shutdown signal, closing {} server...", T::NAME);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_client(
+        transport: Arc<T>,
+        pending: T::Pending,
+        client_addr: &str,
+        history: Arc<History>,
+        control: Arc<WatchControl>,
+        config: Arc<ServerConfig>,
+    ) -> Result<(), TransportError> {
+        let mut connection = transport.establish(pending).await?;
+        if let Some(client_id) = connection.client_id() {
+            println!("Client {} authenticated as {}", client_addr, client_id);
+        }
+        control.catch_up().await;
+        let files = Self::followed_files(&connection, &config);
+        let mut subscription = history.subscribe(connection.resume_from(), &files);
+        let mut state = ClientState {
+            signer: config.signer(),
+            positions: connection.position_unit(),
+            chunk_sync: connection.chunk_sync() && config.chunks.enabled,
+            send_timeout: config.limits.send_timeout(),
+            ..ClientState::default()
+        };
+
+        match subscription.replay.take() {
+            Some(missed) => {
+                for broadcast in &missed {
+                    Self::send_text(&mut connection, broadcast.in_unit(state.positions).text(state.compress), &state).await?;
+                }
+            }
+            // there is no file to read piped content from
+            None if config.stdin => {
+                let file_id = STDIN_FILE_ID.to_string();
+                Self::send_snapshot(&mut connection, &subscription, file_id, None, &mut state, &config).await?;
+            }
+            None => {
+                let digests = connection.digests();
+                for watched_file in files {
+                    if let Some(digest) = digests.get(watched_file) {
+                        if Self::send_changes_since_digest(&mut connection, &history, watched_file, digest, &mut state).await? {
+                            continue;
+                        }
+                    }
+                    if let Some(snapshot) = &config.initial_snapshot {
+                        Self::send_initial_snapshot(&mut connection, &mut subscription, watched_file, snapshot, &mut state, &config).await?;
+                    } else {
+                        Self::send_initial_content(&mut connection, &mut subscription, watched_file, &mut state, &config).await?;
+                    }
+                }
+            }
+        }
+        Self::process_messages(&mut connection, &mut subscription, &mut state, &control, &config).await
+    }
+
+    /// Sends the changes that bring the client's copy of a file, known only
+    /// by its digest, up to date; returns whether the server had content with
+    /// that digest in history, or else the client needs the file in full
+    async fn send_changes_since_digest(
+        connection: &mut T::Connection,
+        history: &History,
+        file_id: &str,
+        digest: &str,
+        state: &mut ClientState,
+    ) -> Result<bool, TransportError> {
+        let Some((seq, changes)) = history.changes_since_digest(file_id, digest) else {
+            println!("Client's copy of {} matches no content in history, sending it in full", file_id);
+            return Ok(false);
+        };
+        println!("Client's copy of {} matches content in history, sending the {} changes since", file_id, changes.len());
+        for broadcast in &changes {
+            Self::send_text(connection, broadcast.in_unit(state.positions).text(state.compress), state).await?;
+        }
+        // broadcasts queued since the connection subscribed are in `changes` already
+        state.views.insert(file_id.to_string(), FileView { part: None, since: seq });
+        Ok(true)
+    }
+
+    /// Sends the file as of the subscription seq, so the broadcasts queued
+    /// after it apply on top
+    async fn send_initial_content(
+        connection: &mut T::Connection,
+        subscription: &mut Subscription,
+        watched_file: &str,
+        state: &mut ClientState,
+        config: &ServerConfig,
+    ) -> Result<(), TransportError> {
+        let seq = subscription.seq;
+        let content = match subscription.initial.remove(watched_file) {
+            Some(content) => content,
+            // nothing valid was read when watching started
+            None => match reader::read_to_string(Path::new(watched_file)).await {
+                Ok(content) => config.trailing_whitespace.apply_owned(content),
+                Err(_) => return Ok(()),
+            },
+        };
+        // nothing that meets `mirror_when` was read yet
+        if config.mirror_when.check(&content).is_err() {
+            return Ok(());
+        }
+        let total_bytes = content.len();
+        let change = match config.check_content(&content) {
+            // the client fetches the chunks its copy lacks with RequestChunks
+            Ok(()) if state.chunk_sync && content.len() >= config.chunks.min_bytes => {
+                let change = FileChange::ChunkHashes {
+                    file_id: watched_file.to_string(),
+                    chunk_size: config.chunks.size,
+                    algorithm: config.chunks.algorithm,
+                    hashes: chunks::hashes(&content, config.chunks.size, config.chunks.algorithm),
+                    digest: config.chunks.algorithm.checksum(&content),
+                    last_modified: reader::modified(Path::new(watched_file)).await,
+                };
+                state.chunked.insert(watched_file.to_string(), (seq, content));
+                change
+            }
+            Ok(()) => FileChange::FullContent {
+                file_id: watched_file.to_string(),
+                content,
+                last_modified: reader::modified(Path::new(watched_file)).await,
+            },
+            Err(message) => FileChange::ValidationError {
+                file_id: watched_file.to_string(),
+                message,
+            },
+        };
+        // a held back file sends no content, and chunks complete once the client fetched them
+        let (report, chunked) = match &change {
+            FileChange::ValidationError { .. } => (false, false),
+            FileChange::ChunkHashes { .. } => (true, true),
+            _ => (true, false),
+        };
+        if report {
+            let start = SyncProgress::SyncStart { file_id: watched_file.to_string(), total_bytes, seq };
+            Self::send_progress(connection, start, state, config).await?;
+        }
+        Self::send(connection, &Sequenced::new(seq, change), state).await?;
+        if report && !chunked {
+            let complete = SyncProgress::SyncComplete { file_id: watched_file.to_string(), seq };
+            Self::send_progress(connection, complete, state, config).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a progress report around a file's initial content with `--sync-report`
+    async fn send_progress(
+        connection: &mut T::Connection,
+        progress: SyncProgress,
+        state: &ClientState,
+        config: &ServerConfig,
+    ) -> Result<(), TransportError> {
+        if config.sync_report {
+            Self::send(connection, &progress, state).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the snapshot file in place of the watched file, then the diffs
+    /// from it to the file as of the latest broadcast
+    async fn send_initial_snapshot(
+        connection: &mut T::Connection,
+        subscription: &mut Subscription,
+        watched_file: &str,
+        snapshot: &Path,
+        state: &mut ClientState,
+        config: &ServerConfig,
+    ) -> Result<(), TransportError> {
+        let baseline = match reader::read_to_string(snapshot).await {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("Cannot read initial snapshot {}: {}, sending {} instead", snapshot.display(), e, watched_file);
+                return Self::send_initial_content(connection, subscription, watched_file, state, config).await;
+            }
+        };
+        let seq = subscription.seq;
+        let start = SyncProgress::SyncStart { file_id: watched_file.to_string(), total_bytes: baseline.len(), seq };
+        Self::send_progress(connection, start, state, config).await?;
+        let change = FileChange::FullContent {
+            file_id: watched_file.to_string(),
+            content: baseline.clone(),
+            last_modified: None,
+        };
+        Self::send(connection, &Sequenced::new(seq, change), state).await?;
+        Self::send_diff_from(connection, subscription, watched_file.to_string(), &baseline, state, config).await?;
+        let complete = SyncProgress::SyncComplete { file_id: watched_file.to_string(), seq };
+        Self::send_progress(connection, complete, state, config).await?;
+        Ok(())
+    }
+
+    /// The watched files the client asked to follow, or all of them
+    fn followed_files<'a>(connection: &T::Connection, config: &'a ServerConfig) -> Vec<&'a str> {
+        let watched = config.file_ids();
+        let Some(requested) = connection.files() else {
+            return watched;
+        };
+        for file_id in requested.iter().filter(|file_id| !watched.contains(&file_id.as_str())) {
+            eprintln!("Ignoring subscription to unwatched file {}", file_id);
+        }
+        watched.into_iter().filter(|file_id| requested.iter().any(|requested| requested == file_id)).collect()
+    }
+
+    async fn process_messages(
+        connection: &mut T::Connection,
+        subscription: &mut Subscription,
+        state: &mut ClientState,
+        control: &WatchControl,
+        config: &ServerConfig,
+    ) -> Result<(), TransportError> {
+        loop {
+            tokio::select! {
+                msg = connection.recv() => {
+                    match msg {
+                        Some(Ok(text)) => {
+                            Self::handle_client_message(&text, connection, subscription, state, control, config).await?;
+                        }
+                        // a malformed message stream, e.g. a broken frame on the Unix socket
+                        Some(Err(e)) => return Err(e),
+                        None => break,
+                    }
+                }
+                change_result = subscription.receiver.recv() => {
+                    let broadcast = match change_result {
+                        Ok(broadcast) => broadcast,
+                        Err(e) => {
+                            if let RecvError::Lagged(skipped) = e {
+                                eprintln!("Client fell {} changes behind, closing the connection so it resyncs", skipped);
+                            }
+                            // a client that far behind may well have stopped reading the close too
+                            let _ = Self::within_send_timeout(connection.close(), state).await;
+                            break;
+                        }
+                    };
+                    // the initial content already covers changes up to the subscription seq
+                    if broadcast.message.seq <= subscription.seq {
+                        continue;
+                    }
+                    let Some(message) = Self::narrow(&broadcast.message, &mut state.views) else {
+                        continue;
+                    };
+                    let sent = match message {
+                        // serialized once for every connection
+                        Cow::Borrowed(_) => Self::send_text(connection, broadcast.in_unit(state.positions).text(state.compress), state).await,
+                        Cow::Owned(message) => Self::send(connection, &message, state).await,
+                    };
+                    // a client that went away is not an error, one that stopped reading is
+                    if let Err(e) = sent {
+                        return if e.is::<SendTimedOut>() { Err(e) } else { Ok(()) };
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_client_message(
+        text: &str,
+        connection: &mut T::Connection,
+        subscription: &Subscription,
+        state: &mut ClientState,
+        control: &WatchControl,
+        config: &ServerConfig,
+    ) -> Result<(), TransportError> {
+        let message: ClientMessage = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Ignoring invalid client message: {}", e);
+                return Ok(());
+            }
+        };
+        let applied = Self::apply_client_message(message.clone(), connection, subscription, state, control, config).await?;
+        if config.echo {
+            Self::send(connection, &ControlReply::Echo { received: message, applied }, state).await?;
+        }
+        Ok(())
+    }
+
+    /// Acts on a client message; returns it as applied, e.g. with a range
+    /// clamped to the file, or `None` if it was ignored
+    async fn apply_client_message(
+        message: ClientMessage,
+        connection: &mut T::Connection,
+        subscription: &Subscription,
+        state: &mut ClientState,
+        control: &WatchControl,
+        config: &ServerConfig,
+    ) -> Result<Option<ClientMessage>, TransportError> {
+        let applied = match message {
+            ClientMessage::Ack { seq } => Some(ClientMessage::Ack { seq: subscription.ack(seq) }),
+            // narrowed diffs are relative to the range, which counts chars
+            ClientMessage::SubscribeRange { file_id, .. } | ClientMessage::SubscribeFrom { file_id, .. }
+                if state.positions != PositionUnit::Chars =>
+            {
+                eprintln!("Ignoring range subscription to {}, ranges are only available with char positions", file_id);
+                None
+            }
+            ClientMessage::SubscribeRange { file_id, start, end } => {
+                let part = Part::Range(start..end.max(start));
+                Self::send_snapshot(connection, subscription, file_id.clone(), Some(part), state, config)
+                    .await?
+                    .then(|| Self::subscription(state, file_id))
+                    .flatten()
+            }
+            ClientMessage::SubscribeFrom { file_id, offset } => {
+                Self::send_snapshot(connection, subscription, file_id.clone(), Some(Part::From(offset)), state, config)
+                    .await?
+                    .then(|| Self::subscription(state, file_id))
+                    .flatten()
+            }
+            ClientMessage::Resync { file_id } => {
+                let part = state.views.get(&file_id).and_then(|view| view.part.clone());
+                Self::send_snapshot(connection, subscription, file_id.clone(), part, state, config)
+                    .await?
+                    .then_some(ClientMessage::Resync { file_id })
+            }
+            ClientMessage::RequestChunks { file_id, indices } => Self::send_chunks(connection, file_id.clone(), &indices, state, config)
+                .await?
+                .then_some(ClientMessage::RequestChunks { file_id, indices }),
+            ClientMessage::SetCompression { enabled } => {
+                state.compress = enabled;
+                Some(ClientMessage::SetCompression { enabled })
+            }
+            ClientMessage::Control(Control::Pause) => {
+                if control.pause() {
+                    println!("Broadcasting paused");
+                }
+                Some(ClientMessage::Control(Control::Pause))
+            }
+            ClientMessage::Control(Control::Resume) => {
+                if control.resume() {
+                    println!("Broadcasting resumed");
+                }
+                Some(ClientMessage::Control(Control::Resume))
+            }
+            ClientMessage::Control(Control::Status) => {
+                let reply = ControlReply::Status(Self::status(subscription, control, config));
+                Self::send(connection, &reply, state).await?;
+                Some(ClientMessage::Control(Control::Status))
+            }
+            ClientMessage::RequestDiffFromContent { file_id, content } => {
+                Self::send_diff_from(connection, subscription, file_id.clone(), &content, state, config)
+                    .await?
+                    .then_some(ClientMessage::RequestDiffFromContent { file_id, content })
+            }
+        };
+        Ok(applied)
+    }
+
+    /// The subscription a client holds to a file, as the server keeps it
+    fn subscription(state: &ClientState, file_id: String) -> Option<ClientMessage> {
+        match state.views.get(&file_id)?.part.clone()? {
+            Part::Range(range) => Some(ClientMessage::SubscribeRange { file_id, start: range.start, end: range.end }),
+            Part::From(offset) => Some(ClientMessage::SubscribeFrom { file_id, offset }),
+        }
+    }
+
+    fn status(subscription: &Subscription, control: &WatchControl, config: &ServerConfig) -> ServerStatus {
+        let (seq, latest) = subscription.snapshot_all();
+        let files = config
+            .file_ids()
+            .into_iter()
+            .map(|file_id| {
+                let content = latest.get(file_id);
+                FileStatus {
+                    file_id: file_id.to_string(),
+                    bytes: content.map(String::len),
+                    digest: content.map(|content| shared::digest(content)),
+                }
+            })
+            .collect();
+        ServerStatus {
+            seq,
+            paused: control.is_paused(),
+            connections: subscription.connections(),
+            files,
+        }
+    }
+
+    /// Sends the file (or the `part` of it) as of the latest broadcast, so later
+    /// broadcasts apply on top of it; false if there was nothing to send
+    async fn send_snapshot(
+        connection: &mut T::Connection,
+        subscription: &Subscription,
+        file_id: String,
+        part: Option<Part>,
+        state: &mut ClientState,
+        config: &ServerConfig,
+    ) -> Result<bool, TransportError> {
+        if !subscription.follows(&file_id) {
+            eprintln!("Ignoring request for {}, which is not watched or not followed by the client", file_id);
+            return Ok(false);
+        }
+        let (seq, content) = match subscription.snapshot(&file_id) {
+            (seq, Some(content)) => (seq, content),
+            // nothing was piped yet
+            (_, None) if config.stdin => return Ok(false),
+            // the file could not be read when watching started
+            (seq, None) => match reader::read_to_string(Path::new(&file_id)).await {
+                Ok(content) => (seq, config.trailing_whitespace.apply_owned(content)),
+                Err(e) => {
+                    eprintln!("Cannot read {}: {}", file_id, e);
+                    return Ok(false);
+                }
+            },
+        };
+        // nothing was read from a file for piped content
+        let last_modified = if config.stdin { None } else { reader::modified(Path::new(&file_id)).await };
+        let mut change = FileChange::FullContent { file_id: file_id.clone(), content, last_modified };
+        let mut part = part;
+        if let Some(part) = &mut part {
+            change = part.narrow(&change).unwrap_or(change);
+        }
+        Self::send(connection, &Sequenced::new(seq, change), state).await?;
+        state.views.insert(file_id, FileView { part, since: seq });
+        Ok(true)
+    }
+
+    /// Sends the chunks at `indices` of the content the file's pending hash
+    /// list was made from, with the seq of the hash list; false without one
+    async fn send_chunks(
+        connection: &mut T::Connection,
+        file_id: String,
+        indices: &[usize],
+        state: &mut ClientState,
+        config: &ServerConfig,
+    ) -> Result<bool, TransportError> {
+        let Some((seq, content)) = state.chunked.remove(&file_id) else {
+            eprintln!("Ignoring request for chunks of {}, no hash list of it was sent", file_id);
+            return Ok(false);
+        };
+        let all = chunks::split(&content, config.chunks.size);
+        let chunks: BTreeMap<usize, String> = indices
+            .iter()
+            .filter_map(|&index| Some((index, all.get(index)?.to_string())))
+            .collect();
+        println!("Sending {} of {} chunks of {}", chunks.len(), all.len(), file_id);
+        let complete = SyncProgress::SyncComplete { file_id: file_id.clone(), seq };
+        Self::send(connection, &Sequenced::new(seq, FileChange::Chunks { file_id, chunks }), state).await?;
+        Self::send_progress(connection, complete, state, config).await?;
+        Ok(true)
+    }
+
+    /// Sends the diffs from the client's content to the file as of the latest
+    /// broadcast; the client then holds the whole file. False if there was nothing to diff.
+    async fn send_diff_from(
+        connection: &mut T::Connection,
+        subscription: &Subscription,
+        file_id: String,
+        client_content: &str,
+        state: &mut ClientState,
+        config: &ServerConfig,
+    ) -> Result<bool, TransportError> {
+        if !subscription.follows(&file_id) {
+            eprintln!("Ignoring request for {}, which is not watched or not followed by the client", file_id);
+            return Ok(false);
+        }
+        let (seq, Some(current)) = subscription.snapshot(&file_id) else {
+            eprintln!("No content of {} to diff against yet", file_id);
+            return Ok(false);
+        };
+        let changes = match config.diff.policy_for(Path::new(&file_id)) {
+            DiffPolicy::Full => vec![FileChange::FullContent { file_id: file_id.clone(), content: current, last_modified: None }],
+            DiffPolicy::Diff => config.diff.strategy_for(Path::new(&file_id)).strategy().diff(&file_id, client_content, &current),
+        };
+        // each diff's positions count in the content the previous ones left
+        let mut base = (state.positions != PositionUnit::Chars).then(|| client_content.to_string());
+        for mut change in changes {
+            if let Some(base) = &mut base {
+                let converted = change.to_unit(base, state.positions);
+                change.apply(base);
+                change = converted.unwrap_or(change);
+            }
+            Self::send(connection, &Sequenced::new(seq, change), state).await?;
+        }
+        state.views.insert(file_id, FileView { part: None, since: seq });
+        Ok(true)
+    }
+
+    /// Restricts a broadcast to the part the client subscribed to, if any;
+    /// `None` when the client has nothing to update or already has the change
+    fn narrow<'a>(message: &'a Sequenced, views: &mut HashMap<String, FileView>) -> Option<Cow<'a, Sequenced>> {
+        let Some(view) = views.get_mut(message.change.file_id()) else {
+            return Some(Cow::Borrowed(message));
+        };
+        if message.seq <= view.since {
+            return None;
+        }
+        let Some(part) = &mut view.part else {
+            return Some(Cow::Borrowed(message));
+        };
+        let change = part.narrow(&message.change)?;
+        Some(Cow::Owned(Sequenced { seq: message.seq, detected_at: message.detected_at, change }))
+    }
+
+    /// Sends a message the way the client asked for, compressed or plain, and
+    /// signed if the server has a key
+    async fn send(connection: &mut T::Connection, message: &impl Serialize, state: &ClientState) -> Result<(), TransportError> {
+        let mut text = serde_json::to_string(message)?;
+        if let Some(signer) = &state.signer {
+            text = signer.sign(&text);
+        }
+        if state.compress {
+            Self::send_text(connection, &compression::compress(&text), state).await
+        } else {
+            Self::send_text(connection, &text, state).await
+        }
+    }
+
+    /// Sends a message as it is, counting it in the bytes sent (see [`metrics`])
+    async fn send_text(connection: &mut T::Connection, text: &str, state: &ClientState) -> Result<(), TransportError> {
+        Self::within_send_timeout(connection.send(text), state).await??;
+        metrics::record_sent(text.len());
+        Ok(())
+    }
+
+    /// Waits for a send to go out, giving up once the client took longer than
+    /// the send timeout to read it, e.g. with a full TCP receive buffer
+    async fn within_send_timeout<F: Future>(send: F, state: &ClientState) -> Result<F::Output, SendTimedOut> {
+        match state.send_timeout {
+            Some(limit) => tokio::time::timeout(limit, send).await.map_err(|_| SendTimedOut(limit)),
+            None => Ok(send.await),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::{DiffStrategy, DiffStrategyKind, LineDiff};
+    use crate::history::HistoryConfig;
+    use super::*;
+
+    /// Records what is sent to it
+    struct Recorder(Vec<Sequenced>);
+
+    impl Connection for Recorder {
+        async fn send(&mut self, message: &str) -> Result<(), TransportError> {
+            self.0.push(serde_json::from_str(message).expect("an uncompressed change"));
+            Ok(())
+        }
+
+        async fn recv(&mut self) -> Option<Result<String, TransportError>> {
+            None
+        }
+
+        async fn close(&mut self) {}
+    }
+
+    struct Loopback;
+
+    impl Transport for Loopback {
+        type Pending = ();
+        type Connection = Recorder;
+        const NAME: &'static str = "test";
+
+        async fn accept(&self) -> std::io::Result<((), String)> {
+            std::future::pending().await
+        }
+
+        async fn establish(&self, _pending: ()) -> Result<Recorder, TransportError> {
+            Ok(Recorder(Vec::new()))
+        }
+    }
+
+    fn document(line_50: &str) -> String {
+        (0..100).map(|i| if i == 50 { format!("{line_50}\n") } else { format!("Line {i} of a long document.\n") }).collect()
+    }
+
+    /// A history whose latest content of doc.md is `current`, and a config diffing by line
+    fn serving(current: &str) -> (Arc<History>, ServerConfig) {
+        let history = Arc::new(History::new(16, HistoryConfig::default(), None));
+        history.publish(FileChange::FullContent { file_id: "doc.md".to_string(), content: current.to_string(), last_modified: None });
+        let mut config = ServerConfig { watch: vec!["doc.md".to_string()], files: vec!["doc.md".to_string()], ..ServerConfig::default() };
+        config.diff.default = DiffStrategyKind::Line;
+        (history, config)
+    }
+
+    #[tokio::test]
+    async fn a_divergent_copy_gets_only_the_diff_that_corrects_it() {
+        let current = document("Line fifty of a long document.");
+        let (history, config) = serving(&current);
+        let subscription = history.subscribe(None, &["doc.md"]);
+        let mut connection = Recorder(Vec::new());
+        let mut state = ClientState::default();
+        // e.g. a copy kept from before a long disconnect
+        let mut copy = document("Line 50 of a long document.");
+        ConnectionHandler::<Loopback>::send_diff_from(&mut connection, &subscription, "doc.md".to_string(), &copy, &mut state, &config)
+            .await
+            .expect("send");
+        for message in &connection.0 {
+            message.change.try_apply(&mut copy).expect("diff fits the copy");
+        }
+        assert_eq!(copy, current);
+        // the one line that differs, not the whole file
+        let line_start = current.find("Line fifty").expect("line 50");
+        assert!(
+            matches!(&connection.0[..], [Sequenced { change: FileChange::Diff { position, insert_text, .. }, .. }] if *position == line_start && insert_text == "Line fifty of a long document.\n"),
+            "{:?}",
+            connection.0
+        );
+    }
+
+    #[tokio::test]
+    async fn later_edits_apply_on_top_of_the_corrected_copy() {
+        let current = document("Line fifty of a long document.");
+        let (history, config) = serving(&current);
+        let mut subscription = history.subscribe(None, &["doc.md"]);
+        let mut connection = Recorder(Vec::new());
+        let mut state = ClientState::default();
+        let mut copy = document("A line only this copy has.");
+        ConnectionHandler::<Loopback>::send_diff_from(&mut connection, &subscription, "doc.md".to_string(), &copy, &mut state, &config)
+            .await
+            .expect("send");
+        for message in &connection.0 {
+            assert!(matches!(message.change, FileChange::Diff { .. }), "{message:?}");
+            message.change.try_apply(&mut copy).expect("diff fits the copy");
+        }
+        assert_eq!(copy, current);
+
+        let edited = current.replace("Line 99 ", "The last line ");
+        for change in LineDiff.diff("doc.md", &current, &edited) {
+            history.publish(change);
+        }
+        while let Ok(broadcast) = subscription.receiver.try_recv() {
+            let Some(message) = ConnectionHandler::<Loopback>::narrow(&broadcast.message, &mut state.views) else {
+                continue;
+            };
+            message.change.try_apply(&mut copy).expect("diff fits the corrected copy");
+        }
+        assert_eq!(copy, edited);
+    }
+
+    #[tokio::test]
+    async fn initial_content_is_the_file_as_of_the_subscription_seq() {
+        let first = document("Line 50 of a long document.");
+        let (history, config) = serving(&first);
+        let mut subscription = history.subscribe(None, &["doc.md"]);
+        // published after subscribing, before the initial content goes out
+        let second = document("Line fifty of a long document.");
+        for change in LineDiff.diff("doc.md", &first, &second) {
+            history.publish(change);
+        }
+        let mut connection = Recorder(Vec::new());
+        ConnectionHandler::<Loopback>::send_initial_content(&mut connection, &mut subscription, "doc.md", &mut ClientState::default(), &config)
+            .await
+            .expect("send");
+        let [Sequenced { seq, change: FileChange::FullContent { content, .. }, .. }] = &connection.0[..] else {
+            panic!("expected the full content, got {:?}", connection.0);
+        };
+        assert_eq!((*seq, content), (subscription.seq, &first));
+        let mut copy = content.clone();
+        while let Ok(broadcast) = subscription.receiver.try_recv() {
+            broadcast.message.change.try_apply(&mut copy).expect("queued diff fits the initial content");
+        }
+        assert_eq!(copy, second);
+    }
+
+    #[tokio::test]
+    async fn unwatched_files_are_ignored() {
+        let (history, config) = serving("# Title\n");
+        let subscription = history.subscribe(None, &["doc.md"]);
+        let mut connection = Recorder(Vec::new());
+        let mut state = ClientState::default();
+        ConnectionHandler::<Loopback>::send_diff_from(&mut connection, &subscription, "other.md".to_string(), "", &mut state, &config)
+            .await
+            .expect("send");
+        assert!(connection.0.is_empty() && state.views.is_empty());
+    }
+}
@@ -0,0 +1,519 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex, OnceLock}, time::{Duration, Instant, SystemTime}};
+use serde::Deserialize;
+use futures_util::future::select_all;
+use tokio::sync::broadcast::{self, error::{RecvError, TryRecvError}};
+use shared::{compression, seq::SeqGen, signing::Signer, FileChange, PositionUnit, Sequenced};
+use crate::publisher::{Channels, Publisher};
+
+/// How long broadcast changes are kept for clients resuming after a reconnect
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Keep broadcast changes so reconnecting clients get the missed diffs instead of full content
+    pub enabled: bool,
+    /// Never keep more than this many changes
+    pub max_count: usize,
+    /// Drop changes older than this, even if a connected client has not acked them
+    pub max_age_secs: u64,
+    /// A client missing more changes than this is sent the content as of the
+    /// last `max_backfill` changes and only those changes (0 replays every one)
+    pub max_backfill: usize,
+    /// Missed changes are replayed while they add up to at most this many
+    /// times the bytes of the files' full content; past it the full content
+    /// is sent instead (0 disables)
+    pub max_replay_ratio: f64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_count: 1000,
+            max_age_secs: 300,
+            max_backfill: 100,
+            max_replay_ratio: 1.0,
+        }
+    }
+}
+
+/// Numbers every broadcast change and keeps the recent ones around for replay.
+///
+/// Changes are kept until every live connection has acked them, or until they
+/// fall out of the `max_count`/`max_age_secs` bounds, whichever comes first.
+pub struct History {
+    channels: Channels,
+    /// Where published changes go: the connected clients, through `channels`, then any broker
+    publishers: Vec<Box<dyn Publisher>>,
+    config: HistoryConfig,
+    signer: Option<Signer>,
+    state: Mutex<HistoryState>,
+}
+
+struct HistoryState {
+    /// Only advanced under the lock, so changes are published in seq order
+    seqs: SeqGen,
+    entries: VecDeque<(Instant, Arc<Broadcast>)>,
+    /// Content of every file as of the last seq
+    latest: HashMap<String, String>,
+    /// When the last change was detected
+    last_detected: SystemTime,
+    /// Highest acked seq of every live connection
+    acks: HashMap<u64, u64>,
+    next_subscriber: u64,
+    backfill: Backfill,
+    /// Seq and [`shared::digest`] of every content each file had, newest
+    /// last, so a client holding one of them gets only the changes since
+    digests: HashMap<String, VecDeque<(u64, String)>>,
+}
+
+/// The last `max_backfill` changes and the content of every file before them,
+/// kept apart from `entries` so acks and age never trim them
+#[derive(Default)]
+struct Backfill {
+    /// Seq of the last change already in `content`
+    seq: u64,
+    content: HashMap<String, String>,
+    recent: VecDeque<Arc<Broadcast>>,
+}
+
+/// A published change, shared by every subscriber. It is serialized (and
+/// signed) once when it is published, and compressed at most once however
+/// many clients ask for it.
+pub struct Broadcast {
+    pub message: Sequenced,
+    json: String,
+    compressed: OnceLock<String>,
+    /// The change with positions in other units than chars, where they differ
+    converted: Vec<(PositionUnit, Broadcast)>,
+}
+
+/// A connection's view of the broadcast stream, unregistered from ack
+/// tracking when dropped
+pub struct Subscription {
+    history: Arc<History>,
+    id: u64,
+    /// Files the connection follows
+    files: Vec<String>,
+    pub receiver: Receivers,
+    /// Seq the connection is up to date with once it has been sent `replay`,
+    /// or the initial content when `replay` is `None`
+    pub seq: u64,
+    /// Missed changes to send instead of the initial content
+    pub replay: Option<Vec<Arc<Broadcast>>>,
+    /// Content of every file as of `seq`, the initial content when `replay` is `None`
+    pub initial: HashMap<String, String>,
+}
+
+impl History {
+    pub fn new(capacity: usize, config: HistoryConfig, signer: Option<Signer>) -> Self {
+        let channels = Channels::new(capacity);
+        Self {
+            publishers: vec![Box::new(channels.clone())],
+            channels,
+            config,
+            signer,
+            state: Mutex::new(HistoryState {
+                seqs: SeqGen::new(),
+                entries: VecDeque::new(),
+                latest: HashMap::new(),
+                last_detected: SystemTime::UNIX_EPOCH,
+                acks: HashMap::new(),
+                next_subscriber: 0,
+                backfill: Backfill::default(),
+                digests: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Also sends every published change to `publisher`
+    pub fn with_publisher(mut self, publisher: impl Publisher + 'static) -> Self {
+        self.publishers.push(Box::new(publisher));
+        self
+    }
+
+    /// Numbers the change, records it and sends it to every subscriber and publisher
+    pub fn publish(&self, change: FileChange) {
+        let mut state = self.state.lock().expect("lock");
+        let seq = state.seqs.next();
+        // converted against the content before the change is applied to it
+        let converted: Vec<_> = match state.latest.get(change.file_id()) {
+            Some(base) => [PositionUnit::Utf16, PositionUnit::Bytes]
+                .into_iter()
+                .filter_map(|unit| Some((unit, change.to_unit(base, unit)?)))
+                .collect(),
+            None => Vec::new(),
+        };
+        apply(&mut state.latest, &change);
+        // never before the previous change, even if the system clock was set back
+        let detected_at = SystemTime::now().max(state.last_detected);
+        state.last_detected = detected_at;
+        let message = Sequenced { seq, detected_at: Some(detected_at), change };
+        let message = Arc::new(Broadcast::new(message, converted, self.signer.as_ref()));
+        if self.config.enabled {
+            self.record_digest(&mut state, message.message.change.file_id(), seq);
+            state.entries.push_back((Instant::now(), Arc::clone(&message)));
+            self.trim(&mut state);
+            if self.config.max_backfill > 0 {
+                let backfill = &mut state.backfill;
+                backfill.recent.push_back(Arc::clone(&message));
+                while backfill.recent.len() > self.config.max_backfill {
+                    let Some(oldest) = backfill.recent.pop_front() else {
+                        break;
+                    };
+                    apply(&mut backfill.content, &oldest.message.change);
+                    backfill.seq = oldest.message.seq;
+                }
+            }
+        }
+        // sent under the lock so receivers see changes in seq order
+        for publisher in &self.publishers {
+            publisher.publish(&message);
+        }
+    }
+
+    /// Subscribes a new connection to the changes of `files`. When `resume_from`
+    /// is given and every change after it is still in history, those of them
+    /// that are to these files are returned for replay.
+    pub fn subscribe(self: &Arc<Self>, resume_from: Option<u64>, files: &[&str]) -> Subscription {
+        let mut state = self.state.lock().expect("lock");
+        let receiver = Receivers::new(files.iter().map(|file_id| self.channels.subscribe(file_id)).collect());
+        let replay = resume_from.and_then(|since| self.replay_since(&state, since)).map(|replay| {
+            replay.into_iter().filter(|broadcast| files.contains(&broadcast.message.change.file_id())).collect()
+        });
+        let seq = state.seqs.current();
+        let acked = resume_from.filter(|_| replay.is_some()).unwrap_or(seq);
+        let id = state.next_subscriber;
+        state.next_subscriber += 1;
+        state.acks.insert(id, acked);
+        let initial = if replay.is_none() { state.latest.clone() } else { HashMap::new() };
+        Subscription {
+            history: Arc::clone(self),
+            id,
+            files: files.iter().map(|file_id| file_id.to_string()).collect(),
+            receiver,
+            seq,
+            replay,
+            initial,
+        }
+    }
+
+    /// Number of live connections
+    pub fn connections(&self) -> usize {
+        self.state.lock().expect("lock").acks.len()
+    }
+
+    /// Whether any connection would receive a change published now
+    pub fn has_subscribers(&self) -> bool {
+        self.channels.has_receivers()
+    }
+
+    /// Records the content of a file before any change to it was broadcast
+    pub fn seed(&self, file_id: &str, content: &str) {
+        let mut state = self.state.lock().expect("lock");
+        state.latest.entry(file_id.to_string()).or_insert_with(|| content.to_string());
+        state.backfill.content.entry(file_id.to_string()).or_insert_with(|| content.to_string());
+        if self.config.enabled && !state.digests.contains_key(file_id) {
+            let seq = state.seqs.current();
+            self.record_digest(&mut state, file_id, seq);
+        }
+    }
+
+    /// The changes to `file_id` that bring a copy with `digest` up to date,
+    /// with the seq they bring it to, for a client that holds a copy but does
+    /// not know which seq it is at, e.g. after applying only some of a save's
+    /// diffs. `None` when the file had no content with that digest since the
+    /// oldest change still in history.
+    pub fn changes_since_digest(&self, file_id: &str, digest: &str) -> Option<(u64, Vec<Arc<Broadcast>>)> {
+        let state = self.state.lock().expect("lock");
+        let current = state.seqs.current();
+        // without history, only a copy that is up to date already needs nothing
+        if shared::digest(state.latest.get(file_id)?) == digest {
+            return Some((current, Vec::new()));
+        }
+        if !self.config.enabled {
+            return None;
+        }
+        let (since, _) = state.digests.get(file_id)?.iter().rev().find(|(_, recorded)| recorded == digest)?;
+        let oldest = state.entries.front().map_or(current + 1, |(_, broadcast)| broadcast.message.seq);
+        if oldest > since + 1 {
+            return None;
+        }
+        let changes = state
+            .entries
+            .iter()
+            .map(|(_, broadcast)| broadcast)
+            .filter(|broadcast| broadcast.message.seq > *since && broadcast.message.change.file_id() == file_id)
+            .cloned()
+            .collect();
+        Some((current, changes))
+    }
+
+    /// Remembers the digest of the content `file_id` has as of `seq`, keeping
+    /// no more per file than history keeps changes
+    fn record_digest(&self, state: &mut HistoryState, file_id: &str, seq: u64) {
+        let Some(content) = state.latest.get(file_id) else {
+            return;
+        };
+        let digests = state.digests.entry(file_id.to_string()).or_default();
+        digests.push_back((seq, shared::digest(content)));
+        while digests.len() > self.config.max_count + 1 {
+            digests.pop_front();
+        }
+    }
+
+    /// The last broadcast seq, with the content of the file as of that seq if
+    /// anything was broadcast for it yet
+    pub fn snapshot(&self, file_id: &str) -> (u64, Option<String>) {
+        let state = self.state.lock().expect("lock");
+        (state.seqs.current(), state.latest.get(file_id).cloned())
+    }
+
+    /// Like [`History::snapshot`], for every file at once
+    pub fn snapshot_all(&self) -> (u64, HashMap<String, String>) {
+        let state = self.state.lock().expect("lock");
+        (state.seqs.current(), state.latest.clone())
+    }
+
+    /// Size in bytes of each file's content as of the last broadcast, without copying it
+    pub fn sizes(&self) -> HashMap<String, usize> {
+        let state = self.state.lock().expect("lock");
+        state.latest.iter().map(|(file_id, content)| (file_id.clone(), content.len())).collect()
+    }
+
+    /// Changes after `since`, or `None` if some of them were already trimmed
+    /// or sending them would cost more than the full content, see `max_replay_ratio`.
+    /// Past `max_backfill` changes, the content before the last ones is sent
+    /// in full in place of the older changes.
+    fn replay_since(&self, state: &HistoryState, since: u64) -> Option<Vec<Arc<Broadcast>>> {
+        if !self.config.enabled || since > state.seqs.current() {
+            return None;
+        }
+        // already starts from full content, with a bounded number of changes after it
+        if since < state.backfill.seq {
+            return Some(self.backfill(&state.backfill));
+        }
+        let oldest = state.entries.front().map_or(state.seqs.current() + 1, |(_, broadcast)| broadcast.message.seq);
+        if oldest > since + 1 {
+            return None;
+        }
+        let replay: Vec<_> = state
+            .entries
+            .iter()
+            .map(|(_, broadcast)| broadcast)
+            .filter(|broadcast| broadcast.message.seq > since)
+            .cloned()
+            .collect();
+        let replay_bytes: usize = replay.iter().map(|broadcast| broadcast.text(false).len()).sum();
+        let snapshot_bytes: usize = state.latest.values().map(String::len).sum();
+        let ratio = self.config.max_replay_ratio;
+        if ratio > 0.0 && replay_bytes as f64 > snapshot_bytes as f64 * ratio {
+            println!(
+                "Changes since seq {} add up to {} bytes, more than the {} bytes of full content; sending that instead",
+                since, replay_bytes, snapshot_bytes
+            );
+            return None;
+        }
+        Some(replay)
+    }
+
+    /// Full content of every file as of `backfill.seq`, then the changes since
+    fn backfill(&self, backfill: &Backfill) -> Vec<Arc<Broadcast>> {
+        let mut files: Vec<_> = backfill.content.iter().collect();
+        files.sort();
+        let snapshot = files.into_iter().map(|(file_id, content)| {
+            let change = FileChange::FullContent {
+                file_id: file_id.clone(),
+                content: content.clone(),
+                last_modified: None,
+            };
+            Arc::new(Broadcast::new(Sequenced::new(backfill.seq, change), Vec::new(), self.signer.as_ref()))
+        });
+        snapshot.chain(backfill.recent.iter().cloned()).collect()
+    }
+
+    fn ack(&self, id: u64, seq: u64) -> u64 {
+        let mut state = self.state.lock().expect("lock");
+        let seq = seq.min(state.seqs.current());
+        if let Some(acked) = state.acks.get_mut(&id) {
+            *acked = (*acked).max(seq);
+        }
+        self.trim(&mut state);
+        seq
+    }
+
+    fn unsubscribe(&self, id: u64) {
+        let mut state = self.state.lock().expect("lock");
+        state.acks.remove(&id);
+    }
+
+    /// Drops changes every live connection has acked, and anything past the
+    /// count and age bounds. With no connections only the bounds apply, so
+    /// clients that dropped off can still resume.
+    fn trim(&self, state: &mut HistoryState) {
+        let min_acked = state.acks.values().min().copied();
+        let max_age = Duration::from_secs(self.config.max_age_secs);
+        let now = Instant::now();
+        while let Some((time, broadcast)) = state.entries.front() {
+            let acked = min_acked.is_some_and(|acked| broadcast.message.seq <= acked);
+            let expired = now.duration_since(*time) > max_age;
+            if acked || expired || state.entries.len() > self.config.max_count {
+                state.entries.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+/// Brings `content`, the content of every file, up to date with `change`
+fn apply(content: &mut HashMap<String, String>, change: &FileChange) {
+    match (change, content.get_mut(change.file_id())) {
+        (FileChange::FullContent { file_id, content: new_content, .. }, _) => {
+            content.insert(file_id.clone(), new_content.clone());
+        }
+        (_, Some(file_content)) => change.apply(file_content),
+        (_, None) => {}
+    }
+}
+
+impl Broadcast {
+    fn new(message: Sequenced, converted: Vec<(PositionUnit, FileChange)>, signer: Option<&Signer>) -> Self {
+        // a change is plain strings and numbers, which always serialize
+        let mut json = serde_json::to_string(&message).expect("serialize change");
+        if let Some(signer) = signer {
+            json = signer.sign(&json);
+        }
+        let converted = converted
+            .into_iter()
+            .map(|(unit, change)| {
+                let message = Sequenced { seq: message.seq, detected_at: message.detected_at, change };
+                (unit, Broadcast::new(message, Vec::new(), signer))
+            })
+            .collect();
+        Self { message, json, compressed: OnceLock::new(), converted }
+    }
+
+    /// The broadcast as sent to a connection counting positions in `unit`
+    pub fn in_unit(&self, unit: PositionUnit) -> &Broadcast {
+        self.converted.iter().find(|(converted, _)| *converted == unit).map_or(self, |(_, broadcast)| broadcast)
+    }
+
+    /// The message as sent to clients, compressed or plain
+    pub fn text(&self, compress: bool) -> &str {
+        if compress {
+            self.compressed.get_or_init(|| compression::compress(&self.json))
+        } else {
+            &self.json
+        }
+    }
+}
+
+impl Subscription {
+    /// See [`History::snapshot`]
+    pub fn snapshot(&self, file_id: &str) -> (u64, Option<String>) {
+        self.history.snapshot(file_id)
+    }
+
+    /// See [`History::snapshot_all`]
+    pub fn snapshot_all(&self) -> (u64, HashMap<String, String>) {
+        self.history.snapshot_all()
+    }
+
+    /// See [`History::connections`]
+    pub fn connections(&self) -> usize {
+        self.history.connections()
+    }
+
+    /// Whether the connection receives the changes of the file
+    pub fn follows(&self, file_id: &str) -> bool {
+        self.files.iter().any(|followed| followed == file_id)
+    }
+
+    /// Records that the client has applied every change up to `seq`; returns
+    /// the seq recorded, which is never past the last broadcast one
+    pub fn ack(&self, seq: u64) -> u64 {
+        self.history.ack(self.id, seq)
+    }
+}
+
+type Receiver = broadcast::Receiver<Arc<Broadcast>>;
+
+/// Receivers of the channels of the files a connection follows, merged back
+/// into seq order
+pub struct Receivers {
+    /// Each receiver with the change taken from it ahead of the others, if any
+    receivers: Vec<(Receiver, Option<Arc<Broadcast>>)>,
+}
+
+impl Receivers {
+    fn new(receivers: Vec<Receiver>) -> Self {
+        Self { receivers: receivers.into_iter().map(|receiver| (receiver, None)).collect() }
+    }
+
+    /// Waits for the next change to any of the files. Cancel-safe: a change
+    /// taken from a channel is kept until it is returned.
+    pub async fn recv(&mut self) -> Result<Arc<Broadcast>, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(broadcast) => return Ok(broadcast),
+                Err(TryRecvError::Lagged(skipped)) => return Err(RecvError::Lagged(skipped)),
+                Err(TryRecvError::Closed) => return Err(RecvError::Closed),
+                Err(TryRecvError::Empty) => {}
+            }
+            if self.receivers.is_empty() {
+                std::future::pending::<()>().await;
+            }
+            let waiting = self.receivers.iter_mut().map(|(receiver, _)| Box::pin(receiver.recv()));
+            let (received, index, _) = select_all(waiting).await;
+            self.receivers[index].1 = Some(received?);
+        }
+    }
+
+    /// The next change already sent to any of the files. Changes are published
+    /// in seq order, so once one was received every earlier one is already in
+    /// its channel and this picks the lowest seq of them.
+    pub fn try_recv(&mut self) -> Result<Arc<Broadcast>, TryRecvError> {
+        for (receiver, taken) in &mut self.receivers {
+            if taken.is_none() {
+                match receiver.try_recv() {
+                    Ok(broadcast) => *taken = Some(broadcast),
+                    Err(TryRecvError::Empty) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        let next = self
+            .receivers
+            .iter_mut()
+            .filter(|(_, taken)| taken.is_some())
+            .min_by_key(|(_, taken)| taken.as_ref().map(|broadcast| broadcast.message.seq));
+        next.and_then(|(_, taken)| taken.take()).ok_or(TryRecvError::Empty)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.history.unsubscribe(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_subscriber_gets_the_same_message_encoded_once() {
+        let history = Arc::new(History::new(16, HistoryConfig::default(), None));
+        let mut subscriptions: Vec<_> = (0..50).map(|_| history.subscribe(None, &["doc.md"])).collect();
+        history.publish(FileChange::FullContent { file_id: "doc.md".to_string(), content: "# Title\n".to_string(), last_modified: None });
+        let received: Vec<_> = subscriptions.iter_mut().map(|subscription| subscription.receiver.try_recv().expect("the change")).collect();
+        let first = &received[0];
+        for broadcast in &received {
+            assert!(Arc::ptr_eq(broadcast, first));
+            // the very same buffers, not equal copies
+            assert!(std::ptr::eq(broadcast.text(false), first.text(false)));
+            assert!(std::ptr::eq(broadcast.text(true), first.text(true)));
+        }
+        assert_eq!(first.text(false), serde_json::to_string(&first.message).expect("serialize"));
+    }
+}
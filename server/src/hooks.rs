@@ -0,0 +1,57 @@
+use serde::Deserialize;
+
+/// A command run after a change to a watched file was broadcast, e.g. to
+/// regenerate an index or call a webhook with `curl`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    /// Only run for changes to this file id; every watched file when unset
+    pub file: Option<String>,
+    /// Shell command, run with the file id in `MARKDOWN_OP_FILE_ID` and the
+    /// digest of the new content in `MARKDOWN_OP_DIGEST`
+    pub command: String,
+}
+
+impl Hook {
+    fn matches(&self, file_id: &str) -> bool {
+        self.file.as_deref().is_none_or(|file| file == file_id)
+    }
+}
+
+/// Whether any hook runs after a change to `file_id` is broadcast
+pub fn any_for(hooks: &[Hook], file_id: &str) -> bool {
+    hooks.iter().any(|hook| hook.matches(file_id))
+}
+
+/// Starts the hooks for a broadcast change of `file_id`, each on its own task
+/// so a slow command does not hold up the next broadcast
+pub fn spawn_after_broadcast(hooks: &[Hook], file_id: &str, digest: &str) {
+    for hook in hooks.iter().filter(|hook| hook.matches(file_id)) {
+        let command = hook.command.clone();
+        let file_id = file_id.to_string();
+        let digest = digest.to_string();
+        tokio::spawn(async move { run(&command, &file_id, &digest).await });
+    }
+}
+
+async fn run(command: &str, file_id: &str, digest: &str) {
+    #[cfg(unix)]
+    let mut process = tokio::process::Command::new("sh");
+    #[cfg(unix)]
+    process.arg("-c");
+    #[cfg(windows)]
+    let mut process = tokio::process::Command::new("cmd");
+    #[cfg(windows)]
+    process.arg("/C");
+    let status = process
+        .arg(command)
+        .env("MARKDOWN_OP_FILE_ID", file_id)
+        .env("MARKDOWN_OP_DIGEST", digest)
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Hook for {} exited with {}: {}", file_id, status, command),
+        Err(e) => eprintln!("Failed to run hook for {}: {}", file_id, e),
+    }
+}
@@ -0,0 +1,322 @@
+use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use shared::{signing::Signer, FileChange, Sequenced};
+use crate::config::ServerConfig;
+use crate::history::{Broadcast, History};
+use crate::metrics;
+use crate::reader;
+use crate::watcher::WatchControl;
+use crate::transport::TransportError;
+
+/// How long a poll is held open when there is nothing new
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+/// Requests with a longer head are rejected
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Serves clients that cannot open a WebSocket, e.g. behind a proxy that
+/// blocks upgrades. `GET /changes?since=N` answers with a JSON array of the
+/// changes after seq N, holding the request until there is one; without
+/// `since`, or when the missed changes are gone, it answers with full content.
+/// `GET /content/{file_id}` answers with the file as of the latest broadcast,
+/// for tools that only want a snapshot, and `GET /metrics` with the diff
+/// quality metrics (see [`metrics`]) for Prometheus. Debug builds also answer
+/// `GET /debug/watcher` with the watcher's state (see [`WatchControl::debug_state`]).
+///
+/// Each change in the array is the JSON a WebSocket client gets for it, signed
+/// if the server has a key. A client holds no subscription between two polls,
+/// so unlike a WebSocket client it does not keep unacked changes in history.
+pub struct LongPollServer {
+    listener: TcpListener,
+    history: Arc<History>,
+    control: Arc<WatchControl>,
+    config: Arc<ServerConfig>,
+    signer: Option<Arc<Signer>>,
+}
+
+/// The parts of an HTTP request the long-poll and SSE servers look at
+pub struct PollRequest {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub bearer: Option<String>,
+    /// Sent by an `EventSource` reconnecting, see [`crate::sse`]
+    pub last_event_id: Option<String>,
+}
+
+impl LongPollServer {
+    pub async fn bind(
+        addr: &str,
+        history: Arc<History>,
+        control: Arc<WatchControl>,
+        config: Arc<ServerConfig>,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        println!("Long-poll server listening on http://{}", listener.local_addr()?);
+        let signer = config.signer().map(Arc::new);
+        Ok(Self { listener, history, control, config, signer })
+    }
+
+    pub async fn start_server(&self, shutdown: CancellationToken) {
+        loop {
+            tokio::select! {
+                accept_result = self.listener.accept() => {
+                    match accept_result {
+                        Ok((stream, client_addr)) => {
+                            let history = Arc::clone(&self.history);
+                            let control = Arc::clone(&self.control);
+                            let config = Arc::clone(&self.config);
+                            let signer = self.signer.clone();
+                            let shutdown = shutdown.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = Self::handle_request(stream, history, control, config, signer, shutdown).await {
+                                    eprintln!("Error from long-poll client {}: {}", client_addr, e);
+                                }
+                            });
+                        }
+                        Err(e) => eprintln!("Error accepting connection: {}", e),
+                    }
+                }
+                _ = shutdown.cancelled() => {
+                    println!("Received shutdown signal, closing long-poll server...");
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn handle_request(
+        mut stream: TcpStream,
+        history: Arc<History>,
+        control: Arc<WatchControl>,
+        config: Arc<ServerConfig>,
+        signer: Option<Arc<Signer>>,
+        shutdown: CancellationToken,
+    ) -> Result<(), TransportError> {
+        let Some(request) = PollRequest::read(&mut stream).await? else {
+            return respond(&mut stream, "400 Bad Request", "malformed request").await;
+        };
+        let content_of = request.path.strip_prefix("/content/").and_then(percent_decode);
+        let debug = cfg!(debug_assertions) && request.path == "/debug/watcher";
+        if request.path != "/changes" && request.path != "/metrics" && content_of.is_none() && !debug {
+            return respond(&mut stream, "404 Not Found", "not found").await;
+        }
+        if request.method != "GET" {
+            return respond(&mut stream, "405 Method Not Allowed", "only GET is supported").await;
+        }
+        if !request.is_authorized(config.auth_token.as_deref()) {
+            return respond(&mut stream, "401 Unauthorized", "invalid or missing token").await;
+        }
+        if request.path == "/metrics" {
+            return respond_with(&mut stream, "200 OK", "text/plain; version=0.0.4", &metrics::render()).await;
+        }
+        #[cfg(debug_assertions)]
+        if debug {
+            return respond(&mut stream, "200 OK", &control.debug_state().to_string()).await;
+        }
+        if let Some(file_id) = content_of {
+            return Self::send_content(&mut stream, &history, &config, &file_id).await;
+        }
+        let since = match request.query.get("since").map(|seq| seq.parse::<u64>()) {
+            None => None,
+            Some(Ok(seq)) => Some(seq),
+            Some(Err(_)) => return respond(&mut stream, "400 Bad Request", "since must be a seq number").await,
+        };
+        control.catch_up().await;
+        let changes = Self::changes_since(&history, &config, signer.as_deref(), since, &shutdown).await;
+        respond(&mut stream, "200 OK", &format!("[{}]", changes.join(","))).await
+    }
+
+    /// The JSON of the changes a client that is up to date with `since` is
+    /// missing, waiting up to `POLL_TIMEOUT` for the next one if there are none yet
+    async fn changes_since(
+        history: &Arc<History>,
+        config: &ServerConfig,
+        signer: Option<&Signer>,
+        since: Option<u64>,
+        shutdown: &CancellationToken,
+    ) -> Vec<String> {
+        let mut subscription = history.subscribe(since, &config.file_ids());
+        // broadcasts were signed when they were published
+        let json = |broadcast: &Broadcast| broadcast.text(false).to_string();
+        let missed = match subscription.replay.take() {
+            Some(missed) => missed.iter().map(|broadcast| json(broadcast)).collect(),
+            // without history a client that is up to date can still wait for the next change
+            None if since == Some(subscription.seq) => Vec::new(),
+            None => return Self::full_content(history, config, signer).await,
+        };
+        if !missed.is_empty() {
+            return missed;
+        }
+        tokio::select! {
+            change_result = subscription.receiver.recv() => match change_result {
+                Ok(broadcast) => {
+                    // whatever else is already queued goes out in the same response
+                    let mut changes = vec![json(&broadcast)];
+                    while let Ok(broadcast) = subscription.receiver.try_recv() {
+                        changes.push(json(&broadcast));
+                    }
+                    changes
+                }
+                Err(RecvError::Lagged(_)) => Self::full_content(history, config, signer).await,
+                Err(RecvError::Closed) => Vec::new(),
+            },
+            _ = tokio::time::sleep(POLL_TIMEOUT) => Vec::new(),
+            _ = shutdown.cancelled() => Vec::new(),
+        }
+    }
+
+    /// Answers `GET /content/{file_id}` with the plain content of the file
+    async fn send_content(stream: &mut TcpStream, history: &History, config: &ServerConfig, file_id: &str) -> Result<(), TransportError> {
+        if !config.file_ids().contains(&file_id) {
+            return respond(stream, "404 Not Found", "no such file").await;
+        }
+        let Some(content) = Self::content_or_read(history.snapshot(file_id).1, file_id, config).await else {
+            return respond(stream, "503 Service Unavailable", "no content yet").await;
+        };
+        match config.check_content(&content) {
+            Ok(()) => respond_with(stream, "200 OK", "text/markdown; charset=utf-8", &content).await,
+            Err(message) => respond(stream, "409 Conflict", &format!("held back: {message}")).await,
+        }
+    }
+
+    /// The content as of the latest broadcast, read from disk when nothing was
+    /// broadcast for the file yet
+    async fn content_or_read(latest: Option<String>, file_id: &str, config: &ServerConfig) -> Option<String> {
+        match latest {
+            Some(content) => Some(content),
+            // nothing was piped yet
+            None if config.stdin => None,
+            None => match reader::read_to_string(Path::new(file_id)).await {
+                // nothing that meets `mirror_when` was read yet
+                Ok(content) => Some(config.trailing_whitespace.apply_owned(content)).filter(|content| config.mirror_when.check(content).is_ok()),
+                Err(e) => {
+                    eprintln!("Cannot read {}: {}", file_id, e);
+                    None
+                }
+            },
+        }
+    }
+
+    /// The JSON of every file as of the latest broadcast, read from disk when
+    /// nothing was broadcast for it yet, signed like broadcasts are
+    async fn full_content(history: &History, config: &ServerConfig, signer: Option<&Signer>) -> Vec<String> {
+        let (seq, mut latest) = history.snapshot_all();
+        let mut changes = Vec::new();
+        for file_id in config.file_ids() {
+            let Some(content) = Self::content_or_read(latest.remove(file_id), file_id, config).await else {
+                continue;
+            };
+            let change = match config.check_content(&content) {
+                Ok(()) => FileChange::FullContent {
+                    file_id: file_id.to_string(),
+                    content,
+                    last_modified: if config.stdin { None } else { reader::modified(Path::new(file_id)).await },
+                },
+                Err(message) => FileChange::ValidationError { file_id: file_id.to_string(), message },
+            };
+            changes.push(sign(&Sequenced::new(seq, change), signer));
+        }
+        changes
+    }
+}
+
+impl PollRequest {
+    /// Reads the request line and headers; `None` if they are not valid HTTP
+    pub async fn read(stream: &mut TcpStream) -> std::io::Result<Option<Self>> {
+        let mut head = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+            if head.len() > MAX_REQUEST_HEAD {
+                return Ok(None);
+            }
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            head.extend_from_slice(&buf[..n]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let mut lines = head.lines();
+        let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+        let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+            return Ok(None);
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let headers: Vec<_> = lines.filter_map(|line| line.split_once(':')).collect();
+        let header = |wanted: &str| {
+            headers
+                .iter()
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case(wanted))
+                .map(|(_, value)| value.trim())
+        };
+        let bearer = header("authorization").and_then(|value| value.strip_prefix("Bearer ")).map(str::to_string);
+        let last_event_id = header("last-event-id").map(str::to_string);
+        Ok(Some(Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            query,
+            bearer,
+            last_event_id,
+        }))
+    }
+
+    /// Same rules as the WebSocket handshake: `Authorization: Bearer <token>`
+    /// or a `token` query parameter
+    pub fn is_authorized(&self, token: Option<&str>) -> bool {
+        let Some(token) = token else {
+            return true;
+        };
+        self.bearer.as_deref() == Some(token) || self.query.get("token").map(String::as_str) == Some(token)
+    }
+}
+
+/// The JSON a WebSocket client would get for `message`
+fn sign(message: &Sequenced, signer: Option<&Signer>) -> String {
+    // a change is plain strings and numbers, which always serialize
+    let json = serde_json::to_string(message).expect("serialize change");
+    match signer {
+        Some(signer) => signer.sign(&json),
+        None => json,
+    }
+}
+
+/// Decodes `%XX` escapes, so a file id with spaces or other reserved chars can
+/// be put in a path; `None` for a malformed escape or a result that is not UTF-8
+pub fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+pub async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<(), TransportError> {
+    let content_type = if status.starts_with("200") { "application/json" } else { "text/plain" };
+    respond_with(stream, status, content_type, body).await
+}
+
+async fn respond_with(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> Result<(), TransportError> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
@@ -1,38 +1,169 @@
+mod bench;
+mod cli;
+mod clock;
+mod config;
+mod content_cache;
+mod doctor;
+mod handler;
+mod history;
+mod hooks;
+mod long_poll;
+mod matcher;
+mod metrics;
+mod nats;
+mod publisher;
+mod reader;
+mod sse;
+mod transport;
+mod tls;
+mod tui;
+#[cfg(unix)]
+mod unix_socket;
+mod validation;
+#[cfg(debug_assertions)]
+mod watch_stats;
 mod watcher;
 mod websocket;
 
-use std::sync::Arc;
-use tokio::sync::{broadcast, oneshot};
+use std::sync::{Arc, Mutex};
+use clap::Parser;
 use tokio::signal;
-use crate::watcher::FileWatcher;
-use crate::websocket::WebSocketHandler;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use crate::cli::{Cli, Command};
+use crate::config::ServerConfig;
+use crate::handler::ConnectionHandler;
+use crate::transport::Transport;
+use crate::history::History;
+use crate::nats::NatsPublisher;
+use crate::tui::{TuiPublisher, TuiState};
+use crate::watcher::{FileWatcher, WatchControl};
+use crate::websocket::WsTransport;
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cli = Cli::parse();
+    if let Some(Command::BenchBroadcast { clients, changes, size }) = cli.command {
+        bench::broadcast(clients, changes, size);
+        return Ok(());
+    }
+    if let Some(Command::BenchDiff { size, edits, strategy, seed }) = cli.command {
+        if !bench::diff(size, edits, strategy, seed) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let runtime = shared::runtime::multi_thread(cli.worker_threads)?;
+    if let Some(Command::Doctor) = &cli.command {
+        if !runtime.block_on(doctor::run(&cli)) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    runtime.block_on(run(cli))
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let config = match ServerConfig::load(&cli) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
     println!("Starting Markdown Mirror Server");
-    let (shutdown_tx, shutdown_rx) = oneshot::channel();
-    let (broadcast_tx, _) = broadcast::channel(1000);
-    let broadcast_tx = Arc::new(broadcast_tx);
-    let watched_file = std::env::args().nth(1).unwrap_or_else(|| "README.md".to_string());
-    let file_id = watched_file.clone();
-    let mut watcher = FileWatcher::new();
-    watcher.watch_file(file_id, &watched_file, broadcast_tx.as_ref().clone())?;
-    println!("Watching file: {}", watched_file);
-    let ws_handler = WebSocketHandler::new(broadcast_tx.as_ref().clone());
-    let ws_task = tokio::spawn(async move {
-        if let Err(e) = ws_handler.start_server("127.0.0.1:3030".to_string(), shutdown_rx).await {
-            eprintln!("WebSocket server error: {}", e);
+    println!("Worker threads: {}", tokio::runtime::Handle::current().metrics().num_workers());
+    let shutdown = CancellationToken::new();
+    let signer = config.signer();
+    if let Some(signer) = &signer {
+        println!("Signing messages, public key: {}", signer.public_key());
+    }
+    let mut history = History::new(config.limits.channel_capacity, config.history.clone(), signer);
+    if let Some(addr) = &config.publish.nats {
+        history = history.with_publisher(NatsPublisher::start(addr.clone(), config.publish.subject_prefix.clone()));
+    }
+    let tui = cli.tui.then(|| Arc::new(Mutex::new(TuiState::new(&config.file_ids()))));
+    if let Some(state) = &tui {
+        history = history.with_publisher(TuiPublisher(Arc::clone(state)));
+    }
+    let history = Arc::new(history);
+    let mut watcher = FileWatcher::new(Arc::clone(&config), Arc::clone(&history));
+    if config.stdin {
+        watcher.watch_stdin(config::STDIN_FILE_ID.to_string());
+        println!("Mirroring stdin");
+    } else if config.matched.is_empty() {
+        for watched_file in &config.files {
+            watcher.watch_file(watched_file.clone(), watched_file)?;
+            println!("Watching file: {}", watched_file);
         }
-    });
+    } else {
+        for (matcher, files) in &config.matched {
+            watcher.watch_matching(matcher.clone(), files)?;
+            for watched_file in files {
+                println!("Watching file: {}", watched_file);
+            }
+        }
+    }
+    let mut servers = JoinSet::new();
+    let control = watcher.control();
+    if config.publish.websocket {
+        let ws_transport = WsTransport::bind(Arc::clone(&config)).await?;
+        spawn_server(&mut servers, ws_transport, &history, &control, &config, &shutdown);
+    }
+    #[cfg(unix)]
+    if let Some(path) = &config.unix_socket {
+        let unix_transport = unix_socket::UnixTransport::bind(path, config.limits.max_frame_bytes)?;
+        spawn_server(&mut servers, unix_transport, &history, &control, &config, &shutdown);
+    }
+    if let Some(addr) = &config.sse {
+        let sse_transport = sse::SseTransport::bind(addr, Arc::clone(&config)).await?;
+        spawn_server(&mut servers, sse_transport, &history, &control, &config, &shutdown);
+    }
+    if let Some(addr) = &config.long_poll {
+        let long_poll = long_poll::LongPollServer::bind(addr, Arc::clone(&history), Arc::clone(&control), Arc::clone(&config)).await?;
+        let shutdown = shutdown.clone();
+        servers.spawn(async move { long_poll.start_server(shutdown).await });
+    }
+    let tui = tui.map(|state| tui::spawn(state, Arc::clone(&history), shutdown.clone()));
     tokio::select! {
+        // the servers stop once shutdown is cancelled, which is not them failing
+        biased;
         _ = signal::ctrl_c() => {
             println!("Received Ctrl+C, shutting down...");
-            let _ = shutdown_tx.send(());
+            shutdown.cancel();
+            while servers.join_next().await.is_some() {}
         }
-        _ = ws_task => {
-            println!("WebSocket server stopped");
+        // only the TUI cancels it, when quit
+        _ = shutdown.cancelled() => {
+            println!("TUI closed, shutting down...");
+            while servers.join_next().await.is_some() {}
         }
+        // without any server, e.g. only publishing to a broker, run until Ctrl+C
+        Some(_) = servers.join_next() => {
+            println!("Server stopped");
+        }
+    }
+    // the terminal is restored before anything else is printed
+    if let Some(tui) = tui {
+        shutdown.cancel();
+        let _ = tui.await;
     }
-    watcher::wait_for_events_processed().await;
+    watcher.shutdown().await;
     Ok(())
 }
+
+fn spawn_server<T: Transport>(
+    servers: &mut JoinSet<()>,
+    transport: T,
+    history: &Arc<History>,
+    control: &Arc<WatchControl>,
+    config: &Arc<ServerConfig>,
+    shutdown: &CancellationToken,
+) {
+    let handler = ConnectionHandler::new(transport, Arc::clone(history), Arc::clone(control), Arc::clone(config));
+    let shutdown = shutdown.clone();
+    servers.spawn(async move {
+        if let Err(e) = handler.start_server(shutdown).await {
+            eprintln!("{} server error: {}", T::NAME, e);
+        }
+    });
+}
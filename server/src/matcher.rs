@@ -0,0 +1,286 @@
+use std::{fmt, fs, io, path::{Component, Path, PathBuf}};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::Deserialize;
+
+/// How the entries of `watch` pick the files to mirror
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// Each entry is the path of one file
+    #[default]
+    Exact,
+    /// Each entry is a glob such as `docs/**/README.md`: `*` matches within a
+    /// directory, `**` any number of directories
+    Glob,
+    /// Each entry is a regex the whole path of a file, relative to the working
+    /// directory, must match, such as `docs/.*\.md`
+    Regex,
+}
+
+/// Picks the watched files out of the paths of file system events, and tells
+/// the file id each of them is mirrored as
+#[derive(Debug, Clone)]
+pub enum PathMatcher {
+    /// One file, at `path` made absolute with its directory resolved
+    Exact { path: PathBuf, file_id: String },
+    /// The files matching a glob or regex
+    Pattern(PatternMatcher),
+}
+
+impl PathMatcher {
+    /// The file id of the file at `path`, if it is one of the watched files
+    pub fn file_id(&self, path: &Path) -> Option<String> {
+        match self {
+            PathMatcher::Exact { path: watched, file_id } => {
+                // cheap check first, most events in the directory are for other files
+                let same_name = match (path.file_name(), watched.file_name()) {
+                    (Some(name), Some(watched)) => same_path(Path::new(name), Path::new(watched)),
+                    _ => false,
+                };
+                (same_name && same_path(&canonical_path(path), watched)).then(|| file_id.clone())
+            }
+            PathMatcher::Pattern(pattern) => pattern.file_id(path),
+        }
+    }
+}
+
+impl fmt::Display for PathMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathMatcher::Exact { path, .. } => write!(f, "{}", path.display()),
+            PathMatcher::Pattern(pattern) => f.write_str(&pattern.pattern),
+        }
+    }
+}
+
+/// A glob or regex `watch` entry. Files are matched by the path they are
+/// mirrored under, which is also their file id: relative to the working
+/// directory unless the glob is absolute, with `/` between directories.
+/// Files in hidden directories, such as `.git`, never match.
+#[derive(Debug, Clone)]
+pub struct PatternMatcher {
+    pattern: String,
+    /// Directory all matching files are below, as written: the part of a glob
+    /// before its first wildcard, or the working directory (empty) for a regex
+    prefix: PathBuf,
+    /// `prefix` made absolute with symlinks resolved, as event paths are
+    root: PathBuf,
+    kind: PatternKind,
+}
+
+#[derive(Debug, Clone)]
+enum PatternKind {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl PatternMatcher {
+    pub fn glob(pattern: &str) -> Result<Self, String> {
+        let glob = GlobBuilder::new(pattern).literal_separator(true).build().map_err(|e| e.to_string())?;
+        let prefix = Path::new(pattern)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .components()
+            .take_while(|component| !component.as_os_str().to_string_lossy().contains(['*', '?', '[', '{']))
+            .collect();
+        Self::new(pattern, prefix, PatternKind::Glob(glob.compile_matcher()))
+    }
+
+    pub fn regex(pattern: &str) -> Result<Self, String> {
+        let regex = Regex::new(&format!("^(?:{pattern})$")).map_err(|e| e.to_string())?;
+        Self::new(pattern, PathBuf::new(), PatternKind::Regex(regex))
+    }
+
+    fn new(pattern: &str, prefix: PathBuf, kind: PatternKind) -> Result<Self, String> {
+        let dir = if prefix.as_os_str().is_empty() { Path::new(".") } else { prefix.as_path() };
+        let root = fs::canonicalize(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        Ok(Self { pattern: pattern.to_string(), prefix, root, kind })
+    }
+
+    /// Directory to watch, recursively, for changes to matching files
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The ids of the matching files there are now, sorted
+    pub fn files(&self) -> io::Result<Vec<String>> {
+        let mut files = Vec::new();
+        self.collect(&self.root, &mut files)?;
+        files.sort();
+        Ok(files)
+    }
+
+    fn collect(&self, dir: &Path, files: &mut Vec<String>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            // symlinked directories are not followed, so a link to a parent can't loop
+            if entry.file_type()?.is_dir() {
+                if !entry.file_name().to_string_lossy().starts_with('.') {
+                    self.collect(&path, files)?;
+                }
+            } else if path.is_file() {
+                files.extend(self.file_id(&path));
+            }
+        }
+        Ok(())
+    }
+
+    /// The file id of the file at the absolute `path`, if it matches
+    pub fn file_id(&self, path: &Path) -> Option<String> {
+        let relative = canonical_path(path).strip_prefix(&self.root).ok()?.to_path_buf();
+        let file_id = self.prefix.join(relative).to_string_lossy().into_owned();
+        let file_id = if cfg!(windows) { file_id.replace('\\', "/") } else { file_id };
+        self.matches(&file_id).then_some(file_id)
+    }
+
+    fn matches(&self, file_id: &str) -> bool {
+        let mut dirs = file_id.split('/').rev().skip(1);
+        if dirs.any(|dir| dir.starts_with('.') && dir != "." && dir != "..") {
+            return false;
+        }
+        match &self.kind {
+            PatternKind::Glob(glob) => glob.is_match(file_id),
+            PatternKind::Regex(regex) => regex.is_match(file_id),
+        }
+    }
+}
+
+/// The path with its directory resolved, so paths to the same file compare
+/// equal: `.`/`..` segments, symlinks and on Windows the `\\?\` prefix. The
+/// file name itself is kept, since the file may be gone (a rename or delete
+/// event) or be a symlink that is mirrored under its own name.
+pub fn canonical_path(path: &Path) -> PathBuf {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let parent = fs::canonicalize(parent).unwrap_or_else(|_| lexically_normalized(parent));
+    match path.file_name() {
+        Some(name) => parent.join(name),
+        None => parent,
+    }
+}
+
+/// Drops `.` segments and resolves `..` against the preceding segment,
+/// without touching the file system
+fn lexically_normalized(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if normalized.file_name().is_some() => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Paths are case-insensitive on Windows
+fn same_path(a: &Path, b: &Path) -> bool {
+    if cfg!(windows) {
+        a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase()
+    } else {
+        a == b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILES: [&str; 7] = [
+        "README.md",
+        "notes.txt",
+        "docs/README.md",
+        "docs/guide.md",
+        "docs/api/README.md",
+        "docs/api/v2/README.md",
+        ".git/README.md",
+    ];
+
+    /// A directory holding `FILES`, below the working directory since regexes
+    /// match paths relative to it; removed when dropped
+    struct Tree {
+        /// The directory relative to the working directory, ending in `/`
+        prefix: String,
+    }
+
+    impl Tree {
+        fn new(name: &str) -> Self {
+            let prefix = format!("target/matcher-tests/{}-{}/", name, std::process::id());
+            for file in FILES {
+                let path = Path::new(&prefix).join(file);
+                fs::create_dir_all(path.parent().expect("parent")).expect("create dir");
+                fs::write(path, "# Title\n").expect("write file");
+            }
+            Tree { prefix }
+        }
+
+        /// The ids, without the prefix, that `matcher` picks out of `FILES`
+        /// given as the absolute paths events carry
+        fn matching(&self, matcher: &PathMatcher) -> Vec<String> {
+            let cwd = std::env::current_dir().expect("cwd");
+            FILES
+                .iter()
+                .filter_map(|file| matcher.file_id(&cwd.join(&self.prefix).join(file)))
+                .map(|file_id| file_id.strip_prefix(&self.prefix).expect("id under the tree").to_string())
+                .collect()
+        }
+
+        fn glob(&self, pattern: &str) -> PatternMatcher {
+            PatternMatcher::glob(&format!("{}{}", self.prefix, pattern)).expect("glob")
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.prefix);
+        }
+    }
+
+    #[test]
+    fn exact_matches_only_the_watched_file() {
+        let tree = Tree::new("exact");
+        let path = canonical_path(&std::env::current_dir().expect("cwd").join(&tree.prefix).join("docs/README.md"));
+        let matcher = PathMatcher::Exact { path: path.clone(), file_id: format!("{}docs/README.md", tree.prefix) };
+        assert_eq!(tree.matching(&matcher), ["docs/README.md"]);
+        // however the event spells the path
+        let dir = path.parent().expect("dir");
+        assert_eq!(matcher.file_id(&dir.join("api/./../README.md")).as_deref(), Some(format!("{}docs/README.md", tree.prefix).as_str()));
+    }
+
+    #[test]
+    fn glob_stars_stay_within_a_directory_and_double_stars_cross_them() {
+        let tree = Tree::new("glob");
+        let readmes = PathMatcher::Pattern(tree.glob("docs/**/README.md"));
+        assert_eq!(tree.matching(&readmes), ["docs/README.md", "docs/api/README.md", "docs/api/v2/README.md"]);
+        let markdown = PathMatcher::Pattern(tree.glob("docs/*.md"));
+        assert_eq!(tree.matching(&markdown), ["docs/README.md", "docs/guide.md"]);
+        let all = tree.glob("**/README.md");
+        // the hidden .git directory is neither matched nor walked
+        assert_eq!(tree.matching(&PathMatcher::Pattern(all.clone())).len(), 4);
+        let files = all.files().expect("walk");
+        assert_eq!(files.len(), 4);
+        assert!(files.iter().all(|file| file.starts_with(&tree.prefix)), "{:?}", files);
+    }
+
+    #[test]
+    fn regex_matches_the_whole_path_relative_to_the_working_directory() {
+        let tree = Tree::new("regex");
+        let prefix = regex::escape(&tree.prefix);
+        let direct = PathMatcher::Pattern(PatternMatcher::regex(&format!(r"{prefix}docs/[^/]+\.md")).expect("regex"));
+        assert_eq!(tree.matching(&direct), ["docs/README.md", "docs/guide.md"]);
+        // unanchored in the pattern, but still matched against the whole path
+        let partial = PathMatcher::Pattern(PatternMatcher::regex("README").expect("regex"));
+        assert!(tree.matching(&partial).is_empty());
+        let nested = PathMatcher::Pattern(PatternMatcher::regex(&format!(r"{prefix}(.*/)?README\.md")).expect("regex"));
+        assert_eq!(tree.matching(&nested), ["README.md", "docs/README.md", "docs/api/README.md", "docs/api/v2/README.md"]);
+    }
+
+    #[test]
+    fn invalid_patterns_are_errors() {
+        assert!(PatternMatcher::glob("docs/[.md").is_err());
+        assert!(PatternMatcher::regex("docs/(.md").is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use std::{fmt::Write, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
+use shared::FileChange;
+
+lazy_static::lazy_static! {
+    static ref DIFF_METRICS: Mutex<DiffMetrics> = Mutex::new(DiffMetrics::default());
+}
+
+/// Bytes of the messages sent to WebSocket and Unix socket clients
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+
+/// File events dropped because a watch's event queue was full
+static EVENTS_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Bounds of the payload ratio buckets; a full content broadcast is just over 1
+const RATIO_BOUNDS: [f64; 9] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.0];
+/// Bounds of the diffs-per-change buckets
+const DIFF_COUNT_BOUNDS: [f64; 8] = [1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 1000.0];
+
+/// How well the diff engine does on the changes the watcher broadcasts, to
+/// spot bloated diffs in production, e.g. an edit near the top of a file
+/// that comes out as a rewrite of everything after it
+struct DiffMetrics {
+    /// Bytes of the JSON broadcast for one change over the size of the new content
+    payload_ratio: Histogram,
+    /// Messages broadcast for one change, 1 for full content
+    diffs_per_change: Histogram,
+}
+
+/// A Prometheus histogram: how many observations fell at or under each bound
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for DiffMetrics {
+    fn default() -> Self {
+        Self {
+            payload_ratio: Histogram::new(&RATIO_BOUNDS),
+            diffs_per_change: Histogram::new(&DIFF_COUNT_BOUNDS),
+        }
+    }
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(&mut self.counts) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum {}", self.sum);
+        let _ = writeln!(out, "{name}_count {}", self.count);
+    }
+}
+
+/// Records the changes broadcast for one new version of a file of `file_bytes`
+/// bytes; held back saves and empty files are left out
+pub fn record_broadcast(changes: &[FileChange], file_bytes: usize) {
+    if changes.is_empty() || file_bytes == 0 || changes.iter().any(|change| matches!(change, FileChange::ValidationError { .. })) {
+        return;
+    }
+    let payload: usize = changes.iter().map(|change| serde_json::to_string(change).map_or(0, |json| json.len())).sum();
+    let mut metrics = DIFF_METRICS.lock().expect("lock");
+    metrics.payload_ratio.observe(payload as f64 / file_bytes as f64);
+    metrics.diffs_per_change.observe(changes.len() as f64);
+}
+
+/// Counts a message of `bytes` bytes sent to a client
+pub fn record_sent(bytes: usize) {
+    BYTES_SENT.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// Bytes sent to clients since the server started
+pub fn bytes_sent() -> u64 {
+    BYTES_SENT.load(Ordering::Relaxed)
+}
+
+/// Counts a file event dropped because its watch's event queue was full
+pub fn record_dropped_event() {
+    EVENTS_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The metrics in the Prometheus text format, for `GET /metrics`
+pub fn render() -> String {
+    let metrics = DIFF_METRICS.lock().expect("lock");
+    let mut out = String::new();
+    metrics.payload_ratio.render(
+        &mut out,
+        "markdown_op_diff_payload_ratio",
+        "Bytes broadcast for a change over the size of the new content",
+    );
+    metrics.diffs_per_change.render(&mut out, "markdown_op_diffs_per_change", "Messages broadcast for a change");
+    let _ = writeln!(out, "# HELP markdown_op_sent_bytes_total Bytes sent to WebSocket and Unix socket clients");
+    let _ = writeln!(out, "# TYPE markdown_op_sent_bytes_total counter");
+    let _ = writeln!(out, "markdown_op_sent_bytes_total {}", bytes_sent());
+    let _ = writeln!(out, "# HELP markdown_op_watch_events_dropped_total File events dropped because the watcher fell behind");
+    let _ = writeln!(out, "# TYPE markdown_op_watch_events_dropped_total counter");
+    let _ = writeln!(out, "markdown_op_watch_events_dropped_total {}", EVENTS_DROPPED.load(Ordering::Relaxed));
+    out
+}
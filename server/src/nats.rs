@@ -0,0 +1,144 @@
+use std::{io, sync::Arc, time::Duration};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines}, net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpStream}, sync::mpsc, time::Instant};
+use crate::history::Broadcast;
+use crate::publisher::Publisher;
+
+/// Changes waiting to be sent to the broker; past this, new ones are dropped
+const QUEUE_LEN: usize = 1000;
+
+/// How long connecting to the broker, handshake included, may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long after a failed connect the next one is tried; changes published meanwhile are dropped
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Publishes every change to a NATS server, as the same JSON message clients
+/// get, on the subject `{prefix}.{file_id}`. Changes are queued and sent from a
+/// task of its own. While the broker can't be reached they are dropped, like
+/// NATS itself drops messages nobody is subscribed to.
+pub struct NatsPublisher {
+    prefix: String,
+    queue: mpsc::Sender<(String, String)>,
+}
+
+impl NatsPublisher {
+    /// Starts publishing to the NATS server at `addr` (`host:port`)
+    pub fn start(addr: String, prefix: String) -> Self {
+        let (queue, receiver) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(send_queued(addr, receiver));
+        Self { prefix, queue }
+    }
+}
+
+impl Publisher for NatsPublisher {
+    fn publish(&self, message: &Arc<Broadcast>) {
+        let subject = subject(&self.prefix, message.message.change.file_id());
+        if self.queue.try_send((subject, message.text(false).to_string())).is_err() {
+            eprintln!("NATS queue full, dropping change {}", message.message.seq);
+        }
+    }
+}
+
+/// `{prefix}.{file_id}`, with the chars NATS gives a meaning in subjects (`.`,
+/// `*`, `>` and whitespace) replaced by `_`, e.g. `markdown-op.docs/index_md`
+fn subject(prefix: &str, file_id: &str) -> String {
+    let file_id: String = file_id
+        .chars()
+        .map(|c| if matches!(c, '.' | '*' | '>') || c.is_whitespace() { '_' } else { c })
+        .collect();
+    format!("{prefix}.{file_id}")
+}
+
+/// A connection to the broker, past the handshake
+struct Connection {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Connection {
+    /// Connects and shakes hands: the server's INFO, our CONNECT, then a PING
+    /// the server answers with PONG once it accepted the connection
+    async fn open(addr: &str) -> io::Result<Self> {
+        let (reader, mut writer) = TcpStream::connect(addr).await?.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let info = next_line(&mut lines).await?;
+        if !info.starts_with("INFO ") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("expected INFO, got {info:?}")));
+        }
+        writer
+            .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"markdown-op\"}\r\nPING\r\n")
+            .await?;
+        loop {
+            match next_line(&mut lines).await?.as_str() {
+                "PONG" => return Ok(Self { lines, writer }),
+                line if line.starts_with("-ERR") => return Err(io::Error::other(format!("connection refused: {line}"))),
+                // +OK, or INFO about the cluster
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn next_line(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> io::Result<String> {
+    lines.next_line().await?.ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+}
+
+async fn publish(writer: &mut OwnedWriteHalf, subject: &str, payload: &str) -> io::Result<()> {
+    writer.write_all(format!("PUB {subject} {}\r\n{payload}\r\n", payload.len()).as_bytes()).await
+}
+
+/// Answers a line the server sent between publishes: PINGs keep the connection, errors end it
+async fn answer(writer: &mut OwnedWriteHalf, line: &str) -> io::Result<()> {
+    match line {
+        "PING" => writer.write_all(b"PONG\r\n").await,
+        line if line.starts_with("-ERR") => Err(io::Error::other(line.to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Sends the queued changes, connecting on the first one and again on the
+/// first one after the connection was lost
+async fn send_queued(addr: String, mut queue: mpsc::Receiver<(String, String)>) {
+    let mut connection: Option<Connection> = None;
+    let mut retry_at = Instant::now();
+    loop {
+        let result = match &mut connection {
+            None => {
+                let Some((subject, payload)) = queue.recv().await else {
+                    return;
+                };
+                if Instant::now() < retry_at {
+                    continue;
+                }
+                let opened = tokio::time::timeout(CONNECT_TIMEOUT, Connection::open(&addr)).await;
+                match opened.unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())) {
+                    Ok(mut opened) => {
+                        println!("Publishing changes to NATS at {addr}");
+                        let result = publish(&mut opened.writer, &subject, &payload).await;
+                        connection = Some(opened);
+                        result
+                    }
+                    Err(e) => {
+                        eprintln!("Can't connect to NATS at {addr}: {e}; dropping changes until it is back");
+                        retry_at = Instant::now() + RECONNECT_DELAY;
+                        continue;
+                    }
+                }
+            }
+            Some(current) => tokio::select! {
+                queued = queue.recv() => match queued {
+                    Some((subject, payload)) => publish(&mut current.writer, &subject, &payload).await,
+                    None => return,
+                },
+                line = next_line(&mut current.lines) => match line {
+                    Ok(line) => answer(&mut current.writer, &line).await,
+                    Err(e) => Err(e),
+                },
+            },
+        };
+        if let Err(e) = result {
+            eprintln!("Lost the NATS connection to {addr}: {e}");
+            connection = None;
+        }
+    }
+}
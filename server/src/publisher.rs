@@ -0,0 +1,87 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+use tokio::sync::broadcast;
+use crate::history::Broadcast;
+
+/// Somewhere broadcast changes are sent as they are published, in seq order.
+///
+/// Called with the history locked, so implementations must not block: one
+/// that talks to a remote service hands the message to a task of its own.
+pub trait Publisher: Send + Sync {
+    fn publish(&self, message: &Arc<Broadcast>);
+}
+
+/// The connected clients: every WebSocket, Unix socket and long-poll
+/// connection receives the changes through subscriptions to these channels,
+/// one per file, so a file changing faster than its clients keep up only
+/// makes the clients following that file lag
+#[derive(Clone)]
+pub struct Channels {
+    capacity: usize,
+    senders: Arc<Mutex<HashMap<String, broadcast::Sender<Arc<Broadcast>>>>>,
+}
+
+impl Channels {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, senders: Arc::default() }
+    }
+
+    /// Subscribes to the changes of one file, opening its channel if needed
+    pub fn subscribe(&self, file_id: &str) -> broadcast::Receiver<Arc<Broadcast>> {
+        let mut senders = self.senders.lock().expect("lock");
+        let sender = senders.entry(file_id.to_string()).or_insert_with(|| broadcast::channel(self.capacity).0);
+        sender.subscribe()
+    }
+
+    /// Whether any file's channel has a receiver
+    pub fn has_receivers(&self) -> bool {
+        self.senders.lock().expect("lock").values().any(|sender| sender.receiver_count() > 0)
+    }
+}
+
+impl Publisher for Channels {
+    fn publish(&self, message: &Arc<Broadcast>) {
+        // no channel or no receivers just means no client follows the file
+        if let Some(sender) = self.senders.lock().expect("lock").get(message.message.change.file_id()) {
+            let _ = sender.send(Arc::clone(message));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::{FileChange, PositionUnit, Sequenced};
+    use crate::history::{History, HistoryConfig};
+    use super::*;
+
+    /// Keeps every published change
+    struct MemoryPublisher(Arc<Mutex<Vec<Sequenced>>>);
+
+    impl Publisher for MemoryPublisher {
+        fn publish(&self, message: &Arc<Broadcast>) {
+            self.0.lock().expect("lock").push(message.message.clone());
+        }
+    }
+
+    #[test]
+    fn publishers_get_every_change_in_seq_order() {
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let history = Arc::new(History::new(10, HistoryConfig::default(), None).with_publisher(MemoryPublisher(Arc::clone(&published))));
+        let mut subscription = history.subscribe(None, &["a.md", "b.md"]);
+        let changes = [
+            FileChange::FullContent { file_id: "a.md".to_string(), content: "# A\n".to_string(), last_modified: None },
+            FileChange::Diff { file_id: "a.md".to_string(), position: 4, delete_count: 0, insert_text: "More.\n".to_string(), unit: PositionUnit::Chars },
+            FileChange::FullContent { file_id: "b.md".to_string(), content: "# B\n".to_string(), last_modified: None },
+        ];
+        for change in changes.clone() {
+            history.publish(change);
+        }
+        let published = published.lock().expect("lock");
+        let seqs: Vec<u64> = published.iter().map(|message| message.seq).collect();
+        assert_eq!(seqs, [1, 2, 3]);
+        assert_eq!(published.iter().map(|message| message.change.clone()).collect::<Vec<_>>(), changes);
+        // connected clients still get them too
+        for seq in seqs {
+            assert_eq!(subscription.receiver.try_recv().expect("broadcast").message.seq, seq);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::{borrow::Cow, fs::File, io::{self, Read}, path::Path, time::SystemTime};
+use flate2::read::MultiGzDecoder;
+use memmap2::Mmap;
+use serde::Deserialize;
+
+/// How the watcher reads a changed file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadStrategy {
+    /// Read the whole file into a new string
+    #[default]
+    Read,
+    /// Memory-map the file, and only copy it out when it differs from the last version
+    Mmap,
+}
+
+/// What the server does with whitespace at the end of a watched file before
+/// it becomes the content clients mirror
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TrailingWhitespace {
+    /// Mirror the file as it is
+    #[default]
+    Preserve,
+    /// End the content with exactly one newline, so editors that add or strip
+    /// the final newline on save don't cause changes
+    Normalize,
+}
+
+impl TrailingWhitespace {
+    /// The content with its end normalized. Whitespace at the end of lines
+    /// inside it is kept, since two spaces there are a line break in markdown.
+    pub fn apply(self, content: &str) -> Cow<'_, str> {
+        if self == Self::Preserve {
+            return Cow::Borrowed(content);
+        }
+        let body = content.trim_end();
+        let newline = if content.contains("\r\n") { "\r\n" } else { "\n" };
+        if body.is_empty() {
+            // only whitespace: nothing to end with a newline
+            if content.is_empty() { Cow::Borrowed(content) } else { Cow::Owned(String::new()) }
+        } else if content.strip_suffix(newline) == Some(body) {
+            Cow::Borrowed(content)
+        } else {
+            Cow::Owned(format!("{body}{newline}"))
+        }
+    }
+
+    /// [`apply`](Self::apply) for content that is owned already
+    pub fn apply_owned(self, content: String) -> String {
+        let normalized = match self.apply(&content) {
+            Cow::Owned(normalized) => Some(normalized),
+            Cow::Borrowed(_) => None,
+        };
+        normalized.unwrap_or(content)
+    }
+}
+
+/// Whether the file is stored gzipped (a `.gz` extension) and mirrored decompressed
+pub fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+}
+
+/// The text of a file read as `bytes`, decompressed first if it is gzipped.
+/// A gzipped file that is cut short, e.g. while it is being rewritten, is an error.
+pub fn decode<'a>(path: &Path, bytes: &'a [u8]) -> io::Result<Cow<'a, str>> {
+    if is_gzip(path) {
+        let mut content = String::new();
+        MultiGzDecoder::new(bytes).read_to_string(&mut content)?;
+        return Ok(Cow::Owned(content));
+    }
+    std::str::from_utf8(bytes)
+        .map(Cow::Borrowed)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Reads the text of a watched file, see [`decode`]
+pub async fn read_to_string(path: &Path) -> io::Result<String> {
+    let bytes = tokio::fs::read(path).await?;
+    decode(path, &bytes).map(Cow::into_owned)
+}
+
+/// Reads `path` through a memory map and returns its content, or `None` when
+/// `unchanged` says it is the same as what was already seen. Content is still
+/// validated as UTF-8. Files that can't be mapped (empty files, pipes, some
+/// network filesystems) are read with `read_to_string` instead.
+pub fn read_mapped(path: &Path, unchanged: impl FnOnce(&str) -> bool) -> io::Result<Option<String>> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        return read_unmapped(path, &mut file, unchanged);
+    }
+    // SAFETY: the map is only read in this function and the content is copied
+    // out before it is dropped. A file truncated by another process while it is
+    // being read can still fault, which is why this strategy is opt-in.
+    let map = match unsafe { Mmap::map(&file) } {
+        Ok(map) => map,
+        Err(_) => return read_unmapped(path, &mut file, unchanged),
+    };
+    let content = decode(path, &map)?;
+    if unchanged(&content) {
+        return Ok(None);
+    }
+    Ok(Some(content.into_owned()))
+}
+
+fn read_unmapped(path: &Path, file: &mut File, unchanged: impl FnOnce(&str) -> bool) -> io::Result<Option<String>> {
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    let content = decode(path, &bytes)?;
+    Ok((!unchanged(&content)).then(|| content.into_owned()))
+}
+
+/// Modification time of the file, sent along with its full content; `None`
+/// when the platform or file system does not record one
+pub async fn modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
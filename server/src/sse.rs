@@ -0,0 +1,130 @@
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use shared::PositionUnit;
+use crate::config::ServerConfig;
+use crate::long_poll::{respond, PollRequest};
+use crate::transport::{Connection, Transport, TransportError};
+
+const STREAM_HEAD: &str = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+    Connection: keep-alive\r\nAccess-Control-Allow-Origin: *\r\n\r\n";
+
+/// Serves browsers over Server-Sent Events: `GET /events` streams every
+/// message a WebSocket client would get as an event with the JSON as `data`
+/// and the change's seq as `id`. An `EventSource` that lost the stream sends
+/// the last id it saw as `Last-Event-ID` when it reconnects, which resumes
+/// like `since` does for a WebSocket client.
+///
+/// The stream only goes one way, so an SSE client cannot ack or ask for a
+/// resync; it reconnects instead.
+pub struct SseTransport {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+}
+
+pub struct SseConnection {
+    stream: TcpStream,
+    resume_from: Option<u64>,
+    position_unit: PositionUnit,
+}
+
+impl SseTransport {
+    pub async fn bind(addr: &str, config: Arc<ServerConfig>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        println!("SSE server listening on http://{}", listener.local_addr()?);
+        Ok(Self { listener, config })
+    }
+}
+
+impl Transport for SseTransport {
+    type Pending = TcpStream;
+    type Connection = SseConnection;
+
+    const NAME: &'static str = "SSE";
+
+    async fn accept(&self) -> std::io::Result<(TcpStream, String)> {
+        let (stream, client_addr) = self.listener.accept().await?;
+        if let Err(e) = self.config.tcp.options().apply(&stream) {
+            eprintln!("Cannot set TCP options for {}: {}", client_addr, e);
+        }
+        Ok((stream, client_addr.to_string()))
+    }
+
+    async fn establish(&self, mut stream: TcpStream) -> Result<SseConnection, TransportError> {
+        let Some(request) = PollRequest::read(&mut stream).await? else {
+            respond(&mut stream, "400 Bad Request", "malformed request").await?;
+            return Err("malformed request".into());
+        };
+        if request.path != "/events" {
+            respond(&mut stream, "404 Not Found", "not found").await?;
+            return Err(format!("no such path {}", request.path).into());
+        }
+        if request.method != "GET" {
+            respond(&mut stream, "405 Method Not Allowed", "method not allowed").await?;
+            return Err(format!("method {} not allowed", request.method).into());
+        }
+        if !request.is_authorized(self.config.auth_token.as_deref()) {
+            respond(&mut stream, "401 Unauthorized", "invalid or missing token").await?;
+            return Err("invalid or missing token".into());
+        }
+        // `since` lets a page resume from a seq it kept across reloads
+        let resume_from = request
+            .last_event_id
+            .as_deref()
+            .or(request.query.get("since").map(String::as_str))
+            .and_then(|seq| seq.parse().ok());
+        // `utf16` for pages applying diffs to JavaScript strings
+        let position_unit = request.query.get("positions").and_then(|unit| unit.parse().ok()).unwrap_or_default();
+        stream.write_all(STREAM_HEAD.as_bytes()).await?;
+        stream.flush().await?;
+        Ok(SseConnection { stream, resume_from, position_unit })
+    }
+}
+
+impl Connection for SseConnection {
+    fn resume_from(&self) -> Option<u64> {
+        self.resume_from
+    }
+
+    fn position_unit(&self) -> PositionUnit {
+        self.position_unit
+    }
+
+    async fn send(&mut self, message: &str) -> Result<(), TransportError> {
+        // serialized JSON has no raw newlines, so it always fits on one `data` line
+        let event = match event_id(message) {
+            Some(seq) => format!("id: {seq}\ndata: {message}\n\n"),
+            None => format!("data: {message}\n\n"),
+        };
+        self.stream.write_all(event.as_bytes()).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Option<Result<String, TransportError>> {
+        // nothing comes from an SSE client after the request; reading only
+        // tells when it went away
+        let mut buf = [0u8; 512];
+        loop {
+            match self.stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    async fn close(&mut self) {
+        let _ = self.stream.shutdown().await;
+    }
+}
+
+/// The seq of a change, also inside a signed envelope; other messages, e.g.
+/// sync progress, have none and are sent without an id, so they do not move
+/// the id a reconnecting `EventSource` resumes from
+fn event_id(message: &str) -> Option<u64> {
+    let value: serde_json::Value = serde_json::from_str(message).ok()?;
+    match value.get("Signed").and_then(|signed| signed["message"].as_str()) {
+        Some(signed) => event_id(signed),
+        None => value.get("seq")?.as_u64(),
+    }
+}
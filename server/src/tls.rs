@@ -0,0 +1,84 @@
+use std::{fs::File, io::BufReader, path::{Path, PathBuf}, sync::Arc};
+use serde::Deserialize;
+use tokio_rustls::rustls::{self, pki_types::{CertificateDer, PrivateKeyDer}, server::WebPkiClientVerifier, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// TLS for the WebSocket server, optionally verifying client certificates
+/// (mutual TLS) instead of relying on a shared token
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain presented to clients; with `key`, clients connect over `wss://`
+    pub cert: Option<PathBuf>,
+    /// PEM private key of `cert`
+    pub key: Option<PathBuf>,
+    /// PEM certificates of the CAs client certificates must be signed by. Clients
+    /// without such a certificate are turned away during the TLS handshake.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Checks the settings fit together, without reading the files
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.cert, &self.key, &self.client_ca) {
+            (Some(_), None, _) | (None, Some(_), _) => Err("tls.cert and tls.key must be given together".to_string()),
+            (None, None, Some(_)) => Err("tls.client_ca needs tls.cert and tls.key".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The acceptor TLS connections are completed with, `None` when TLS is off
+    pub fn acceptor(&self) -> Result<Option<TlsAcceptor>, String> {
+        let (Some(cert), Some(key)) = (&self.cert, &self.key) else {
+            return Ok(None);
+        };
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for ca in read_certs(client_ca)? {
+                    roots.add(ca).map_err(|e| format!("invalid CA certificate in {}: {}", client_ca.display(), e))?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                    .build()
+                    .map_err(|e| format!("tls.client_ca: {}", e))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(read_certs(cert)?, read_key(key)?)
+            .map_err(|e| format!("tls.cert and tls.key: {}", e))?;
+        Ok(Some(TlsAcceptor::from(Arc::new(config))))
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid PEM in {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("invalid PEM in {}: {}", path.display(), e))?
+        .ok_or_else(|| format!("no private key in {}", path.display()))
+}
+
+/// The client id a verified client certificate stands for: the common name
+/// of its subject
+pub fn client_id(certs: &[CertificateDer<'_>]) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(certs.first()?).ok()?;
+    let common_name = cert.subject().iter_common_name().next()?;
+    common_name.as_str().ok().map(str::to_string)
+}
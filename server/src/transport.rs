@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::future::Future;
+use shared::PositionUnit;
+
+pub type TransportError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A way for clients to reach the broadcast engine (WebSocket, Unix socket, ...)
+pub trait Transport: Send + Sync + 'static {
+    /// An accepted client that has not completed the protocol handshake yet
+    type Pending: Send + 'static;
+    type Connection: Connection;
+
+    /// Human readable name used in logs
+    const NAME: &'static str;
+
+    /// Waits for the next client, returning it with a printable peer address
+    fn accept(&self) -> impl Future<Output = std::io::Result<(Self::Pending, String)>> + Send;
+
+    /// Completes the handshake; runs on the client's own task
+    fn establish(&self, pending: Self::Pending) -> impl Future<Output = Result<Self::Connection, TransportError>> + Send;
+}
+
+/// An established client connection exchanging JSON messages
+pub trait Connection: Send + 'static {
+    /// Seq of the last change the client already has, when it asked to resume
+    fn resume_from(&self) -> Option<u64> {
+        None
+    }
+
+    /// What diff positions sent to the client count, when it asked for other than chars
+    fn position_unit(&self) -> PositionUnit {
+        PositionUnit::Chars
+    }
+
+    /// Whether the client holds copies of the files and asked for chunk sync, see [`shared::chunks`]
+    fn chunk_sync(&self) -> bool {
+        false
+    }
+
+    /// The id of the client, from its verified TLS client certificate
+    fn client_id(&self) -> Option<&str> {
+        None
+    }
+
+    /// The files the client asked to follow, when not all of them
+    fn files(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// The [`shared::digest`] of each file the client already holds a copy
+    /// of, by file id, so it can be sent only the changes since that content
+    fn digests(&self) -> HashMap<String, String> {
+        HashMap::new()
+    }
+
+    /// Sends one message, a serialized `Sequenced` change or its compressed form
+    fn send(&mut self, message: &str) -> impl Future<Output = Result<(), TransportError>> + Send;
+
+    /// Waits for the next text message from the client, `None` once it is gone.
+    /// Must be cancel-safe, it is raced against outgoing broadcasts.
+    fn recv(&mut self) -> impl Future<Output = Option<Result<String, TransportError>>> + Send;
+
+    /// Tells the client the connection is being closed
+    fn close(&mut self) -> impl Future<Output = ()> + Send;
+}
@@ -0,0 +1,228 @@
+use std::{collections::{HashMap, VecDeque}, fs::OpenOptions, io::{self, Write}, sync::{Arc, Mutex}, time::Duration};
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+        execute,
+        terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout},
+    widgets::{Block, Borders, List, Paragraph, Row, Table},
+    Frame, Terminal,
+};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use shared::{FileChange, Sequenced};
+use crate::history::{Broadcast, History};
+use crate::metrics;
+use crate::publisher::Publisher;
+
+/// How many changes the recent changes list keeps
+const RECENT_CHANGES: usize = 50;
+
+/// How often the screen is redrawn, and the keyboard checked
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What the TUI shows: the watched files, connected clients, recent changes
+/// and bytes sent. Kept apart from drawing so it can be tested without a terminal.
+#[derive(Debug, Clone, Default)]
+pub struct TuiState {
+    pub files: Vec<FileRow>,
+    /// Most recent first
+    pub recent: VecDeque<RecentChange>,
+    pub connections: usize,
+    pub bytes_sent: u64,
+    /// Seq of the last broadcast change
+    pub seq: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileRow {
+    pub file_id: String,
+    /// Size of the content as of the last broadcast, if it is known yet
+    pub bytes: Option<usize>,
+    /// Changes broadcast since the server started
+    pub changes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecentChange {
+    pub seq: u64,
+    pub file_id: String,
+    pub summary: String,
+}
+
+/// Feeds every broadcast change to the TUI's state
+pub struct TuiPublisher(pub Arc<Mutex<TuiState>>);
+
+impl Publisher for TuiPublisher {
+    fn publish(&self, message: &Arc<Broadcast>) {
+        self.0.lock().expect("lock").on_change(&message.message);
+    }
+}
+
+impl TuiState {
+    pub fn new(file_ids: &[&str]) -> Self {
+        Self {
+            files: file_ids.iter().map(|file_id| FileRow { file_id: file_id.to_string(), bytes: None, changes: 0 }).collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Counts a broadcast change against its file and adds it to the recent changes
+    pub fn on_change(&mut self, message: &Sequenced) {
+        let file_id = message.change.file_id();
+        self.seq = message.seq;
+        match self.files.iter_mut().find(|file| file.file_id == file_id) {
+            Some(file) => file.changes += 1,
+            None => self.files.push(FileRow { file_id: file_id.to_string(), bytes: None, changes: 1 }),
+        }
+        self.recent.push_front(RecentChange { seq: message.seq, file_id: file_id.to_string(), summary: summary(&message.change) });
+        self.recent.truncate(RECENT_CHANGES);
+    }
+
+    /// Takes in the counters that are kept elsewhere
+    pub fn refresh(&mut self, connections: usize, bytes_sent: u64, sizes: &HashMap<String, usize>) {
+        self.connections = connections;
+        self.bytes_sent = bytes_sent;
+        for file in &mut self.files {
+            file.bytes = sizes.get(&file.file_id).copied().or(file.bytes);
+        }
+    }
+}
+
+/// One line describing a change for the recent changes list
+fn summary(change: &FileChange) -> String {
+    match change {
+        FileChange::FullContent { content, .. } => format!("full content, {} bytes", content.len()),
+        FileChange::Diff { position, delete_count, insert_text, .. } => {
+            format!("diff at {}: -{} +{}", position, delete_count, insert_text.chars().count())
+        }
+        FileChange::ValidationError { message, .. } => format!("held back: {}", message),
+        FileChange::ChunkHashes { hashes, .. } => format!("{} chunk hashes", hashes.len()),
+        FileChange::Chunks { chunks, .. } => format!("{} chunks", chunks.len()),
+        FileChange::MetadataChanged { mode: Some(mode), .. } => format!("metadata, mode {:o}", mode & 0o7777),
+        FileChange::MetadataChanged { .. } => "metadata".to_string(),
+    }
+}
+
+/// Draws the TUI until `q`, Esc or Ctrl+C is pressed, or `shutdown` is
+/// cancelled; quitting the TUI cancels `shutdown`, which stops the server
+pub fn spawn(state: Arc<Mutex<TuiState>>, history: Arc<History>, shutdown: CancellationToken) -> JoinHandle<()> {
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = run(&state, &history, &shutdown) {
+            eprintln!("TUI error: {}", e);
+        }
+        shutdown.cancel();
+    })
+}
+
+fn run(state: &Mutex<TuiState>, history: &History, shutdown: &CancellationToken) -> io::Result<()> {
+    let mut output = terminal_output();
+    enable_raw_mode()?;
+    execute!(output, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(output))?;
+    let result = draw_until_quit(&mut terminal, state, history, shutdown);
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    result
+}
+
+/// The controlling terminal, so the server's log can be redirected away from
+/// the TUI, e.g. `server --tui > server.log`; stdout if there is none
+fn terminal_output() -> Box<dyn Write + Send> {
+    match OpenOptions::new().write(true).open("/dev/tty") {
+        Ok(tty) => Box::new(tty),
+        Err(_) => Box::new(io::stdout()),
+    }
+}
+
+fn draw_until_quit(
+    terminal: &mut Terminal<CrosstermBackend<Box<dyn Write + Send>>>,
+    state: &Mutex<TuiState>,
+    history: &History,
+    shutdown: &CancellationToken,
+) -> io::Result<()> {
+    while !shutdown.is_cancelled() {
+        // drawn from a copy, so publishing is not held up by the terminal
+        let snapshot = {
+            let mut state = state.lock().expect("lock");
+            state.refresh(history.connections(), metrics::bytes_sent(), &history.sizes());
+            state.clone()
+        };
+        terminal.draw(|frame| render(frame, &snapshot))?;
+        if event::poll(REFRESH_INTERVAL)? {
+            if let Event::Key(key) = event::read()? {
+                let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                // raw mode keeps Ctrl+C from raising SIGINT
+                if key.kind == KeyEventKind::Press && (ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)) {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn render(frame: &mut Frame, state: &TuiState) {
+    let [header, files, recent] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(state.files.len() as u16 + 3),
+        Constraint::Min(3),
+    ])
+    .areas(frame.area());
+    let status = format!(
+        "seq {}   clients {}   sent {} bytes   (q to quit)",
+        state.seq, state.connections, state.bytes_sent
+    );
+    frame.render_widget(Paragraph::new(status).block(Block::default().borders(Borders::ALL).title("markdown-op")), header);
+    let rows = state.files.iter().map(|file| {
+        let bytes = file.bytes.map_or_else(|| "-".to_string(), |bytes| bytes.to_string());
+        Row::new([file.file_id.clone(), bytes, file.changes.to_string()])
+    });
+    let table = Table::new(rows, [Constraint::Fill(1), Constraint::Length(12), Constraint::Length(8)])
+        .header(Row::new(["File", "Bytes", "Changes"]))
+        .block(Block::default().borders(Borders::ALL).title("Watched files"));
+    frame.render_widget(table, files);
+    let lines = state.recent.iter().map(|change| format!("{:>6}  {}  {}", change.seq, change.file_id, change.summary));
+    frame.render_widget(List::new(lines).block(Block::default().borders(Borders::ALL).title("Recent changes")), recent);
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::PositionUnit;
+    use super::*;
+
+    #[test]
+    fn state_follows_broadcast_changes() {
+        let mut state = TuiState::new(&["a.md", "b.md"]);
+        let changes = [
+            FileChange::FullContent { file_id: "a.md".to_string(), content: "# A\n".to_string(), last_modified: None },
+            FileChange::Diff { file_id: "a.md".to_string(), position: 4, delete_count: 0, insert_text: "More.\n".to_string(), unit: PositionUnit::Chars },
+            FileChange::ValidationError { file_id: "b.md".to_string(), message: "unclosed code fence".to_string() },
+        ];
+        for (seq, change) in (1..).zip(changes) {
+            state.on_change(&Sequenced::new(seq, change));
+        }
+        state.refresh(2, 120, &HashMap::from([("a.md".to_string(), 10)]));
+
+        assert_eq!((state.seq, state.connections, state.bytes_sent), (3, 2, 120));
+        assert_eq!(state.files[0], FileRow { file_id: "a.md".to_string(), bytes: Some(10), changes: 2 });
+        assert_eq!(state.files[1], FileRow { file_id: "b.md".to_string(), bytes: None, changes: 1 });
+        let summaries: Vec<_> = state.recent.iter().map(|change| (change.seq, change.summary.as_str())).collect();
+        assert_eq!(summaries, [(3, "held back: unclosed code fence"), (2, "diff at 4: -0 +6"), (1, "full content, 4 bytes")]);
+    }
+
+    #[test]
+    fn recent_changes_are_bounded() {
+        let mut state = TuiState::new(&["a.md"]);
+        for seq in 1..=RECENT_CHANGES as u64 + 10 {
+            let change = FileChange::FullContent { file_id: "a.md".to_string(), content: String::new(), last_modified: None };
+            state.on_change(&Sequenced::new(seq, change));
+        }
+        assert_eq!(state.recent.len(), RECENT_CHANGES);
+        assert_eq!(state.recent.front().map(|change| change.seq), Some(RECENT_CHANGES as u64 + 10));
+        assert_eq!(state.files[0].changes, RECENT_CHANGES as u64 + 10);
+    }
+}
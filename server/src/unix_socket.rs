@@ -0,0 +1,127 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::{SinkExt, StreamExt};
+use shared::framing::{self, FrameError};
+use tokio::net::{UnixListener, UnixStream};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use crate::transport::{Connection, Transport, TransportError};
+
+/// Serves clients over a Unix domain socket, one length-prefixed JSON frame
+/// per message (see [`shared::framing`])
+pub struct UnixTransport {
+    listener: UnixListener,
+    path: PathBuf,
+    accepted: AtomicU64,
+    max_frame_bytes: usize,
+}
+
+pub struct UnixConnection {
+    framed: Framed<UnixStream, FrameCodec>,
+}
+
+/// [`shared::framing`] as a codec; a malformed frame ends the connection with
+/// an error instead of being buffered or waited on
+pub struct FrameCodec {
+    max_frame_bytes: usize,
+}
+
+impl UnixTransport {
+    pub fn bind(path: &Path, max_frame_bytes: usize) -> std::io::Result<Self> {
+        // a socket file left behind by a previous run would make bind fail
+        if std::fs::symlink_metadata(path).is_ok_and(|m| {
+            use std::os::unix::fs::FileTypeExt;
+            m.file_type().is_socket()
+        }) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        println!("Unix socket server listening on {}", path.display());
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+            accepted: AtomicU64::new(0),
+            max_frame_bytes,
+        })
+    }
+}
+
+impl Drop for UnixTransport {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Transport for UnixTransport {
+    type Pending = UnixStream;
+    type Connection = UnixConnection;
+
+    const NAME: &'static str = "Unix socket";
+
+    async fn accept(&self) -> std::io::Result<(UnixStream, String)> {
+        let (stream, _) = self.listener.accept().await?;
+        let id = self.accepted.fetch_add(1, Ordering::Relaxed) + 1;
+        Ok((stream, format!("unix:{}#{}", self.path.display(), id)))
+    }
+
+    async fn establish(&self, stream: UnixStream) -> Result<UnixConnection, TransportError> {
+        Ok(UnixConnection {
+            framed: Framed::new(stream, FrameCodec { max_frame_bytes: self.max_frame_bytes }),
+        })
+    }
+}
+
+fn protocol_error(error: FrameError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+impl Decoder for FrameCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let Some((payload, len)) = framing::decode(src, self.max_frame_bytes).map_err(protocol_error)? else {
+            return Ok(None);
+        };
+        let payload_len = payload.len();
+        let mut frame = src.split_to(len);
+        frame.advance(len - payload_len);
+        Ok(Some(frame))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None => framing::finish(src).map(|()| None).map_err(protocol_error),
+        }
+    }
+}
+
+impl Encoder<Bytes> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        let frame = framing::encode(&message, self.max_frame_bytes).map_err(protocol_error)?;
+        dst.extend_from_slice(&frame);
+        Ok(())
+    }
+}
+
+impl Connection for UnixConnection {
+    async fn send(&mut self, message: &str) -> Result<(), TransportError> {
+        self.framed.send(Bytes::copy_from_slice(message.as_bytes())).await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Option<Result<String, TransportError>> {
+        match self.framed.next().await? {
+            Ok(frame) => Some(String::from_utf8(frame.to_vec()).map_err(Into::into)),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+
+    async fn close(&mut self) {
+        let _ = SinkExt::<Bytes>::close(&mut self.framed).await;
+    }
+}
@@ -0,0 +1,188 @@
+use pulldown_cmark::{CodeBlockKind, Event, Parser, Tag};
+use regex::Regex;
+use serde::{de::Error, Deserialize, Deserializer};
+
+/// Checks that can hold back a broadcast of a half-written document
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rule {
+    /// A fenced code block is opened but never closed
+    UnclosedFence,
+    /// An HTML comment `<!--` is never closed
+    UnclosedComment,
+    /// A `---` front matter block at the top is never closed
+    UnterminatedFrontMatter,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidationConfig {
+    /// Validate content before broadcasting it
+    pub enabled: bool,
+    pub rules: Vec<Rule>,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rules: vec![Rule::UnclosedFence, Rule::UnclosedComment, Rule::UnterminatedFrontMatter],
+        }
+    }
+}
+
+impl ValidationConfig {
+    /// Returns a description of the first failing rule, if any
+    pub fn check(&self, content: &str) -> Result<(), String> {
+        if !self.enabled {
+            return Ok(());
+        }
+        for rule in &self.rules {
+            rule.check(content)?;
+        }
+        Ok(())
+    }
+}
+
+/// Conditions a file's content must meet to be mirrored at all, e.g. a
+/// `<!-- ready -->` marker line in a draft. Unlike a save that fails
+/// validation, content that misses one is not reported to clients: they keep
+/// the last version that met them. Every condition given must hold.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MirrorWhen {
+    /// A line the content must have, compared without surrounding whitespace
+    pub marker: Option<String>,
+    /// A regex that must match somewhere in the content
+    #[serde(deserialize_with = "regex")]
+    pub matches: Option<Regex>,
+    /// Fewest bytes the content must have
+    pub min_bytes: usize,
+}
+
+impl MirrorWhen {
+    /// Returns why the content is not to be mirrored, if it is not
+    pub fn check(&self, content: &str) -> Result<(), String> {
+        if let Some(marker) = &self.marker {
+            let marker = marker.trim();
+            if !content.lines().any(|line| line.trim() == marker) {
+                return Err(format!("has no {marker} line"));
+            }
+        }
+        if let Some(regex) = self.matches.as_ref().filter(|regex| !regex.is_match(content)) {
+            return Err(format!("does not match /{regex}/"));
+        }
+        if content.len() < self.min_bytes {
+            return Err(format!("is {} bytes, fewer than min_bytes {}", content.len(), self.min_bytes));
+        }
+        Ok(())
+    }
+}
+
+fn regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    let Some(pattern) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    Regex::new(&pattern).map(Some).map_err(D::Error::custom)
+}
+
+impl Rule {
+    fn check(self, content: &str) -> Result<(), String> {
+        match self {
+            Rule::UnclosedFence => check_fences(content),
+            Rule::UnclosedComment => check_comments(content),
+            Rule::UnterminatedFrontMatter => check_front_matter(content),
+        }
+    }
+}
+
+fn check_fences(content: &str) -> Result<(), String> {
+    for (event, range) in Parser::new(content).into_offset_iter() {
+        let Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(_))) = event else {
+            continue;
+        };
+        // a fenced block without its closing fence runs to the end of the document
+        let block = &content[range.clone()];
+        let mut lines = block.lines();
+        let opening = lines.next().unwrap_or("").trim_start();
+        let fence_char = opening.chars().next().unwrap_or('`');
+        let fence_len = opening.chars().take_while(|&c| c == fence_char).count();
+        let closed = lines.last().is_some_and(|line| {
+            let line = line.trim();
+            line.chars().take_while(|&c| c == fence_char).count() >= fence_len
+                && line.chars().all(|c| c == fence_char)
+        });
+        if !closed {
+            let line = line_number(content, range.start);
+            return Err(format!("unclosed code fence opened on line {}", line));
+        }
+    }
+    Ok(())
+}
+
+fn check_comments(content: &str) -> Result<(), String> {
+    let mut rest = content;
+    while let Some(start) = rest.find("<!--") {
+        match rest[start + 4..].find("-->") {
+            Some(end) => rest = &rest[start + 4 + end + 3..],
+            None => {
+                let offset = content.len() - rest.len() + start;
+                let line = line_number(content, offset);
+                return Err(format!("unclosed HTML comment opened on line {}", line));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_front_matter(content: &str) -> Result<(), String> {
+    let mut lines = content.lines();
+    if lines.next().map(str::trim_end) != Some("---") {
+        return Ok(());
+    }
+    if lines.any(|line| matches!(line.trim_end(), "---" | "...")) {
+        Ok(())
+    } else {
+        Err("front matter opened on line 1 is never closed".to_string())
+    }
+}
+
+fn line_number(content: &str, offset: usize) -> usize {
+    content[..offset].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled() -> ValidationConfig {
+        ValidationConfig { enabled: true, ..ValidationConfig::default() }
+    }
+
+    #[test]
+    fn complete_documents_pass() {
+        let content = "---\ntitle: Doc\n---\n# Title\n\n```rust\nfn main() {}\n```\n\n<!-- note -->\nText.\n";
+        assert_eq!(enabled().check(content), Ok(()));
+    }
+
+    #[test]
+    fn each_rule_reports_where_the_document_is_broken() {
+        assert_eq!(enabled().check("# Title\n\n```rust\nfn main() {}\n"), Err("unclosed code fence opened on line 3".to_string()));
+        assert_eq!(enabled().check("# Title\n<!-- note\n"), Err("unclosed HTML comment opened on line 2".to_string()));
+        assert_eq!(enabled().check("---\ntitle: Doc\n# Title\n"), Err("front matter opened on line 1 is never closed".to_string()));
+    }
+
+    #[test]
+    fn only_the_configured_rules_run_and_only_when_enabled() {
+        let broken = "```\ncode\n";
+        assert_eq!(ValidationConfig::default().check(broken), Ok(()));
+        let comments_only = ValidationConfig { enabled: true, rules: vec![Rule::UnclosedComment] };
+        assert_eq!(comments_only.check(broken), Ok(()));
+    }
+
+    #[test]
+    fn a_longer_closing_fence_closes_the_block() {
+        assert_eq!(enabled().check("```\ncode\n`````\n"), Ok(()));
+        assert!(enabled().check("````\ncode\n```\n").is_err());
+    }
+}
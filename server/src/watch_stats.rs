@@ -0,0 +1,55 @@
+use std::{collections::BTreeMap, sync::Mutex};
+use serde::Serialize;
+
+lazy_static::lazy_static! {
+    static ref FILE_STATS: Mutex<BTreeMap<String, FileStats>> = Mutex::new(BTreeMap::new());
+}
+
+/// What the watcher did with the events for one file since the server
+/// started. Debounced and coalesced events never lead to a read, so
+/// `events - debounced - coalesced` is how many reads were started.
+///
+/// Only kept in debug builds, for tests to assert on the watcher's otherwise
+/// invisible behavior through `GET /debug/watcher` (see [`crate::watcher::WatchControl::debug_state`]).
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FileStats {
+    /// File system events for the file, before debouncing
+    pub events: u64,
+    /// Events dropped for coming within `debounce_ms` of the one before
+    pub debounced: u64,
+    /// Events folded into a read already scheduled by `min_read_interval_ms`
+    pub coalesced: u64,
+    /// Reads that broadcast nothing, e.g. because the content did not change
+    pub unchanged: u64,
+    /// Versions of the file broadcast; held back saves are not counted
+    pub broadcasts: u64,
+}
+
+/// Something the watcher did for a file
+#[derive(Debug, Clone, Copy)]
+pub enum Counter {
+    Event,
+    Debounced,
+    Coalesced,
+    Unchanged,
+    Broadcast,
+}
+
+/// Counts `counter` for the file
+pub fn record(file_id: &str, counter: Counter) {
+    let mut stats = FILE_STATS.lock().expect("lock");
+    let stats = stats.entry(file_id.to_string()).or_default();
+    let count = match counter {
+        Counter::Event => &mut stats.events,
+        Counter::Debounced => &mut stats.debounced,
+        Counter::Coalesced => &mut stats.coalesced,
+        Counter::Unchanged => &mut stats.unchanged,
+        Counter::Broadcast => &mut stats.broadcasts,
+    };
+    *count += 1;
+}
+
+/// The counters of every file that had any
+pub fn snapshot() -> BTreeMap<String, FileStats> {
+    FILE_STATS.lock().expect("lock").clone()
+}
@@ -1,58 +1,327 @@
-use std::{collections::HashMap, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Instant};
-use tokio::sync::{broadcast, mpsc};
+use std::{borrow::Cow, collections::{HashMap, HashSet, VecDeque}, io, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, Weak}, time::{Duration, Instant, SystemTime}};
+use tokio::{io::{AsyncRead, AsyncReadExt}, sync::mpsc::{self, error::TrySendError}, task::{JoinHandle, JoinSet}};
 use notify::{RecommendedWatcher, RecursiveMode, Watcher, Event};
-use shared::FileChange;
+use shared::{DiffStrategy, FileChange};
+use crate::clock::{Clock, SystemClock};
+use crate::config::{DiffBase, DiffPolicy, ServerConfig};
+use crate::content_cache::ContentCache;
+use crate::history::History;
+use crate::hooks;
+use crate::matcher::{canonical_path, PathMatcher, PatternMatcher};
+use crate::metrics;
+use crate::reader::{self, ReadStrategy, TrailingWhitespace};
+#[cfg(debug_assertions)]
+use crate::watch_stats::{self, Counter};
 
-const DEBOUNCE_MS: u64 = 25;
+/// Upper bound on how many quiescence intervals a read waits for the file to settle
+const MAX_SETTLE_ROUNDS: u32 = 20;
 
-lazy_static::lazy_static! {
-    static ref LAST_CONTENT: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
-    static ref DEBOUNCE_STATE: Mutex<HashMap<PathBuf, Instant>> = Mutex::new(HashMap::new());
+/// How often a gzipped file that can't be decompressed is read before the change is skipped
+const GZIP_READ_ATTEMPTS: u32 = 3;
+const GZIP_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// How often a watched directory that was removed is checked for until it is back
+const REWATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Tracks full reads of a path so they can be rate-limited
+struct ReadState {
+    last_read: Instant,
+    deferred: bool,
 }
 
-/// File watcher for a single file
+/// What a `FileWatcher` remembers between events, shared by its watched
+/// files and owned by it alone: two watchers never see each other's content
+/// or debounce windows
+#[derive(Default)]
+struct WatchState {
+    /// Last content of each file, which new versions are diffed against
+    last_content: Mutex<ContentCache>,
+    /// When each path last passed the debounce window
+    debounce: Mutex<HashMap<PathBuf, Instant>>,
+    /// Full reads of each path, for their rate limit
+    reads: Mutex<HashMap<PathBuf, ReadState>>,
+    /// Versions of each file diffed since it was last sent in full
+    diffs_since_full: Mutex<HashMap<String, u64>>,
+}
+
+/// When the next full read of a path may happen
+enum ReadSlot {
+    Now,
+    After(Duration),
+    AlreadyScheduled,
+}
+
+/// Everything needed to turn events for one watched file into broadcasts
+struct WatchContext {
+    file_id: String,
+    history: Arc<History>,
+    strategy: Box<dyn DiffStrategy>,
+    policy: DiffPolicy,
+    config: Arc<ServerConfig>,
+    control: Arc<WatchControl>,
+    state: Arc<WatchState>,
+    clock: Arc<dyn Clock>,
+    /// Held from reading the file until its changes are published, so two reads
+    /// are never diffed against the same broadcast base
+    publishing: tokio::sync::Mutex<()>,
+    rate: Mutex<BroadcastRate>,
+    /// Mode and modification time last broadcast as `MetadataChanged`
+    metadata: Mutex<Option<(Option<u32>, Option<SystemTime>)>>,
+    /// Events for the file may have been dropped, so its next read is sent as
+    /// full content rather than diffed against what the server last saw
+    resend_full: AtomicBool,
+}
+
+/// Recent broadcasts of a file, to throttle one that changes more than
+/// `max_broadcasts_per_sec` times. A throttled file is broadcast every
+/// `throttled_interval_ms` if it changed, until a whole interval passes without a change.
+#[derive(Default)]
+struct BroadcastRate {
+    /// When the file was broadcast within the last second
+    recent: VecDeque<Instant>,
+    throttled: bool,
+    /// Changed since the last periodic broadcast
+    dirty: bool,
+}
+
+/// Pauses and resumes broadcasting, e.g. around a bulk operation like a git
+/// checkout. While paused nothing is broadcast; only the latest content of
+/// each changed file is kept and sent once as full content on resume.
+///
+/// With `lazy` watching it also remembers the files that changed while no
+/// client was connected, so they are only read once one connects.
+pub struct WatchControl {
+    paused: AtomicBool,
+    /// Latest content and modification time of each file changed while paused
+    held: Mutex<HashMap<String, (String, Option<SystemTime>)>>,
+    stale: Mutex<HashMap<String, (PathBuf, Arc<WatchContext>)>>,
+    /// Digest of the content each file's hooks last ran for, or it started with
+    hooked: Mutex<HashMap<String, String>>,
+    history: Arc<History>,
+    config: Arc<ServerConfig>,
+    state: Arc<WatchState>,
+}
+
+/// File watcher for the files being mirrored
 pub struct FileWatcher {
-    watcher: RecommendedWatcher,
+    /// Shared with the event processing, which watches a directory again when
+    /// its watch is invalidated
+    watchers: Vec<Arc<Mutex<RecommendedWatcher>>>,
+    /// Event processing of each watched file, ending once its watcher is dropped
+    tasks: JoinSet<()>,
+    /// Reads stdin, which is never closed from this side, so it is aborted instead
+    stdin_task: Option<JoinHandle<()>>,
+    config: Arc<ServerConfig>,
+    history: Arc<History>,
+    control: Arc<WatchControl>,
+    clock: Arc<dyn Clock>,
 }
 
 impl FileWatcher {
     /// Creates a new file watcher
-    pub fn new() -> Self {
+    pub fn new(config: Arc<ServerConfig>, history: Arc<History>) -> Self {
+        Self::with_clock(config, history, Arc::new(SystemClock))
+    }
+
+    /// Creates a file watcher that debounces and throttles reads against `clock`
+    pub fn with_clock(config: Arc<ServerConfig>, history: Arc<History>, clock: Arc<dyn Clock>) -> Self {
+        let state = WatchState::default();
+        state.last_content.lock().expect("lock").max_bytes = config.limits.max_cached_bytes;
+        let control = Arc::new(WatchControl {
+            paused: AtomicBool::new(false),
+            held: Mutex::new(HashMap::new()),
+            stale: Mutex::new(HashMap::new()),
+            hooked: Mutex::new(HashMap::new()),
+            history: Arc::clone(&history),
+            config: Arc::clone(&config),
+            state: Arc::new(state),
+        });
         Self {
-            watcher: notify::recommended_watcher(|_| {}).expect("Failed to create watcher"),
+            watchers: Vec::new(),
+            tasks: JoinSet::new(),
+            stdin_task: None,
+            config,
+            history,
+            control,
+            clock,
         }
     }
-    
+
+    pub fn control(&self) -> Arc<WatchControl> {
+        Arc::clone(&self.control)
+    }
+
     /// Starts watching a file with
     /// event processing
     pub fn watch_file(
         &mut self,
         file_id: String,
         watch_path: &str,
-        sender: broadcast::Sender<FileChange>,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let abs_path = Self::absolute_path(watch_path)?;
-        let parent_dir = abs_path.parent().unwrap_or_else(|| Path::new("."));
-        let file_id = Arc::new(file_id);
-        let (event_tx, mut event_rx) = mpsc::channel(500);
+        let abs_path = canonical_path(&Self::absolute_path(watch_path)?);
+        let parent_dir = abs_path.parent().unwrap_or_else(|| Path::new(".")).to_path_buf();
+        self.seed(&file_id, &abs_path);
+        let contexts = HashMap::from([(file_id.clone(), self.context(file_id.clone()))]);
+        let matcher = PathMatcher::Exact { path: abs_path, file_id };
+        self.watch(matcher, &parent_dir, RecursiveMode::NonRecursive, contexts)
+    }
+
+    /// Starts watching the files a glob or regex selected, `files`, with one
+    /// recursive watch of the directory they are in. A matching file created
+    /// later is logged but not mirrored: clients are only sent the files
+    /// there were when the server started.
+    pub fn watch_matching(
+        &mut self,
+        matcher: PatternMatcher,
+        files: &[String],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut contexts = HashMap::new();
+        for file_id in files {
+            self.seed(file_id, Path::new(file_id));
+            contexts.insert(file_id.clone(), self.context(file_id.clone()));
+        }
+        let root = matcher.root().to_path_buf();
+        self.watch(PathMatcher::Pattern(matcher), &root, RecursiveMode::Recursive, contexts)
+    }
+
+    /// Clients start from the content on disk, so the first change can already be a diff
+    fn seed(&self, file_id: &str, path: &Path) {
+        let content = std::fs::read(path).and_then(|bytes| reader::decode(path, &bytes).map(Cow::into_owned));
+        if let Ok(content) = content.map(|content| self.config.trailing_whitespace.apply_owned(content)) {
+            if self.config.check_content(&content).is_ok() && self.config.mirror_when.check(&content).is_ok() {
+                self.history.seed(file_id, &content);
+                self.control.seed_hooks(file_id, &content);
+                self.control.state.last_content.lock().expect("lock").insert(file_id.to_string(), content);
+            }
+        }
+    }
+
+    fn context(&self, file_id: String) -> Arc<WatchContext> {
+        Arc::new(WatchContext {
+            strategy: self.config.diff.strategy_for(Path::new(&file_id)).strategy(),
+            policy: self.config.diff.policy_for(Path::new(&file_id)),
+            file_id,
+            history: Arc::clone(&self.history),
+            config: Arc::clone(&self.config),
+            control: Arc::clone(&self.control),
+            state: Arc::clone(&self.control.state),
+            clock: Arc::clone(&self.clock),
+            publishing: tokio::sync::Mutex::default(),
+            rate: Mutex::default(),
+            metadata: Mutex::default(),
+            resend_full: AtomicBool::new(false),
+        })
+    }
+
+    /// Watches `dir` and turns the events for the files `matcher` picks out
+    /// into broadcasts, through the context of each file
+    fn watch(
+        &mut self,
+        matcher: PathMatcher,
+        dir: &Path,
+        mode: RecursiveMode,
+        contexts: HashMap<String, Arc<WatchContext>>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (event_tx, mut event_rx) = mpsc::channel(self.config.limits.event_queue_capacity);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        let dropping = Arc::clone(&overflowed);
+        let queue_name = dir.display().to_string();
         let mut watcher = notify::recommended_watcher(move |result| {
-            if let Ok(event) = result {
-                let _ = event_tx.blocking_send(event);
-            } else if let Err(e) = result {
-                eprintln!("Watcher error: {e:?}");
+            // never blocks notify's thread, which would delay or lose OS events
+            // for every watch, behind a stalled event processing
+            if let Err(TrySendError::Full(_)) = event_tx.try_send(result) {
+                metrics::record_dropped_event();
+                if !dropping.swap(true, Ordering::Relaxed) {
+                    eprintln!("Events for {} come in faster than they are processed, dropping them until it catches up", queue_name);
+                }
             }
         })?;
-        watcher.watch(parent_dir, RecursiveMode::NonRecursive)?;
-        self.watcher = watcher;
-        let file_id_clone = Arc::clone(&file_id);
-        tokio::spawn(async move {
-            while let Some(event) = event_rx.recv().await {
-                handle_event(event, sender.clone(), &file_id_clone).await;
+        watcher.watch(dir, mode)?;
+        let watcher = Arc::new(Mutex::new(watcher));
+        let rewatcher = Rewatcher {
+            watcher: Arc::downgrade(&watcher),
+            dir: dir.to_path_buf(),
+            mode,
+            files: contexts
+                .iter()
+                .map(|(file_id, context)| {
+                    let path = match &matcher {
+                        PathMatcher::Exact { path, .. } => path.clone(),
+                        PathMatcher::Pattern(_) => PathBuf::from(file_id),
+                    };
+                    (path, Arc::clone(context))
+                })
+                .collect(),
+        };
+        self.watchers.push(watcher);
+        let config = Arc::clone(&self.config);
+        self.tasks.spawn(async move {
+            let mut unmirrored = HashSet::new();
+            while let Some(result) = event_rx.recv().await {
+                let event = match result {
+                    Ok(event) => event,
+                    Err(e) => {
+                        eprintln!("Watcher error for {}: {}, watching it again", rewatcher.dir.display(), e);
+                        rewatcher.rewatch().await;
+                        continue;
+                    }
+                };
+                if rewatcher.is_gone(&event) {
+                    eprintln!(
+                        "{} was removed or moved away, {} is not mirrored until it is back",
+                        rewatcher.dir.display(),
+                        matcher
+                    );
+                    rewatcher.rewatch().await;
+                    continue;
+                }
+                let metadata_only = is_metadata_event(&event);
+                for (path, file_id) in filter_relevant_paths(&event, &matcher, config.watch_metadata) {
+                    match contexts.get(&file_id) {
+                        Some(context) if metadata_only => publish_metadata(&path, context).await,
+                        Some(context) => handle_path(path, context).await,
+                        // an earlier pattern selected it too and mirrors it
+                        None if config.files.contains(&file_id) => {}
+                        None => {
+                            if unmirrored.insert(file_id.clone()) {
+                                println!("{} matches {} but was created after the server started; restart it to mirror the file", file_id, matcher);
+                            }
+                        }
+                    }
+                }
+                // the queue was full at some point, so what the dropped events
+                // said is unknown: read every file again, in full
+                if overflowed.swap(false, Ordering::Relaxed) {
+                    println!("Caught up with the events for {}, reading its files again", rewatcher.dir.display());
+                    rewatcher.read_files(true).await;
+                }
             }
         });
         Ok(())
     }
 
+    /// Mirrors content piped to stdin instead of a file. Whatever arrived before
+    /// stdin goes quiet for `stdin_interval_ms`, or is closed, is one version of
+    /// the content and replaces the previous one.
+    pub fn watch_stdin(&mut self, file_id: String) {
+        let context = self.context(file_id);
+        self.stdin_task = Some(tokio::spawn(async move {
+            mirror_versions(tokio::io::stdin(), &context).await;
+            println!("Stdin closed, keeping the last version");
+        }));
+    }
+
+    /// Stops watching: drops the `notify` watchers, which closes their event
+    /// channels, and waits until the events already received are processed
+    pub async fn shutdown(mut self) {
+        self.watchers.clear();
+        if let Some(stdin_task) = self.stdin_task.take() {
+            stdin_task.abort();
+        }
+        while self.tasks.join_next().await.is_some() {}
+        println!("All events processed");
+    }
+
     fn absolute_path(path: &str) -> Result<PathBuf, std::io::Error> {
         let path = PathBuf::from(path);
         if path.is_absolute() {
@@ -63,114 +332,1042 @@ impl FileWatcher {
     }
 }
 
-/// event processing with better filtering and faster response
-async fn handle_event(
-    event: Event,
-    sender: broadcast::Sender<FileChange>,
-    file_id: &Arc<String>,
-) {
-    if should_filter_event(&event) {
+/// Watches a directory again after its watch was invalidated: `notify`
+/// reports an error, or the directory itself was removed or moved away, after
+/// which no more events arrive for it
+struct Rewatcher {
+    /// Dropped by `FileWatcher::shutdown`, which ends any waiting for the directory
+    watcher: Weak<Mutex<RecommendedWatcher>>,
+    dir: PathBuf,
+    mode: RecursiveMode,
+    /// The watched files in `dir`, read again once it is watched again or
+    /// after events for them were dropped
+    files: Vec<(PathBuf, Arc<WatchContext>)>,
+}
+
+impl Rewatcher {
+    /// Whether `event` is the watched directory itself going away
+    fn is_gone(&self, event: &Event) -> bool {
+        let removed_or_moved = matches!(
+            event.kind,
+            notify::EventKind::Remove(_) | notify::EventKind::Modify(notify::event::ModifyKind::Name(_))
+        );
+        removed_or_moved && event.paths.contains(&self.dir) && !self.dir.is_dir()
+    }
+
+    /// Waits until the directory exists, watches it again, then reads the
+    /// watched files in case they were written before the new watch was in place
+    async fn rewatch(&self) {
+        loop {
+            let Some(watcher) = self.watcher.upgrade() else {
+                return;
+            };
+            if self.dir.is_dir() {
+                let mut watcher = watcher.lock().expect("lock");
+                // the old watch is usually gone already
+                let _ = watcher.unwatch(&self.dir);
+                match watcher.watch(&self.dir, self.mode) {
+                    Ok(()) => break,
+                    Err(e) => eprintln!("Cannot watch {} again yet: {}", self.dir.display(), e),
+                }
+            }
+            drop(watcher);
+            tokio::time::sleep(REWATCH_INTERVAL).await;
+        }
+        println!("Watching {} again", self.dir.display());
+        self.read_files(false).await;
+    }
+
+    /// Reads the watched files in the directory and broadcasts any change,
+    /// as full content with `full`
+    async fn read_files(&self, full: bool) {
+        for (path, context) in &self.files {
+            if path.exists() {
+                if full {
+                    context.resend_full.store(true, Ordering::Relaxed);
+                }
+                broadcast_changes(path, context).await;
+            }
+        }
+    }
+}
+
+/// Turns an event for a watched file into a broadcast, once it is past the
+/// debounce window and read rate limit
+async fn handle_path(path: PathBuf, context: &Arc<WatchContext>) {
+    #[cfg(debug_assertions)]
+    watch_stats::record(&context.file_id, Counter::Event);
+    if !context.state.should_process_path(&path, &context.config, context.clock.as_ref()) {
+        #[cfg(debug_assertions)]
+        watch_stats::record(&context.file_id, Counter::Debounced);
+        return;
+    }
+    match context.state.reserve_read(&path, &context.config, context.clock.as_ref()) {
+        ReadSlot::Now => broadcast_changes(&path, context).await,
+        ReadSlot::After(delay) => {
+            let context = Arc::clone(context);
+            tokio::spawn(async move {
+                context.clock.sleep(delay).await;
+                context.state.start_deferred_read(&path, context.clock.as_ref());
+                broadcast_changes(&path, &context).await;
+            });
+        }
+        ReadSlot::AlreadyScheduled => {
+            #[cfg(debug_assertions)]
+            watch_stats::record(&context.file_id, Counter::Coalesced);
+        }
+    }
+}
+
+async fn broadcast_changes(path: &Path, context: &Arc<WatchContext>) {
+    // nobody would receive the change, so don't read and diff the file until
+    // someone connects; a broadcast diff base only moves once a client got it
+    let hold_back = context.config.lazy || context.config.diff.base == DiffBase::Broadcast;
+    if hold_back && !context.history.has_subscribers() {
+        context.control.mark_stale(path, context);
         return;
     }
-    let target_filename = extract_filename(file_id);
-    let relevant_paths = filter_relevant_paths(&event, &target_filename);
-    if relevant_paths.is_empty() {
+    if context.config.max_broadcasts_per_sec > 0 && throttle(path, context) {
         return;
     }
-    for path in relevant_paths {
-        if !should_process_path(&path) {
-            continue;
+    publish_changes(path, context).await;
+}
+
+/// Counts a change against the file's broadcast rate; true when it is left to
+/// the periodic broadcast of a throttled file instead of broadcast now
+fn throttle(path: &Path, context: &Arc<WatchContext>) -> bool {
+    let mut rate = context.rate.lock().expect("lock");
+    if rate.throttled {
+        rate.dirty = true;
+        return true;
+    }
+    let now = context.clock.now();
+    while rate.recent.front().is_some_and(|&time| now.duration_since(time) >= Duration::from_secs(1)) {
+        rate.recent.pop_front();
+    }
+    if rate.recent.len() < context.config.max_broadcasts_per_sec as usize {
+        rate.recent.push_back(now);
+        return false;
+    }
+    rate.throttled = true;
+    rate.dirty = true;
+    println!(
+        "{} changes more than {} times per second, broadcasting it every {}ms",
+        context.file_id, context.config.max_broadcasts_per_sec, context.config.throttled_interval_ms
+    );
+    let path = path.to_path_buf();
+    let context = Arc::clone(context);
+    tokio::spawn(async move { broadcast_periodically(&path, &context).await });
+    true
+}
+
+/// Broadcasts a throttled file every `throttled_interval_ms` while it keeps changing
+async fn broadcast_periodically(path: &Path, context: &WatchContext) {
+    let interval = Duration::from_millis(context.config.throttled_interval_ms);
+    loop {
+        tokio::time::sleep(interval).await;
+        {
+            let mut rate = context.rate.lock().expect("lock");
+            if !rate.dirty {
+                rate.throttled = false;
+                rate.recent.clear();
+                println!("{} calmed down, broadcasting every change again", context.file_id);
+                return;
+            }
+            rate.dirty = false;
+        }
+        publish_changes(path, context).await;
+    }
+}
+
+async fn publish_changes(path: &Path, context: &WatchContext) {
+    let _publishing = context.publishing.lock().await;
+    if context.control.is_paused() {
+        if let Ok(Some(content)) = read_content(path, context, false).await {
+            context.control.hold(&context.file_id, content, reader::modified(path).await);
+        }
+        return;
+    }
+    match detect_file_changes(path, context).await {
+        Some(changes) => publish(changes, context),
+        None => {
+            #[cfg(debug_assertions)]
+            watch_stats::record(&context.file_id, Counter::Unchanged);
         }
-        if let Some(changes) = detect_file_changes(&path, file_id).await {
-            for change in changes {
-                let _ = sender.send(change);
+    }
+}
+
+/// Broadcasts the changes of one read of a file, then starts its hooks
+fn publish(changes: Vec<FileChange>, context: &WatchContext) {
+    // a held back save was not broadcast
+    let broadcast = !changes.is_empty() && !changes.iter().any(|change| matches!(change, FileChange::ValidationError { .. }));
+    for change in changes {
+        context.history.publish(change);
+    }
+    #[cfg(debug_assertions)]
+    if broadcast {
+        watch_stats::record(&context.file_id, Counter::Broadcast);
+    }
+    if broadcast && hooks::any_for(&context.config.hooks, &context.file_id) {
+        if let (_, Some(content)) = context.history.snapshot(&context.file_id) {
+            context.control.run_hooks(&context.file_id, &content);
+        }
+    }
+}
+
+fn publish_version(version: &mut Vec<u8>, context: &WatchContext) {
+    let content = context.config.trailing_whitespace.apply_owned(String::from_utf8_lossy(version).into_owned());
+    version.clear();
+    if context.control.is_paused() {
+        context.control.hold(&context.file_id, content, None);
+        return;
+    }
+    if let Some(changes) = content_changes(content, None, context) {
+        publish(changes, context);
+    }
+}
+
+/// Publishes whatever arrives on `input` before it goes quiet for
+/// `stdin_interval_ms` as one version of the content, until it is closed
+async fn mirror_versions(mut input: impl AsyncRead + Unpin, context: &WatchContext) {
+    let interval = Duration::from_millis(context.config.stdin_interval_ms);
+    let mut version = Vec::new();
+    let mut chunk = [0; 8192];
+    loop {
+        let read = if version.is_empty() {
+            input.read(&mut chunk).await
+        } else {
+            match tokio::time::timeout(interval, input.read(&mut chunk)).await {
+                Ok(read) => read,
+                Err(_) => {
+                    publish_version(&mut version, context);
+                    continue;
+                }
+            }
+        };
+        match read {
+            Ok(0) => break,
+            Ok(n) => version.extend_from_slice(&chunk[..n]),
+            Err(e) => {
+                eprintln!("Error reading stdin: {}", e);
+                break;
             }
         }
     }
+    if !version.is_empty() {
+        publish_version(&mut version, context);
+    }
 }
 
-fn should_filter_event(event: &Event) -> bool {
-    use notify::event::ModifyKind;
-    matches!(
-        &event.kind,
-        notify::EventKind::Access(_)
-            | notify::EventKind::Modify(ModifyKind::Metadata(_))
-            | notify::EventKind::Other
-    )
+/// Broadcasts a change of a file's permissions or modification time, with
+/// `watch_metadata`. The content is not read: a chmod leaves it as it was.
+async fn publish_metadata(path: &Path, context: &WatchContext) {
+    if context.control.is_paused() {
+        return;
+    }
+    let Ok(metadata) = tokio::fs::metadata(path).await else {
+        return;
+    };
+    #[cfg(unix)]
+    let mode = Some(std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()));
+    #[cfg(not(unix))]
+    let mode = None;
+    let current = (mode, metadata.modified().ok());
+    // one chmod can be reported more than once
+    if context.metadata.lock().expect("lock").replace(current) == Some(current) {
+        return;
+    }
+    context.history.publish(FileChange::MetadataChanged {
+        file_id: context.file_id.clone(),
+        mode,
+        mtime: current.1,
+    });
 }
 
-fn extract_filename(file_id: &Arc<String>) -> String {
-    Path::new(file_id.as_str())
-        .file_name()
-        .and_then(|f| f.to_str())
-        .unwrap_or("")
-        .to_string()
+fn is_metadata_event(event: &Event) -> bool {
+    matches!(&event.kind, notify::EventKind::Modify(notify::event::ModifyKind::Metadata(_)))
 }
 
-fn filter_relevant_paths(event: &Event, target_filename: &str) -> Vec<PathBuf> {
+fn should_filter_event(event: &Event, watch_metadata: bool) -> bool {
+    match &event.kind {
+        notify::EventKind::Access(_) | notify::EventKind::Other => true,
+        _ if is_metadata_event(event) => !watch_metadata,
+        _ => false,
+    }
+}
+
+/// Event paths that are watched files, with their file ids, whatever case,
+/// `.`/`..` segments or prefix notify reports them with
+fn filter_relevant_paths(event: &Event, matcher: &PathMatcher, watch_metadata: bool) -> Vec<(PathBuf, String)> {
+    if should_filter_event(event, watch_metadata) {
+        return Vec::new();
+    }
     event
         .paths
         .iter()
-        .filter(|path| {
-            path.file_name()
-                .and_then(|f| f.to_str())
-                .map_or(false, |name| name == target_filename)
-        })
-        .cloned()
+        .filter_map(|path| matcher.file_id(path).map(|file_id| (path.clone(), file_id)))
         .collect()
 }
 
-/// Check if path should be processed (debouncing logic)
-fn should_process_path(path: &PathBuf) -> bool {
-    let mut last_seen = DEBOUNCE_STATE.lock().expect("lock");
-    let now = Instant::now();
-    if let Some(&last_time) = last_seen.get(path) {
-        if now.duration_since(last_time) < std::time::Duration::from_millis(DEBOUNCE_MS) {
+impl WatchState {
+    /// Check if path should be processed (debouncing logic)
+    fn should_process_path(&self, path: &PathBuf, config: &ServerConfig, clock: &dyn Clock) -> bool {
+        let mut last_seen = self.debounce.lock().expect("lock");
+        let now = clock.now();
+        if let Some(&last_time) = last_seen.get(path) {
+            if now.duration_since(last_time) < Duration::from_millis(config.debounce_ms) {
+                return false;
+            }
+        }
+        last_seen.insert(path.clone(), now);
+        true
+    }
+
+    /// Rate-limits full reads of a path: events arriving too soon after a read
+    /// are folded into a single deferred read that picks up the latest content
+    fn reserve_read(&self, path: &PathBuf, config: &ServerConfig, clock: &dyn Clock) -> ReadSlot {
+        let mut reads = self.reads.lock().expect("lock");
+        let now = clock.now();
+        let min_interval = Duration::from_millis(config.min_read_interval_ms);
+        match reads.get_mut(path) {
+            Some(state) if state.deferred => ReadSlot::AlreadyScheduled,
+            Some(state) if now.duration_since(state.last_read) < min_interval => {
+                state.deferred = true;
+                ReadSlot::After(min_interval - now.duration_since(state.last_read))
+            }
+            _ => {
+                reads.insert(path.clone(), ReadState { last_read: now, deferred: false });
+                ReadSlot::Now
+            }
+        }
+    }
+
+    fn start_deferred_read(&self, path: &Path, clock: &dyn Clock) {
+        let mut reads = self.reads.lock().expect("lock");
+        reads.insert(path.to_path_buf(), ReadState { last_read: clock.now(), deferred: false });
+    }
+
+    /// Counts diffed versions of a file and reports when the next one should be
+    /// sent as full content instead, so clients that missed a diff recover
+    fn due_full_content(&self, file_id: &str, every: u64) -> bool {
+        if every == 0 {
             return false;
         }
+        let mut counts = self.diffs_since_full.lock().expect("lock");
+        let count = counts.entry(file_id.to_string()).or_insert(0);
+        *count += 1;
+        if *count >= every {
+            *count = 0;
+            true
+        } else {
+            false
+        }
     }
-    last_seen.insert(path.clone(), now);
-    true
 }
 
 /// Process file changes and return changes to broadcast
-async fn detect_file_changes(
-    path: &PathBuf,
-    file_id: &Arc<String>,
-) -> Option<Vec<FileChange>> {
-    let new_content = tokio::time::timeout(
-        std::time::Duration::from_millis(100),
-        tokio::fs::read_to_string(path),
-    )
-    .await
-    .ok()
-    .and_then(|r| r.ok())?;
-    
-    // only use FullContent for very small files (< 1KB)
-    if new_content.len() < 1024 {
-        return Some(vec![FileChange::FullContent {
+async fn detect_file_changes(path: &Path, context: &WatchContext) -> Option<Vec<FileChange>> {
+    if context.config.quiescence_ms > 0 {
+        wait_until_settled(path, Duration::from_millis(context.config.quiescence_ms)).await;
+    }
+    let mut new_content = read_changed(path, context).await?;
+    // editors that truncate then rewrite briefly leave an empty file behind;
+    // give the rewrite a moment to land before mirroring an empty document
+    if new_content.is_empty() && context.config.empty_settle_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(context.config.empty_settle_ms)).await;
+        new_content = read_changed(path, context).await?;
+    }
+    let file_bytes = new_content.len();
+    let changes = content_changes(new_content, reader::modified(path).await, context)?;
+    metrics::record_broadcast(&changes, file_bytes);
+    Some(changes)
+}
+
+/// Waits until the file's size and modification time stayed the same for a
+/// whole `interval`, so a save written in several steps is read once it is
+/// complete. A file that never settles is read after `MAX_SETTLE_ROUNDS` intervals.
+async fn wait_until_settled(path: &Path, interval: Duration) {
+    let stamp = |metadata: std::fs::Metadata| (metadata.len(), metadata.modified().ok());
+    let Ok(mut last) = tokio::fs::metadata(path).await.map(stamp) else {
+        return;
+    };
+    for _ in 0..MAX_SETTLE_ROUNDS {
+        tokio::time::sleep(interval).await;
+        let Ok(current) = tokio::fs::metadata(path).await.map(stamp) else {
+            return;
+        };
+        if current == last {
+            return;
+        }
+        last = current;
+    }
+}
+
+/// Changes that bring clients from the last broadcast version to `new_content`,
+/// last modified at `modified` if it was read from a file
+fn content_changes(new_content: String, modified: Option<SystemTime>, context: &WatchContext) -> Option<Vec<FileChange>> {
+    let file_id = &context.file_id;
+    // withheld content is not broadcast at all, and does not become the diff base
+    if let Err(reason) = context.config.mirror_when.check(&new_content) {
+        println!("Not mirroring {}: content {}", file_id, reason);
+        return None;
+    }
+    // a broken intermediate save is reported instead of mirrored, and does not
+    // become the diff base
+    if let Err(message) = context.config.check_content(&new_content) {
+        eprintln!("Validation failed for {}: {}", file_id, message);
+        return Some(vec![FileChange::ValidationError {
             file_id: file_id.to_string(),
-            content: new_content,
+            message,
         }]);
     }
-    
-    let mut last_content = LAST_CONTENT.lock().expect("lock");
-    let old_content = last_content.get(file_id.as_str()).map(String::as_str).unwrap_or("");
+    let mut last_content = context.state.last_content.lock().expect("lock");
+    if context.resend_full.swap(false, Ordering::Relaxed) || context.policy == DiffPolicy::Full {
+        if last_content.get(file_id).is_some_and(|last| *last == new_content) {
+            return None;
+        }
+        last_content.insert(file_id.to_string(), new_content.clone());
+        return Some(vec![full_content(file_id, new_content, modified)]);
+    }
+    // only use FullContent for very small files, which are sent again on every
+    // save unless only their trailing whitespace was normalized away
+    if new_content.len() < context.config.limits.full_content_threshold {
+        if context.config.trailing_whitespace == TrailingWhitespace::Normalize
+            && last_content.get(file_id).is_some_and(|last| *last == new_content)
+        {
+            return None;
+        }
+        last_content.insert(file_id.to_string(), new_content.clone());
+        return Some(vec![full_content(file_id, new_content, modified)]);
+    }
+    let broadcast;
+    let base = match context.config.diff.base {
+        DiffBase::Read => last_content.get(file_id),
+        DiffBase::Broadcast => {
+            broadcast = context.history.snapshot(file_id).1;
+            broadcast.as_ref()
+        }
+    };
+    let old_content = match base {
+        Some(old) if !old.is_empty() => old.as_str(),
+        // nothing to diff against yet (first read of the file, or it was empty):
+        // the diff would be one insert of the whole file, so send it as full content
+        _ => {
+            last_content.insert(file_id.to_string(), new_content.clone());
+            return Some(vec![full_content(file_id, new_content, modified)]);
+        }
+    };
     if old_content != new_content {
-        let changes = FileChange::create_diff(file_id.as_str(), old_content, &new_content);
-        last_content.insert(file_id.to_string(), new_content);
-        if !changes.is_empty() {
-            Some(changes)
-        } else {
-            None
+        let mut changes = context.strategy.diff(file_id, old_content, &new_content);
+        if let [FileChange::FullContent { last_modified, .. }] = changes.as_mut_slice() {
+            println!("Diff of {} too costly, sending full content", file_id);
+            *last_modified = modified;
+        }
+        if changes.is_empty() {
+            last_content.insert(file_id.to_string(), new_content);
+            return None;
+        }
+        if context.config.diff.verify && !reproduces(&changes, old_content, &new_content) {
+            eprintln!("Diff of {} does not reproduce the new content, sending full content; this is a bug in the diff strategy", file_id);
+            last_content.insert(file_id.to_string(), new_content.clone());
+            return Some(vec![full_content(file_id, new_content, modified)]);
         }
+        if context.state.due_full_content(file_id, context.config.full_content_every) {
+            last_content.insert(file_id.to_string(), new_content.clone());
+            return Some(vec![full_content(file_id, new_content, modified)]);
+        }
+        last_content.insert(file_id.to_string(), new_content);
+        Some(changes)
     } else {
         None
     }
 }
 
-/// Wait for all events to be processed with shorter timeout
-pub async fn wait_for_events_processed() {
-    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
-    println!("All events processed");
+/// Whether applying `changes` to `old_content`, as clients do, gives `new_content`
+fn reproduces(changes: &[FileChange], old_content: &str, new_content: &str) -> bool {
+    let mut content = old_content.to_string();
+    changes.iter().all(|change| change.try_apply(&mut content).is_ok()) && content == new_content
+}
+
+fn full_content(file_id: &str, content: String, last_modified: Option<SystemTime>) -> FileChange {
+    FileChange::FullContent {
+        file_id: file_id.to_string(),
+        content,
+        last_modified,
+    }
+}
+
+/// Reads the file with the configured strategy and normalizes its trailing
+/// whitespace. With `skip_unchanged` a mapped file equal to the last broadcast
+/// version is not copied and `None` is returned, which is what `content_changes`
+/// would find for it anyway.
+async fn read_content(path: &Path, context: &WatchContext, skip_unchanged: bool) -> io::Result<Option<String>> {
+    let timeout = Duration::from_millis(100);
+    let trailing_whitespace = context.config.trailing_whitespace;
+    let read = match context.config.read_strategy {
+        ReadStrategy::Read => tokio::time::timeout(timeout, reader::read_to_string(path)).await.map(|read| read.map(Some)),
+        ReadStrategy::Mmap => {
+            let path = path.to_path_buf();
+            let file_id = context.file_id.clone();
+            // small files are always sent as full content, even when unchanged
+            let threshold = context.config.limits.full_content_threshold;
+            let state = Arc::clone(&context.state);
+            let read = tokio::task::spawn_blocking(move || {
+                reader::read_mapped(&path, |content| {
+                    skip_unchanged
+                        && content.len() >= threshold
+                        && state.last_content.lock().expect("lock").get(&file_id).is_some_and(|last| *last == trailing_whitespace.apply(content))
+                })
+            });
+            tokio::time::timeout(timeout, read).await.map(|joined| joined.unwrap_or_else(|e| Err(io::Error::other(e))))
+        }
+    };
+    let read = read.unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+    read.map(|content| content.map(|content| trailing_whitespace.apply_owned(content)))
+}
+
+/// Reads a changed file for `detect_file_changes`. A gzipped file that can't
+/// be decompressed may still be being rewritten, so it is read again a few
+/// times before the change is skipped.
+async fn read_changed(path: &Path, context: &WatchContext) -> Option<String> {
+    let mut attempt = 1;
+    loop {
+        match read_content(path, context, true).await {
+            Ok(content) => return content,
+            Err(_) if reader::is_gzip(path) && attempt < GZIP_READ_ATTEMPTS => {
+                attempt += 1;
+                tokio::time::sleep(GZIP_RETRY_DELAY).await;
+            }
+            Err(e) if reader::is_gzip(path) => {
+                eprintln!("Skipping change to {}, it is not a complete gzip file: {}", path.display(), e);
+                return None;
+            }
+            Err(_) => return None,
+        }
+    }
+}
+
+impl WatchControl {
+    /// The watcher's counters (see [`watch_stats`]), when each watched path last
+    /// passed the debounce window, and the last content of each file, which new
+    /// versions are diffed against; as JSON for `GET /debug/watcher` in debug builds
+    #[cfg(debug_assertions)]
+    pub fn debug_state(&self) -> serde_json::Value {
+        let now = Instant::now();
+        let debounce: std::collections::BTreeMap<_, _> = self
+            .state
+            .debounce
+            .lock()
+            .expect("lock")
+            .iter()
+            .map(|(path, last)| (path.display().to_string(), now.saturating_duration_since(*last).as_millis() as u64))
+            .collect();
+        let last_content: std::collections::BTreeMap<_, _> =
+            self.state.last_content.lock().expect("lock").iter().map(|(file_id, content)| (file_id.clone(), content.clone())).collect();
+        serde_json::json!({
+            "files": watch_stats::snapshot(),
+            "debounce_ms_ago": debounce,
+            "last_content": last_content,
+        })
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Stops broadcasting changes until `resume`; returns false if already paused
+    pub fn pause(&self) -> bool {
+        !self.paused.swap(true, Ordering::SeqCst)
+    }
+
+    /// Broadcasts the content of every file that changed while paused, once,
+    /// as full content; returns false if not paused
+    pub fn resume(&self) -> bool {
+        let mut held = self.held.lock().expect("lock");
+        if !self.paused.swap(false, Ordering::SeqCst) {
+            return false;
+        }
+        for (file_id, (content, modified)) in held.drain() {
+            self.publish_full(file_id, content, modified);
+        }
+        true
+    }
+
+    /// Reads and broadcasts the files that changed while nobody was connected;
+    /// called before subscribing a new connection
+    pub async fn catch_up(&self) {
+        let stale: Vec<_> = self.stale.lock().expect("lock").drain().map(|(_, entry)| entry).collect();
+        for (path, context) in stale {
+            publish_changes(&path, &context).await;
+        }
+    }
+
+    fn mark_stale(&self, path: &Path, context: &Arc<WatchContext>) {
+        let mut stale = self.stale.lock().expect("lock");
+        stale.insert(context.file_id.clone(), (path.to_path_buf(), Arc::clone(context)));
+    }
+
+    /// Keeps the latest content of a file that changed while paused
+    fn hold(&self, file_id: &str, content: String, modified: Option<SystemTime>) {
+        let mut held = self.held.lock().expect("lock");
+        if self.is_paused() {
+            held.insert(file_id.to_string(), (content, modified));
+        } else {
+            // resumed while the content was being read
+            self.publish_full(file_id.to_string(), content, modified);
+        }
+    }
+
+    fn publish_full(&self, file_id: String, content: String, modified: Option<SystemTime>) {
+        if let Err(reason) = self.config.mirror_when.check(&content) {
+            println!("Not mirroring {}: content {}", file_id, reason);
+            return;
+        }
+        if let Err(message) = self.config.check_content(&content) {
+            eprintln!("Validation failed for {}: {}", file_id, message);
+            self.history.publish(FileChange::ValidationError { file_id, message });
+            return;
+        }
+        self.state.last_content.lock().expect("lock").insert(file_id.clone(), content.clone());
+        self.run_hooks(&file_id, &content);
+        self.history.publish(full_content(&file_id, content, modified));
+    }
+
+    /// Records the content a file starts out with, which runs no hooks
+    fn seed_hooks(&self, file_id: &str, content: &str) {
+        if hooks::any_for(&self.config.hooks, file_id) {
+            self.hooked.lock().expect("lock").insert(file_id.to_string(), shared::digest(content));
+        }
+    }
+
+    /// Starts the hooks of a file for newly broadcast content, unless they
+    /// already ran for the same content, e.g. a small file that is sent in
+    /// full again on every save even when nothing changed
+    fn run_hooks(&self, file_id: &str, content: &str) {
+        if !hooks::any_for(&self.config.hooks, file_id) {
+            return;
+        }
+        let digest = shared::digest(content);
+        let previous = self.hooked.lock().expect("lock").insert(file_id.to_string(), digest.clone());
+        if previous.as_ref() != Some(&digest) {
+            hooks::spawn_after_broadcast(&self.config.hooks, file_id, &digest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn events_within_the_debounce_window_are_dropped() {
+        let clock = ManualClock::new();
+        let config = ServerConfig { debounce_ms: 100, ..ServerConfig::default() };
+        let state = WatchState::default();
+        let path = PathBuf::from("debounce.md");
+        assert!(state.should_process_path(&path, &config, &clock));
+        clock.advance(Duration::from_millis(99));
+        assert!(!state.should_process_path(&path, &config, &clock));
+        // the window runs from the last event that passed, not the last one dropped
+        clock.advance(Duration::from_millis(1));
+        assert!(state.should_process_path(&path, &config, &clock));
+        assert!(state.should_process_path(&PathBuf::from("debounce-other.md"), &config, &clock), "each path has its own window");
+    }
+
+    #[test]
+    fn reads_within_the_read_interval_are_folded_into_one_deferred_read() {
+        let clock = ManualClock::new();
+        let config = ServerConfig { min_read_interval_ms: 100, ..ServerConfig::default() };
+        let state = WatchState::default();
+        let path = PathBuf::from("burst.md");
+        assert!(matches!(state.reserve_read(&path, &config, &clock), ReadSlot::Now));
+        clock.advance(Duration::from_millis(40));
+        assert!(matches!(state.reserve_read(&path, &config, &clock), ReadSlot::After(delay) if delay == Duration::from_millis(60)));
+        for _ in 0..20 {
+            clock.advance(Duration::from_millis(1));
+            assert!(matches!(state.reserve_read(&path, &config, &clock), ReadSlot::AlreadyScheduled));
+        }
+        // the deferred read counts as the last read
+        clock.advance(Duration::from_millis(40));
+        state.start_deferred_read(&path, &clock);
+        assert!(matches!(state.reserve_read(&path, &config, &clock), ReadSlot::After(delay) if delay == Duration::from_millis(100)));
+        clock.advance(Duration::from_millis(100));
+        state.start_deferred_read(&path, &clock);
+        clock.advance(Duration::from_millis(100));
+        assert!(matches!(state.reserve_read(&path, &config, &clock), ReadSlot::Now));
+    }
+
+    #[test]
+    fn paths_are_rate_limited_independently() {
+        let clock = ManualClock::new();
+        let config = ServerConfig::default();
+        let state = WatchState::default();
+        assert!(matches!(state.reserve_read(&PathBuf::from("one.md"), &config, &clock), ReadSlot::Now));
+        assert!(matches!(state.reserve_read(&PathBuf::from("two.md"), &config, &clock), ReadSlot::Now));
+    }
+
+    #[tokio::test]
+    async fn a_deferred_read_waits_for_the_clock() {
+        let file = std::env::temp_dir().join(format!("markdown-op-deferred-{}.md", std::process::id()));
+        std::fs::write(&file, "# First\n").expect("write file");
+        let clock = Arc::new(ManualClock::new());
+        let config = ServerConfig { debounce_ms: 0, min_read_interval_ms: 100, ..ServerConfig::default() };
+        let file_id = file.to_str().expect("UTF-8 path");
+        let context = Arc::new(context_on(Arc::clone(&clock) as Arc<dyn Clock>, file_id, config));
+        let latest = || context.history.snapshot(file_id).1;
+        handle_path(file.clone(), &context).await;
+        assert_eq!(latest().as_deref(), Some("# First\n"));
+
+        std::fs::write(&file, "# Second\n").expect("write file");
+        handle_path(file.clone(), &context).await;
+        // real time passing does not end the wait, only the clock does
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(latest().as_deref(), Some("# First\n"));
+        clock.advance(Duration::from_millis(100));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while latest().as_deref() != Some("# Second\n") && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(latest().as_deref(), Some("# Second\n"));
+        let _ = std::fs::remove_file(&file);
+    }
+
+    fn context(file_id: &str, config: ServerConfig) -> WatchContext {
+        context_on(Arc::new(SystemClock), file_id, config)
+    }
+
+    /// Like [`context`], for a watcher timing events with `clock`
+    fn context_on(clock: Arc<dyn Clock>, file_id: &str, config: ServerConfig) -> WatchContext {
+        let config = Arc::new(config);
+        let history = Arc::new(History::new(16, config.history.clone(), None));
+        let watcher = FileWatcher::with_clock(Arc::clone(&config), Arc::clone(&history), clock);
+        let control = watcher.control();
+        WatchContext {
+            file_id: file_id.to_string(),
+            history,
+            strategy: Box::new(shared::CharDiff),
+            policy: config.diff.policy_for(Path::new(file_id)),
+            config,
+            state: Arc::clone(&control.state),
+            control,
+            clock: Arc::clone(&watcher.clock),
+            publishing: tokio::sync::Mutex::default(),
+            rate: Mutex::default(),
+            metadata: Mutex::default(),
+            resend_full: AtomicBool::new(false),
+        }
+    }
+
+    /// The changes for `content` read from `path` when nothing was read before
+    fn full_content(path: &Path, file_id: &str, content: &str) -> Option<Vec<FileChange>> {
+        let last_modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        Some(vec![FileChange::FullContent { file_id: file_id.to_string(), content: content.to_string(), last_modified }])
+    }
+
+    #[tokio::test]
+    async fn a_truncate_followed_by_a_write_never_mirrors_an_empty_document() {
+        let path = std::env::temp_dir().join(format!("markdown-op-truncate-{}.md", std::process::id()));
+        std::fs::write(&path, "").expect("truncate");
+        let rewrite = tokio::spawn({
+            let path = path.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                std::fs::write(&path, "# Title\n").expect("rewrite");
+            }
+        });
+        let config = ServerConfig { empty_settle_ms: 500, ..ServerConfig::default() };
+        let changes = detect_file_changes(&path, &context("truncate.md", config)).await;
+        rewrite.await.expect("rewrite task");
+        let expected = full_content(&path, "truncate.md", "# Title\n");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(changes, expected);
+    }
+
+    #[tokio::test]
+    async fn a_file_that_stays_empty_is_mirrored_empty() {
+        let path = std::env::temp_dir().join(format!("markdown-op-empty-{}.md", std::process::id()));
+        std::fs::write(&path, "").expect("truncate");
+        let config = ServerConfig { empty_settle_ms: 10, ..ServerConfig::default() };
+        let changes = detect_file_changes(&path, &context("empty.md", config)).await;
+        let expected = full_content(&path, "empty.md", "");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(changes, expected);
+    }
+
+    #[tokio::test]
+    async fn a_save_written_in_steps_is_read_once_it_settles() {
+        let path = std::env::temp_dir().join(format!("markdown-op-steps-{}.md", std::process::id()));
+        std::fs::write(&path, "# Title\n").expect("first step");
+        let save = tokio::spawn({
+            let path = path.clone();
+            async move {
+                for step in ["# Title\n\nFirst paragraph.\n", "# Title\n\nFirst paragraph.\n\nSecond paragraph.\n"] {
+                    tokio::time::sleep(Duration::from_millis(40)).await;
+                    std::fs::write(&path, step).expect("next step");
+                }
+            }
+        });
+        let config = ServerConfig { quiescence_ms: 100, ..ServerConfig::default() };
+        let changes = detect_file_changes(&path, &context("steps.md", config)).await;
+        save.await.expect("save task");
+        let expected = full_content(&path, "steps.md", "# Title\n\nFirst paragraph.\n\nSecond paragraph.\n");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(changes, expected);
+    }
+
+    #[tokio::test]
+    async fn lazy_watching_reads_nothing_until_a_client_connects() {
+        let path = std::env::temp_dir().join(format!("markdown-op-lazy-{}.md", std::process::id()));
+        std::fs::write(&path, "# Draft\n").expect("write file");
+        let config = ServerConfig { lazy: true, ..ServerConfig::default() };
+        let context = Arc::new(context("lazy.md", config));
+        broadcast_changes(&path, &context).await;
+        assert_eq!(context.history.snapshot("lazy.md").1, None);
+        assert!(context.control.stale.lock().expect("lock").contains_key("lazy.md"));
+
+        let _subscription = context.history.subscribe(None, &[&context.file_id]);
+        context.control.catch_up().await;
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(context.history.snapshot("lazy.md").1.as_deref(), Some("# Draft\n"));
+        assert!(context.control.stale.lock().expect("lock").is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_change_nobody_receives_does_not_move_the_broadcast_base() {
+        let path = std::env::temp_dir().join(format!("markdown-op-base-{}.md", std::process::id()));
+        let mut config = ServerConfig::default();
+        config.diff.base = DiffBase::Broadcast;
+        let context = Arc::new(context("base.md", config));
+        let original: String = (0..100).map(|i| format!("Line {i} of a long document.\n")).collect();
+        context.history.publish(FileChange::FullContent { file_id: "base.md".to_string(), content: original.clone(), last_modified: None });
+
+        let unseen = original.replace("Line 20 ", "Line twenty ");
+        std::fs::write(&path, &unseen).expect("write");
+        broadcast_changes(&path, &context).await;
+        assert_eq!(context.history.snapshot("base.md").1.as_ref(), Some(&original));
+
+        // the next diff goes from what clients were last sent
+        let mut subscription = context.history.subscribe(None, &[&context.file_id]);
+        let edited = unseen.replace("Line 60 ", "Line sixty ");
+        std::fs::write(&path, &edited).expect("write");
+        broadcast_changes(&path, &context).await;
+        let _ = std::fs::remove_file(&path);
+        let mut copy = original;
+        while let Ok(broadcast) = subscription.receiver.try_recv() {
+            broadcast.message.change.try_apply(&mut copy).expect("diff fits the last broadcast content");
+        }
+        assert_eq!(copy, edited);
+    }
+
+    #[tokio::test]
+    async fn every_nth_change_is_sent_as_full_content() {
+        let path = std::env::temp_dir().join(format!("markdown-op-every-{}.md", std::process::id()));
+        let context = context("every.md", ServerConfig { full_content_every: 3, ..ServerConfig::default() });
+        // over full_content_threshold so that changes are diffed at all
+        let body = "Some text.\n".repeat(200);
+        let mut kinds = Vec::new();
+        for version in 1..=7 {
+            std::fs::write(&path, format!("# Version {version}\n{body}")).expect("write");
+            let changes = detect_file_changes(&path, &context).await.expect("changes");
+            kinds.push(matches!(changes[..], [FileChange::FullContent { .. }]));
+        }
+        let _ = std::fs::remove_file(&path);
+        // the first read has no diff base to diff against
+        assert_eq!(kinds, [true, false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn full_content_is_never_due_when_disabled() {
+        let state = WatchState::default();
+        assert!((0..10).all(|_| !state.due_full_content("disabled.md", 0)));
+    }
+
+    #[tokio::test]
+    async fn invalid_markdown_is_reported_and_leaves_the_diff_base_alone() {
+        let path = std::env::temp_dir().join(format!("markdown-op-invalid-{}.md", std::process::id()));
+        let mut config = ServerConfig::default();
+        config.validation.enabled = true;
+        let context = context("invalid.md", config);
+        let body = "Some text.\n".repeat(200);
+        let valid = format!("# Title\n{body}");
+        std::fs::write(&path, &valid).expect("write");
+        detect_file_changes(&path, &context).await.expect("changes");
+
+        std::fs::write(&path, format!("{valid}```rust\nfn main() {{}}\n")).expect("write");
+        let changes = detect_file_changes(&path, &context).await.expect("changes");
+        assert!(matches!(&changes[..], [FileChange::ValidationError { .. }]), "{changes:?}");
+
+        let fixed = format!("{valid}```rust\nfn main() {{}}\n```\n");
+        std::fs::write(&path, &fixed).expect("write");
+        let changes = detect_file_changes(&path, &context).await.expect("changes");
+        let _ = std::fs::remove_file(&path);
+        let mut content = valid;
+        for change in &changes {
+            change.apply(&mut content);
+        }
+        assert_eq!(content, fixed);
+    }
+
+    #[tokio::test]
+    async fn each_burst_read_from_stdin_is_one_version() {
+        let context = WatchContext { strategy: Box::new(shared::LineDiff), ..context("stdin", ServerConfig::default()) };
+        let mut subscription = context.history.subscribe(None, &[&context.file_id]);
+        let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+        let reading = tokio::spawn(async move { mirror_versions(reader, &context).await });
+
+        let first: String = (0..100).map(|i| format!("Line {i} of generated output.\n")).collect();
+        // written in pieces, which still make one version
+        let (start, end) = first.split_at(first.len() / 2);
+        writer.write_all(start.as_bytes()).await.expect("write");
+        writer.write_all(end.as_bytes()).await.expect("write");
+        let change = subscription.receiver.recv().await.expect("first version").message.change.clone();
+        assert_eq!(change, FileChange::FullContent { file_id: "stdin".to_string(), content: first.clone(), last_modified: None });
+
+        // longer than stdin_interval_ms, so the next write is a new version
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let second = first.replace("Line 50 ", "Line fifty ");
+        writer.write_all(second.as_bytes()).await.expect("write");
+        drop(writer);
+        reading.await.expect("reading task");
+        let change = subscription.receiver.recv().await.expect("second version").message.change.clone();
+        assert!(matches!(change, FileChange::Diff { .. }), "{change:?}");
+        let mut content = first;
+        change.try_apply(&mut content).expect("diff fits");
+        assert_eq!(content, second);
+        // and nothing else
+        assert!(subscription.receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn edits_while_paused_are_sent_once_as_full_content_on_resume() {
+        let path = std::env::temp_dir().join(format!("markdown-op-paused-{}.md", std::process::id()));
+        let context = Arc::new(context("paused.md", ServerConfig::default()));
+        let content: String = (0..100).map(|i| format!("Line {i} of a long document.\n")).collect();
+        std::fs::write(&path, &content).expect("write");
+        broadcast_changes(&path, &context).await;
+        let mut subscription = context.history.subscribe(None, &[&context.file_id]);
+
+        assert!(context.control.pause());
+        assert!(!context.control.pause(), "already paused");
+        let mut edited = content;
+        for i in 0..3 {
+            edited = edited.replace(&format!("Line {i} "), &format!("Line {i} edited "));
+            std::fs::write(&path, &edited).expect("write");
+            broadcast_changes(&path, &context).await;
+        }
+        assert!(subscription.receiver.try_recv().is_err(), "nothing is broadcast while paused");
+
+        assert!(context.control.resume());
+        assert!(!context.control.resume(), "already resumed");
+        let _ = std::fs::remove_file(&path);
+        let change = subscription.receiver.try_recv().expect("the held content").message.change.clone();
+        assert!(matches!(&change, FileChange::FullContent { content, .. } if *content == edited), "{change:?}");
+        // and nothing else: the edits in between were folded into it
+        assert!(subscription.receiver.try_recv().is_err());
+    }
+
+    /// Stands in for a diff strategy with a bug: its diffs are off by one char
+    struct OffByOne;
+
+    impl DiffStrategy for OffByOne {
+        fn diff(&self, file_id: &str, old_content: &str, new_content: &str) -> Vec<FileChange> {
+            let mut changes = shared::LineDiff.diff(file_id, old_content, new_content);
+            for change in &mut changes {
+                if let FileChange::Diff { position, .. } = change {
+                    *position += 1;
+                }
+            }
+            changes
+        }
+    }
+
+    /// Like [`context`], diffing with `strategy`
+    fn context_with(file_id: &str, strategy: Box<dyn DiffStrategy>, config: ServerConfig) -> WatchContext {
+        WatchContext { strategy, ..context(file_id, config) }
+    }
+
+    #[test]
+    fn a_diff_that_does_not_reproduce_the_content_is_replaced_by_full_content() {
+        // over the full content threshold, so it is diffed
+        let old: String = (0..100).map(|line| format!("Line {line} of the document\n")).collect();
+        let new = old.replace("Line 50 ", "Line fifty ");
+        let mut config = ServerConfig::default();
+        for (file_id, verify) in [("verified.md", true), ("unverified.md", false)] {
+            config.diff.verify = verify;
+            let context = context_with(file_id, Box::new(OffByOne), config.clone());
+            context.state.last_content.lock().expect("lock").insert(file_id.to_string(), old.clone());
+            let changes = content_changes(new.clone(), None, &context).expect("changes");
+            if verify {
+                assert_eq!(changes, [super::full_content(file_id, new.clone(), None)]);
+            } else {
+                assert!(matches!(changes[..], [FileChange::Diff { .. }]), "{changes:?}");
+            }
+        }
+        // a correct diff passes the check
+        config.diff.verify = true;
+        let context = context_with("correct.md", Box::new(shared::LineDiff), config);
+        context.state.last_content.lock().expect("lock").insert("correct.md".to_string(), old.clone());
+        let changes = content_changes(new.clone(), None, &context).expect("changes");
+        assert!(matches!(changes[..], [FileChange::Diff { .. }]), "{changes:?}");
+    }
+
+    #[test]
+    fn watchers_do_not_share_debounce_state() {
+        let config = ServerConfig { debounce_ms: 60_000, ..ServerConfig::default() };
+        let first = context_with("doc.md", Box::new(shared::LineDiff), config.clone());
+        let second = context_with("doc.md", Box::new(shared::LineDiff), config.clone());
+        let path = PathBuf::from("/tmp/doc.md");
+        assert!(first.state.should_process_path(&path, &config, &SystemClock));
+        assert!(!first.state.should_process_path(&path, &config, &SystemClock), "within the debounce window");
+        // the same path in another watcher has its own window
+        assert!(second.state.should_process_path(&path, &config, &SystemClock));
+        assert!(!second.state.should_process_path(&path, &config, &SystemClock));
+    }
+
+    #[test]
+    fn watchers_do_not_share_the_content_they_diff_against() {
+        let content: String = (0..100).map(|line| format!("Line {line} of the document\n")).collect();
+        let first = context_with("doc.md", Box::new(shared::LineDiff), ServerConfig::default());
+        let second = context_with("doc.md", Box::new(shared::LineDiff), ServerConfig::default());
+        first.state.last_content.lock().expect("lock").insert("doc.md".to_string(), content.clone());
+        assert_eq!(content_changes(content.clone(), None, &first), None, "unchanged for the watcher that read it");
+        // the other watcher never read the file, so it starts from full content
+        assert_eq!(content_changes(content.clone(), None, &second), Some(vec![super::full_content("doc.md", content, None)]));
+    }
+
+    fn open_descriptors() -> usize {
+        std::fs::read_dir("/proc/self/fd").map_or(0, Iterator::count)
+    }
+
+    #[tokio::test]
+    async fn shutdown_leaves_no_tasks_or_descriptors_behind() {
+        let dir = std::env::temp_dir().join(format!("markdown-op-watcher-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("create dir");
+        let file = dir.join("doc.md");
+        std::fs::write(&file, "# Title\n").expect("write file");
+        let config = Arc::new(ServerConfig::default());
+        let start = || {
+            let history = Arc::new(History::new(10, config.history.clone(), None));
+            let mut watcher = FileWatcher::new(Arc::clone(&config), history);
+            watcher.watch_file("doc.md".to_string(), file.to_str().expect("UTF-8 path")).expect("watch");
+            watcher
+        };
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let (tasks, descriptors) = (metrics.num_alive_tasks(), open_descriptors());
+        for _ in 0..20 {
+            let watcher = start();
+            assert_eq!(metrics.num_alive_tasks(), tasks + 1);
+            watcher.shutdown().await;
+        }
+        assert_eq!(metrics.num_alive_tasks(), tasks);
+        // notify closes its descriptors from its own thread, shortly after the watcher is dropped
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while open_descriptors() > descriptors && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(open_descriptors() <= descriptors, "{} descriptors open, {} before", open_descriptors(), descriptors);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -1,154 +1,230 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpStream, TcpListener};
-use tokio::sync::{broadcast, oneshot};
-use tokio_tungstenite::{accept_async, tungstenite::{protocol::Message, Error as WsError}, WebSocketStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::{accept_hdr_async_with_config, tungstenite::protocol::{Message, WebSocketConfig}, WebSocketStream};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{header::AUTHORIZATION, StatusCode};
 use futures_util::{StreamExt, SinkExt};
-use shared::FileChange;
+use shared::PositionUnit;
+use crate::config::ServerConfig;
+use crate::long_poll::percent_decode;
+use crate::tls;
+use crate::transport::{Connection, Transport, TransportError};
 
-pub struct WebSocketHandler {
-    sender: broadcast::Sender<FileChange>,
+/// The byte stream under a WebSocket: plain TCP, or TCP wrapped in TLS
+trait ByteStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ByteStream for S {}
+
+/// Serves clients over WebSocket, one JSON text frame per change
+pub struct WsTransport {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    /// Completes the TLS handshake first, see [`ServerConfig::tls`]
+    tls: Option<TlsAcceptor>,
+}
+
+pub struct WsConnection {
+    stream: WebSocketStream<Box<dyn ByteStream>>,
+    resume_from: Option<u64>,
+    position_unit: PositionUnit,
+    chunk_sync: bool,
+    files: Option<Vec<String>>,
+    digests: HashMap<String, String>,
+    client_id: Option<String>,
 }
 
-impl WebSocketHandler {
-    pub fn new(sender: broadcast::Sender<FileChange>) -> Self {
-        Self { sender }
+impl WsTransport {
+    pub async fn bind(config: Arc<ServerConfig>) -> std::io::Result<Self> {
+        let tls = config.tls.acceptor().map_err(|e| std::io::Error::other(format!("TLS: {e}")))?;
+        let listener = TcpListener::bind(&config.bind).await?;
+        let scheme = if tls.is_some() { "wss" } else { "ws" };
+        // the actual address, in case port 0 was asked for
+        println!("WebSocket server listening on {}://{}", scheme, listener.local_addr()?);
+        Ok(Self { listener, config, tls })
     }
 
-    pub async fn start_server(
-        &self,
-        addr: String,
-        mut shutdown_rx: oneshot::Receiver<()>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let listener = TcpListener::bind(&addr).await?;
-        println!("WebSocket server listening on ws://{}", addr);
-        let sender = self.sender.clone();
-        let watched_file = std::env::args().nth(1).unwrap_or_else(|| "README.md".to_string());
-        let mut connection_count = 0;
+    /// Checks the request carries the configured token, either as
+    /// `Authorization: Bearer <token>` or as a `token` query parameter
+    fn is_authorized(request: &Request, token: Option<&str>) -> bool {
+        let Some(token) = token else {
+            return true;
+        };
+        let from_header = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        from_header == Some(token) || Self::query_param(request, "token") == Some(token)
+    }
 
-        loop {
-            tokio::select! {
-                accept_result = listener.accept() => {
-                    match accept_result {
-                        Ok((stream, client_addr)) => {
-                            connection_count += 1;
-                            println!("New connection from: {} (total: {})", client_addr, connection_count);
-                            if connection_count > 100 {
-                                eprintln!("Too many connections, rejecting: {}", client_addr);
-                                continue;
-                            }
-                            let sender_clone = sender.clone();
-                            let watched_file_clone = watched_file.clone();
-                            tokio::spawn(async move {
-                                if let Err(e) = Self::handle_client(stream, sender_clone, watched_file_clone).await {
-                                    eprintln!("Error from client {}: {}", client_addr, e);
-                                }
-                                println!("Client {} disconnected", client_addr);
-                            });
-                        }
-                        Err(e) => eprintln!("Error accepting connection: {}", e),
-                    }
-                }
-                _ = &mut shutdown_rx => {
-                    println!("Received shutdown signal, closing WebSocket server...");
-                    break;
-                }
-            }
-        }
-        Ok(())
+    fn query_param<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+        request
+            .uri()
+            .query()
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
     }
 
-    async fn handle_client(
-        stream: TcpStream,
-        sender: broadcast::Sender<FileChange>,
-        watched_file: String,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let ws_stream = accept_async(stream).await?;
-        let (mut write, mut read) = ws_stream.split();
-        let mut rx = sender.subscribe();
-
-        Self::send_initial_content(&mut write, &watched_file).await.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-        Self::process_messages(&mut write, &mut read, &mut rx, &watched_file).await.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
-    }
-
-    async fn send_initial_content(
-        write: &mut futures_util::stream::SplitSink<WebSocketStream<TcpStream>, Message>,
-        watched_file: &str,
-    ) -> Result<(), WsError> {
-        if let Ok(content) = tokio::fs::read_to_string(watched_file).await {
-            let change = FileChange::FullContent {
-                file_id: watched_file.to_string(),
-                content,
-            };
-            let content = serde_json::to_string(&change).map_err(|e| WsError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
-            write.send(Message::Text(content)).await?;
-            write.flush().await?;
-        }
-        Ok(())
+    /// The `since` query parameter a reconnecting client sends with the seq it is up to date with
+    fn resume_from(request: &Request) -> Option<u64> {
+        Self::query_param(request, "since").and_then(|seq| seq.parse().ok())
     }
 
-    async fn process_messages(
-        write: &mut futures_util::stream::SplitSink<WebSocketStream<TcpStream>, Message>,
-        read: &mut futures_util::stream::SplitStream<WebSocketStream<TcpStream>>,
-        rx: &mut broadcast::Receiver<FileChange>,
-        _watched_file: &str,
-    ) -> Result<(), WsError> {
-        loop {
-            tokio::select! {
-                msg = read.next() => {
-                    if !Self::handle_incoming_message(msg, write).await? {
-                        break;
-                    }
-                }
-                change_result = rx.recv() => {
-                    if !Self::handle_broadcast(change_result, write).await? {
-                        break;
-                    }
-                }
-            }
+    /// The `positions` query parameter, `utf16` for clients indexing strings the
+    /// way JavaScript does or `bytes` for ones slicing the raw UTF-8
+    fn position_unit(request: &Request) -> PositionUnit {
+        Self::query_param(request, "positions").and_then(|unit| unit.parse().ok()).unwrap_or_default()
+    }
+
+    /// The `chunks` query parameter, `1` from clients that hold copies of the files
+    fn chunk_sync(request: &Request) -> bool {
+        Self::query_param(request, "chunks") == Some("1")
+    }
+
+    /// The `file` query parameters, one per file a client that only mirrors some
+    /// of the watched files follows
+    fn files(request: &Request) -> Option<Vec<String>> {
+        let files: Vec<_> = request
+            .uri()
+            .query()
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .filter_map(|pair| pair.strip_prefix("file="))
+            .filter_map(|file_id| percent_decode(&file_id.replace('+', " ")))
+            .collect();
+        (!files.is_empty()).then_some(files)
+    }
+
+    /// The `digest` query parameters, `FILE:DIGEST` for each file a
+    /// reconnecting client holds a copy of
+    fn digests(request: &Request) -> HashMap<String, String> {
+        request
+            .uri()
+            .query()
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .filter_map(|pair| pair.strip_prefix("digest="))
+            .filter_map(|param| percent_decode(&param.replace('+', " ")))
+            .filter_map(|param| {
+                let (file_id, digest) = param.rsplit_once(':')?;
+                Some((file_id.to_string(), digest.to_string()))
+            })
+            .collect()
+    }
+}
+
+impl Transport for WsTransport {
+    type Pending = TcpStream;
+    type Connection = WsConnection;
+
+    const NAME: &'static str = "WebSocket";
+
+    async fn accept(&self) -> std::io::Result<(TcpStream, String)> {
+        let (stream, client_addr) = self.listener.accept().await?;
+        if let Err(e) = self.config.tcp.options().apply(&stream) {
+            eprintln!("Cannot set TCP options for {}: {}", client_addr, e);
         }
-        Ok(())
+        Ok((stream, client_addr.to_string()))
     }
 
-    async fn handle_incoming_message(
-        msg: Option<Result<Message, WsError>>,
-        write: &mut futures_util::stream::SplitSink<WebSocketStream<TcpStream>, Message>,
-    ) -> Result<bool, WsError> {
-        match msg {
-            Some(Ok(Message::Close(_))) => {
-                let _ = write.send(Message::Close(None)).await;
-                Ok(false)
+    // the handshake callback signature is fixed by tungstenite
+    #[allow(clippy::result_large_err)]
+    async fn establish(&self, stream: TcpStream) -> Result<WsConnection, TransportError> {
+        let auth_token = self.config.auth_token.clone();
+        let mut resume_from = None;
+        let mut position_unit = PositionUnit::Chars;
+        let mut chunk_sync = false;
+        let mut files = None;
+        let mut digests = HashMap::new();
+        // clients without a certificate the server trusts fail here, before
+        // anything is sent to them
+        let (stream, client_id): (Box<dyn ByteStream>, _) = match &self.tls {
+            Some(acceptor) => {
+                let stream = acceptor.accept(stream).await?;
+                let client_id = stream.get_ref().1.peer_certificates().and_then(tls::client_id);
+                (Box::new(stream), client_id)
             }
-            Some(Ok(Message::Ping(data))) => {
-                if write.send(Message::Pong(data)).await.is_err() {
-                    return Ok(false);
-                }
-                Ok(true)
+            None => (Box::new(stream), None),
+        };
+        let ws_config = WebSocketConfig {
+            max_message_size: Some(self.config.limits.max_message_bytes),
+            max_frame_size: Some(self.config.limits.max_message_bytes),
+            ..WebSocketConfig::default()
+        };
+        let stream = accept_hdr_async_with_config(stream, |request: &Request, response: Response| {
+            resume_from = Self::resume_from(request);
+            position_unit = Self::position_unit(request);
+            chunk_sync = Self::chunk_sync(request);
+            files = Self::files(request);
+            digests = Self::digests(request);
+            if Self::is_authorized(request, auth_token.as_deref()) {
+                Ok(response)
+            } else {
+                let mut error = ErrorResponse::new(Some("invalid or missing token".to_string()));
+                *error.status_mut() = StatusCode::UNAUTHORIZED;
+                Err(error)
             }
-            Some(Ok(Message::Pong(_))) => Ok(true),
-            Some(Ok(_)) => Ok(true),
-            Some(Err(_)) => Ok(false),
-            None => Ok(false),
-        }
+        }, Some(ws_config))
+        .await?;
+        Ok(WsConnection { stream, resume_from, position_unit, chunk_sync, files, digests, client_id })
+    }
+}
+
+impl Connection for WsConnection {
+    fn resume_from(&self) -> Option<u64> {
+        self.resume_from
+    }
+
+    fn position_unit(&self) -> PositionUnit {
+        self.position_unit
     }
 
-    async fn handle_broadcast(
-        change_result: Result<FileChange, broadcast::error::RecvError>,
-        write: &mut futures_util::stream::SplitSink<WebSocketStream<TcpStream>, Message>,
-    ) -> Result<bool, WsError> {
-        match change_result {
-            Ok(change) => {
-                let content = serde_json::to_string(&change).map_err(|e| WsError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
-                if write.send(Message::Text(content)).await.is_err() {
-                    return Ok(false);
+    fn chunk_sync(&self) -> bool {
+        self.chunk_sync
+    }
+
+    fn files(&self) -> Option<Vec<String>> {
+        self.files.clone()
+    }
+
+    fn digests(&self) -> HashMap<String, String> {
+        self.digests.clone()
+    }
+
+    fn client_id(&self) -> Option<&str> {
+        self.client_id.as_deref()
+    }
+
+    async fn send(&mut self, message: &str) -> Result<(), TransportError> {
+        self.stream.send(Message::Text(message.to_string())).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Option<Result<String, TransportError>> {
+        loop {
+            match self.stream.next().await? {
+                Ok(Message::Text(text)) => return Some(Ok(text)),
+                Ok(Message::Close(_)) => {
+                    let _ = self.stream.send(Message::Close(None)).await;
+                    return None;
                 }
-                if write.flush().await.is_err() {
-                    return Ok(false);
+                Ok(Message::Ping(data)) => {
+                    if self.stream.send(Message::Pong(data)).await.is_err() {
+                        return None;
+                    }
                 }
-                Ok(true)
-            }
-            Err(_) => {
-                let _ = write.send(Message::Close(None)).await;
-                Ok(false)
+                Ok(_) => {}
+                Err(_) => return None,
             }
         }
     }
+
+    async fn close(&mut self) {
+        let _ = self.stream.send(Message::Close(None)).await;
+    }
 }
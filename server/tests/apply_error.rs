@@ -0,0 +1,96 @@
+mod common;
+
+use std::{fs, net::{TcpListener, TcpStream}, path::{Path, PathBuf}};
+use common::{spawn_client, temp_dir, wait_until, Process, CONVERGENCE_TIMEOUT};
+use shared::{ClientMessage, FileChange, PositionUnit, Sequenced};
+use tokio_tungstenite::tungstenite::{self, Message, WebSocket};
+
+const FILE_ID: &str = "doc.md";
+
+/// A fake server that has sent the client `# Title\n` and had it acked,
+/// with the client's output path
+struct Desync {
+    socket: WebSocket<TcpStream>,
+    client: Process,
+    output: PathBuf,
+}
+
+impl Desync {
+    fn start(policy: &str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let url = format!("ws://{}", listener.local_addr().expect("local address"));
+        let dir = temp_dir();
+        let client = spawn_client(&dir, &url, &["--on-apply-error", policy]);
+        let (stream, _) = listener.accept().expect("accept");
+        stream.set_read_timeout(Some(CONVERGENCE_TIMEOUT)).expect("set timeout");
+        let socket = tungstenite::accept(stream).expect("WebSocket handshake");
+        let mut desync = Self { socket, client, output: dir.join("out").join("client1_README.md") };
+        desync.send(1, FileChange::FullContent { file_id: FILE_ID.to_string(), content: "# Title\n".to_string(), last_modified: None });
+        assert_eq!(desync.reply(), Some(ClientMessage::Ack { seq: 1 }));
+        desync
+    }
+
+    fn send(&mut self, seq: u64, change: FileChange) {
+        let text = serde_json::to_string(&Sequenced::new(seq, change)).expect("JSON");
+        self.socket.send(Message::Text(text)).expect("send");
+    }
+
+    /// Sends a diff past the end of the client's 8 char copy
+    fn send_bad_diff(&mut self, seq: u64) {
+        self.send(seq, diff(100, "Oops"));
+    }
+
+    /// The client's next message, `None` once it closed the connection
+    fn reply(&mut self) -> Option<ClientMessage> {
+        loop {
+            match self.socket.read() {
+                Ok(Message::Text(text)) => return Some(serde_json::from_str(&text).expect("client message")),
+                Ok(Message::Close(_)) | Err(_) => return None,
+                Ok(_) => {}
+            }
+        }
+    }
+}
+
+fn diff(position: usize, insert_text: &str) -> FileChange {
+    FileChange::Diff { file_id: FILE_ID.to_string(), position, delete_count: 0, insert_text: insert_text.to_string(), unit: PositionUnit::Chars }
+}
+
+fn output_is(path: &Path, expected: &str) -> bool {
+    wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(path).is_ok_and(|content| content == expected))
+}
+
+#[test]
+fn resync_policy_asks_for_the_full_content() {
+    let mut desync = Desync::start("resync");
+    desync.send_bad_diff(2);
+    assert_eq!(desync.reply(), Some(ClientMessage::Resync { file_id: FILE_ID.to_string() }));
+    desync.send(3, FileChange::FullContent { file_id: FILE_ID.to_string(), content: "# Resynced\n".to_string(), last_modified: None });
+    assert_eq!(desync.reply(), Some(ClientMessage::Ack { seq: 3 }));
+    assert!(output_is(&desync.output, "# Resynced\n"));
+}
+
+#[test]
+fn ignore_policy_skips_the_diff_and_keeps_mirroring() {
+    let mut desync = Desync::start("ignore");
+    desync.send_bad_diff(2);
+    assert_eq!(desync.reply(), Some(ClientMessage::Ack { seq: 2 }));
+    desync.client.wait_for_line("ignoring it");
+    // later diffs still apply to the copy as it was
+    desync.send(3, diff(8, "\nBody.\n"));
+    assert_eq!(desync.reply(), Some(ClientMessage::Ack { seq: 3 }));
+    assert!(output_is(&desync.output, "# Title\n\nBody.\n"));
+}
+
+#[test]
+fn fail_policy_exits_with_an_error() {
+    let mut desync = Desync::start("fail");
+    desync.send_bad_diff(2);
+    assert_eq!(desync.reply(), None);
+    let status = desync.client.wait_for_exit();
+    assert!(!status.success());
+    desync.client.wait_for_line("cannot apply diff to doc.md: diff at position 100");
+    // no reconnect attempts after a desync it was told to fail on
+    assert!(desync.client.lines().iter().all(|line| !line.contains("Retrying")), "{}", desync.client.lines().join("\n"));
+    assert!(output_is(&desync.output, "# Title\n"));
+}
@@ -0,0 +1,37 @@
+mod common;
+
+use common::Mirror;
+use shared::{FileChange, Sequenced};
+
+#[test]
+fn a_very_old_seq_gets_a_snapshot_and_only_the_recent_changes() {
+    let content: String = (0..100).map(|i| format!("Line {i} of a long document.\n")).collect();
+    let mirror = Mirror::start_server(&content, &["--history", "--max-backfill", "3", "--diff", "line", "--long-poll", "127.0.0.1:0"]);
+    let poll = |since| -> Vec<Sequenced> { serde_json::from_str(&mirror.changes_since(since)).expect("JSON changes") };
+    let first = poll(None)[0].seq;
+    let mut versions = vec![content];
+    let mut last = first;
+    for i in 0..8 {
+        mirror.edit(|content| format!("{content}Appended line {i}.\n"));
+        versions.push(mirror.source());
+        // one diff per edit
+        last = poll(Some(last)).last().expect("a change").seq;
+    }
+
+    let backfill = poll(Some(first));
+    let [snapshot, recent @ ..] = backfill.as_slice() else {
+        panic!("nothing replayed");
+    };
+    assert_eq!(snapshot.seq, last - 3);
+    let FileChange::FullContent { content, .. } = &snapshot.change else {
+        panic!("expected a snapshot first, got {snapshot:?}");
+    };
+    assert_eq!(content, &versions[5]);
+    assert_eq!(recent.iter().map(|message| message.seq).collect::<Vec<_>>(), [last - 2, last - 1, last]);
+    let mut mirrored = content.clone();
+    for message in recent {
+        assert!(matches!(message.change, FileChange::Diff { .. }), "{message:?}");
+        message.change.try_apply(&mut mirrored).expect("apply");
+    }
+    assert_eq!(mirrored, versions[8]);
+}
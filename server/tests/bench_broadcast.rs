@@ -0,0 +1,18 @@
+mod common;
+
+use std::process::Command;
+use common::binary;
+
+#[test]
+fn bench_broadcast_compares_shared_and_per_client_serialization() {
+    let output = Command::new(binary("server"))
+        .args(["bench-broadcast", "--clients", "50", "--changes", "5", "--size", "1000"])
+        .output()
+        .expect("run server");
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{report}{}", String::from_utf8_lossy(&output.stderr));
+    assert!(report.contains("5 changes of 1000 chars to 50 clients"), "{report}");
+    assert!(report.contains("shared:"), "{report}");
+    assert!(report.contains("per client:"), "{report}");
+    assert!(report.contains("speedup:"), "{report}");
+}
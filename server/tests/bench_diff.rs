@@ -0,0 +1,19 @@
+mod common;
+
+use std::process::Command;
+use common::{binary, temp_dir};
+
+#[test]
+fn bench_diff_reports_throughput_and_correctness() {
+    let output = Command::new(binary("server"))
+        .current_dir(temp_dir())
+        .args(["bench-diff", "--size", "500", "--edits", "10", "--strategy", "line", "--seed", "7"])
+        .output()
+        .expect("run server");
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{report}{}", String::from_utf8_lossy(&output.stderr));
+    assert!(report.contains("10 edits of 500 chars with the line strategy (seed 7)"), "{report}");
+    assert!(report.contains("diffs/s"), "{report}");
+    assert!(report.contains("average diff:"), "{report}");
+    assert!(report.contains("correct:      10 of 10 diffs"), "{report}");
+}
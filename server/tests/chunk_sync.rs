@@ -0,0 +1,73 @@
+mod common;
+
+use std::fs;
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+use shared::ChecksumAlgorithm;
+
+const CONFIG: &str = "[chunks]\nenabled = true\nsize = 1000\nmin_bytes = 10000\n";
+
+/// 2000 lines of 10 chars, 20 chunks of 1000 chars
+fn large_content() -> String {
+    (0..2000).map(|line| format!("{line:09}\n")).collect()
+}
+
+fn start(content: &str) -> Mirror {
+    start_with_config(content, CONFIG)
+}
+
+fn start_with_config(content: &str, config: &str) -> Mirror {
+    let mut mirror = Mirror::start_server_with_files(&[(SOURCE_FILE, content), ("markdown-op.toml", config)], &["--watch", SOURCE_FILE]);
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+    mirror
+}
+
+/// Edits one chunk of `content` while the server is down, and waits for the
+/// reconnected client to rebuild the file from the one changed chunk
+fn edit_while_disconnected(mirror: &mut Mirror, content: &str) {
+    mirror.stop_server();
+    // in chunk 12 of 20, keeping the length
+    let edited = content.replace("000001234\n", "EDITED!!!\n");
+    fs::write(mirror.source_path(), &edited).expect("write source file");
+    mirror.restart_server(&["--watch", SOURCE_FILE]);
+
+    assert!(
+        wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(mirror.output_path()).is_ok_and(|c| c == edited)),
+        "client output:\n{}",
+        mirror.client_log().join("\n")
+    );
+    assert_eq!(mirror.client_log_count("Fetching 1 of 20 chunks of doc.md"), 1);
+    assert_eq!(mirror.server_log_count("Sending 1 of 20 chunks of doc.md"), 1);
+}
+
+#[test]
+fn a_reconnecting_client_fetches_only_the_chunk_that_changed() {
+    let content = large_content();
+    let mut mirror = start(&content);
+    // the first connect has no copy to compare against
+    assert_eq!(mirror.server_log_count("chunks of"), 0);
+    edit_while_disconnected(&mut mirror, &content);
+
+    // changes after the rebuild are diffs as usual
+    mirror.edit_and_await(|content| content.replace("000001999\n", "LAST LINE\n"));
+}
+
+#[test]
+fn every_checksum_algorithm_round_trips() {
+    for algorithm in ChecksumAlgorithm::ALL {
+        let content = large_content();
+        let mut mirror = start_with_config(&content, &format!("{CONFIG}algorithm = \"{algorithm}\"\n"));
+        edit_while_disconnected(&mut mirror, &content);
+        assert_eq!(mirror.client_log_count("don't add up"), 0, "{algorithm}");
+    }
+}
+
+#[test]
+fn files_under_the_threshold_are_sent_in_full() {
+    let mut mirror = start("# Small\n\nWell under min_bytes.\n");
+    mirror.stop_server();
+    fs::write(mirror.source_path(), "# Small\n\nStill under min_bytes.\n").expect("write source file");
+    mirror.restart_server(&["--watch", SOURCE_FILE]);
+    mirror.await_convergence();
+    assert_eq!(mirror.client_log_count("chunks of"), 0);
+}
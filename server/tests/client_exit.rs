@@ -0,0 +1,74 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT};
+
+#[test]
+fn mirrored_file_is_removed_on_interrupt() {
+    let mut mirror = Mirror::start_with("# Title\n", &[], &["--mirror-delete-on-exit"]);
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| format!("{content}\nMore.\n"));
+    assert!(mirror.interrupt_client().success());
+    // the client log is collected by a thread that may still be catching up
+    wait_until(CONVERGENCE_TIMEOUT, || mirror.client_log_count("Removed") == 1);
+    assert!(!mirror.output_path().exists(), "output was not removed:\n{}", mirror.client_log().join("\n"));
+}
+
+#[test]
+fn modified_mirror_is_kept_on_interrupt() {
+    let mut mirror = Mirror::start_with("# Title\n", &[], &["--mirror-delete-on-exit"]);
+    mirror.await_convergence();
+    fs::write(mirror.output_path(), "# Title\n\nLocal notes\n").expect("modify output");
+    assert!(mirror.interrupt_client().success());
+    wait_until(CONVERGENCE_TIMEOUT, || mirror.client_log_count("Not removing") == 1);
+    assert_eq!(fs::read_to_string(mirror.output_path()).expect("output kept"), "# Title\n\nLocal notes\n");
+    assert_eq!(mirror.client_log_count("Not removing"), 1);
+}
+
+#[test]
+fn mirrored_file_is_kept_on_interrupt_without_the_option() {
+    let mut mirror = Mirror::start("# Title\n");
+    mirror.await_convergence();
+    assert!(mirror.interrupt_client().success());
+    assert!(mirror.output_path().exists());
+}
+
+#[test]
+fn client_sends_a_close_frame_on_interrupt() {
+    use std::{net::TcpListener, thread};
+    use shared::{ClientMessage, FileChange, Sequenced};
+    use tokio_tungstenite::tungstenite::{self, Message};
+
+    // stands in for the server, to see exactly which frames the client sends
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let url = format!("ws://{}", listener.local_addr().expect("address"));
+    let dir = common::temp_dir();
+    let mut client = common::spawn_client(&dir, &url, &[]);
+    let mut socket = tungstenite::accept(listener.accept().expect("accept").0).expect("WebSocket handshake");
+    let change = Sequenced::new(
+        1,
+        FileChange::FullContent {
+            file_id: "doc.md".to_string(),
+            content: "# Title\n".to_string(),
+            last_modified: None,
+        },
+    );
+    socket.send(Message::Text(serde_json::to_string(&change).expect("JSON"))).expect("send");
+    let ack = socket.read().expect("read ack");
+    assert_eq!(serde_json::from_str::<ClientMessage>(ack.to_text().expect("text")).expect("JSON"), ClientMessage::Ack { seq: 1 });
+    let frames = thread::spawn(move || {
+        let mut frames = Vec::new();
+        // reading the close frame also answers it
+        while let Ok(frame) = socket.read() {
+            frames.push(frame);
+        }
+        frames
+    });
+    assert!(client.interrupt().success());
+    let frames = frames.join().expect("reader thread");
+    assert!(matches!(frames.last(), Some(Message::Close(_))), "no close frame, got {frames:?}:\n{}", client.lines().join("\n"));
+    assert!(client.lines().iter().all(|line| !line.contains("Reconnecting")));
+    let _ = fs::remove_dir_all(dir);
+}
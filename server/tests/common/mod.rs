@@ -0,0 +1,363 @@
+//! End to end harness: runs the real server and client binaries against a
+//! temporary source file and output directory.
+
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use shared::{FileChange, Sequenced};
+use tokio_tungstenite::tungstenite::{self, stream::MaybeTlsStream, Message, WebSocket};
+
+/// How long the mirror may take to catch up with an edit
+pub const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause before each edit, longer than the server's debounce and read
+/// interval, so back to back edits are not folded into the previous one
+pub const EDIT_INTERVAL: Duration = Duration::from_millis(150);
+
+/// Name of the watched file inside the temporary directory
+pub const SOURCE_FILE: &str = "doc.md";
+
+/// A server watching a temporary file and a client mirroring it
+pub struct Mirror {
+    pub dir: PathBuf,
+    pub port: u16,
+    /// Port of the long-poll endpoint, when the server was started with `--long-poll`
+    pub long_poll_port: Option<u16>,
+    /// Port of the SSE endpoint, when the server was started with `--sse`
+    pub sse_port: Option<u16>,
+    server: Process,
+    client: Option<Process>,
+}
+
+/// A running binary with its output collected line by line, killed when dropped
+pub struct Process {
+    child: Child,
+    output: Arc<Mutex<Vec<String>>>,
+}
+
+impl Mirror {
+    /// Starts a server watching `content` and a client connected to it
+    pub fn start(content: &str) -> Self {
+        Self::start_with(content, &[], &[])
+    }
+
+    /// Like [`Mirror::start`], with extra arguments for the server and client
+    pub fn start_with(content: &str, server_args: &[&str], client_args: &[&str]) -> Self {
+        let mut mirror = Self::start_server(content, server_args);
+        mirror.start_client(client_args);
+        mirror
+    }
+
+    /// Starts only the server; see [`Mirror::start_client`]
+    pub fn start_server(content: &str, server_args: &[&str]) -> Self {
+        Self::start_server_watching(content, SOURCE_FILE, server_args)
+    }
+
+    /// Like [`Mirror::start_server`], passing `watch_path` to the server instead
+    /// of `SOURCE_FILE`, e.g. to refer to it with another case or `..` segments
+    pub fn start_server_watching(content: &str, watch_path: &str, server_args: &[&str]) -> Self {
+        Self::start_server_with_files(&[(SOURCE_FILE, content)], &[&[watch_path], server_args].concat())
+    }
+
+    /// Starts the server in a directory holding `files` (name and content),
+    /// e.g. several files to watch or a config file; `server_args` say what to watch
+    pub fn start_server_with_files(files: &[(&str, &str)], server_args: &[&str]) -> Self {
+        let dir = temp_dir();
+        for (name, content) in files {
+            fs::write(dir.join(name), content).expect("write file");
+        }
+        Self::start_server_in(dir, server_args)
+    }
+
+    /// Starts the server in `dir`, for files that are not plain text, e.g. gzipped
+    pub fn start_server_in(dir: PathBuf, server_args: &[&str]) -> Self {
+        let mut command = Command::new(binary("server"));
+        // the working directory keeps a markdown-op.toml of the repo out of the way
+        command.current_dir(&dir).args(["--bind", "127.0.0.1:0"]).args(server_args);
+        let server = Process::spawn(command);
+        let line = server.wait_for_line("WebSocket server listening on ");
+        let port = port_of(&line);
+        let long_poll_port = server_args
+            .contains(&"--long-poll")
+            .then(|| port_of(&server.wait_for_line("Long-poll server listening on http://")));
+        let sse_port = server_args
+            .contains(&"--sse")
+            .then(|| port_of(&server.wait_for_line("SSE server listening on http://")));
+        Self { dir, port, long_poll_port, sse_port, server, client: None }
+    }
+
+    /// Stops the server, e.g. to change the watched file while it is down
+    pub fn stop_server(&mut self) {
+        self.server.kill();
+    }
+
+    /// Starts the server again on the same port with `server_args`, stopping
+    /// it first if it still runs; a connected client reconnects on its own
+    pub fn restart_server(&mut self, server_args: &[&str]) {
+        self.server.kill();
+        let mut command = Command::new(binary("server"));
+        command.current_dir(&self.dir).args(["--bind", &format!("127.0.0.1:{}", self.port)]).args(server_args);
+        self.server = Process::spawn(command);
+        self.server.wait_for_line("WebSocket server listening on ");
+    }
+
+    /// Starts the client, writing to the `out` directory
+    pub fn start_client(&mut self, client_args: &[&str]) {
+        let client = spawn_client(&self.dir, &self.url(), client_args);
+        client.wait_for_line("Connected to server");
+        self.client = Some(client);
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.port)
+    }
+
+    pub fn source_path(&self) -> PathBuf {
+        self.dir.join(SOURCE_FILE)
+    }
+
+    pub fn output_path(&self) -> PathBuf {
+        self.dir.join("out").join("client1_README.md")
+    }
+
+    pub fn source(&self) -> String {
+        fs::read_to_string(self.source_path()).expect("read source file")
+    }
+
+    /// Replaces the content of the watched file
+    pub fn write(&self, content: &str) {
+        thread::sleep(EDIT_INTERVAL);
+        fs::write(self.source_path(), content).expect("write source file");
+    }
+
+    /// Rewrites the watched file with `edit` applied to its content
+    pub fn edit(&self, edit: impl FnOnce(&str) -> String) {
+        self.write(&edit(&self.source()));
+    }
+
+    /// Edits the watched file and waits until the client's copy matches it
+    pub fn edit_and_await(&self, edit: impl FnOnce(&str) -> String) {
+        self.edit(edit);
+        self.await_convergence();
+    }
+
+    /// Waits until the client's copy matches the watched file, panicking with
+    /// both contents and the logs after `CONVERGENCE_TIMEOUT`
+    pub fn await_convergence(&self) {
+        let expected = self.source();
+        let converged = wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(self.output_path()).is_ok_and(|c| c == expected));
+        if !converged {
+            panic!(
+                "mirror did not converge\n--- source ---\n{}\n--- output ---\n{}\n--- server ---\n{}\n--- client ---\n{}",
+                expected,
+                fs::read_to_string(self.output_path()).unwrap_or_default(),
+                self.server_log().join("\n"),
+                self.client_log().join("\n"),
+            );
+        }
+    }
+
+    /// Body of `GET /changes?since=N` on the long-poll endpoint: the JSON array
+    /// of changes after `since`, waiting for the next one when there are none
+    pub fn changes_since(&self, since: Option<u64>) -> String {
+        let mut target = "/changes".to_string();
+        if let Some(since) = since {
+            target = format!("{target}?since={since}");
+        }
+        let (head, body) = self.http_get(&target);
+        assert!(head.starts_with("HTTP/1.1 200"), "long-poll request failed: {}", head);
+        body
+    }
+
+    /// Head and body of the response to `GET target` on the long-poll endpoint
+    pub fn http_get(&self, target: &str) -> (String, String) {
+        let port = self.long_poll_port.expect("server started without --long-poll");
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("connect to long-poll endpoint");
+        write!(stream, "GET {target} HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n").expect("send request");
+        let mut response = String::new();
+        stream.read_to_string(&mut response).expect("read response");
+        let (head, body) = response.split_once("\r\n\r\n").expect("HTTP response");
+        (head.to_string(), body.to_string())
+    }
+
+    /// See [`Process::interrupt`]
+    #[cfg(unix)]
+    pub fn interrupt_client(&mut self) -> ExitStatus {
+        self.client.as_mut().expect("client not started").interrupt()
+    }
+
+    pub fn server_log(&self) -> Vec<String> {
+        self.server.lines()
+    }
+
+    /// How many server log lines contain `pattern`
+    pub fn server_log_count(&self, pattern: &str) -> usize {
+        self.server_log().iter().filter(|line| line.contains(pattern)).count()
+    }
+
+    pub fn client_log(&self) -> Vec<String> {
+        self.client.as_ref().map(Process::lines).unwrap_or_default()
+    }
+
+    /// How many client log lines contain `pattern`, e.g. `"Applied diff"`
+    pub fn client_log_count(&self, pattern: &str) -> usize {
+        self.client_log().iter().filter(|line| line.contains(pattern)).count()
+    }
+}
+
+impl Drop for Mirror {
+    fn drop(&mut self) {
+        // the client goes first, so it does not start reconnecting
+        self.client = None;
+        self.server.kill();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+impl Process {
+    fn spawn(mut command: Command) -> Self {
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|e| panic!("failed to start {:?}: {}", command.get_program(), e));
+        let output = Arc::new(Mutex::new(Vec::new()));
+        collect_lines(child.stdout.take().expect("stdout"), Arc::clone(&output));
+        collect_lines(child.stderr.take().expect("stderr"), Arc::clone(&output));
+        Self { child, output }
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.output.lock().expect("lock").clone()
+    }
+
+    /// The first output line containing `pattern`, waiting for it to appear
+    pub fn wait_for_line(&self, pattern: &str) -> String {
+        let mut found = None;
+        wait_until(CONVERGENCE_TIMEOUT, || {
+            found = self.lines().into_iter().find(|line| line.contains(pattern));
+            found.is_some()
+        });
+        found.unwrap_or_else(|| panic!("no output line with {:?}:\n{}", pattern, self.lines().join("\n")))
+    }
+
+    /// Sends SIGINT, as Ctrl+C would, and waits for the process to exit
+    #[cfg(unix)]
+    pub fn interrupt(&mut self) -> ExitStatus {
+        let status = Command::new("kill").args(["-INT", &self.child.id().to_string()]).status().expect("run kill");
+        assert!(status.success(), "kill -INT failed");
+        self.wait_for_exit()
+    }
+
+    pub fn wait_for_exit(&mut self) -> ExitStatus {
+        let mut status = None;
+        wait_until(CONVERGENCE_TIMEOUT, || {
+            status = self.child.try_wait().expect("wait for process");
+            status.is_some()
+        });
+        status.unwrap_or_else(|| panic!("process did not exit:\n{}", self.lines().join("\n")))
+    }
+
+    fn kill(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A WebSocket connection to the server, standing in for a client
+pub type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+/// Connects to the WebSocket server at `url`, query included; reads time out
+/// after `CONVERGENCE_TIMEOUT`, so a message that never comes fails the test
+pub fn connect(url: &str) -> Socket {
+    let (socket, _) = tungstenite::connect(url).expect("connect");
+    if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+        stream.set_read_timeout(Some(CONVERGENCE_TIMEOUT)).expect("set timeout");
+    }
+    socket
+}
+
+/// The next text message, skipping pings and the like
+pub fn read_text(socket: &mut Socket) -> String {
+    loop {
+        match socket.read().expect("read message") {
+            Message::Text(text) => return text,
+            Message::Close(frame) => panic!("connection closed: {frame:?}"),
+            _ => {}
+        }
+    }
+}
+
+/// The change in the next text message
+pub fn next_change(socket: &mut Socket) -> FileChange {
+    serde_json::from_str::<Sequenced>(&read_text(socket)).expect("parse change").change
+}
+
+/// Starts a client of the server at `url`, writing to `dir/out`
+pub fn spawn_client(dir: &Path, url: &str, client_args: &[&str]) -> Process {
+    let mut command = Command::new(binary("client"));
+    command
+        .current_dir(dir)
+        .args(["--server-url", url, "--output-dir", "out"])
+        .args(client_args)
+        .arg("1");
+    Process::spawn(command)
+}
+
+fn collect_lines(stream: impl Read + Send + 'static, output: Arc<Mutex<Vec<String>>>) {
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines().map_while(Result::ok) {
+            output.lock().expect("lock").push(line);
+        }
+    });
+}
+
+fn port_of(address_line: &str) -> u16 {
+    address_line.rsplit(':').next().and_then(|port| port.trim().parse().ok()).expect("port in address")
+}
+
+/// Polls `condition` until it holds or `timeout` passes; returns whether it held
+pub fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    condition()
+}
+
+/// Path of a workspace binary; the client is built next to the server
+pub fn binary(name: &str) -> PathBuf {
+    let path = Path::new(env!("CARGO_BIN_EXE_server")).with_file_name(format!("{}{}", name, std::env::consts::EXE_SUFFIX));
+    assert!(path.exists(), "{} is missing, build the workspace first (cargo build --workspace)", path.display());
+    path
+}
+
+/// A new empty directory under the system temp dir; see [`Mirror::dir`]
+pub fn temp_dir() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "markdown-op-e2e-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir).expect("create temp dir");
+    dir
+}
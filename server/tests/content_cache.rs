@@ -0,0 +1,34 @@
+mod common;
+
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+
+const CONFIG: &str = r#"
+watch = ["doc.md", "other.md"]
+
+[limits]
+max_cached_bytes = 3000
+"#;
+
+fn document(title: &str) -> String {
+    let lines: String = (0..100).map(|i| format!("Line {i} of {title}.\n")).collect();
+    format!("# {title}\n\n{lines}")
+}
+
+#[test]
+fn least_recently_changed_file_is_evicted_and_resent_in_full() {
+    let (doc, other) = (document("doc"), document("other"));
+    assert!(doc.len() + other.len() > 3000 && doc.len() < 2500);
+    let mut mirror = Mirror::start_server_with_files(
+        &[(SOURCE_FILE, &doc), ("other.md", &other), ("markdown-op.toml", CONFIG)],
+        &[],
+    );
+    // both files are read at startup, the first one read no longer fits
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("Evicted cached content of doc.md") == 1));
+    mirror.start_client(&[]);
+    mirror.edit_and_await(|content| format!("{content}\nA new paragraph.\n"));
+    assert_eq!(mirror.client_log_count("Applied diff"), 0, "evicted file was diffed");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("Evicted cached content of other.md") == 1));
+    // cached again, so the next change is a diff
+    mirror.edit_and_await(|content| content.replace("Line 50 ", "Line fifty "));
+    assert!(mirror.client_log_count("Applied diff") >= 1);
+}
@@ -0,0 +1,37 @@
+mod common;
+
+use common::{Mirror, SOURCE_FILE};
+
+#[test]
+fn content_endpoint_serves_the_latest_content() {
+    let mirror = Mirror::start_with("# Snapshot\n", &["--long-poll", "127.0.0.1:0"], &[]);
+    let (head, body) = mirror.http_get(&format!("/content/{SOURCE_FILE}"));
+    assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+    assert!(head.contains("Content-Type: text/markdown"), "{head}");
+    assert_eq!(body, "# Snapshot\n");
+
+    mirror.edit_and_await(|content| format!("{content}\nEdited.\n"));
+    let (head, body) = mirror.http_get(&format!("/content/{SOURCE_FILE}"));
+    assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+    assert_eq!(body, "# Snapshot\n\nEdited.\n");
+}
+
+#[test]
+fn unknown_file_is_not_found() {
+    let mirror = Mirror::start_server("# Snapshot\n", &["--long-poll", "127.0.0.1:0"]);
+    let (head, _) = mirror.http_get("/content/missing.md");
+    assert!(head.starts_with("HTTP/1.1 404"), "{head}");
+    // a malformed escape names no file
+    let (head, _) = mirror.http_get("/content/%2");
+    assert!(head.starts_with("HTTP/1.1 404"), "{head}");
+}
+
+#[test]
+fn content_endpoint_needs_the_token() {
+    let mirror = Mirror::start_server("# Secret\n", &["--long-poll", "127.0.0.1:0", "--auth-token", "letmein"]);
+    let (head, _) = mirror.http_get(&format!("/content/{SOURCE_FILE}"));
+    assert!(head.starts_with("HTTP/1.1 401"), "{head}");
+    let (head, body) = mirror.http_get(&format!("/content/{SOURCE_FILE}?token=letmein"));
+    assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+    assert_eq!(body, "# Secret\n");
+}
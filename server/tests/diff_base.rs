@@ -0,0 +1,19 @@
+mod common;
+
+use std::{thread, time::Duration};
+use common::Mirror;
+
+#[test]
+fn a_change_made_while_nobody_is_connected_reaches_clients_that_connect_later() {
+    let content: String = (0..100).map(|i| format!("Line {i} of a long document.\n")).collect();
+    let mut mirror = Mirror::start_server(&content, &["--diff-base", "broadcast"]);
+    // nobody is connected to receive this one, so it is read once the client connects
+    mirror.edit(|content| content.replace("Line 20 ", "Line twenty "));
+    thread::sleep(Duration::from_millis(500));
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| content.replace("Line 60 ", "Line sixty "));
+    mirror.edit_and_await(|content| format!("# Heading\n\n{content}"));
+    assert!(mirror.client_log_count("Applied diff") >= 2);
+    assert!(!mirror.client_log().iter().any(|line| line.contains("requesting resync")));
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use std::time::{Duration, Instant};
+use common::Mirror;
+use shared::{CharDiff, DiffStrategy, FileChange, LineDiff, WordDiff};
+use shared::diff::{DIFF_TIMEOUT, MAX_DIFF_CHANGES};
+
+/// Generous bound on how long a guarded diff takes, even on a slow machine
+const BOUND: Duration = Duration::from_secs(2);
+
+/// Distinct lines, the worst case for the line diff once they are reversed
+fn numbered_lines(count: usize) -> Vec<String> {
+    (0..count).map(|n| format!("Line {n} of a document nobody would write by hand\n")).collect()
+}
+
+fn assert_full_content(changes: &[FileChange], new: &str) {
+    match changes {
+        [FileChange::FullContent { content, .. }] => assert!(content == new, "full content differs from the new content"),
+        _ => panic!("expected the full content, got {} changes", changes.len()),
+    }
+}
+
+#[test]
+fn reversed_lines_fall_back_to_full_content_in_time() {
+    let lines = numbered_lines(40_000);
+    let old = lines.concat();
+    let new: String = lines.iter().rev().map(String::as_str).collect();
+    let started = Instant::now();
+    let changes = LineDiff.diff("doc.md", &old, &new);
+    let took = started.elapsed();
+    assert_full_content(&changes, &new);
+    assert!(took >= DIFF_TIMEOUT && took < BOUND, "took {took:?}");
+}
+
+#[test]
+fn fragmenting_edit_falls_back_to_full_content() {
+    // removing every other char, or word, is one change each
+    let edits: [(&dyn DiffStrategy, _, _); 2] = [
+        (&CharDiff, "ab".repeat(100_000), "a".repeat(100_000)),
+        (&WordDiff, "keep drop ".repeat(20_000), "keep ".repeat(20_000)),
+    ];
+    let started = Instant::now();
+    for (strategy, old, new) in &edits {
+        assert_full_content(&strategy.diff("doc.md", old, new), new);
+    }
+    assert!(started.elapsed() < BOUND, "took {:?}", started.elapsed());
+}
+
+#[test]
+fn edits_within_the_budget_stay_diffs() {
+    let old = "ab".repeat(MAX_DIFF_CHANGES);
+    let new = "a".repeat(MAX_DIFF_CHANGES);
+    let changes = CharDiff.diff("doc.md", &old, &new);
+    assert_eq!(changes.len(), MAX_DIFF_CHANGES);
+    let mut content = old;
+    for change in &changes {
+        change.try_apply(&mut content).expect("apply");
+    }
+    assert_eq!(content, new);
+}
+
+#[test]
+fn watcher_mirrors_a_diff_bomb_as_full_content() {
+    let lines = numbered_lines(40_000);
+    let mirror = Mirror::start_with(&lines.concat(), &["--diff", "line"], &[]);
+    mirror.edit_and_await(|_| lines.iter().rev().map(String::as_str).collect());
+    assert_eq!(mirror.server_log_count("Diff of doc.md too costly, sending full content"), 1);
+}
@@ -0,0 +1,102 @@
+//! Locks down the exact changes every diff strategy produces for a set of
+//! edits, so a change to an algorithm that alters what is sent shows up as a
+//! failing test instead of silently. After an intended change, regenerate the
+//! golden files with `UPDATE_GOLDENS=1 cargo test -p server --test diff_golden`
+//! and review their diff.
+
+use std::{collections::BTreeMap, fs, path::PathBuf};
+use serde::{Deserialize, Serialize};
+use shared::{DiffStrategyKind, FileChange};
+
+const STRATEGIES: [DiffStrategyKind; 5] = [
+    DiffStrategyKind::Char,
+    DiffStrategyKind::Line,
+    DiffStrategyKind::FrontMatter,
+    DiffStrategyKind::Grapheme,
+    DiffStrategyKind::Word,
+];
+
+/// Name of the golden file, old content and new content
+const FIXTURES: [(&str, &str, &str); 14] = [
+    ("append_line", "# Title\n\nFirst line.\n", "# Title\n\nFirst line.\nSecond line.\n"),
+    ("prepend_heading", "Some text.\nMore text.\n", "# Heading\n\nSome text.\nMore text.\n"),
+    ("delete_paragraph", "# Title\n\nFirst paragraph.\n\nSecond paragraph.\n\nThird paragraph.\n", "# Title\n\nFirst paragraph.\n\nThird paragraph.\n"),
+    ("replace_word", "The quick brown fox jumps over the lazy dog.\n", "The quick red fox jumps over the lazy dog.\n"),
+    ("scattered_edits", "alpha beta gamma\ndelta epsilon zeta\neta theta iota\n", "alpha BETA gamma\ndelta epsilon zeta\neta theta kappa\n"),
+    ("repeated_lines", "- item\n- item\n- item\n", "- item\n- other\n- item\n- item\n"),
+    ("reordered_lines", "one\ntwo\nthree\n", "three\none\ntwo\n"),
+    ("unicode", "Wave 👋 and e\u{301}t\u{e9} 🇵🇹\n", "Wave 👋🏽 and e\u{301}te\u{301} 🇧🇷\n"),
+    ("crlf", "line one\r\nline two\r\n", "line one\r\nline 2\r\nline three\r\n"),
+    ("front_matter", "---\ntitle: Draft\ntags: [a]\n---\n# Body\n\nText.\n", "---\ntitle: Final\ntags: [a, b]\n---\n# Body\n\nText, edited.\n"),
+    ("table_row", "| a | b |\n|---|---|\n| 1 | 2 |\n", "| a | b |\n|---|---|\n| 1 | 2 |\n| 3 | 4 |\n"),
+    // over 256 chars, which the line strategy diffs char by char
+    (
+        "wide_line",
+        "| wide | table | row | with | many | cells | to | make | it | longer | than | two | hundred | fifty | six | characters | so | that | the | line | diff | treats | it | as | minified | content | and | diffs | it | char | by | char | instead | of | as | a | whole |\n",
+        "| wide | table | row | with | many | cells | to | make | it | longer | than | two | hundred | fifty | six | characters | so | that | the | line | diff | treats | it | as | minified | content | and | diffs | it | char | by | char | rather | than | as | a | whole |\n",
+    ),
+    ("from_empty", "", "# New document\n"),
+    ("to_empty", "# Old document\n\nGone.\n", ""),
+];
+
+/// What a golden file holds: the edit, and the changes each strategy makes of it
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Golden {
+    old: String,
+    new: String,
+    changes: BTreeMap<String, Vec<FileChange>>,
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden/diffs").join(format!("{name}.json"))
+}
+
+fn strategy_name(kind: DiffStrategyKind) -> String {
+    serde_json::to_value(kind).ok().and_then(|name| name.as_str().map(str::to_string)).expect("strategy name")
+}
+
+fn diff_all(old: &str, new: &str) -> Golden {
+    let changes = STRATEGIES
+        .into_iter()
+        .map(|kind| (strategy_name(kind), kind.strategy().diff("doc.md", old, new)))
+        .collect();
+    Golden { old: old.to_string(), new: new.to_string(), changes }
+}
+
+#[test]
+fn diffs_match_the_golden_files() {
+    let update = std::env::var_os("UPDATE_GOLDENS").is_some();
+    let mut mismatched = Vec::new();
+    for (name, old, new) in FIXTURES {
+        let actual = diff_all(old, new);
+        // goldens must at least be right before they are worth locking down
+        for (strategy, changes) in &actual.changes {
+            let mut content = old.to_string();
+            for change in changes {
+                match change {
+                    FileChange::FullContent { content: full, .. } => content = full.clone(),
+                    change => change.try_apply(&mut content).expect("apply"),
+                }
+            }
+            assert_eq!(content, new, "{strategy} diff of {name} does not produce the new content");
+        }
+        let path = golden_path(name);
+        if update {
+            let json = serde_json::to_string_pretty(&actual).expect("serialize golden");
+            fs::write(&path, json + "\n").expect("write golden file");
+            continue;
+        }
+        let expected: Golden = fs::read_to_string(&path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_else(|| panic!("missing or unreadable golden file {}", path.display()));
+        if expected != actual {
+            mismatched.push(format!("{name}:\n  expected {:?}\n  actual   {:?}", expected.changes, actual.changes));
+        }
+    }
+    assert!(
+        mismatched.is_empty(),
+        "diffs changed, rerun with UPDATE_GOLDENS=1 if that is intended:\n{}",
+        mismatched.join("\n")
+    );
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use common::Mirror;
+
+/// Over `full_content_threshold`, so edits are sent as diffs
+fn long_document() -> String {
+    let paragraph = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. ".repeat(4);
+    (1..=10).map(|n| format!("## Section {n}\n\n{paragraph}\n\n")).collect()
+}
+
+/// The `_count` sample of a histogram in a `/metrics` body
+fn count(body: &str, histogram: &str) -> u64 {
+    let prefix = format!("{histogram}_count ");
+    body.lines()
+        .find_map(|line| line.strip_prefix(&prefix))
+        .and_then(|count| count.parse().ok())
+        .unwrap_or_else(|| panic!("no {histogram} in:\n{body}"))
+}
+
+#[test]
+fn diff_metrics_record_every_broadcast_change() {
+    let mirror = Mirror::start_with(&long_document(), &["--long-poll", "127.0.0.1:0", "--diff", "line"], &[]);
+    let (head, body) = mirror.http_get("/metrics");
+    assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+    assert!(body.contains("# TYPE markdown_op_diff_payload_ratio histogram"), "{body}");
+    assert_eq!(count(&body, "markdown_op_diff_payload_ratio"), 0);
+
+    for n in 1..=3 {
+        mirror.edit_and_await(|content| content.replace(&format!("Section {n}\n"), &format!("Section {n} (edited)\n")));
+    }
+    let (_, body) = mirror.http_get("/metrics");
+    assert_eq!(count(&body, "markdown_op_diff_payload_ratio"), 3);
+    assert_eq!(count(&body, "markdown_op_diffs_per_change"), 3);
+    // a one-line edit to a long file is a small fraction of it
+    assert!(body.contains("markdown_op_diff_payload_ratio_bucket{le=\"0.25\"} 3"), "{body}");
+}
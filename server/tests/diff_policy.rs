@@ -0,0 +1,70 @@
+mod common;
+
+use std::fs;
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT, EDIT_INTERVAL};
+use shared::{FileChange, Sequenced};
+
+const CONFIG: &str = r#"
+watch = ["doc.md", "logo.svg"]
+
+[diff.policies]
+svg = "full"
+"#;
+
+/// Content over the full content threshold, so only the policy decides
+fn document(line: &str, edits: usize) -> String {
+    let mut content = line.repeat(60);
+    for edit in 0..edits {
+        content.push_str(&format!("Edit {edit}\n"));
+    }
+    content
+}
+
+#[test]
+fn full_policy_sends_full_content_where_other_files_get_diffs() {
+    let markdown = |edits| document("Some markdown prose in a paragraph.\n", edits);
+    let svg = |edits| document("<path d=\"M0 0L10 10\" stroke=\"black\"/>\n", edits);
+    let mut mirror = Mirror::start_server_with_files(
+        &[("doc.md", &markdown(0)), ("logo.svg", &svg(0)), ("markdown-op.toml", CONFIG)],
+        &[],
+    );
+    mirror.start_client(&["--output-template", "{file_id}"]);
+    let mirrored = |name: &str, expected: &str| {
+        let output = mirror.dir.join("out").join(name);
+        wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(&output).is_ok_and(|c| c == expected))
+    };
+    assert!(mirrored("doc.md", &markdown(0)) && mirrored("logo.svg", &svg(0)));
+    for edits in 1..=3 {
+        std::thread::sleep(EDIT_INTERVAL);
+        fs::write(mirror.dir.join("doc.md"), markdown(edits)).expect("write doc.md");
+        fs::write(mirror.dir.join("logo.svg"), svg(edits)).expect("write logo.svg");
+        assert!(mirrored("doc.md", &markdown(edits)), "client output:\n{}", mirror.client_log().join("\n"));
+        assert!(mirrored("logo.svg", &svg(edits)), "client output:\n{}", mirror.client_log().join("\n"));
+    }
+    assert!(mirror.client_log_count("Applied diff to file: out/doc.md") >= 3);
+    assert_eq!(mirror.client_log_count("Applied diff to file: out/logo.svg"), 0);
+    assert!(mirror.client_log_count("Updated file: out/logo.svg") >= 4);
+}
+
+#[test]
+fn no_diff_only_ever_broadcasts_full_content() {
+    let markdown = |edits| document("Some markdown prose in a paragraph.\n", edits);
+    let mut mirror = Mirror::start_server(&markdown(0), &["--no-diff", "--history", "--long-poll", "127.0.0.1:0"]);
+    let poll = |since| -> Vec<Sequenced> { serde_json::from_str(&mirror.changes_since(since)).expect("JSON changes") };
+    let mut last = poll(None)[0].seq;
+    let mut broadcast = Vec::new();
+    for edits in 1..=3 {
+        mirror.edit(|_| markdown(edits));
+        for message in poll(Some(last)) {
+            last = message.seq;
+            broadcast.push(message.change);
+        }
+    }
+    assert!(broadcast.len() >= 3);
+    assert!(broadcast.iter().all(|change| matches!(change, FileChange::FullContent { .. })), "{broadcast:?}");
+
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| format!("{content}Edit 4\n"));
+    assert_eq!(mirror.client_log_count("Applied diff"), 0);
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use common::{next_change, wait_until, Mirror, Socket, CONVERGENCE_TIMEOUT};
+use shared::FileChange;
+
+/// Enough lines that edits are sent as diffs
+fn document(first_line: &str) -> String {
+    format!("{first_line}\n{}", "A line that stays the same.\n".repeat(100))
+}
+
+fn connect(mirror: &Mirror, query: &str) -> Socket {
+    common::connect(&format!("{}/?{query}", mirror.url()))
+}
+
+/// Applies changes to `content` until it is `expected`, returning them
+fn follow(socket: &mut Socket, content: &mut String, expected: &str) -> Vec<FileChange> {
+    let mut changes = Vec::new();
+    while content != expected {
+        let change = next_change(socket);
+        change.apply(content);
+        changes.push(change);
+    }
+    changes
+}
+
+/// A server with history that broadcast an edit of the initial document,
+/// with a copy of the document from before the edit
+fn edited_mirror() -> (Mirror, String) {
+    let original = document("# Title");
+    let mirror = Mirror::start_server(&original, &["--history"]);
+    let mut watcher = connect(&mirror, "");
+    let FileChange::FullContent { content, .. } = next_change(&mut watcher) else {
+        panic!("expected the initial content");
+    };
+    assert_eq!(content, original);
+    mirror.write(&document("# Edited title"));
+    let mut followed = content.clone();
+    follow(&mut watcher, &mut followed, &document("# Edited title"));
+    (mirror, original)
+}
+
+#[test]
+fn a_copy_matching_content_in_history_gets_only_the_diffs_since() {
+    let (mirror, original) = edited_mirror();
+    let mut socket = connect(&mirror, &format!("digest=doc.md:{}", shared::digest(&original)));
+    let mut content = original;
+    let changes = follow(&mut socket, &mut content, &document("# Edited title"));
+    assert!(changes.iter().all(|change| matches!(change, FileChange::Diff { .. })), "{changes:?}");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("Client's copy of doc.md matches content in history") == 1));
+}
+
+#[test]
+fn a_copy_matching_no_content_in_history_gets_the_full_content() {
+    let (mirror, _) = edited_mirror();
+    let mut socket = connect(&mirror, &format!("digest=doc.md:{}", shared::digest("# Something else\n")));
+    let change = next_change(&mut socket);
+    assert!(matches!(&change, FileChange::FullContent { content, .. } if *content == document("# Edited title")), "{change:?}");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("Client's copy of doc.md matches no content in history") == 1));
+}
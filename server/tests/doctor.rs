@@ -0,0 +1,73 @@
+mod common;
+
+use std::{fs, net::TcpListener, process::{Command, Output}};
+use common::{binary, temp_dir, Mirror};
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn server_doctor_explains_a_missing_file_and_a_taken_address() {
+    let dir = temp_dir();
+    fs::write(dir.join("present.md"), "# Present\n").expect("write file");
+    let taken = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = taken.local_addr().expect("address").to_string();
+    let output = Command::new(binary("server"))
+        .current_dir(&dir)
+        .args(["--watch", "present.md", "--watch", "missing.md", "--bind", &addr, "doctor"])
+        .output()
+        .expect("run server");
+    let report = stdout(&output);
+    assert!(!output.status.success(), "{report}");
+    assert!(report.contains("ok    watch present.md: readable, 10 bytes"), "{report}");
+    assert!(report.contains("ok    watcher present.md"), "{report}");
+    assert!(report.contains("FAIL  watch missing.md: does not exist"), "{report}");
+    assert!(report.contains(&format!("FAIL  bind: can't listen on {addr}")), "{report}");
+    assert!(report.contains("pick another address with --bind"), "{report}");
+    assert!(report.contains("2 checks failed"), "{report}");
+}
+
+#[test]
+fn server_doctor_passes_a_working_setup() {
+    let dir = temp_dir();
+    fs::write(dir.join("doc.md"), "# Doc\n").expect("write file");
+    let output = Command::new(binary("server"))
+        .current_dir(&dir)
+        .args(["doc.md", "--bind", "127.0.0.1:0", "doctor"])
+        .output()
+        .expect("run server");
+    assert!(output.status.success(), "{}", stdout(&output));
+    assert!(stdout(&output).ends_with("All checks passed\n"));
+}
+
+#[test]
+fn client_doctor_explains_an_unreachable_server() {
+    let dir = temp_dir();
+    let port = TcpListener::bind("127.0.0.1:0").and_then(|listener| listener.local_addr()).expect("free port").port();
+    let output = Command::new(binary("client"))
+        .current_dir(&dir)
+        .env("SERVER_URL", format!("ws://127.0.0.1:{port}"))
+        .env("OUTPUT_DIR", "out")
+        .arg("doctor")
+        .output()
+        .expect("run client");
+    let report = stdout(&output);
+    assert!(!output.status.success(), "{report}");
+    assert!(report.contains("ok    output: out is writable"), "{report}");
+    assert!(report.contains("nothing is listening there; start the server"), "{report}");
+    assert!(report.contains("1 check failed"), "{report}");
+}
+
+#[test]
+fn client_doctor_connects_to_a_running_server() {
+    let mirror = Mirror::start_server("# Title\n", &[]);
+    let output = Command::new(binary("client"))
+        .current_dir(&mirror.dir)
+        .env("SERVER_URL", mirror.url())
+        .arg("doctor")
+        .output()
+        .expect("run client");
+    assert!(output.status.success(), "{}", stdout(&output));
+    assert!(stdout(&output).contains("accepted the connection"));
+}
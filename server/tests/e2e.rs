@@ -0,0 +1,33 @@
+mod common;
+
+use common::Mirror;
+
+#[test]
+fn small_file_is_mirrored_as_full_content() {
+    let mirror = Mirror::start("# Title\n\nFirst paragraph.\n");
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| format!("{content}\nSecond paragraph.\n"));
+    mirror.edit_and_await(|content| content.replace("First", "Opening"));
+    assert!(mirror.client_log_count("Updated file") >= 3);
+    assert_eq!(mirror.client_log_count("Applied diff"), 0);
+}
+
+#[test]
+fn large_file_edits_are_mirrored_as_diffs() {
+    let content: String = (0..200).map(|i| format!("Line {i} of a long document.\n")).collect();
+    let mirror = Mirror::start(&content);
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| content.replace("Line 100 of", "Line one hundred of"));
+    mirror.edit_and_await(|content| format!("# Heading\n\n{content}"));
+    assert!(mirror.client_log_count("Applied diff") >= 2);
+}
+
+#[test]
+fn compressed_client_is_mirrored_from_shared_broadcasts() {
+    let content: String = (0..200).map(|i| format!("Line {i} of a long document.\n")).collect();
+    let mirror = Mirror::start_with(&content, &[], &["--compress"]);
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| content.replace("Line 100 of", "Line one hundred of"));
+    mirror.edit_and_await(|content| format!("# Heading\n\n{content}"));
+    assert!(mirror.client_log_count("Applied diff") >= 2);
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use common::{connect, read_text, Mirror, Socket};
+use shared::{ClientMessage, Control, ControlReply, FileChange, Sequenced};
+use tokio_tungstenite::tungstenite::Message;
+
+/// Sends `message` and returns the changes sent in answer to it, then the echo
+fn send(socket: &mut Socket, message: &ClientMessage) -> (Vec<FileChange>, ControlReply) {
+    socket.send(Message::Text(serde_json::to_string(message).expect("serialize"))).expect("send");
+    let mut changes = Vec::new();
+    loop {
+        let text = read_text(socket);
+        match serde_json::from_str::<ControlReply>(&text) {
+            Ok(echo @ ControlReply::Echo { .. }) => return (changes, echo),
+            _ => changes.push(serde_json::from_str::<Sequenced>(&text).expect("parse change").change),
+        }
+    }
+}
+
+fn echo(received: ClientMessage, applied: Option<ClientMessage>) -> ControlReply {
+    ControlReply::Echo { received, applied }
+}
+
+#[test]
+fn every_client_message_is_echoed_as_applied() {
+    let content = "# Title\n\nSome text.\n";
+    let mirror = Mirror::start_server(content, &["--echo"]);
+    let mut socket = connect(&mirror.url());
+    let initial: Sequenced = serde_json::from_str(&read_text(&mut socket)).expect("initial content");
+
+    // the range is clamped to the file, and the narrowed content is sent first
+    let subscribe = ClientMessage::SubscribeRange { file_id: "doc.md".to_string(), start: 9, end: 1000 };
+    let (changes, reply) = send(&mut socket, &subscribe);
+    let len = content.chars().count();
+    assert!(matches!(&changes[..], [FileChange::FullContent { content, .. }] if content == "Some text.\n"), "{changes:?}");
+    let applied = ClientMessage::SubscribeRange { file_id: "doc.md".to_string(), start: 9, end: len };
+    assert_eq!(reply, echo(subscribe, Some(applied)));
+
+    // acks never go past the last broadcast seq
+    let ack = ClientMessage::Ack { seq: initial.seq + 100 };
+    assert_eq!(send(&mut socket, &ack), (Vec::new(), echo(ack, Some(ClientMessage::Ack { seq: initial.seq }))));
+
+    // ignored messages are echoed with nothing applied
+    let unwatched = ClientMessage::Resync { file_id: "other.md".to_string() };
+    assert_eq!(send(&mut socket, &unwatched), (Vec::new(), echo(unwatched, None)));
+
+    let pause = ClientMessage::Control(Control::Pause);
+    assert_eq!(send(&mut socket, &pause), (Vec::new(), echo(pause.clone(), Some(pause))));
+}
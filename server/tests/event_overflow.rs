@@ -0,0 +1,39 @@
+mod common;
+
+use std::fs;
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+
+/// Every read waits for the file to settle, which stalls the event processing
+/// while a queue of one event overflows
+const CONFIG: &str = "quiescence_ms = 300\n\n[limits]\nevent_queue_capacity = 1\n";
+
+#[test]
+fn a_stalled_watcher_drops_events_and_reads_the_file_again() {
+    let content = "# Notes\n";
+    let mut mirror = Mirror::start_server_with_files(
+        &[(SOURCE_FILE, content), ("markdown-op.toml", CONFIG)],
+        &["--watch", SOURCE_FILE, "--long-poll", "127.0.0.1:0"],
+    );
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+
+    let mut content = content.to_string();
+    for i in 0..10 {
+        content.push_str(&format!("- item {i}\n"));
+        fs::write(mirror.source_path(), &content).expect("write source file");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    mirror.await_convergence();
+    assert!(mirror.server_log_count("dropping them") > 0, "server output:\n{}", mirror.server_log().join("\n"));
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("reading its files again") > 0));
+    let (_, metrics) = mirror.http_get("/metrics");
+    let dropped: u64 = metrics
+        .lines()
+        .find_map(|line| line.strip_prefix("markdown_op_watch_events_dropped_total "))
+        .and_then(|count| count.parse().ok())
+        .expect("dropped events metric");
+    assert!(dropped > 0);
+
+    // caught up: the next edit is mirrored as usual
+    mirror.edit_and_await(|content| format!("{content}- one more\n"));
+}
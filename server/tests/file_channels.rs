@@ -0,0 +1,39 @@
+mod common;
+
+use std::{fs, thread};
+use common::{connect, next_change, wait_until, Mirror, CONVERGENCE_TIMEOUT, EDIT_INTERVAL};
+use shared::FileChange;
+use tokio_tungstenite::tungstenite::Message;
+
+#[test]
+fn a_flood_on_one_file_does_not_hold_up_clients_following_another() {
+    // a few changes queued per file, and every change a large message
+    let config = "[limits]\nchannel_capacity = 2\n";
+    let mirror = Mirror::start_server_with_files(
+        &[("a.md", "# A\n"), ("b.md", "# B\n"), ("markdown-op.toml", config)],
+        &["--watch", "a.md", "--watch", "b.md", "--no-diff"],
+    );
+    let mut follows_b = connect(&format!("{}/?file=b.md", mirror.url()));
+    assert!(matches!(next_change(&mut follows_b), FileChange::FullContent { file_id, .. } if file_id == "b.md"));
+    // follows every file but reads nothing while a.md is rewritten
+    let mut follows_all = connect(&mirror.url());
+
+    let a = mirror.source_path().with_file_name("a.md");
+    for i in 0..10 {
+        thread::sleep(EDIT_INTERVAL);
+        fs::write(&a, format!("# A {i}\n{}", "Filler text.\n".repeat(150_000))).expect("write a.md");
+    }
+    thread::sleep(EDIT_INTERVAL);
+    fs::write(a.with_file_name("b.md"), "# B\n\nEdited.\n").expect("write b.md");
+    assert!(
+        matches!(next_change(&mut follows_b), FileChange::FullContent { file_id, content, .. } if file_id == "b.md" && content == "# B\n\nEdited.\n")
+    );
+
+    // the other client fell behind on a.md once it read what was sent so far
+    while let Ok(message) = follows_all.read() {
+        if let Message::Close(_) = message {
+            break;
+        }
+    }
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("changes behind, closing the connection") == 1));
+}
@@ -0,0 +1,34 @@
+#!/bin/sh
+# Regenerates the certificates of the mutual TLS tests: a trusted CA with a
+# server and a client certificate signed by it, and a client certificate
+# signed by another CA the server does not trust. Valid for 100 years.
+set -e
+cd "$(dirname "$0")"
+days=36500
+
+key() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1"
+}
+
+ca() {
+    key "$1.key"
+    openssl req -x509 -new -key "$1.key" -subj "/CN=$2" -days $days -out "$1.pem" \
+        -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign"
+}
+
+# signs certificate $1 for common name $2 with CA $3, with the extensions in $4
+cert() {
+    key "$1.key"
+    openssl req -new -key "$1.key" -subj "/CN=$2" -out "$1.csr"
+    printf '%s\n' "basicConstraints=critical,CA:FALSE" "keyUsage=critical,digitalSignature" "$4" > "$1.ext"
+    openssl x509 -req -in "$1.csr" -CA "$3.pem" -CAkey "$3.key" -CAcreateserial -days $days -extfile "$1.ext" -out "$1.pem"
+    rm "$1.csr" "$1.ext"
+}
+
+ca ca "markdown-op test CA"
+ca rogue-ca "markdown-op rogue CA"
+cert server localhost ca "extendedKeyUsage=serverAuth
+subjectAltName=DNS:localhost,IP:127.0.0.1"
+cert client alice ca "extendedKeyUsage=clientAuth"
+cert rogue-client mallory rogue-ca "extendedKeyUsage=clientAuth"
+rm -f ca.srl rogue-ca.srl ca.key rogue-ca.key
@@ -0,0 +1,81 @@
+mod common;
+
+use std::fs;
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT};
+use shared::{CharDiff, DiffStrategy, FileChange, GraphemeDiff};
+
+/// Versions whose edits touch part of a multi-codepoint cluster: a flag made of
+/// two regional indicators, a ZWJ family, a skin tone modifier, a combining accent
+const EDITS: [(&str, &str); 4] = [
+    ("Flags: 🇫🇷 and more", "Flags: 🇫🇮 and more"),
+    ("Family: 👨‍👩‍👧 here", "Family: 👨‍👩‍👦 here"),
+    ("Wave 👋🏽 hello", "Wave 👋🏿 hello"),
+    ("Cafe\u{301} au lait", "Cafe\u{300} au lait"),
+];
+
+/// Char offsets of every cluster boundary of `text`
+fn boundaries(text: &str) -> Vec<usize> {
+    use unicode_segmentation::UnicodeSegmentation;
+    let mut offsets = vec![0];
+    for grapheme in text.graphemes(true) {
+        offsets.push(offsets.last().copied().unwrap_or_default() + grapheme.chars().count());
+    }
+    offsets
+}
+
+/// Applies the changes in order, checking each starts and ends on a cluster
+/// boundary of the content it applies to
+fn apply_checking_boundaries(old: &str, changes: &[FileChange]) -> String {
+    let mut content = old.to_string();
+    for change in changes {
+        let FileChange::Diff { position, delete_count, .. } = change else {
+            panic!("expected a diff, got {change:?}");
+        };
+        let boundaries = boundaries(&content);
+        assert!(
+            boundaries.contains(position) && boundaries.contains(&(position + delete_count)),
+            "{change:?} splits a cluster of {content:?}"
+        );
+        change.try_apply(&mut content).expect("apply");
+    }
+    content
+}
+
+#[test]
+fn grapheme_diff_never_splits_a_cluster() {
+    for (old, new) in EDITS {
+        let changes = GraphemeDiff.diff("doc.md", old, new);
+        assert!(!changes.is_empty());
+        assert_eq!(apply_checking_boundaries(old, &changes), new);
+    }
+}
+
+#[test]
+fn char_diff_splits_the_same_clusters() {
+    // what the grapheme diff is for: the char diff only replaces the codepoint that changed
+    for (old, new) in EDITS {
+        let changes = CharDiff.diff("doc.md", old, new);
+        let split = changes.iter().any(|change| {
+            let range = change.affected_range().expect("diff");
+            let boundaries = boundaries(old);
+            !boundaries.contains(&range.start) || !boundaries.contains(&range.end)
+        });
+        assert!(split, "{old:?} -> {new:?}: {changes:?}");
+    }
+}
+
+#[test]
+fn grapheme_strategy_mirrors_emoji_edits() {
+    // long enough to be sent as diffs
+    let padding = "Plain text around the emoji.\n".repeat(50);
+    let document = |line: &str| format!("{line}\n{padding}");
+    let mut mirror = Mirror::start_server(&document(EDITS[0].0), &["--diff", "grapheme"]);
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+    for (_, new) in EDITS {
+        mirror.write(&document(new));
+        mirror.await_convergence();
+    }
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(mirror.output_path()).is_ok_and(|c| c == mirror.source())));
+    assert!(mirror.client_log_count("Applied diff") >= EDITS.len());
+}
@@ -0,0 +1,35 @@
+mod common;
+
+use std::{fs, io::Write};
+use flate2::{write::GzEncoder, Compression};
+use common::{temp_dir, wait_until, Mirror, CONVERGENCE_TIMEOUT};
+
+fn gzip(content: &str) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(content.as_bytes()).expect("compress");
+    encoder.finish().expect("compress")
+}
+
+#[test]
+fn gzipped_file_is_mirrored_decompressed() {
+    let dir = temp_dir();
+    let gz_path = dir.join("doc.md.gz");
+    let content: String = (0..100).map(|i| format!("Line {i} of a long document.\n")).collect();
+    fs::write(&gz_path, gzip(&content)).expect("write gzipped file");
+    let mut mirror = Mirror::start_server_in(dir, &["doc.md.gz"]);
+    mirror.start_client(&[]);
+    let mirrored = |expected: &str| {
+        wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(mirror.output_path()).is_ok_and(|c| c == expected))
+    };
+    assert!(mirrored(&content), "client output:\n{}", mirror.client_log().join("\n"));
+
+    // a rewrite caught halfway is skipped, the complete file is mirrored
+    let edited = content.replace("Line 50 ", "Line fifty ");
+    let compressed = gzip(&edited);
+    fs::write(&gz_path, &compressed[..compressed.len() / 2]).expect("write partial file");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("not a complete gzip file") == 1));
+    assert!(mirrored(&content));
+    fs::write(&gz_path, &compressed).expect("write gzipped file");
+    assert!(mirrored(&edited), "server output:\n{}", mirror.server_log().join("\n"));
+    assert!(mirror.client_log_count("Applied diff") >= 1);
+}
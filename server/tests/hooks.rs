@@ -0,0 +1,46 @@
+mod common;
+
+use std::{fs, thread, time::Duration};
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+
+/// One hook for the watched file recording what it was run with, one for a
+/// file that is not watched
+const CONFIG: &str = r#"
+watch = ["doc.md"]
+
+[[hooks]]
+file = "doc.md"
+command = 'echo "$MARKDOWN_OP_FILE_ID $MARKDOWN_OP_DIGEST" >> hooks.log'
+
+[[hooks]]
+file = "other.md"
+command = "echo other >> other.log"
+"#;
+
+fn invocations(mirror: &Mirror) -> Vec<String> {
+    fs::read_to_string(mirror.dir.join("hooks.log")).unwrap_or_default().lines().map(str::to_string).collect()
+}
+
+#[test]
+fn hook_runs_once_per_broadcast_change() {
+    let mut mirror = Mirror::start_server_with_files(&[(SOURCE_FILE, "# Index\n"), ("markdown-op.toml", CONFIG)], &[]);
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+
+    let mut digests = Vec::new();
+    for page in 1..=3 {
+        mirror.edit_and_await(|content| format!("{content}\n- Page {page}\n"));
+        digests.push(shared::digest(&mirror.source()));
+        let expected: Vec<_> = digests.iter().map(|digest| format!("{SOURCE_FILE} {digest}")).collect();
+        assert!(
+            wait_until(CONVERGENCE_TIMEOUT, || invocations(&mirror) == expected),
+            "hook runs: {:?}, server output:\n{}",
+            invocations(&mirror),
+            mirror.server_log().join("\n")
+        );
+    }
+    // nothing runs late, nor for the file that did not change
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(invocations(&mirror).len(), 3);
+    assert!(!mirror.dir.join("other.log").exists());
+}
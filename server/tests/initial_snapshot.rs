@@ -0,0 +1,21 @@
+mod common;
+
+use common::{Mirror, SOURCE_FILE};
+
+fn document(changed_line: &str) -> String {
+    (0..100).map(|i| if i == 40 { format!("{changed_line}\n") } else { format!("Line {i} of a long document.\n") }).collect()
+}
+
+#[test]
+fn client_starts_from_the_snapshot_and_is_diffed_to_the_live_file() {
+    let mut mirror = Mirror::start_server_with_files(
+        &[(SOURCE_FILE, &document("Line 40, as edited since the snapshot.")), ("golden.md", &document("Line 40 of the golden file."))],
+        &[SOURCE_FILE, "--initial-snapshot", "golden.md"],
+    );
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+    assert_eq!(mirror.client_log_count("Updated file"), 1);
+    assert!(mirror.client_log_count("Applied diff") >= 1);
+    mirror.edit_and_await(|content| content.replace("Line 70 ", "Line seventy "));
+    assert!(!mirror.client_log().iter().any(|line| line.contains("Ignoring diff") || line.contains("requesting resync")));
+}
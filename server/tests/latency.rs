@@ -0,0 +1,37 @@
+mod common;
+
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+use shared::Sequenced;
+
+#[test]
+fn changes_are_stamped_in_detection_order() {
+    let content: String = (0..100).map(|i| format!("Line {i} of a long document.\n")).collect();
+    // an edit can be seen half written, and replaying it must not turn into full content
+    let config = "[history]\nmax_replay_ratio = 0\n";
+    let mut mirror = Mirror::start_server_with_files(
+        &[(SOURCE_FILE, &content), ("markdown-op.toml", config)],
+        &["--watch", SOURCE_FILE, "--history", "--long-poll", "127.0.0.1:0"],
+    );
+    let poll = |since| -> Vec<Sequenced> { serde_json::from_str(&mirror.changes_since(since)).expect("JSON changes") };
+    // initial content is not a detected change
+    let initial = poll(None);
+    assert_eq!(initial[0].detected_at, None);
+
+    let mut last = initial[0].seq;
+    let mut stamps = Vec::new();
+    for i in 0..3 {
+        mirror.edit(|content| content.replace(&format!("Line {i} "), &format!("Line #{i} ")));
+        for message in poll(Some(last)) {
+            stamps.push(message.detected_at.expect("detection time"));
+            last = message.seq;
+        }
+    }
+    assert!(stamps.len() >= 3);
+    assert!(stamps.windows(2).all(|pair| pair[0] <= pair[1]), "{stamps:?}");
+    assert!(stamps.first() < stamps.last(), "{stamps:?}");
+
+    mirror.start_client(&["--report-latency"]);
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| content.replace("Line 50 ", "Line #50 "));
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.client_log_count("after the server detected it") >= 1));
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use std::{fs, net::{TcpListener, TcpStream}};
+use common::{spawn_client, temp_dir, wait_until, CONVERGENCE_TIMEOUT};
+use shared::{ClientMessage, FileChange, PositionUnit, Received, Sequenced};
+use tokio_tungstenite::tungstenite::{self, handshake::server::{Request, Response}, Message, WebSocket};
+
+/// One accepted client connection of a hand-driven server, and the URI it asked for
+fn accept(listener: &TcpListener) -> (WebSocket<TcpStream>, String) {
+    let (stream, _) = listener.accept().expect("accept");
+    // a client that stops answering fails the test instead of hanging it
+    stream.set_read_timeout(Some(CONVERGENCE_TIMEOUT)).expect("read timeout");
+    let mut uri = String::new();
+    #[allow(clippy::result_large_err)]
+    let socket = tungstenite::accept_hdr(stream, |request: &Request, response: Response| {
+        uri = request.uri().to_string();
+        Ok(response)
+    })
+    .expect("handshake");
+    (socket, uri)
+}
+
+fn send_text(socket: &mut WebSocket<TcpStream>, text: &str) {
+    socket.send(Message::Text(text.to_string())).expect("send");
+}
+
+fn send(socket: &mut WebSocket<TcpStream>, seq: u64, change: FileChange) {
+    send_text(socket, &serde_json::to_string(&Sequenced::new(seq, change)).expect("serialize"));
+}
+
+/// The client's next message
+fn receive(socket: &mut WebSocket<TcpStream>) -> ClientMessage {
+    loop {
+        if let Message::Text(text) = socket.read().expect("read") {
+            return serde_json::from_str(&text).expect("client message");
+        }
+    }
+}
+
+fn full_content(content: &str) -> FileChange {
+    FileChange::FullContent { file_id: "doc.md".to_string(), content: content.to_string(), last_modified: None }
+}
+
+#[test]
+fn changes_of_an_unknown_kind_are_acked_and_skipped() {
+    let dir = temp_dir();
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let url = format!("ws://{}", listener.local_addr().expect("address"));
+    let client = spawn_client(&dir, &url, &[]);
+    let (mut socket, _) = accept(&listener);
+
+    send(&mut socket, 1, full_content("# Title\n"));
+    assert_eq!(receive(&mut socket), ClientMessage::Ack { seq: 1 });
+    // a kind of change only a newer server would send
+    send_text(&mut socket, r#"{"seq":2,"detected_at":{"secs_since_epoch":1,"nanos_since_epoch":0},"Rename":{"file_id":"doc.md","to":"index.md"}}"#);
+    assert_eq!(receive(&mut socket), ClientMessage::Ack { seq: 2 });
+    let diff = FileChange::Diff { file_id: "doc.md".to_string(), position: 8, delete_count: 0, insert_text: "More.\n".to_string(), unit: PositionUnit::Chars };
+    send(&mut socket, 3, diff);
+    assert_eq!(receive(&mut socket), ClientMessage::Ack { seq: 3 });
+
+    let output = dir.join("out").join("client1_README.md");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(&output).is_ok_and(|c| c == "# Title\nMore.\n")));
+    client.wait_for_line("Ignoring a change of unknown kind Rename");
+    assert!(client.lines().iter().all(|line| !line.contains("Error processing message")));
+}
+
+#[test]
+fn a_future_kind_of_change_parses_as_unknown() {
+    let future = r#"{"seq":7,"detected_at":{"secs_since_epoch":1,"nanos_since_epoch":0},"Rename":{"file_id":"doc.md","to":"index.md"}}"#;
+    assert_eq!(Received::parse(future).expect("parse"), Received::Unknown { kind: "Rename".to_string(), seq: Some(7) });
+    let in_a_batch: Vec<serde_json::Value> = serde_json::from_str(&format!("[{future}]")).expect("JSON");
+    let unknown = in_a_batch.into_iter().map(Received::from_value).collect::<Result<Vec<_>, _>>().expect("parse");
+    assert_eq!(unknown, [Received::Unknown { kind: "Rename".to_string(), seq: Some(7) }]);
+
+    let known = serde_json::to_string(&Sequenced::new(8, full_content("# Title\n"))).expect("serialize");
+    assert_eq!(Received::parse(&known).expect("parse"), Received::Change(Sequenced::new(8, full_content("# Title\n"))));
+    // a known kind that is broken is still an error, as is a change with two kinds
+    assert!(Received::parse(r#"{"seq":9,"Diff":{"file_id":"doc.md","position":"one"}}"#).is_err());
+    assert!(Received::parse(r#"{"seq":9,"Rename":{},"Move":{}}"#).is_err());
+}
+
+#[test]
+fn corrupt_changes_trigger_a_resync_then_a_reconnect() {
+    let dir = temp_dir();
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let url = format!("ws://{}", listener.local_addr().expect("address"));
+    let client = spawn_client(&dir, &url, &[]);
+    let (mut socket, _) = accept(&listener);
+
+    send(&mut socket, 1, full_content("# Title\n"));
+    assert_eq!(receive(&mut socket), ClientMessage::Ack { seq: 1 });
+    // a diff whose position is mangled is lost, so the file is asked for again
+    send_text(&mut socket, r#"{"seq":2,"Diff":{"file_id":"doc.md","position":"8","delete_count":0,"insert_text":"x"}}"#);
+    assert_eq!(receive(&mut socket), ClientMessage::Resync { file_id: "doc.md".to_string() });
+    send(&mut socket, 3, full_content("# Title\nResent.\n"));
+    assert_eq!(receive(&mut socket), ClientMessage::Ack { seq: 3 });
+
+    // past the limit of unreadable messages in a row, the client starts over
+    for _ in 0..3 {
+        send_text(&mut socket, "\u{0}garbage");
+    }
+    let (mut socket, uri) = accept(&listener);
+    assert!(!uri.contains("since"), "reconnected to {uri}, expected no resume point");
+    send(&mut socket, 4, full_content("# Title\nAfter reconnecting.\n"));
+    assert_eq!(receive(&mut socket), ClientMessage::Ack { seq: 4 });
+    client.wait_for_line("3 corrupt messages in a row, reconnecting");
+    let output = dir.join("out").join("client1_README.md");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(&output).is_ok_and(|c| c == "# Title\nAfter reconnecting.\n")));
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use std::fs;
+use common::{spawn_client, Mirror, SOURCE_FILE};
+
+const CONFIG: &str = r#"
+watch = ["doc.md"]
+
+[limits]
+max_message_bytes = 65536
+"#;
+
+/// Markdown of about `len` bytes
+fn document(len: usize) -> String {
+    "A line of a large document.\n".repeat(len / 28)
+}
+
+#[test]
+fn content_near_the_limit_is_mirrored_and_over_it_held_back() {
+    let near_limit = document(56_000);
+    let mut mirror = Mirror::start_server_with_files(&[(SOURCE_FILE, &near_limit), ("markdown-op.toml", CONFIG)], &[]);
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+
+    mirror.write(&document(70_000));
+    let held_back = || mirror.client_log_count("does not fit in a message of at most 65536 bytes");
+    assert!(
+        common::wait_until(common::CONVERGENCE_TIMEOUT, || held_back() >= 1),
+        "client output:\n{}",
+        mirror.client_log().join("\n")
+    );
+    assert_eq!(fs::read_to_string(mirror.output_path()).expect("output"), near_limit);
+
+    // back under the limit, the file is mirrored again
+    mirror.edit_and_await(|_| document(50_000));
+}
+
+#[test]
+fn client_gives_up_on_a_message_over_its_limit() {
+    let mirror = Mirror::start_server(&document(30_000), &[]);
+    let mut client = spawn_client(&mirror.dir, &mirror.url(), &["--max-message-bytes", "20000"]);
+    assert!(!client.wait_for_exit().success());
+    client.wait_for_line("over the --max-message-bytes limit of 20000");
+    assert!(client.lines().iter().all(|line| !line.contains("Reconnecting")));
+}
@@ -0,0 +1,28 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs, os::unix::fs::PermissionsExt};
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT};
+
+#[test]
+fn a_mode_change_is_broadcast_without_the_content() {
+    let mirror = Mirror::start_with("# Secrets\n", &["--watch-metadata"], &[]);
+    mirror.await_convergence();
+    fs::set_permissions(mirror.source_path(), fs::Permissions::from_mode(0o600)).expect("chmod");
+    assert!(
+        wait_until(CONVERGENCE_TIMEOUT, || mirror.client_log_count("Metadata of doc.md changed, mode 600") == 1),
+        "client output:\n{}",
+        mirror.client_log().join("\n")
+    );
+    assert_eq!(mirror.client_log_count("Applied diff"), 0);
+}
+
+#[test]
+fn metadata_changes_are_ignored_by_default() {
+    let mirror = Mirror::start("# Secrets\n");
+    mirror.await_convergence();
+    fs::set_permissions(mirror.source_path(), fs::Permissions::from_mode(0o600)).expect("chmod");
+    mirror.edit_and_await(|content| format!("{content}\nMore.\n"));
+    assert_eq!(mirror.client_log_count("Metadata of"), 0);
+}
@@ -0,0 +1,48 @@
+mod common;
+
+use std::{fs, thread, time::Duration};
+use common::{spawn_client, wait_until, Mirror, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+
+const READY: &str = "<!-- ready -->";
+
+#[test]
+fn content_without_the_marker_is_withheld_until_it_has_it() {
+    let mut mirror = Mirror::start_server("# Draft\n", &["--mirror-when-marker", READY]);
+    mirror.start_client(&[]);
+    mirror.write("# Draft\n\nStill writing.\n");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("Not mirroring doc.md: content has no <!-- ready --> line") >= 1));
+    // give a broadcast that should not have happened time to arrive
+    thread::sleep(Duration::from_millis(300));
+    assert!(!mirror.output_path().exists(), "{:?}", fs::read_to_string(mirror.output_path()));
+
+    let ready = format!("# Draft\n\nDone.\n\n{READY}\n");
+    mirror.write(&ready);
+    mirror.await_convergence();
+
+    // taking the marker out again leaves clients with the last ready version
+    mirror.write("# Draft\n\nRewriting.\n");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("Not mirroring") >= 2));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(fs::read_to_string(mirror.output_path()).expect("output"), ready);
+}
+
+#[test]
+fn a_client_connecting_while_content_is_withheld_gets_the_last_mirrored_version() {
+    let ready = format!("# Notes\n{READY}\n");
+    let config = format!("[mirror_when]\nmarker = \"{READY}\"\nmatches = \"(?m)^# \"\n");
+    let mut mirror = Mirror::start_server_with_files(&[(SOURCE_FILE, &ready), ("markdown-op.toml", &config)], &["--watch", SOURCE_FILE]);
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+    // has the marker, but no heading
+    mirror.write(&format!("Notes without a heading\n{READY}\n"));
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("does not match /(?m)^# /") >= 1));
+
+    let other_dir = common::temp_dir();
+    let other = spawn_client(&other_dir, &mirror.url(), &[]);
+    let output = other_dir.join("out").join("client1_README.md");
+    assert!(
+        wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(&output).is_ok_and(|content| content == ready)),
+        "client output:\n{}",
+        other.lines().join("\n")
+    );
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use std::{fs::{self, File}, path::Path, time::{Duration, SystemTime}};
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT};
+
+/// Mtimes are compared loosely, some file systems store them coarsely
+const TOLERANCE: Duration = Duration::from_secs(1);
+
+fn modified(path: &Path) -> SystemTime {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).expect("modification time")
+}
+
+fn same_mtime(a: &Path, b: &Path) -> bool {
+    let (a, b) = (modified(a), modified(b));
+    a.duration_since(b).or_else(|_| b.duration_since(a)).is_ok_and(|delta| delta <= TOLERANCE)
+}
+
+#[test]
+fn mirror_keeps_the_modification_time_of_the_source() {
+    let mut mirror = Mirror::start_server("# Title\n\nFirst paragraph.\n", &[]);
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    // a metadata change is not broadcast, the client gets it with the initial content
+    File::options().write(true).open(mirror.source_path()).and_then(|file| file.set_modified(an_hour_ago)).expect("set mtime");
+    mirror.start_client(&["--preserve-mtime"]);
+    mirror.await_convergence();
+    assert!(
+        wait_until(CONVERGENCE_TIMEOUT, || same_mtime(&mirror.source_path(), &mirror.output_path())),
+        "output mtime {:?}, source mtime {:?}",
+        modified(&mirror.output_path()),
+        modified(&mirror.source_path()),
+    );
+    mirror.edit_and_await(|content| format!("{content}\nSecond paragraph.\n"));
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || same_mtime(&mirror.source_path(), &mirror.output_path())));
+}
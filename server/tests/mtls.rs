@@ -0,0 +1,57 @@
+mod common;
+
+use std::{fs, path::PathBuf};
+use common::{spawn_client, wait_until, Mirror, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+
+/// A certificate in `tests/fixtures/tls`, see `generate.sh` there
+fn fixture(name: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name);
+    path.to_string_lossy().into_owned()
+}
+
+fn start_server() -> Mirror {
+    let (cert, key, client_ca) = (fixture("server.pem"), fixture("server.key"), fixture("ca.pem"));
+    Mirror::start_server_with_files(
+        &[(SOURCE_FILE, "# Title\n")],
+        &[SOURCE_FILE, "--tls-cert", &cert, "--tls-key", &key, "--tls-client-ca", &client_ca],
+    )
+}
+
+#[test]
+fn client_with_a_trusted_certificate_is_mirrored_under_its_common_name() {
+    let mirror = start_server();
+    assert_eq!(mirror.server_log_count("WebSocket server listening on wss://"), 1);
+    let (ca, cert, key) = (fixture("ca.pem"), fixture("client.pem"), fixture("client.key"));
+    let url = format!("wss://localhost:{}", mirror.port);
+    let client = spawn_client(&mirror.dir, &url, &["--tls-ca", &ca, "--tls-cert", &cert, "--tls-key", &key]);
+    let output = mirror.output_path();
+    assert!(
+        wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(&output).is_ok_and(|content| content == "# Title\n")),
+        "client output:\n{}",
+        client.lines().join("\n")
+    );
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("authenticated as alice") == 1));
+}
+
+#[test]
+fn client_with_an_untrusted_certificate_is_rejected_before_any_content() {
+    let mirror = start_server();
+    let (ca, cert, key) = (fixture("ca.pem"), fixture("rogue-client.pem"), fixture("rogue-client.key"));
+    let url = format!("wss://localhost:{}", mirror.port);
+    let mut client = spawn_client(&mirror.dir, &url, &["--tls-ca", &ca, "--tls-cert", &cert, "--tls-key", &key]);
+    let status = client.wait_for_exit();
+    assert!(!status.success());
+    client.wait_for_line("TLS: ");
+    assert!(!mirror.output_path().exists());
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("Error from client") >= 1));
+    assert_eq!(mirror.server_log_count("authenticated as"), 0);
+}
+
+#[test]
+fn client_without_a_certificate_is_rejected() {
+    let mirror = start_server();
+    let url = format!("wss://localhost:{}", mirror.port);
+    let mut client = spawn_client(&mirror.dir, &url, &["--tls-ca", &fixture("ca.pem")]);
+    assert!(!client.wait_for_exit().success());
+    assert!(!mirror.output_path().exists());
+}
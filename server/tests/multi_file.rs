@@ -0,0 +1,45 @@
+mod common;
+
+use std::fs;
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT};
+
+const FILES: [&str; 3] = ["index.md", "guide.md", "api.md"];
+const WATCH_ARGS: [&str; 6] = ["--watch", "index.md", "--watch", "guide.md", "--watch", "api.md"];
+
+#[test]
+fn each_watched_file_gets_its_own_output_file() {
+    let files: Vec<_> = FILES.iter().map(|name| (*name, "# Draft\n")).collect();
+    let mut mirror = Mirror::start_server_with_files(&files, &WATCH_ARGS);
+    mirror.start_client(&["--output-template", "{file_id}"]);
+
+    for name in FILES {
+        fs::write(mirror.dir.join(name), format!("# {name}\n\nOnly in {name}.\n")).expect("write file");
+    }
+    for name in FILES {
+        let output = mirror.dir.join("out").join(name);
+        let expected = format!("# {name}\n\nOnly in {name}.\n");
+        assert!(
+            wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(&output).is_ok_and(|c| c == expected)),
+            "{} was not mirrored, client output:\n{}",
+            name,
+            mirror.client_log().join("\n")
+        );
+    }
+    assert_eq!(mirror.client_log_count("are both mirrored to"), 0);
+}
+
+#[test]
+fn files_sharing_an_output_file_are_warned_about() {
+    let files: Vec<_> = FILES.iter().map(|name| (*name, "# Draft\n")).collect();
+    let mut mirror = Mirror::start_server_with_files(&files, &WATCH_ARGS);
+    mirror.start_client(&[]);
+
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.client_log_count("are both mirrored to") == 1));
+    for name in FILES {
+        fs::write(mirror.dir.join(name), format!("# {name}\n")).expect("write file");
+    }
+    // the three initial full contents, then an update of each file
+    let updates = || mirror.client_log_count("Updated file") + mirror.client_log_count("Applied diff");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || updates() >= 6), "client output:\n{}", mirror.client_log().join("\n"));
+    assert_eq!(mirror.client_log_count("are both mirrored to"), 1);
+}
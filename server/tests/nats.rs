@@ -0,0 +1,96 @@
+mod common;
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+use shared::{FileChange, Sequenced};
+
+/// Just enough of a NATS server to accept one publisher: it collects what is
+/// published and sends a PING after the first message
+struct FakeNats {
+    addr: String,
+    published: Arc<Mutex<Vec<(String, String)>>>,
+    pongs: mpsc::Receiver<()>,
+}
+
+impl FakeNats {
+    fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("address").to_string();
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let (pong_tx, pongs) = mpsc::channel();
+        let collected = Arc::clone(&published);
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("accept");
+            let mut writer = stream.try_clone().expect("clone stream");
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"INFO {\"server_id\":\"fake\",\"max_payload\":1048576}\r\n").expect("write INFO");
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|read| read > 0) {
+                let command = std::mem::take(&mut line);
+                let command = command.trim_end();
+                if command == "PING" {
+                    writer.write_all(b"PONG\r\n").expect("write PONG");
+                } else if command == "PONG" {
+                    let _ = pong_tx.send(());
+                } else if let Some(args) = command.strip_prefix("PUB ") {
+                    let (subject, len) = args.split_once(' ').expect("PUB <subject> <len>");
+                    let mut payload = vec![0; len.parse::<usize>().expect("length") + 2];
+                    reader.read_exact(&mut payload).expect("payload");
+                    assert!(payload.ends_with(b"\r\n"), "payload not followed by CRLF");
+                    payload.truncate(payload.len() - 2);
+                    let mut collected = collected.lock().expect("lock");
+                    if collected.is_empty() {
+                        writer.write_all(b"PING\r\n").expect("write PING");
+                    }
+                    collected.push((subject.to_string(), String::from_utf8(payload).expect("UTF-8 payload")));
+                }
+            }
+        });
+        Self { addr, published, pongs }
+    }
+
+    fn published(&self) -> Vec<(String, Sequenced)> {
+        let published = self.published.lock().expect("lock");
+        published
+            .iter()
+            .map(|(subject, payload)| (subject.clone(), serde_json::from_str(payload).expect("JSON change")))
+            .collect()
+    }
+}
+
+#[test]
+fn every_broadcast_change_is_published_to_nats() {
+    let nats = FakeNats::start();
+    let mirror = Mirror::start_with("# Title\n", &["--nats", &nats.addr], &[]);
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| format!("{content}\nFirst paragraph.\n"));
+    mirror.edit_and_await(|content| format!("{content}\nSecond paragraph.\n"));
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || nats.published().len() >= 2), "published: {:?}", nats.published());
+    let published = nats.published();
+    assert!(published.iter().all(|(subject, _)| subject == "markdown-op.doc_md"), "{published:?}");
+    let seqs: Vec<u64> = published.iter().map(|(_, message)| message.seq).collect();
+    assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]), "{seqs:?}");
+    // the same content the client mirrored, as the last change published
+    let Some((_, Sequenced { change: FileChange::FullContent { file_id, content, .. }, .. })) = published.last() else {
+        panic!("small files are sent as full content: {published:?}");
+    };
+    assert_eq!(file_id, SOURCE_FILE);
+    assert_eq!(*content, mirror.source());
+    nats.pongs.recv_timeout(CONVERGENCE_TIMEOUT).expect("the server's PING answered");
+    assert_eq!(mirror.server_log_count("Publishing changes to NATS"), 1);
+}
+
+#[test]
+fn broker_outage_does_not_hold_up_clients() {
+    // nothing listens on the port anymore
+    let addr = TcpListener::bind("127.0.0.1:0").and_then(|listener| listener.local_addr()).expect("free port").to_string();
+    let mirror = Mirror::start_with("# Title\n", &["--nats", &addr], &[]);
+    mirror.edit_and_await(|content| format!("{content}\nStill mirrored.\n"));
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("Can't connect to NATS") >= 1));
+    mirror.edit_and_await(|content| format!("{content}\nAnd again.\n"));
+}
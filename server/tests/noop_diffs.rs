@@ -0,0 +1,61 @@
+use shared::{DiffStrategyKind, FileChange};
+
+const STRATEGIES: [DiffStrategyKind; 5] = [
+    DiffStrategyKind::Char,
+    DiffStrategyKind::Line,
+    DiffStrategyKind::FrontMatter,
+    DiffStrategyKind::Grapheme,
+    DiffStrategyKind::Word,
+];
+
+fn apply(old: &str, changes: &[FileChange]) -> String {
+    let mut content = old.to_string();
+    for change in changes {
+        change.try_apply(&mut content).expect("apply");
+    }
+    content
+}
+
+#[test]
+fn identical_content_produces_no_changes() {
+    let long_line = format!("{}\n", "wide | table | row ".repeat(40));
+    let documents = [
+        String::new(),
+        "\n".to_string(),
+        "# Title\n\nSome text.\n".to_string(),
+        "no trailing newline".to_string(),
+        "---\ntitle: Doc\n---\n# Body\n".to_string(),
+        "flags 🇵🇹 and e\u{301} and 👩‍👩‍👧\n".to_string(),
+        long_line,
+    ];
+    for kind in STRATEGIES {
+        for document in &documents {
+            let changes = kind.strategy().diff("doc.md", document, document);
+            assert!(changes.is_empty(), "{kind:?} on {document:?}: {changes:?}");
+        }
+    }
+}
+
+#[test]
+fn diffs_never_contain_a_change_that_does_nothing() {
+    let long_old = format!("{}\n", "a".repeat(300));
+    let long_new = format!("{}b\n", "a".repeat(300));
+    let edits = [
+        ("", "# Added\n"),
+        ("# Removed\n", ""),
+        ("abc", "abcdef"),
+        ("abcdef", "abc"),
+        ("def", "abcdef"),
+        ("line one\nline two\n", "line one\nline 2\nline three\n"),
+        ("---\ntitle: A\n---\nbody\n", "---\ntitle: B\n---\nbody\n"),
+        (long_old.as_str(), long_new.as_str()),
+    ];
+    for kind in STRATEGIES {
+        for (old, new) in edits {
+            let changes = kind.strategy().diff("doc.md", old, new);
+            assert!(!changes.iter().any(FileChange::is_noop), "{kind:?} on {old:?} -> {new:?}: {changes:?}");
+            assert_eq!(apply(old, &changes), new, "{kind:?}");
+        }
+    }
+    assert!(FileChange::create_diff("doc.md", "same", "same").is_empty());
+}
@@ -0,0 +1,37 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs, os::unix::fs::PermissionsExt, process::Command};
+use common::{binary, temp_dir, Mirror};
+
+#[test]
+fn the_output_directory_is_created_with_the_given_mode() {
+    let mirror = Mirror::start_with("# Title\n", &[], &["--output-dir-mode", "750"]);
+    mirror.await_convergence();
+    let mode = fs::metadata(mirror.dir.join("out")).expect("output dir").permissions().mode();
+    assert_eq!(mode & 0o7777, 0o750);
+}
+
+#[test]
+fn a_directory_that_is_not_writable_fails_before_connecting() {
+    let dir = temp_dir();
+    let read_only = dir.join("read-only");
+    fs::create_dir(&read_only).expect("create dir");
+    fs::set_permissions(&read_only, fs::Permissions::from_mode(0o555)).expect("chmod");
+    if fs::write(read_only.join("probe"), "").is_ok() {
+        // permissions don't apply to root
+        eprintln!("skipping, {} is writable anyway", read_only.display());
+        return;
+    }
+    // nothing listens there, the client must not get as far as trying
+    let output = Command::new(binary("client"))
+        .args(["--server-url", "ws://127.0.0.1:9", "--output-dir"])
+        .arg(&read_only)
+        .output()
+        .expect("run client");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("is not writable"), "{stderr}");
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Connect"), "{}", String::from_utf8_lossy(&output.stdout));
+}
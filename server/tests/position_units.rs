@@ -0,0 +1,95 @@
+mod common;
+
+use common::{connect, next_change, Mirror};
+use shared::{ApplyError, FileChange, PositionUnit};
+
+/// 'b' is char 2, UTF-16 offset 3 and byte 5
+const TEXT: &str = "a👋b";
+
+fn insert(position: usize, unit: PositionUnit) -> FileChange {
+    FileChange::Diff { file_id: "doc.md".to_string(), position, delete_count: 0, insert_text: "_".to_string(), unit }
+}
+
+fn applied(change: &FileChange) -> Result<String, ApplyError> {
+    let mut content = TEXT.to_string();
+    change.try_apply(&mut content).map(|()| content)
+}
+
+#[test]
+fn chars_count_scalar_values() {
+    assert_eq!(applied(&insert(2, PositionUnit::Chars)).expect("apply"), "a👋_b");
+    assert_eq!(
+        applied(&insert(4, PositionUnit::Chars)),
+        Err(ApplyError::OutOfRange { position: 4, delete_count: 0, len: 3, unit: PositionUnit::Chars })
+    );
+}
+
+#[test]
+fn utf16_counts_code_units() {
+    assert_eq!(applied(&insert(3, PositionUnit::Utf16)).expect("apply"), "a👋_b");
+    // between the two halves of the surrogate pair
+    let error = applied(&insert(2, PositionUnit::Utf16)).expect_err("splits the emoji");
+    assert_eq!(error.to_string(), "diff at position 2 deleting 0 is out of range for content of 4 UTF-16 code units");
+}
+
+#[test]
+fn bytes_count_utf8_bytes() {
+    assert_eq!(applied(&insert(5, PositionUnit::Bytes)).expect("apply"), "a👋_b");
+    let delete_emoji = FileChange::Diff {
+        file_id: "doc.md".to_string(),
+        position: 1,
+        delete_count: 4,
+        insert_text: String::new(),
+        unit: PositionUnit::Bytes,
+    };
+    assert_eq!(applied(&delete_emoji).expect("apply"), "ab");
+    assert!(applied(&insert(3, PositionUnit::Bytes)).is_err(), "inside the emoji");
+}
+
+#[test]
+fn conversion_labels_the_diff_with_its_unit() {
+    let diff = insert(2, PositionUnit::Chars);
+    for (unit, position) in [(PositionUnit::Utf16, 3), (PositionUnit::Bytes, 5)] {
+        let converted = diff.to_unit(TEXT, unit).expect("counts differently");
+        assert_eq!(converted, insert(position, unit));
+        assert_eq!(applied(&converted), applied(&diff));
+    }
+    // nothing to convert before the emoji
+    assert_eq!(insert(1, PositionUnit::Chars).to_unit(TEXT, PositionUnit::Bytes), None);
+}
+
+#[test]
+fn unit_is_only_on_the_wire_when_not_chars() {
+    let json = serde_json::to_string(&insert(2, PositionUnit::Chars)).expect("serialize");
+    assert!(!json.contains("unit"), "{json}");
+    // diffs from before the unit existed count chars
+    let old: FileChange =
+        serde_json::from_str(r#"{"Diff":{"file_id":"doc.md","position":2,"delete_count":0,"insert_text":"_"}}"#).expect("parse");
+    assert_eq!(old, insert(2, PositionUnit::Chars));
+
+    let json = serde_json::to_string(&insert(5, PositionUnit::Bytes)).expect("serialize");
+    assert!(json.contains(r#""unit":"bytes""#), "{json}");
+    assert_eq!(serde_json::from_str::<FileChange>(&json).expect("parse"), insert(5, PositionUnit::Bytes));
+}
+
+#[test]
+fn connections_asking_for_bytes_get_byte_offsets() {
+    let line = "Wave 👋 here.\n";
+    let document = |line: &str| format!("{line}{}", "Plain text after the emoji.\n".repeat(50));
+    let mirror = Mirror::start_server(&document(line), &[]);
+    let mut socket = connect(&format!("{}/?positions=bytes", mirror.url()));
+    let FileChange::FullContent { mut content, .. } = next_change(&mut socket) else {
+        panic!("expected the initial content");
+    };
+
+    let edited = document("Wave 👋 HERE.\n");
+    mirror.write(&edited);
+    let mut diffs = Vec::new();
+    while content != edited {
+        let change = next_change(&mut socket);
+        change.try_apply(&mut content).expect("byte offsets apply");
+        diffs.push(change);
+    }
+    // "here" is at byte 10, while it is char 7
+    assert!(matches!(diffs[0], FileChange::Diff { position: 10, unit: PositionUnit::Bytes, .. }), "{diffs:?}");
+}
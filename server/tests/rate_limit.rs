@@ -0,0 +1,30 @@
+mod common;
+
+use std::{fs, thread, time::Duration};
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+
+const CONFIG: &str = r#"
+watch = ["doc.md"]
+debounce_ms = 0
+min_read_interval_ms = 0
+max_broadcasts_per_sec = 10
+throttled_interval_ms = 300
+"#;
+
+#[test]
+fn file_rewritten_too_often_is_broadcast_periodically() {
+    let mut mirror = Mirror::start_server_with_files(&[(SOURCE_FILE, "Version 0\n"), ("markdown-op.toml", CONFIG)], &[]);
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+    // about 1.5s of rewrites, far more than 10 per second
+    for version in 1..=300 {
+        fs::write(mirror.source_path(), format!("Version {version}\n")).expect("write source file");
+        thread::sleep(Duration::from_millis(5));
+    }
+    mirror.await_convergence();
+    assert_eq!(mirror.server_log_count("broadcasting it every 300ms"), 1);
+    // 10 changes before the limiter engages, then one every 300ms
+    let updates = mirror.client_log_count("Updated file");
+    assert!(updates <= 25, "{} updates broadcast:\n{}", updates, mirror.client_log().join("\n"));
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("calmed down") == 1));
+}
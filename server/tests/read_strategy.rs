@@ -0,0 +1,69 @@
+mod common;
+
+use common::{Mirror, SOURCE_FILE};
+use shared::{FileChange, Sequenced};
+
+/// Rewrites the watched file's content
+type Edit = Box<dyn Fn(&str) -> String>;
+
+/// Edits applied to the watched file, in order; `None` rewrites it unchanged
+fn edits() -> Vec<Option<Edit>> {
+    vec![
+        Some(Box::new(|content| content.replace("Line 10 ", "Line ten "))),
+        None,
+        Some(Box::new(|content| format!("# Título ✓\n\n{content}"))),
+        Some(Box::new(|content| content.lines().filter(|line| !line.contains('7')).map(|line| format!("{line}\n")).collect())),
+        Some(Box::new(|content| format!("{content}{}\n", "ünïcödé ".repeat(100)))),
+        None,
+        // small enough to be sent as full content
+        Some(Box::new(|_| "# Short\n".to_string())),
+        Some(Box::new(|_| (0..150).map(|i| format!("Back to line {i}\n")).collect())),
+    ]
+}
+
+/// Every change the server broadcast for `edits()` when reading with `read_strategy`
+fn broadcast_changes(read_strategy: &str) -> Vec<Sequenced> {
+    let content: String = (0..150).map(|i| format!("Line {i} of a long document.\n")).collect();
+    // every change is replayed, however many piled up between two polls
+    let config = "[history]\nmax_replay_ratio = 0\n";
+    let mirror = Mirror::start_server_with_files(
+        &[(SOURCE_FILE, &content), ("markdown-op.toml", config)],
+        &["--watch", SOURCE_FILE, "--history", "--long-poll", "127.0.0.1:0", "--read-strategy", read_strategy],
+    );
+    let poll = |since| -> Vec<Sequenced> { serde_json::from_str(&mirror.changes_since(since)).expect("JSON changes") };
+    let initial = poll(None);
+    let mut last = initial[0].seq;
+    let mut mirrored = content;
+    let mut changes = Vec::new();
+    for edit in edits() {
+        match edit {
+            Some(edit) => mirror.edit(edit),
+            None => mirror.write(&mirror.source()),
+        }
+        // an unchanged rewrite broadcasts nothing, which the next edit's changes would show
+        while mirrored != mirror.source() {
+            for mut message in poll(Some(last)) {
+                // the two runs write the file at different times
+                message.detected_at = None;
+                if let FileChange::FullContent { last_modified, .. } = &mut message.change {
+                    *last_modified = None;
+                }
+                match &message.change {
+                    FileChange::FullContent { content, .. } => mirrored = content.clone(),
+                    change => change.apply(&mut mirrored),
+                }
+                last = message.seq;
+                changes.push(message);
+            }
+        }
+    }
+    changes
+}
+
+#[test]
+fn mmap_and_read_broadcast_identical_changes() {
+    let read = broadcast_changes("read");
+    let mmap = broadcast_changes("mmap");
+    assert!(read.iter().any(|message| matches!(message.change, FileChange::Diff { .. })), "no diffs broadcast");
+    assert_eq!(read, mmap);
+}
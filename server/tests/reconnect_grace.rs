@@ -0,0 +1,51 @@
+mod common;
+
+use std::{fs, net::TcpListener, thread, time::Duration};
+use common::{spawn_client, temp_dir, wait_until, CONVERGENCE_TIMEOUT};
+use shared::{FileChange, Sequenced};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+/// A URL nothing listens on, so every connect is refused at once
+fn refused_url() -> String {
+    let port = TcpListener::bind("127.0.0.1:0").and_then(|listener| listener.local_addr()).expect("free port").port();
+    format!("ws://127.0.0.1:{port}")
+}
+
+#[test]
+fn instant_failures_within_the_grace_period_cost_no_attempts() {
+    let dir = temp_dir();
+    let client = spawn_client(&dir, &refused_url(), &["--grace-ms", "2000"]);
+    let count = |pattern: &str| client.lines().iter().filter(|line| line.contains(pattern)).count();
+    // more retries than the whole attempt budget of 15
+    assert!(
+        wait_until(CONVERGENCE_TIMEOUT, || count("(grace period)") > 15),
+        "client output:\n{}",
+        client.lines().join("\n")
+    );
+    assert_eq!(count("attempt "), 0);
+    assert_eq!(count("Max reconnection attempts"), 0);
+    // once the grace period is over, failures count again
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || count("(attempt 1/15)") == 1), "client output:\n{}", client.lines().join("\n"));
+    client.wait_for_line("(attempt 2/15)");
+    drop(client);
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn client_connects_to_a_server_that_comes_up_within_the_grace_period() {
+    let url = refused_url();
+    let dir = temp_dir();
+    let client = spawn_client(&dir, &url, &["--grace-ms", "5000"]);
+    client.wait_for_line("(grace period)");
+    thread::sleep(Duration::from_millis(500));
+    // stands in for a server restarting on the same port
+    let listener = TcpListener::bind(url.trim_start_matches("ws://")).expect("bind");
+    let mut socket = tungstenite::accept(listener.accept().expect("accept").0).expect("WebSocket handshake");
+    let change = FileChange::FullContent { file_id: "doc.md".to_string(), content: "# Back\n".to_string(), last_modified: None };
+    socket.send(Message::Text(serde_json::to_string(&Sequenced::new(1, change)).expect("JSON"))).expect("send");
+    socket.read().expect("read ack");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(dir.join("out").join("client1_README.md")).is_ok_and(|c| c == "# Back\n")));
+    assert!(client.lines().iter().all(|line| !line.contains("attempt ")), "client output:\n{}", client.lines().join("\n"));
+    drop(client);
+    let _ = fs::remove_dir_all(dir);
+}
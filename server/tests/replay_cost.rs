@@ -0,0 +1,38 @@
+mod common;
+
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT};
+use shared::{FileChange, Sequenced};
+
+#[test]
+fn a_small_divergence_is_replayed_and_a_large_one_sent_in_full() {
+    let content: String = (0..100).map(|i| format!("Line {i} of a long document.\n")).collect();
+    let mirror = Mirror::start_server(&content, &["--history", "--diff", "line", "--long-poll", "127.0.0.1:0"]);
+    let poll = |since| -> Vec<Sequenced> { serde_json::from_str(&mirror.changes_since(since)).expect("JSON changes") };
+    let start = poll(None)[0].seq;
+    let mirrored = |changes: &[Sequenced]| {
+        let mut mirrored = content.clone();
+        for message in changes {
+            message.change.try_apply(&mut mirrored).expect("apply");
+        }
+        mirrored
+    };
+
+    mirror.edit(|content| content.replace("Line 10 ", "Line ten "));
+    let mut changes = Vec::new();
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || {
+        changes = poll(Some(start));
+        mirrored(&changes) == mirror.source()
+    }));
+    assert!(changes.iter().all(|message| matches!(message.change, FileChange::Diff { .. })), "{changes:?}");
+
+    // every line rewritten, three times over: far more than the file itself
+    for round in 0..3 {
+        mirror.write(&(0..100).map(|i| format!("Round {round} rewrote line {i}.\n")).collect::<String>());
+    }
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || {
+        changes = poll(Some(start));
+        mirrored(&changes) == mirror.source()
+    }));
+    assert!(matches!(changes.as_slice(), [Sequenced { change: FileChange::FullContent { .. }, .. }]), "{changes:?}");
+    assert!(mirror.server_log_count("sending that instead") > 0);
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use std::{fs, net::TcpListener};
+use common::{wait_until, CONVERGENCE_TIMEOUT};
+use shared::{ClientMessage, FileChange, PositionUnit, Sequenced};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+#[test]
+fn diff_arriving_after_a_newer_resync_is_ignored() {
+    // stands in for the server, to deliver a diff after the full content that includes it
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let url = format!("ws://{}", listener.local_addr().expect("address"));
+    let dir = common::temp_dir();
+    let client = common::spawn_client(&dir, &url, &[]);
+    let mut socket = tungstenite::accept(listener.accept().expect("accept").0).expect("WebSocket handshake");
+    let diff = |position, insert_text: &str| FileChange::Diff {
+        file_id: "doc.md".to_string(),
+        position,
+        delete_count: 0,
+        insert_text: insert_text.to_string(),
+        unit: PositionUnit::Chars,
+    };
+    let messages = [
+        Sequenced::new(
+            5,
+            FileChange::FullContent {
+                file_id: "doc.md".to_string(),
+                content: "# Title\n\nLate.\n".to_string(),
+                last_modified: None,
+            },
+        ),
+        // already part of the full content above, applying it again would duplicate it
+        Sequenced::new(4, diff(9, "Late.\n")),
+        Sequenced::new(6, diff(15, "Next.\n")),
+    ];
+    for message in messages {
+        socket.send(Message::Text(serde_json::to_string(&message).expect("JSON"))).expect("send");
+        let ack = socket.read().expect("read ack");
+        let reply = serde_json::from_str::<ClientMessage>(ack.to_text().expect("text")).expect("JSON");
+        assert_eq!(reply, ClientMessage::Ack { seq: message.seq });
+    }
+    let output = dir.join("out").join("client1_README.md");
+    let expected = "# Title\n\nLate.\nNext.\n";
+    assert!(
+        wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(&output).is_ok_and(|content| content == expected)),
+        "output {:?}, client output:\n{}",
+        fs::read_to_string(&output),
+        client.lines().join("\n"),
+    );
+    assert!(client.lines().iter().any(|line| line.contains("Ignoring diff 4 to doc.md")));
+    drop(client);
+    let _ = fs::remove_dir_all(dir);
+}
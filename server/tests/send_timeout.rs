@@ -0,0 +1,40 @@
+mod common;
+
+use std::{net::{SocketAddr, TcpStream}, thread, time::Duration};
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+use socket2::{Domain, Socket, Type};
+use tokio_tungstenite::tungstenite;
+
+/// More than the server's send buffer and the stuck client's receive buffer hold
+fn large_document() -> String {
+    "A line of the document that fills up the socket buffers.\n".repeat(150_000)
+}
+
+/// Connects with a small receive buffer, so the kernel does not take in
+/// megabytes on behalf of a client that never reads
+fn connect_without_reading(port: u16) -> tungstenite::WebSocket<TcpStream> {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).expect("socket");
+    socket.set_recv_buffer_size(4096).expect("set receive buffer");
+    socket.connect(&SocketAddr::from(([127, 0, 0, 1], port)).into()).expect("connect");
+    let (socket, _) = tungstenite::client(format!("ws://127.0.0.1:{port}"), TcpStream::from(socket)).expect("WebSocket handshake");
+    socket
+}
+
+#[test]
+fn a_client_that_stops_reading_is_disconnected_and_frees_its_slot() {
+    let config = "[limits]\nmax_connections = 1\nsend_timeout_ms = 300\n";
+    let mut mirror = Mirror::start_server_with_files(&[(SOURCE_FILE, &large_document()), ("markdown-op.toml", config)], &["--watch", SOURCE_FILE]);
+    let _stuck = connect_without_reading(mirror.port);
+    assert!(
+        wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("client is not reading, a send took over 300 ms") == 1),
+        "{}",
+        mirror.server_log().join("\n")
+    );
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("disconnected") == 1));
+
+    // the only connection slot is free again
+    thread::sleep(Duration::from_millis(100));
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+    assert_eq!(mirror.server_log_count("Too many connections"), 0);
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use std::{fs, io::{BufRead, BufReader, Write}, net::TcpListener};
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT};
+use shared::{signing::Signer, ClientMessage, FileChange, Sequenced};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+/// Base64 of a fixed 32-byte secret key
+const SIGNING_KEY: &str = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+
+fn public_key() -> String {
+    Signer::from_base64(SIGNING_KEY).expect("valid key").public_key()
+}
+
+fn full_content(seq: u64, content: &str) -> String {
+    let change = FileChange::FullContent { file_id: "doc.md".to_string(), content: content.to_string(), last_modified: None };
+    serde_json::to_string(&Sequenced::new(seq, change)).expect("JSON")
+}
+
+#[test]
+fn signed_changes_are_verified_and_mirrored() {
+    let content: String = (0..100).map(|i| format!("Line {i} of a long document.\n")).collect();
+    let public_key = public_key();
+    let mirror = Mirror::start_with(&content, &["--signing-key", SIGNING_KEY], &["--verify-key", &public_key]);
+    assert_eq!(mirror.server_log_count(&format!("Signing messages, public key: {public_key}")), 1);
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| content.replace("Line 50 ", "Line fifty "));
+    assert!(mirror.client_log_count("Applied diff") >= 1);
+}
+
+#[test]
+fn client_without_a_key_accepts_signed_changes() {
+    let mirror = Mirror::start_with("# Title\n", &["--signing-key", SIGNING_KEY], &[]);
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| format!("{content}\nMore.\n"));
+}
+
+#[test]
+fn tampered_and_unsigned_changes_are_rejected() {
+    // stands in for the server, or a relay altering what it forwards
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let url = format!("ws://{}", listener.local_addr().expect("address"));
+    let dir = common::temp_dir();
+    let client = common::spawn_client(&dir, &url, &["--verify-key", &public_key()]);
+    let mut socket = tungstenite::accept(listener.accept().expect("accept").0).expect("WebSocket handshake");
+    let signer = Signer::from_base64(SIGNING_KEY).expect("valid key");
+    let tampered = signer.sign(&full_content(1, "# Title\n")).replace("Title", "Forged");
+    let frames = [tampered, full_content(2, "# Unsigned\n"), signer.sign(&full_content(3, "# Title\n"))];
+    for frame in frames {
+        socket.send(Message::Text(frame)).expect("send");
+    }
+    // only the genuine change is acked
+    let ack = socket.read().expect("read ack");
+    assert_eq!(serde_json::from_str::<ClientMessage>(ack.to_text().expect("text")).expect("JSON"), ClientMessage::Ack { seq: 3 });
+    let output = dir.join("out").join("client1_README.md");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(&output).is_ok_and(|content| content == "# Title\n")));
+    let lines = client.lines();
+    assert!(lines.iter().any(|line| line.contains("message signature is invalid")), "client output:\n{}", lines.join("\n"));
+    assert!(lines.iter().any(|line| line.contains("message is not signed")));
+    drop(client);
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn long_poll_changes_are_signed_and_verified() {
+    let content: String = (0..100).map(|i| format!("Line {i} of a long document.\n")).collect();
+    let mirror = Mirror::start_server(&content, &["--signing-key", SIGNING_KEY, "--history", "--long-poll", "127.0.0.1:0"]);
+    let body = mirror.changes_since(None);
+    assert!(body.starts_with(r#"[{"Signed":"#), "{body}");
+    let long_poll_url = format!("http://127.0.0.1:{}/", mirror.long_poll_port.expect("long-poll port"));
+    let client = common::spawn_client(&mirror.dir, &mirror.url(), &["--long-poll-url", &long_poll_url, "--long-poll", "--verify-key", &public_key()]);
+    client.wait_for_line("Long-polling");
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| content.replace("Line 50 ", "Line fifty "));
+    assert!(!client.lines().iter().any(|line| line.contains("Error processing message")), "client output:\n{}", client.lines().join("\n"));
+}
+
+/// Answers the next long-poll request with `body`, returning the request line
+fn answer_poll(listener: &TcpListener, body: &str) -> String {
+    let (mut stream, _) = listener.accept().expect("accept");
+    let mut request = String::new();
+    let mut reader = BufReader::new(stream.try_clone().expect("clone stream"));
+    // up to the empty line ending the head
+    while reader.read_line(&mut request).expect("read request") > 2 {}
+    write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())
+        .expect("send response");
+    request.lines().next().unwrap_or_default().to_string()
+}
+
+#[test]
+fn tampered_long_poll_changes_are_rejected() {
+    // stands in for the long-poll endpoint, or a relay altering what it forwards
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let long_poll_url = format!("http://{}/", listener.local_addr().expect("address"));
+    let dir = common::temp_dir();
+    let client = common::spawn_client(&dir, "ws://127.0.0.1:1", &["--long-poll-url", &long_poll_url, "--long-poll", "--verify-key", &public_key()]);
+    let signer = Signer::from_base64(SIGNING_KEY).expect("valid key");
+    let tampered = signer.sign(&full_content(1, "# Title\n")).replace("Title", "Forged");
+    assert_eq!(answer_poll(&listener, &format!("[{tampered}]")), "GET /changes HTTP/1.1");
+    // the forged change is dropped, and the client asks for full content again
+    assert_eq!(answer_poll(&listener, &format!("[{}]", signer.sign(&full_content(2, "# Title\n")))), "GET /changes HTTP/1.1");
+    assert_eq!(answer_poll(&listener, "[]"), "GET /changes?since=2 HTTP/1.1");
+    let output = dir.join("out").join("client1_README.md");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(&output).is_ok_and(|content| content == "# Title\n")));
+    let lines = client.lines();
+    assert!(lines.iter().any(|line| line.contains("message signature is invalid")), "client output:\n{}", lines.join("\n"));
+    drop(client);
+    let _ = fs::remove_dir_all(dir);
+}
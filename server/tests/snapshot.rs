@@ -0,0 +1,33 @@
+mod common;
+
+use std::{net::TcpListener, process::Command, time::{Duration, Instant}};
+use common::{binary, Mirror};
+
+#[test]
+fn snapshot_writes_the_current_content_and_exits() {
+    let mirror = Mirror::start_server("# Title\n\nSome text.\n", &[]);
+    let started = Instant::now();
+    let output = Command::new(binary("client"))
+        .env("SERVER_URL", mirror.url())
+        .arg("--snapshot")
+        .output()
+        .expect("run client");
+    assert!(output.status.success(), "snapshot failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), mirror.source());
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn snapshot_fails_when_the_server_sends_nothing() {
+    // accepts connections but never answers the handshake
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let url = format!("ws://{}", listener.local_addr().expect("addr"));
+    let output = Command::new(binary("client"))
+        .env("SERVER_URL", &url)
+        .args(["--snapshot", "--snapshot-timeout-ms", "300", "--connect-timeout-ms", "60000"])
+        .output()
+        .expect("run client");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no content received"));
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use std::{fs, path::Path};
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT};
+
+/// Contents of the timestamped snapshots in `dir`, oldest first
+fn snapshots(dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<_> = entries
+        .map(|entry| entry.expect("directory entry").path())
+        .filter(|path| {
+            let name = path.file_name().expect("file name").to_string_lossy();
+            name.starts_with("client1_README.") && name.ends_with("Z.md")
+        })
+        .collect();
+    paths.sort();
+    paths.iter().filter_map(|path| fs::read_to_string(path).ok()).collect()
+}
+
+#[test]
+fn every_update_is_kept_in_its_own_file_up_to_the_cap() {
+    let mut mirror = Mirror::start_server("# Version 0\n", &[]);
+    mirror.start_client(&["--snapshot-history", "3"]);
+    let out = mirror.dir.join("out");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || snapshots(&out) == ["# Version 0\n"]), "{:?}", snapshots(&out));
+    for version in 1..=4 {
+        let content = format!("# Version {version}\n");
+        mirror.write(&content);
+        assert!(
+            wait_until(CONVERGENCE_TIMEOUT, || snapshots(&out).last() == Some(&content)),
+            "{:?}\nclient output:\n{}",
+            snapshots(&out),
+            mirror.client_log().join("\n")
+        );
+    }
+    let retained = ["# Version 2\n", "# Version 3\n", "# Version 4\n"];
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || snapshots(&out) == retained), "{:?}", snapshots(&out));
+    // nothing is rewritten in place
+    assert!(!mirror.output_path().exists());
+    // the file name is reported, e.g. for the resync hook
+    assert!(mirror.client_log().iter().any(|line| line.starts_with("Updated file: ") && line.ends_with("Z.md")));
+}
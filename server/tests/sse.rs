@@ -0,0 +1,121 @@
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use common::{Mirror, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+use shared::{FileChange, Sequenced};
+
+/// An `EventSource` stand-in reading the stream line by line
+struct EventStream {
+    reader: BufReader<TcpStream>,
+}
+
+/// One event: its `id`, when it has one, and its `data`
+#[derive(Debug)]
+struct Event {
+    id: Option<u64>,
+    data: String,
+}
+
+impl EventStream {
+    /// Opens `GET /events`, sending `last_event_id` the way a reconnecting `EventSource` does
+    fn open(mirror: &Mirror, last_event_id: Option<u64>) -> Self {
+        let port = mirror.sse_port.expect("server started without --sse");
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("connect to SSE endpoint");
+        stream.set_read_timeout(Some(CONVERGENCE_TIMEOUT)).expect("set timeout");
+        let resume = last_event_id.map(|id| format!("Last-Event-ID: {id}\r\n")).unwrap_or_default();
+        write!(stream, "GET /events HTTP/1.1\r\nHost: 127.0.0.1\r\nAccept: text/event-stream\r\n{resume}\r\n").expect("send request");
+        let mut events = Self { reader: BufReader::new(stream) };
+        let head = events.block();
+        assert!(head[0].starts_with("HTTP/1.1 200"), "{head:?}");
+        assert!(head.iter().any(|line| line == "Content-Type: text/event-stream"), "{head:?}");
+        events
+    }
+
+    /// Lines up to the next blank one
+    fn block(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            assert!(self.reader.read_line(&mut line).expect("read line") > 0, "stream ended");
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                return lines;
+            }
+            lines.push(line.to_string());
+        }
+    }
+
+    fn next(&mut self) -> Event {
+        let mut event = Event { id: None, data: String::new() };
+        for line in self.block() {
+            if let Some(id) = line.strip_prefix("id: ") {
+                event.id = Some(id.parse().expect("numeric id"));
+            } else if let Some(data) = line.strip_prefix("data: ") {
+                event.data = data.to_string();
+            }
+        }
+        event
+    }
+
+    /// The next event holding a change, with its id checked against the change's seq
+    fn next_change(&mut self) -> Sequenced {
+        let event = self.next();
+        let change: Sequenced = serde_json::from_str(&event.data).expect("change as data");
+        assert_eq!(event.id, Some(change.seq), "{event:?}");
+        change
+    }
+
+    /// Applies changes to `content` until it is `expected`, returning them
+    fn follow(&mut self, content: &mut String, expected: &str) -> Vec<Sequenced> {
+        let mut changes = Vec::new();
+        while content != expected {
+            let change = self.next_change();
+            change.change.try_apply(content).expect("apply");
+            changes.push(change);
+        }
+        changes
+    }
+}
+
+#[test]
+fn changes_are_streamed_as_events_with_their_seq_as_id() {
+    let mirror = Mirror::start_server("# Title\n", &["--sse", "127.0.0.1:0"]);
+    let mut events = EventStream::open(&mirror, None);
+    let initial = events.next_change();
+    assert_eq!(initial.seq, 0);
+    assert!(matches!(&initial.change, FileChange::FullContent { file_id, content, .. } if file_id == SOURCE_FILE && content == "# Title\n"));
+
+    mirror.write("# Title\n\nBody.\n");
+    let edit = events.next_change();
+    assert_eq!(edit.seq, 1);
+    assert_eq!(edit.change.file_id(), SOURCE_FILE);
+}
+
+#[test]
+fn reconnecting_with_the_last_event_id_resumes_after_it() {
+    // long enough to be sent as diffs; replays however many changes a save is read as
+    let original: String = (0..100).map(|line| format!("Line {line} of the document.\n")).collect();
+    let edited = original.replace("Line 50 ", "Line fifty ");
+    let config = "[history]\nenabled = true\nmax_backfill = 0\nmax_replay_ratio = 0\n";
+    let mirror = Mirror::start_server_with_files(
+        &[(SOURCE_FILE, &original), ("markdown-op.toml", config)],
+        &["--watch", SOURCE_FILE, "--sse", "127.0.0.1:0"],
+    );
+    let mut events = EventStream::open(&mirror, None);
+    let mut content = String::new();
+    let last_id = events.follow(&mut content, &original)[0].seq;
+    drop(events);
+
+    // a second page sees the edits made while the first one is gone
+    let mut other = EventStream::open(&mirror, None);
+    let mut other_content = String::new();
+    other.follow(&mut other_content, &original);
+    mirror.write(&edited);
+    let missed = other.follow(&mut other_content, &edited);
+
+    // the same diffs, where a client that did not resume would get full content
+    let mut events = EventStream::open(&mirror, Some(last_id));
+    assert!(missed.iter().all(|change| matches!(change.change, FileChange::Diff { .. })), "{missed:?}");
+    assert_eq!(events.follow(&mut content, &edited), missed);
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use std::{net::TcpListener, process::Command};
+use common::{binary, Mirror, SOURCE_FILE};
+use shared::ServerStatus;
+
+#[test]
+fn status_lists_files_connections_and_digests() {
+    let mirror = Mirror::start("# Title\n");
+    mirror.edit_and_await(|content| format!("{content}\nMore.\n"));
+    let output = Command::new(binary("client")).env("SERVER_URL", mirror.url()).arg("status").output().expect("run client");
+    assert!(output.status.success(), "status failed: {}", String::from_utf8_lossy(&output.stderr));
+    let status: ServerStatus = serde_json::from_slice(&output.stdout).expect("JSON status");
+    assert!(!status.paused);
+    assert!(status.seq > 0);
+    // the mirroring client and the one asking
+    assert_eq!(status.connections, 2);
+    assert_eq!(status.files.len(), 1);
+    let file = &status.files[0];
+    assert_eq!(file.file_id, SOURCE_FILE);
+    assert_eq!(file.bytes, Some(mirror.source().len()));
+    assert_eq!(file.digest, Some(shared::digest(&mirror.source())));
+}
+
+#[test]
+fn status_fails_without_a_server() {
+    // a port nothing listens on anymore
+    let port = TcpListener::bind("127.0.0.1:0").and_then(|listener| listener.local_addr()).expect("free port").port();
+    let url = format!("ws://127.0.0.1:{port}");
+    let output = Command::new(binary("client")).env("SERVER_URL", &url).arg("status").output().expect("run client");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use std::{fs, thread, time::Duration};
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT};
+
+fn lines(name: &str) -> String {
+    (0..40).map(|i| format!("{name} line {i} of the document.\n")).collect()
+}
+
+#[test]
+fn only_the_tail_and_the_changes_to_it_are_delivered() {
+    let (head, tail) = (lines("Head"), lines("Tail"));
+    // a char diff of an edit in the head can rewrite everything after it
+    let mut mirror = Mirror::start_server(&format!("{head}{tail}"), &["--diff", "line"]);
+    let offset = head.chars().count();
+    mirror.start_client(&["--from", &format!("doc.md:{offset}")]);
+    let mirrored = |expected: &str| wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(mirror.output_path()).is_ok_and(|c| c == expected));
+    assert!(mirrored(&tail));
+
+    // before the offset: nothing to send, the tail just moves
+    mirror.edit(|content| content.replace("Head line 3 ", "Head line three "));
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(mirror.client_log_count("Applied diff"), 0, "{}", mirror.client_log().join("\n"));
+    assert_eq!(fs::read_to_string(mirror.output_path()).expect("read output"), tail);
+
+    mirror.edit(|content| content.replace("Tail line 5 ", "Tail line five "));
+    let tail = tail.replace("Tail line 5 ", "Tail line five ");
+    assert!(mirrored(&tail), "client output:\n{}", mirror.client_log().join("\n"));
+    mirror.edit(|content| format!("{content}Appended at the end.\n"));
+    let tail = format!("{tail}Appended at the end.\n");
+    assert!(mirrored(&tail), "client output:\n{}", mirror.client_log().join("\n"));
+    // the last line of the head and the first of the tail, in one edit
+    mirror.edit(|content| content.replace("Head line 39 of the document.\nTail line 0", "Both"));
+    let tail = tail.replacen("Tail line 0", "Both", 1);
+    assert!(mirrored(&tail), "client output:\n{}", mirror.client_log().join("\n"));
+    assert!(mirror.client_log_count("Applied diff") >= 3);
+    // the whole file on connecting, then the tail, and never again
+    assert_eq!(mirror.client_log_count("Updated file"), 2);
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use common::{connect, read_text, Mirror, Socket, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+use shared::{ClientMessage, FileChange, Received, Sequenced, SyncProgress};
+use tokio_tungstenite::tungstenite::Message;
+
+fn read(socket: &mut Socket) -> Received {
+    Received::parse(&read_text(socket)).expect("parse message")
+}
+
+fn start(file_id: &str, total_bytes: usize, seq: u64) -> Received {
+    Received::Sync(SyncProgress::SyncStart { file_id: file_id.to_string(), total_bytes, seq })
+}
+
+fn complete(file_id: &str, seq: u64) -> Received {
+    Received::Sync(SyncProgress::SyncComplete { file_id: file_id.to_string(), seq })
+}
+
+fn full_content(file_id: &str, content: &str, seq: u64) -> Received {
+    Received::Change(Sequenced::new(seq, FileChange::FullContent { file_id: file_id.to_string(), content: content.to_string(), last_modified: None }))
+}
+
+/// Clears the modification time, which the test does not know upfront
+fn without_mtime(received: Received) -> Received {
+    match received {
+        Received::Change(Sequenced { seq, change: FileChange::FullContent { file_id, content, .. }, .. }) => {
+            Received::Change(Sequenced::new(seq, FileChange::FullContent { file_id, content, last_modified: None }))
+        }
+        received => received,
+    }
+}
+
+#[test]
+fn initial_content_of_every_file_is_bracketed_by_start_and_complete() {
+    let files = [("a.md", "# Ä\n"), ("b.md", "# B\n\nBody.\n")];
+    let mirror = Mirror::start_server_with_files(&files, &["--watch", "a.md", "--watch", "b.md", "--sync-report"]);
+    let mut socket = connect(&mirror.url());
+    for (file_id, content) in files {
+        // total_bytes counts UTF-8 bytes, not chars
+        assert_eq!(read(&mut socket), start(file_id, content.len(), 0));
+        assert_eq!(without_mtime(read(&mut socket)), full_content(file_id, content, 0));
+        assert_eq!(read(&mut socket), complete(file_id, 0));
+    }
+}
+
+#[test]
+fn chunked_transfer_completes_once_the_chunks_were_sent() {
+    let content: String = (0..2000).map(|line| format!("{line:09}\n")).collect();
+    let config = "sync_report = true\n\n[chunks]\nenabled = true\nsize = 1000\nmin_bytes = 10000\n";
+    let mirror = Mirror::start_server_with_files(&[(SOURCE_FILE, &content), ("markdown-op.toml", config)], &["--watch", SOURCE_FILE]);
+    let mut socket = connect(&format!("{}/?chunks=1", mirror.url()));
+    assert_eq!(read(&mut socket), start(SOURCE_FILE, content.len(), 0));
+    let Received::Change(Sequenced { change: FileChange::ChunkHashes { hashes, .. }, .. }) = read(&mut socket) else {
+        panic!("expected chunk hashes");
+    };
+    let request = ClientMessage::RequestChunks { file_id: SOURCE_FILE.to_string(), indices: (0..hashes.len()).collect() };
+    socket.send(Message::Text(serde_json::to_string(&request).expect("serialize"))).expect("send");
+    let chunks: serde_json::Value = serde_json::from_str(&read_text(&mut socket)).expect("JSON");
+    assert_eq!(chunks["Chunks"]["chunks"].as_object().expect("chunks").len(), 20);
+    assert_eq!(read(&mut socket), complete(SOURCE_FILE, 0));
+}
+
+#[test]
+fn the_client_reports_progress_without_acking_it() {
+    let mut mirror = Mirror::start_server("# Title\n", &["--sync-report"]);
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+    assert!(common::wait_until(CONVERGENCE_TIMEOUT, || mirror.client_log_count("Synced doc.md as of seq 0") == 1));
+    assert_eq!(mirror.client_log_count("Syncing doc.md (8 bytes) as of seq 0"), 1);
+    assert_eq!(mirror.client_log_count("unknown kind"), 0);
+    mirror.edit_and_await(|content| format!("{content}\nMore.\n"));
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use std::{fs, net::TcpListener};
+use common::{wait_until, CONVERGENCE_TIMEOUT};
+use shared::{ClientMessage, FileChange, PositionUnit, Sequenced};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+fn diff(position: usize, delete_count: usize, insert_text: &str) -> FileChange {
+    FileChange::Diff {
+        file_id: "log.md".to_string(),
+        position,
+        delete_count,
+        insert_text: insert_text.to_string(),
+        unit: PositionUnit::Chars,
+    }
+}
+
+#[test]
+fn tail_prints_appended_text_and_rewrites_on_other_changes() {
+    // stands in for the server, to send exactly the diffs under test
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let url = format!("ws://{}", listener.local_addr().expect("address"));
+    let dir = common::temp_dir();
+    let client = common::spawn_client(&dir, &url, &["--tail"]);
+    let mut socket = tungstenite::accept(listener.accept().expect("accept").0).expect("WebSocket handshake");
+    let changes = [
+        FileChange::FullContent { file_id: "log.md".to_string(), content: "# Log\n".to_string(), last_modified: None },
+        diff(6, 0, "- one\n"),
+        // renames the heading, so the whole file is printed again
+        diff(2, 3, "Journal"),
+        diff(16, 0, "- two\n"),
+    ];
+    for (seq, change) in (1..).zip(changes) {
+        socket.send(Message::Text(serde_json::to_string(&Sequenced::new(seq, change)).expect("JSON"))).expect("send");
+        let ack = socket.read().expect("read ack");
+        assert_eq!(serde_json::from_str::<ClientMessage>(ack.to_text().expect("text")).expect("JSON"), ClientMessage::Ack { seq });
+    }
+    let printed = || -> Vec<String> { client.lines().into_iter().skip_while(|line| line != "Connected to server").skip(1).collect() };
+    let expected = ["# Log", "- one", "# Journal", "- one", "- two"];
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || printed().len() >= expected.len()));
+    assert_eq!(printed(), expected, "client output:\n{}", client.lines().join("\n"));
+    drop(client);
+    let _ = fs::remove_dir_all(dir);
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use common::{Mirror, SOURCE_FILE};
+use shared::{FileChange, Sequenced};
+
+const CONTENT: &str = "# Title\n\nSome text.  \nMore text.\n";
+
+/// Starts a server with `args`, saves `CONTENT` with cosmetic changes to its
+/// end, then with a real edit, and returns everything broadcast after the
+/// initial content along with it
+fn broadcast_after_cosmetic_saves(args: &[&str]) -> (String, Vec<FileChange>) {
+    let mut server_args = vec!["--watch", SOURCE_FILE, "--history", "--long-poll", "127.0.0.1:0"];
+    server_args.extend(args);
+    // every change is replayed, however many piled up between two polls
+    let config = "[history]\nmax_replay_ratio = 0\n";
+    let mirror = Mirror::start_server_with_files(&[(SOURCE_FILE, &format!("{CONTENT}\n\n")), ("markdown-op.toml", config)], &server_args);
+    let poll = |since| -> Vec<Sequenced> { serde_json::from_str(&mirror.changes_since(since)).expect("JSON changes") };
+    let initial = poll(None).remove(0);
+    let FileChange::FullContent { content, .. } = initial.change else {
+        panic!("initial content expected, got {:?}", initial.change);
+    };
+    mirror.write(&format!("{CONTENT}\n"));
+    mirror.write(CONTENT.trim_end());
+    mirror.write(&format!("{CONTENT} \t\n"));
+    mirror.write(&CONTENT.replace("More", "Other"));
+    let mut changes = Vec::new();
+    let mut last = initial.seq;
+    while !changes.iter().any(|change| matches!(change, FileChange::FullContent { content, .. } if content.contains("Other"))) {
+        for message in poll(Some(last)) {
+            last = message.seq;
+            changes.push(message.change);
+        }
+    }
+    (content, changes)
+}
+
+#[test]
+fn saves_that_only_change_trailing_whitespace_broadcast_nothing_when_normalized() {
+    let (initial, changes) = broadcast_after_cosmetic_saves(&["--trailing-whitespace", "normalize"]);
+    // trailing spaces inside the file are a markdown line break and are kept
+    assert_eq!(initial, CONTENT);
+    assert!(
+        matches!(&changes[..], [FileChange::FullContent { content, .. }] if *content == CONTENT.replace("More", "Other")),
+        "{changes:?}"
+    );
+}
+
+#[test]
+fn trailing_whitespace_is_mirrored_as_it_is_by_default() {
+    let (initial, changes) = broadcast_after_cosmetic_saves(&[]);
+    assert_eq!(initial, format!("{CONTENT}\n\n"));
+    let mut contents: Vec<&str> = changes
+        .iter()
+        .filter_map(|change| match change {
+            FileChange::FullContent { content, .. } => Some(content.as_str()),
+            _ => None,
+        })
+        .collect();
+    // a save can be read twice when it raises two events, and small files are sent in full either way
+    contents.dedup();
+    let other = CONTENT.replace("More", "Other");
+    let expected = [format!("{CONTENT}\n"), CONTENT.trim_end().to_string(), format!("{CONTENT} \t\n"), other];
+    assert_eq!(contents, expected, "{changes:?}");
+}
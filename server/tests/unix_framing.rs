@@ -0,0 +1,81 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{io::{Read, Write}, os::unix::net::UnixStream, time::Duration};
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+use shared::{framing, Sequenced};
+
+const CONFIG: &str = r#"
+watch = ["doc.md"]
+unix_socket = "mirror.sock"
+
+[limits]
+max_frame_bytes = 1024
+"#;
+
+fn connect(mirror: &Mirror) -> UnixStream {
+    let socket = UnixStream::connect(mirror.dir.join("mirror.sock")).expect("connect");
+    socket.set_read_timeout(Some(CONVERGENCE_TIMEOUT)).expect("set timeout");
+    socket
+}
+
+/// Reads the initial content, which the server sends as soon as a client connects
+fn read_initial_content(socket: &mut UnixStream) -> Sequenced {
+    let mut header = [0; framing::HEADER_LEN];
+    socket.read_exact(&mut header).expect("read frame header");
+    let mut payload = vec![0; u32::from_be_bytes(header) as usize];
+    socket.read_exact(&mut payload).expect("read frame");
+    serde_json::from_slice(&payload).expect("parse change")
+}
+
+fn start() -> Mirror {
+    Mirror::start_server_with_files(&[(SOURCE_FILE, "# Framed\n"), ("markdown-op.toml", CONFIG)], &[])
+}
+
+#[test]
+fn oversized_frame_is_a_protocol_error() {
+    let mirror = start();
+    let mut socket = connect(&mirror);
+    read_initial_content(&mut socket);
+    // only the header: the frame is rejected before its bytes arrive
+    socket.write_all(&(64 * 1024 * 1024u32).to_be_bytes()).expect("write header");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("frame of 67108864 bytes is over the 1024 byte limit") == 1));
+    // the server hung up on the client
+    let mut rest = Vec::new();
+    assert_eq!(socket.read_to_end(&mut rest).expect("read until closed"), 0);
+    // and still serves others
+    assert_eq!(read_initial_content(&mut connect(&mirror)).seq, 0);
+}
+
+#[test]
+fn truncated_frame_is_a_protocol_error() {
+    let mirror = start();
+    let mut socket = connect(&mirror);
+    read_initial_content(&mut socket);
+    let frame = framing::encode(br#"{"Ack":{"seq":0}}"#, 1024).expect("encode");
+    socket.write_all(&frame[..10]).expect("write part of a frame");
+    socket.shutdown(std::net::Shutdown::Write).expect("shut down");
+    let message = format!("stream ended in the middle of a frame, after 10 of {} bytes", frame.len());
+    assert!(
+        wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count(&message) == 1),
+        "server output:\n{}",
+        mirror.server_log().join("\n")
+    );
+    assert_eq!(read_initial_content(&mut connect(&mirror)).seq, 0);
+}
+
+#[test]
+fn frame_within_the_limit_is_accepted() {
+    let mirror = start();
+    let mut socket = connect(&mirror);
+    read_initial_content(&mut socket);
+    // an ack sent in two writes is put back together
+    let frame = framing::encode(br#"{"Ack":{"seq":0}}"#, 1024).expect("encode");
+    socket.write_all(&frame[..3]).expect("write header");
+    std::thread::sleep(Duration::from_millis(50));
+    socket.write_all(&frame[3..]).expect("write rest");
+    drop(socket);
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("disconnected") == 1));
+    assert_eq!(mirror.server_log_count("Error from client"), 0);
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use common::{connect, next_change, Mirror, Socket};
+use shared::{utf16, FileChange, PositionUnit};
+
+const LINE: &str = "Wave 👋 and rocket 🚀 here.\n";
+
+/// The line followed by enough plain text that changes are sent as diffs
+fn document(line: &str) -> String {
+    format!("{line}{}", "Plain text after the emoji.\n".repeat(50))
+}
+
+/// Connects the way a browser viewer would, asking for JavaScript string indices
+fn connect_utf16(mirror: &Mirror) -> Socket {
+    connect(&format!("{}/?positions=utf16", mirror.url()))
+}
+
+/// Applies the diffs of the next change the way a browser viewer would, until
+/// its copy matches `expected`; returns the diffs
+fn apply_change(socket: &mut Socket, text: &mut Vec<u16>, expected: &str) -> Vec<FileChange> {
+    let mut diffs = Vec::new();
+    while String::from_utf16(text).expect("UTF-16") != expected {
+        let change = next_change(socket);
+        apply_as_javascript(text, &change);
+        diffs.push(change);
+    }
+    diffs
+}
+
+/// Applies a diff the way `text.slice(0, position) + insert_text + text.slice(position + delete_count)` would
+fn apply_as_javascript(text: &mut Vec<u16>, change: &FileChange) {
+    let FileChange::Diff { position, delete_count, insert_text, .. } = change else {
+        panic!("expected a diff, got {change:?}");
+    };
+    text.splice(*position..position + delete_count, insert_text.encode_utf16());
+}
+
+#[test]
+fn known_javascript_indices() {
+    // "Wave 👋 and rocket 🚀 here.\n".length === 28, .indexOf("here") === 22
+    assert_eq!(utf16::len(LINE), 28);
+    assert_eq!(utf16::offset(LINE, LINE.chars().position(|c| c == 'h').expect("here")), 22);
+    // "a👋b".indexOf("b") === 3
+    assert_eq!(utf16::offset("a👋b", 2), 3);
+    // positions past the end count up to it
+    assert_eq!(utf16::offset("a👋b", 10), 4);
+}
+
+#[test]
+fn diffs_after_emoji_count_utf16_code_units() {
+    let mirror = Mirror::start_server(&document(LINE), &[]);
+    let mut socket = connect_utf16(&mirror);
+    let FileChange::FullContent { content, .. } = next_change(&mut socket) else {
+        panic!("expected the initial content");
+    };
+    assert_eq!(content, document(LINE));
+    let mut text: Vec<u16> = content.encode_utf16().collect();
+
+    let edited = document("Wave 👋 and rocket 🚀 HERE.\n");
+    mirror.write(&edited);
+    let diffs = apply_change(&mut socket, &mut text, &edited);
+    // "Wave 👋 and rocket 🚀 here.\n".indexOf("here") === 22, while it is char 20
+    assert!(matches!(diffs[0], FileChange::Diff { position: 22, unit: PositionUnit::Utf16, .. }), "{diffs:?}");
+
+    // "Wave 👋 and rocket 🚀 HERE.\n".indexOf("🚀") === 19, while it is char 18
+    let edited = document("Wave 👋 and rocket HERE.\n");
+    mirror.write(&edited);
+    let diffs = apply_change(&mut socket, &mut text, &edited);
+    assert!(matches!(diffs[0], FileChange::Diff { position: 19, .. }), "{diffs:?}");
+}
+
+#[test]
+fn diffs_default_to_chars() {
+    let mirror = Mirror::start_server(&document(LINE), &[]);
+    let mut socket = connect(&mirror.url());
+    next_change(&mut socket);
+    mirror.write(&document("Wave 👋 and rocket 🚀 HERE.\n"));
+    let change = next_change(&mut socket);
+    assert!(matches!(change, FileChange::Diff { position: 20, .. }), "{change:?}");
+}
@@ -0,0 +1,25 @@
+mod common;
+
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT};
+
+#[test]
+fn verified_writes_mirror_as_usual() {
+    let mirror = Mirror::start_with("# Title\n", &[], &["--verify-writes"]);
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| format!("{content}\nVerified paragraph.\n"));
+    assert_eq!(mirror.client_log_count("Verifying"), 0);
+    assert_eq!(mirror.client_log_count("Failed to write"), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn a_file_that_reads_back_different_is_rewritten_then_reported() {
+    // a device that accepts every write and reads back empty, like a filesystem losing data
+    let mirror = Mirror::start_with("# Title\n", &[], &["--verify-writes", "--sink", "file:/dev/null"]);
+    let reported = || mirror.client_log_count("Failed to write to /dev/null: verification failed again after rewriting");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || reported() == 1), "client output:\n{}", mirror.client_log().join("\n"));
+    assert_eq!(
+        mirror.client_log_count("Verifying /dev/null failed: read back 0 bytes that differ from the 8 written, writing it again"),
+        1
+    );
+}
@@ -0,0 +1,29 @@
+mod common;
+
+use std::fs;
+use common::{temp_dir, wait_until, Mirror, CONVERGENCE_TIMEOUT, EDIT_INTERVAL};
+
+#[test]
+fn watching_resumes_once_the_removed_directory_is_back() {
+    let dir = temp_dir();
+    let docs = dir.join("docs");
+    fs::create_dir(&docs).expect("create docs");
+    fs::write(docs.join("notes.md"), "# Notes\n").expect("write notes.md");
+    let mut mirror = Mirror::start_server_in(dir, &["docs/notes.md"]);
+    mirror.start_client(&[]);
+    let mirrored = |expected: &str| wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(mirror.output_path()).is_ok_and(|c| c == expected));
+    assert!(mirrored("# Notes\n"));
+
+    fs::remove_dir_all(&docs).expect("remove docs");
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || mirror.server_log_count("was removed or moved away") > 0));
+    std::thread::sleep(EDIT_INTERVAL);
+    fs::create_dir(&docs).expect("recreate docs");
+    fs::write(docs.join("notes.md"), "# Notes\n\nWritten after the directory came back.\n").expect("write notes.md");
+    assert!(mirrored("# Notes\n\nWritten after the directory came back.\n"), "server output:\n{}", mirror.server_log().join("\n"));
+    assert!(mirror.server_log_count("again") > 0);
+
+    // and later edits are seen through the new watch
+    std::thread::sleep(EDIT_INTERVAL);
+    fs::write(docs.join("notes.md"), "# Notes\n\nEdited once more.\n").expect("write notes.md");
+    assert!(mirrored("# Notes\n\nEdited once more.\n"), "server output:\n{}", mirror.server_log().join("\n"));
+}
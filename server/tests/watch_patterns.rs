@@ -0,0 +1,93 @@
+mod common;
+
+use std::{fs, path::PathBuf, process::Command};
+use common::{binary, temp_dir, wait_until, Mirror, CONVERGENCE_TIMEOUT};
+
+const FILES: [&str; 4] = ["README.md", "docs/README.md", "docs/guide.md", "docs/api/README.md"];
+
+/// A directory holding `FILES`
+fn tree() -> PathBuf {
+    let dir = temp_dir();
+    for file in FILES {
+        let path = dir.join(file);
+        fs::create_dir_all(path.parent().expect("parent")).expect("create dir");
+        fs::write(path, "# Draft\n").expect("write file");
+    }
+    dir
+}
+
+fn assert_mirrored(mirror: &Mirror, file: &str) {
+    let expected = format!("# {file}\n\nEdited.\n");
+    fs::write(mirror.dir.join(file), &expected).expect("write file");
+    let output = mirror.dir.join("out").join(file);
+    assert!(
+        wait_until(CONVERGENCE_TIMEOUT, || fs::read_to_string(&output).is_ok_and(|c| c == expected)),
+        "{} was not mirrored, client output:\n{}",
+        file,
+        mirror.client_log().join("\n")
+    );
+}
+
+fn assert_not_mirrored(mirror: &Mirror, file: &str) {
+    assert!(!mirror.dir.join("out").join(file).exists(), "{} was mirrored", file);
+    assert_eq!(mirror.server_log_count(&format!("Watching file: {file}")), 0);
+}
+
+#[test]
+fn a_glob_mirrors_every_matching_file_under_its_relative_path() {
+    let mut mirror = Mirror::start_server_in(tree(), &["--match", "glob", "--watch", "docs/**/README.md"]);
+    mirror.start_client(&["--output-template", "{file_id}"]);
+
+    assert_mirrored(&mirror, "docs/README.md");
+    assert_mirrored(&mirror, "docs/api/README.md");
+    assert_not_mirrored(&mirror, "docs/guide.md");
+    assert_not_mirrored(&mirror, "README.md");
+
+    // clients only know the files there were at startup
+    fs::create_dir_all(mirror.dir.join("docs/new")).expect("create dir");
+    // the new directory is only watched once its creation is seen, so a write
+    // landing before that goes unnoticed; writing again until one is seen
+    assert!(wait_until(CONVERGENCE_TIMEOUT, || {
+        fs::write(mirror.dir.join("docs/new/README.md"), "# New\n").expect("write file");
+        mirror.server_log_count("docs/new/README.md matches docs/**/README.md") == 1
+    }));
+}
+
+#[test]
+fn a_regex_mirrors_every_file_whose_whole_path_matches() {
+    let mut mirror = Mirror::start_server_in(tree(), &["--match", "regex", "--watch", r"docs/[^/]+\.md"]);
+    mirror.start_client(&["--output-template", "{file_id}"]);
+
+    assert_mirrored(&mirror, "docs/README.md");
+    assert_mirrored(&mirror, "docs/guide.md");
+    assert_not_mirrored(&mirror, "docs/api/README.md");
+    assert_not_mirrored(&mirror, "README.md");
+}
+
+#[test]
+fn exact_matching_takes_a_pattern_literally() {
+    let dir = tree();
+    let output = Command::new(binary("server"))
+        .current_dir(&dir)
+        .args(["--bind", "127.0.0.1:0", "--watch", "docs/*.md"])
+        .arg("doctor")
+        .output()
+        .expect("run server");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("FAIL  watch docs/*.md: does not exist"));
+}
+
+#[test]
+fn a_pattern_matching_no_file_is_a_config_error() {
+    let output = Command::new(binary("server"))
+        .current_dir(tree())
+        .args(["--bind", "127.0.0.1:0", "--match", "glob", "--watch", "notes/*.md"])
+        .output()
+        .expect("run server");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("watch pattern \"notes/*.md\""),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use std::{thread, time::Duration};
+use common::{wait_until, Mirror, CONVERGENCE_TIMEOUT, SOURCE_FILE};
+use serde_json::Value;
+
+/// Starts the server with `config` and the long-poll endpoint, which serves
+/// `GET /debug/watcher` in debug builds
+fn start(content: &str, config: &str) -> Mirror {
+    Mirror::start_server_with_files(
+        &[(SOURCE_FILE, content), ("markdown-op.toml", config)],
+        &["--watch", SOURCE_FILE, "--long-poll", "127.0.0.1:0"],
+    )
+}
+
+fn state(mirror: &Mirror) -> Value {
+    let (head, body) = mirror.http_get("/debug/watcher");
+    assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+    serde_json::from_str(&body).expect("JSON state")
+}
+
+fn counter(state: &Value, name: &str) -> u64 {
+    state["files"][SOURCE_FILE][name].as_u64().unwrap_or(0)
+}
+
+/// Waits until the watcher's state satisfies `condition`, returning it
+fn await_state(mirror: &Mirror, condition: impl Fn(&Value) -> bool) -> Value {
+    let mut last = Value::Null;
+    let held = wait_until(CONVERGENCE_TIMEOUT, || {
+        last = state(mirror);
+        condition(&last)
+    });
+    assert!(held, "watcher state: {last:#}");
+    last
+}
+
+#[test]
+fn events_within_the_debounce_window_are_dropped() {
+    let mirror = start("# Title\n", "debounce_ms = 60000\n");
+    for version in 1..=3 {
+        mirror.write(&format!("# Version {version}\n"));
+        thread::sleep(Duration::from_millis(50));
+    }
+    let state = await_state(&mirror, |state| counter(state, "events") >= 3 && counter(state, "broadcasts") == 1);
+    // only the first event made it through, however many the writes raised
+    assert_eq!(counter(&state, "debounced"), counter(&state, "events") - 1, "{state:#}");
+    let source = mirror.source_path().canonicalize().expect("source path");
+    assert!(state["debounce_ms_ago"][source.display().to_string()].is_u64(), "{state:#}");
+}
+
+#[test]
+fn events_during_the_read_interval_are_coalesced_into_one_read() {
+    let mirror = start("# Title\n", "debounce_ms = 0\nmin_read_interval_ms = 1000\n");
+    for version in 1..=5 {
+        mirror.write(&format!("# Version {version}\n"));
+    }
+    // the deferred read picks up the latest content
+    let state = await_state(&mirror, |state| state["last_content"][SOURCE_FILE] == "# Version 5\n");
+    assert!(counter(&state, "coalesced") >= 1, "{state:#}");
+    assert!(counter(&state, "broadcasts") <= 2, "{state:#}");
+    assert!(counter(&state, "events") > counter(&state, "broadcasts"), "{state:#}");
+}
+
+#[test]
+fn saving_the_same_content_again_broadcasts_nothing() {
+    // over the full content threshold, so unchanged content is not sent again
+    let content: String = (0..100).map(|line| format!("Line {line} of the document.\n")).collect();
+    let mirror = start(&content, "");
+    assert_eq!(state(&mirror)["last_content"][SOURCE_FILE], content.as_str());
+    mirror.write(&content);
+    let state = await_state(&mirror, |state| counter(state, "unchanged") >= 1);
+    assert_eq!(counter(&state, "broadcasts"), 0, "{state:#}");
+
+    let edited = content.replace("Line 50 ", "Line fifty ");
+    mirror.write(&edited);
+    let state = await_state(&mirror, |state| counter(state, "broadcasts") == 1);
+    assert_eq!(state["last_content"][SOURCE_FILE], edited.as_str());
+}
@@ -0,0 +1,36 @@
+//! Windows paths are case-insensitive, and notify reports events with the
+//! on-disk case and a resolved directory, whatever path the server was given.
+#![cfg(windows)]
+
+mod common;
+
+use common::Mirror;
+
+fn assert_mirrors_edits(watch_path: &str) {
+    let content: String = (0..100).map(|i| format!("Line {i} of a long document.\n")).collect();
+    let mut mirror = Mirror::start_server_watching(&content, watch_path, &[]);
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| content.replace("Line 50 ", "Line fifty "));
+    mirror.edit_and_await(|content| format!("# Heading\n\n{content}"));
+}
+
+#[test]
+fn upper_case_file_name_matches_events() {
+    assert_mirrors_edits("DOC.MD");
+}
+
+#[test]
+fn mixed_case_file_name_matches_events() {
+    assert_mirrors_edits("Doc.Md");
+}
+
+#[test]
+fn dot_segments_match_events() {
+    assert_mirrors_edits(r".\.\doc.md");
+}
+
+#[test]
+fn parent_segments_and_mixed_case_match_events() {
+    assert_mirrors_edits(r"missing\..\Doc.MD");
+}
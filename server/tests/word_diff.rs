@@ -0,0 +1,82 @@
+mod common;
+
+use common::Mirror;
+use shared::{CharDiff, DiffStrategy, FileChange, WordDiff};
+
+const PARAGRAPH: &str = "The quick brown fox jumps over the lazy dog, then naps in the warm afternoon sun.\n";
+const EDITED: &str = "The quiet brown fox leaps over the sleepy dog; then rests in the warm evening sun.\n";
+
+/// Char offsets where a word, a run of whitespace or a punctuation mark of `text` starts or ends
+fn word_boundaries(text: &str) -> Vec<usize> {
+    use unicode_segmentation::UnicodeSegmentation;
+    let mut offsets = vec![0];
+    for word in text.split_word_bounds() {
+        offsets.push(offsets.last().copied().unwrap_or_default() + word.chars().count());
+    }
+    offsets
+}
+
+fn apply(old: &str, changes: &[FileChange]) -> String {
+    let mut content = old.to_string();
+    for change in changes {
+        change.try_apply(&mut content).expect("apply");
+    }
+    content
+}
+
+/// Whether every change starts and ends on a word boundary of the content it applies to
+fn aligned_to_words(old: &str, changes: &[FileChange]) -> bool {
+    let mut content = old.to_string();
+    changes.iter().all(|change| {
+        let range = change.affected_range().expect("diff");
+        let boundaries = word_boundaries(&content);
+        let aligned = boundaries.contains(&range.start) && boundaries.contains(&range.end);
+        change.try_apply(&mut content).expect("apply");
+        aligned
+    })
+}
+
+#[test]
+fn word_diff_replaces_whole_words_and_round_trips() {
+    let word_changes = WordDiff.diff("doc.md", PARAGRAPH, EDITED);
+    assert_eq!(apply(PARAGRAPH, &word_changes), EDITED);
+    assert!(aligned_to_words(PARAGRAPH, &word_changes), "{word_changes:?}");
+    let inserted: Vec<&str> = word_changes
+        .iter()
+        .filter_map(|change| match change {
+            FileChange::Diff { insert_text, .. } => Some(insert_text.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(inserted, ["quiet", "leaps", "sleepy", ";", "rests", "evening"]);
+
+    // the char diff reconstructs the same text, but through fragments of words
+    let char_changes = CharDiff.diff("doc.md", PARAGRAPH, EDITED);
+    assert_eq!(apply(PARAGRAPH, &char_changes), EDITED);
+    assert!(!aligned_to_words(PARAGRAPH, &char_changes), "{char_changes:?}");
+}
+
+#[test]
+fn word_diff_round_trips_line_and_whitespace_edits() {
+    let edits = [
+        ("", "A new paragraph.\n"),
+        ("One line.\n", ""),
+        ("Two  spaces.\n", "Two spaces.\n\nAnd a second paragraph.\n"),
+        ("Ünïcödé wörds — dash.\n", "Ünïcödé words – dash!\n"),
+    ];
+    for (old, new) in edits {
+        let changes = WordDiff.diff("doc.md", old, new);
+        assert_eq!(apply(old, &changes), new, "{old:?} -> {new:?}: {changes:?}");
+    }
+}
+
+#[test]
+fn word_strategy_mirrors_prose_edits() {
+    // long enough to be sent as diffs
+    let padding = "Another sentence of the essay.\n".repeat(50);
+    let mut mirror = Mirror::start_server(&format!("{PARAGRAPH}{padding}"), &["--diff", "char,md=word"]);
+    mirror.start_client(&[]);
+    mirror.await_convergence();
+    mirror.edit_and_await(|content| content.replacen(PARAGRAPH, EDITED, 1));
+    assert!(mirror.client_log_count("Applied diff") >= 1);
+}
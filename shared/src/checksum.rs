@@ -0,0 +1,54 @@
+//! Checksums that tell whether two copies of some content are the same, with
+//! a choice of speed against strength: the server picks one, and every
+//! algorithm here is supported by every client.
+
+use std::{fmt, str::FromStr};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// How a checksum is computed; every algorithm writes it as lowercase hex
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    /// FNV-1a 64-bit, see [`digest`](crate::digest)
+    #[default]
+    Fnv1a,
+    /// CRC-32 (IEEE), the fastest to compute
+    Crc32,
+    /// SHA-256, for when a corrupted or tampered copy must not go unnoticed
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub const ALL: [ChecksumAlgorithm; 3] = [ChecksumAlgorithm::Fnv1a, ChecksumAlgorithm::Crc32, ChecksumAlgorithm::Sha256];
+
+    /// The checksum of `content`
+    pub fn checksum(self, content: &str) -> String {
+        match self {
+            ChecksumAlgorithm::Fnv1a => crate::digest(content),
+            ChecksumAlgorithm::Crc32 => format!("{:08x}", crc32fast::hash(content.as_bytes())),
+            ChecksumAlgorithm::Sha256 => Sha256::digest(content.as_bytes()).iter().map(|byte| format!("{byte:02x}")).collect(),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChecksumAlgorithm::Fnv1a => "fnv1a",
+            ChecksumAlgorithm::Crc32 => "crc32",
+            ChecksumAlgorithm::Sha256 => "sha256",
+        })
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ChecksumAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.to_string().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| format!("unknown checksum algorithm: {s}"))
+    }
+}
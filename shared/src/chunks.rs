@@ -0,0 +1,37 @@
+//! Chunk sync for large files, like rsync with fixed block boundaries.
+//!
+//! Instead of the full content, a client that already holds a copy of the
+//! file is sent [`FileChange::ChunkHashes`](crate::FileChange::ChunkHashes):
+//! the checksum of each chunk of `chunk_size` chars. It asks for the chunks
+//! whose hash differs from the same chunk of its copy, gets them as
+//! [`FileChange::Chunks`](crate::FileChange::Chunks) and rebuilds the file from
+//! those and its own. Text added or removed shifts every later chunk, so this
+//! saves the most for edits that keep the length of the file, or near its end.
+
+use crate::ChecksumAlgorithm;
+
+/// `content` split into chunks of `size` chars, the last one possibly shorter
+pub fn split(content: &str, size: usize) -> Vec<&str> {
+    let size = size.max(1);
+    let mut chunks = Vec::new();
+    let mut rest = content;
+    while !rest.is_empty() {
+        let end = rest.char_indices().nth(size).map_or(rest.len(), |(offset, _)| offset);
+        let (chunk, after) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = after;
+    }
+    chunks
+}
+
+/// The checksum of each chunk of `content`, see [`split`]
+pub fn hashes(content: &str, size: usize, algorithm: ChecksumAlgorithm) -> Vec<String> {
+    split(content, size).into_iter().map(|chunk| algorithm.checksum(chunk)).collect()
+}
+
+/// Indices of the chunks in `hashes`, computed with `algorithm`, that
+/// `content` does not hold at the same index
+pub fn missing(content: &str, size: usize, hashes: &[String], algorithm: ChecksumAlgorithm) -> Vec<usize> {
+    let own = self::hashes(content, size, algorithm);
+    (0..hashes.len()).filter(|&index| own.get(index) != Some(&hashes[index])).collect()
+}
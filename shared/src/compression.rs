@@ -0,0 +1,36 @@
+use std::{borrow::Cow, io::{Read, Write}};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+const PREFIX: &str = "{\"Compressed\":\"";
+const SUFFIX: &str = "\"}";
+
+/// Why a compressed message could not be decoded
+#[derive(Debug, thiserror::Error)]
+pub enum DecompressError {
+    #[error("invalid base64: {0}")]
+    Base64(#[from] data_encoding::DecodeError),
+    #[error("invalid deflate stream: {0}")]
+    Deflate(#[from] std::io::Error),
+}
+
+/// Compresses a JSON message for a client that asked for it with
+/// `ClientMessage::SetCompression`: the message is deflated, base64 encoded
+/// and sent as `{"Compressed":"<base64>"}`
+pub fn compress(message: &str) -> String {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    // writing to a Vec cannot fail
+    encoder.write_all(message.as_bytes()).expect("deflate into memory");
+    let deflated = encoder.finish().expect("deflate into memory");
+    format!("{PREFIX}{}{SUFFIX}", data_encoding::BASE64.encode(&deflated))
+}
+
+/// The JSON message carried by `text`, which is either compressed or already plain
+pub fn decompress(text: &str) -> Result<Cow<'_, str>, DecompressError> {
+    let Some(encoded) = text.strip_prefix(PREFIX).and_then(|rest| rest.strip_suffix(SUFFIX)) else {
+        return Ok(Cow::Borrowed(text));
+    };
+    let deflated = data_encoding::BASE64.decode(encoded.as_bytes())?;
+    let mut message = String::new();
+    DeflateDecoder::new(deflated.as_slice()).read_to_string(&mut message)?;
+    Ok(Cow::Owned(message))
+}
@@ -0,0 +1,431 @@
+use std::{str::FromStr, time::{Duration, Instant}};
+use serde::{Deserialize, Serialize};
+use similar::{DiffOp, TextDiff};
+use unicode_segmentation::UnicodeSegmentation;
+use crate::{FileChange, PositionUnit};
+
+/// Turns two versions of a file into the changes needed to go from one to the other.
+///
+/// Positions are expressed in chars and the returned changes must be applied in order,
+/// each one against the result of the previous. On pathological input, where
+/// diffing would take longer than [`DIFF_TIMEOUT`] or produce more than
+/// [`MAX_DIFF_CHANGES`] changes, the new content is returned as one `FullContent`
+/// change instead.
+pub trait DiffStrategy: Send + Sync {
+    fn diff(&self, file_id: &str, old_content: &str, new_content: &str) -> Vec<FileChange>;
+}
+
+/// How long diffing two versions of a file may take before the new content is
+/// sent in full instead, so crafted content can't stall the watcher
+pub const DIFF_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Most changes one diff may produce before the new content is sent in full
+/// instead; past it, the changes cost more to send and apply than the file
+pub const MAX_DIFF_CHANGES: usize = 1000;
+
+/// The whole new content as the only change, for input too costly to diff
+fn full_content(file_id: &str, new_content: &str) -> Vec<FileChange> {
+    vec![FileChange::FullContent {
+        file_id: file_id.to_string(),
+        content: new_content.to_string(),
+        last_modified: None,
+    }]
+}
+
+/// `changes`, or the new content in full when there are too many of them
+fn within_budget(file_id: &str, changes: Vec<FileChange>, new_content: &str) -> Vec<FileChange> {
+    debug_assert!(!changes.iter().any(FileChange::is_noop), "no-op diff in {changes:?}");
+    if changes.len() > MAX_DIFF_CHANGES {
+        return full_content(file_id, new_content);
+    }
+    changes
+}
+
+/// Adds a diff to `changes` unless it neither deletes nor inserts anything,
+/// which would only cost a message
+fn push_diff(changes: &mut Vec<FileChange>, file_id: &str, position: usize, delete_count: usize, insert_text: String) {
+    if delete_count == 0 && insert_text.is_empty() {
+        return;
+    }
+    changes.push(FileChange::Diff {
+        file_id: file_id.to_string(),
+        position,
+        delete_count,
+        insert_text,
+        unit: PositionUnit::Chars,
+    });
+}
+
+/// Character granularity diff (greedy resync on the next matching char)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CharDiff;
+
+impl DiffStrategy for CharDiff {
+    fn diff(&self, file_id: &str, old_content: &str, new_content: &str) -> Vec<FileChange> {
+        let mut changes = Vec::new();
+        let mut i = 0;
+        let mut j = 0;
+        let old_chars: Vec<char> = old_content.chars().collect();
+        let new_chars: Vec<char> = new_content.chars().collect();
+        while i < old_chars.len() && j < new_chars.len() {
+            if old_chars[i] == new_chars[j] {
+                i += 1;
+                j += 1;
+            } else {
+                let start = i;
+                while i < old_chars.len() && (j >= new_chars.len() || old_chars[i] != new_chars[j]) {
+                    i += 1;
+                }
+                let delete_count = i - start;
+                let mut insert_end = j;
+                while insert_end < new_chars.len() && i < old_chars.len() && old_chars[i] != new_chars[insert_end] {
+                    insert_end += 1;
+                }
+                let insert_text: String = new_chars[j..insert_end].iter().collect();
+                // everything before `j` already matches the new content once applied
+                push_diff(&mut changes, file_id, j, delete_count, insert_text);
+                if changes.len() > MAX_DIFF_CHANGES {
+                    return full_content(file_id, new_content);
+                }
+                j = insert_end;
+            }
+        }
+        // whatever is left on one side once the other ran out
+        let trailing_insert: String = new_chars[j..].iter().collect();
+        push_diff(&mut changes, file_id, j, old_chars.len() - i, trailing_insert);
+        within_budget(file_id, changes, new_content)
+    }
+}
+
+/// Line granularity diff, better suited to prose and markdown edits.
+///
+/// Replaced lines longer than [`LONG_LINE_CHARS`], such as minified content or
+/// a wide table row, are diffed char by char instead of being sent whole.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LineDiff;
+
+/// Changed lines longer than this are diffed within the line
+pub const LONG_LINE_CHARS: usize = 256;
+
+/// How long diffing within long lines may take before settling for a coarser diff
+const LONG_LINE_TIMEOUT: Duration = Duration::from_millis(50);
+
+impl DiffStrategy for LineDiff {
+    fn diff(&self, file_id: &str, old_content: &str, new_content: &str) -> Vec<FileChange> {
+        let deadline = Instant::now() + DIFF_TIMEOUT;
+        let text_diff = TextDiff::configure().deadline(deadline).diff_lines(old_content, new_content);
+        let mut changes = Vec::new();
+        push_changes(file_id, &text_diff, 0, deadline, &mut changes);
+        if Instant::now() >= deadline {
+            return full_content(file_id, new_content);
+        }
+        within_budget(file_id, changes, new_content)
+    }
+}
+
+/// Turns the ops of a line diff into changes, starting at char `position`;
+/// diffs within long lines stop at `deadline` like the line diff itself
+fn push_changes(
+    file_id: &str,
+    text_diff: &TextDiff<'_, '_, '_, str>,
+    mut position: usize,
+    deadline: Instant,
+    changes: &mut Vec<FileChange>,
+) {
+    let old_lines = text_diff.old_slices();
+    let new_lines = text_diff.new_slices();
+    let char_len = |lines: &[&str]| lines.iter().map(|l| l.chars().count()).sum::<usize>();
+    for op in text_diff.ops() {
+        let (deleted, inserted) = match *op {
+            DiffOp::Equal { new_index, len, .. } => {
+                position += char_len(&new_lines[new_index..new_index + len]);
+                continue;
+            }
+            DiffOp::Delete { old_index, old_len, .. } => (&old_lines[old_index..old_index + old_len], &[][..]),
+            DiffOp::Insert { new_index, new_len, .. } => (&[][..], &new_lines[new_index..new_index + new_len]),
+            DiffOp::Replace { old_index, old_len, new_index, new_len } => (
+                &old_lines[old_index..old_index + old_len],
+                &new_lines[new_index..new_index + new_len],
+            ),
+        };
+        let is_long = |lines: &[&str]| lines.iter().any(|line| line.chars().count() > LONG_LINE_CHARS);
+        if !deleted.is_empty() && !inserted.is_empty() && (is_long(deleted) || is_long(inserted)) {
+            let (old_text, new_text) = (deleted.concat(), inserted.concat());
+            let char_diff = TextDiff::configure()
+                .deadline(deadline.min(Instant::now() + LONG_LINE_TIMEOUT))
+                .diff_chars(old_text.as_str(), new_text.as_str());
+            push_char_changes(file_id, &char_diff, position, changes);
+        } else {
+            push_diff(changes, file_id, position, char_len(deleted), inserted.concat());
+        }
+        position += char_len(inserted);
+    }
+}
+
+/// Turns the ops of a char (or grapheme, or word) diff into changes, starting at char `position`
+fn push_char_changes(file_id: &str, text_diff: &TextDiff<'_, '_, '_, str>, mut position: usize, changes: &mut Vec<FileChange>) {
+    let old_slices = text_diff.old_slices();
+    let new_slices = text_diff.new_slices();
+    let char_len = |slices: &[&str]| slices.iter().map(|s| s.chars().count()).sum::<usize>();
+    for op in text_diff.ops() {
+        let (deleted, inserted) = match *op {
+            DiffOp::Equal { new_index, len, .. } => {
+                position += char_len(&new_slices[new_index..new_index + len]);
+                continue;
+            }
+            DiffOp::Delete { old_index, old_len, .. } => (&old_slices[old_index..old_index + old_len], &[][..]),
+            DiffOp::Insert { new_index, new_len, .. } => (&[][..], &new_slices[new_index..new_index + new_len]),
+            DiffOp::Replace { old_index, old_len, new_index, new_len } => (
+                &old_slices[old_index..old_index + old_len],
+                &new_slices[new_index..new_index + new_len],
+            ),
+        };
+        push_diff(changes, file_id, position, char_len(deleted), inserted.concat());
+        position += char_len(inserted);
+    }
+}
+
+/// Changes of a diff of the slices `old` and `new` makes up, or `new_content` in
+/// full when the diff runs past [`DIFF_TIMEOUT`] or into too many changes
+fn slice_changes(file_id: &str, old: &[&str], new: &[&str], new_content: &str) -> Vec<FileChange> {
+    let deadline = Instant::now() + DIFF_TIMEOUT;
+    let text_diff = TextDiff::configure().deadline(deadline).diff_slices(old, new);
+    if Instant::now() >= deadline {
+        return full_content(file_id, new_content);
+    }
+    let mut changes = Vec::new();
+    push_char_changes(file_id, &text_diff, 0, &mut changes);
+    within_budget(file_id, changes, new_content)
+}
+
+/// Grapheme cluster granularity diff: positions still count chars, but every
+/// change starts and ends on a cluster boundary, so a flag, a ZWJ emoji
+/// sequence or a letter with combining marks is never split between changes
+/// and viewers never show half of one
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GraphemeDiff;
+
+impl DiffStrategy for GraphemeDiff {
+    fn diff(&self, file_id: &str, old_content: &str, new_content: &str) -> Vec<FileChange> {
+        let old_graphemes: Vec<&str> = old_content.graphemes(true).collect();
+        let new_graphemes: Vec<&str> = new_content.graphemes(true).collect();
+        slice_changes(file_id, &old_graphemes, &new_graphemes, new_content)
+    }
+}
+
+/// Word granularity diff for prose: the text is split on word boundaries
+/// (words, runs of whitespace and single punctuation marks), and every change
+/// replaces whole tokens, so a reworded sentence reads as words swapped rather
+/// than scattered letters. Positions still count chars.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WordDiff;
+
+impl DiffStrategy for WordDiff {
+    fn diff(&self, file_id: &str, old_content: &str, new_content: &str) -> Vec<FileChange> {
+        let old_words: Vec<&str> = old_content.split_word_bounds().collect();
+        let new_words: Vec<&str> = new_content.split_word_bounds().collect();
+        slice_changes(file_id, &old_words, &new_words, new_content)
+    }
+}
+
+/// Diffs a document's front matter and body separately with the wrapped
+/// strategy, so an edit to one never produces a change straddling into the
+/// other. Documents without front matter on both sides are diffed whole.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrontMatterDiff<S>(pub S);
+
+impl<S: DiffStrategy> DiffStrategy for FrontMatterDiff<S> {
+    fn diff(&self, file_id: &str, old_content: &str, new_content: &str) -> Vec<FileChange> {
+        let (Some(old_len), Some(new_len)) = (front_matter_len(old_content), front_matter_len(new_content)) else {
+            return self.0.diff(file_id, old_content, new_content);
+        };
+        let (old_front, old_body) = old_content.split_at(old_len);
+        let (new_front, new_body) = new_content.split_at(new_len);
+        let mut changes = self.0.diff(file_id, old_front, new_front);
+        let body_changes = self.0.diff(file_id, old_body, new_body);
+        // either part being too costly to diff makes the whole document full content
+        let gave_up = |changes: &[FileChange]| matches!(changes, [FileChange::FullContent { .. }]);
+        if gave_up(&changes) || gave_up(&body_changes) {
+            return full_content(file_id, new_content);
+        }
+        // the body changes apply after the front matter already has its new length
+        let offset = new_front.chars().count();
+        changes.extend(body_changes.into_iter().map(|change| match change {
+            FileChange::Diff { file_id, position, delete_count, insert_text, unit } => FileChange::Diff {
+                file_id,
+                position: position + offset,
+                delete_count,
+                insert_text,
+                unit,
+            },
+            other => other,
+        }));
+        within_budget(file_id, changes, new_content)
+    }
+}
+
+/// Byte length of the front matter block at the start of `content`, closing
+/// line included: YAML (`---`), TOML (`+++`) or JSON (`{` to `}`)
+pub fn front_matter_len(content: &str) -> Option<usize> {
+    let mut lines = content.split_inclusive('\n');
+    let opening = lines.next()?;
+    let closing = match opening.trim_end() {
+        "---" => "---",
+        "+++" => "+++",
+        "{" => "}",
+        _ => return None,
+    };
+    let mut len = opening.len();
+    for line in lines {
+        len += line.len();
+        if line.trim_end() == closing {
+            return Some(len);
+        }
+    }
+    None
+}
+
+/// Names the available strategies so they can be picked from configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffStrategyKind {
+    #[default]
+    Char,
+    Line,
+    /// Line diff of front matter and body separately
+    FrontMatter,
+    /// Char diff that never splits a grapheme cluster
+    Grapheme,
+    /// Diff of whole words, for prose
+    Word,
+}
+
+impl DiffStrategyKind {
+    pub fn strategy(self) -> Box<dyn DiffStrategy> {
+        match self {
+            DiffStrategyKind::Char => Box::new(CharDiff),
+            DiffStrategyKind::Line => Box::new(LineDiff),
+            DiffStrategyKind::FrontMatter => Box::new(FrontMatterDiff(LineDiff)),
+            DiffStrategyKind::Grapheme => Box::new(GraphemeDiff),
+            DiffStrategyKind::Word => Box::new(WordDiff),
+        }
+    }
+}
+
+impl FromStr for DiffStrategyKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "char" => Ok(DiffStrategyKind::Char),
+            "line" => Ok(DiffStrategyKind::Line),
+            "frontmatter" => Ok(DiffStrategyKind::FrontMatter),
+            "grapheme" => Ok(DiffStrategyKind::Grapheme),
+            "word" => Ok(DiffStrategyKind::Word),
+            other => Err(format!("unknown diff strategy: {other}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STRATEGIES: [DiffStrategyKind; 5] = [
+        DiffStrategyKind::Char,
+        DiffStrategyKind::Line,
+        DiffStrategyKind::FrontMatter,
+        DiffStrategyKind::Grapheme,
+        DiffStrategyKind::Word,
+    ];
+
+    /// Pairs of versions every strategy must turn into changes that make the new one
+    const PAIRS: &[(&str, &str)] = &[
+        ("", ""),
+        ("", "# Title\n\nSome text.\n"),
+        ("# Title\n\nSome text.\n", ""),
+        ("Some text.\n", "Some more text.\n"),
+        ("Some more text.\n", "Some text.\n"),
+        ("Some text.\n", "Other words entirely.\n"),
+        ("first\nsecond\nthird\n", "first\nthird\n"),
+        ("first\nthird\n", "first\nsecond\nthird\n"),
+        ("café au lait\n", "café noir\n"),
+        ("Wave 👋 here.\n", "Wave 👋👋 HERE.\n"),
+        ("言葉 and words\n", "words and 言葉\n"),
+        ("🇫🇷 e\u{301}\n", "🇩🇪 e\u{301}e\n"),
+        ("---\ntitle: Old\n---\nBody 👋\n", "---\ntitle: New 🚀\n---\nBody 👋 edited\n"),
+        ("---\ntitle: Old\n---\nBody\n", "No front matter any more\n"),
+    ];
+
+    fn applied(changes: &[FileChange], old: &str) -> String {
+        let mut content = old.to_string();
+        for change in changes {
+            change.try_apply(&mut content).unwrap_or_else(|error| panic!("{change:?}: {error}"));
+        }
+        content
+    }
+
+    #[test]
+    fn every_strategy_diffs_old_into_new() {
+        for strategy in STRATEGIES {
+            for (old, new) in PAIRS {
+                let changes = strategy.strategy().diff("doc.md", old, new);
+                assert_eq!(applied(&changes, old), *new, "{strategy:?} from {old:?} to {new:?}: {changes:?}");
+                assert!(changes.iter().all(|change| !change.is_noop()), "{strategy:?}: {changes:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn identical_content_makes_no_changes() {
+        for strategy in STRATEGIES {
+            for content in ["", "Some text.\n", "café 👋\n"] {
+                assert_eq!(strategy.strategy().diff("doc.md", content, content), Vec::new(), "{strategy:?}");
+            }
+        }
+    }
+
+    fn diff(position: usize, delete_count: usize, insert_text: &str) -> FileChange {
+        FileChange::Diff {
+            file_id: "doc.md".to_string(),
+            position,
+            delete_count,
+            insert_text: insert_text.to_string(),
+            unit: PositionUnit::Chars,
+        }
+    }
+
+    #[test]
+    fn char_diff_positions_count_chars_of_the_content_so_far() {
+        // '👋' is one char, so 'h' is char 7 while it is byte 10
+        assert_eq!(CharDiff.diff("doc.md", "Wave 👋 here", "Wave 👋 HERE"), vec![diff(7, 4, ""), diff(7, 0, "HERE")]);
+        // the second delete is positioned after the first one applied: 'c' is char 3 before it
+        assert_eq!(CharDiff.diff("doc.md", "a👋bcd", "abd"), vec![diff(1, 1, ""), diff(2, 1, "")]);
+        // greedy: an insert before matching text deletes and reinserts that text
+        assert_eq!(CharDiff.diff("doc.md", "ab", "a👋b"), vec![diff(1, 1, ""), diff(1, 0, "👋b")]);
+    }
+
+    #[test]
+    fn pure_inserts_and_deletes_are_single_changes() {
+        assert_eq!(CharDiff.diff("doc.md", "", "言葉"), vec![diff(0, 0, "言葉")]);
+        assert_eq!(CharDiff.diff("doc.md", "言葉", ""), vec![diff(0, 2, "")]);
+        assert_eq!(CharDiff.diff("doc.md", "a👋", "a👋b"), vec![diff(2, 0, "b")]);
+        assert_eq!(CharDiff.diff("doc.md", "a👋b", "ab"), vec![diff(1, 1, "")]);
+        assert_eq!(GraphemeDiff.diff("doc.md", "ab", "a👋b"), vec![diff(1, 0, "👋")]);
+        assert_eq!(LineDiff.diff("doc.md", "one\nthree\n", "one\ntwo\nthree\n"), vec![diff(4, 0, "two\n")]);
+        assert_eq!(LineDiff.diff("doc.md", "one\ntwo\nthree\n", "one\nthree\n"), vec![diff(4, 4, "")]);
+    }
+
+    #[test]
+    fn front_matter_and_body_changes_do_not_straddle() {
+        let old = "---\ntitle: Old\n---\nBody\n";
+        let new = "---\ntitle: New\n---\nBody text\n";
+        let changes = FrontMatterDiff(CharDiff).diff("doc.md", old, new);
+        let front_len = new.find("Body").expect("body");
+        for change in &changes {
+            let FileChange::Diff { position, delete_count, .. } = change else { panic!("{change:?}") };
+            assert!(position + delete_count <= front_len || *position >= front_len, "{change:?}");
+        }
+        assert_eq!(applied(&changes, old), new);
+    }
+}
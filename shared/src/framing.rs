@@ -0,0 +1,63 @@
+//! Length-prefixed framing for transports that carry messages over a byte
+//! stream, e.g. the server's Unix socket: every message is preceded by its
+//! length as a 4-byte big-endian integer.
+//!
+//! A frame longer than the agreed maximum is rejected from its header alone,
+//! before anything is buffered for it, and a stream that ends in the middle of
+//! a frame is reported as [`FrameError::Truncated`] instead of being waited on.
+
+/// Size of the length prefix
+pub const HEADER_LEN: usize = 4;
+
+/// Largest frame accepted unless configured otherwise
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum FrameError {
+    #[error("frame of {len} bytes is over the {max} byte limit")]
+    Oversized { len: usize, max: usize },
+    #[error("stream ended in the middle of a frame, after {received} of {expected} bytes")]
+    Truncated { expected: usize, received: usize },
+}
+
+/// The message with its length prefix
+pub fn encode(message: &[u8], max_len: usize) -> Result<Vec<u8>, FrameError> {
+    let len = check_len(message.len(), max_len)?;
+    let mut frame = Vec::with_capacity(HEADER_LEN + message.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(message);
+    Ok(frame)
+}
+
+/// The first complete frame in `buf` and the number of bytes it takes up,
+/// header included; `None` while more bytes are needed
+pub fn decode(buf: &[u8], max_len: usize) -> Result<Option<(&[u8], usize)>, FrameError> {
+    let Some(header) = buf.first_chunk::<HEADER_LEN>() else {
+        return Ok(None);
+    };
+    let len = u32::from_be_bytes(*header) as usize;
+    if len > max_len {
+        return Err(FrameError::Oversized { len, max: max_len });
+    }
+    let end = HEADER_LEN + len;
+    Ok(buf.get(HEADER_LEN..end).map(|payload| (payload, end)))
+}
+
+/// Checks the bytes left over when the stream ended: anything but nothing is
+/// a frame cut short
+pub fn finish(buf: &[u8]) -> Result<(), FrameError> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    let expected = buf
+        .first_chunk::<HEADER_LEN>()
+        .map_or(HEADER_LEN, |header| HEADER_LEN + u32::from_be_bytes(*header) as usize);
+    Err(FrameError::Truncated { expected, received: buf.len() })
+}
+
+fn check_len(len: usize, max_len: usize) -> Result<u32, FrameError> {
+    match u32::try_from(len) {
+        Ok(prefix) if len <= max_len => Ok(prefix),
+        _ => Err(FrameError::Oversized { len, max: max_len }),
+    }
+}
@@ -1,10 +1,28 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::time::SystemTime;
+
+pub mod checksum;
+pub mod chunks;
+pub mod compression;
+pub mod diff;
+pub mod framing;
+pub mod runtime;
+pub mod seq;
+pub mod signing;
+pub mod tcp;
+pub mod utf16;
+
+pub use checksum::ChecksumAlgorithm;
+pub use diff::{CharDiff, DiffStrategy, DiffStrategyKind, FrontMatterDiff, GraphemeDiff, LineDiff, WordDiff};
+pub use utf16::PositionUnit;
 
 /// Protocol constants for WebSocket communication
 pub mod protocol {
     pub const DEFAULT_SERVER_URL: &str = "ws://localhost:3030";
     pub const DEFAULT_SERVER_PORT: u16 = 3030;
+    pub const DEFAULT_BIND_ADDR: &str = "127.0.0.1:3030";
     pub const DEFAULT_WATCH_FILE: &str = "README.md";
 }
 
@@ -15,6 +33,9 @@ pub enum FileChange {
     FullContent {
         file_id: String,
         content: String,
+        /// Modification time of the source file, when the content was read from one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_modified: Option<SystemTime>,
     },
     
     /// Represents a diff between versions
@@ -23,76 +44,438 @@ pub enum FileChange {
         position: usize,
         delete_count: usize,
         insert_text: String,
-    }
+        /// What `position` and `delete_count` count; left out of the JSON for chars
+        #[serde(default, skip_serializing_if = "PositionUnit::is_chars")]
+        unit: PositionUnit,
+    },
+
+    /// The file failed validation; clients keep their last good copy
+    ValidationError {
+        file_id: String,
+        message: String,
+    },
+
+    /// Sent in place of the full content of a large file to a client that
+    /// holds a copy already, see [`chunks`]; the client answers with
+    /// [`ClientMessage::RequestChunks`] for the chunks its copy lacks
+    ChunkHashes {
+        file_id: String,
+        /// Chars per chunk, the last one may be shorter
+        chunk_size: usize,
+        /// How `hashes` and `digest` were computed
+        #[serde(default)]
+        algorithm: ChecksumAlgorithm,
+        hashes: Vec<String>,
+        /// Checksum of the whole content, to check the rebuilt file against
+        digest: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_modified: Option<SystemTime>,
+    },
+
+    /// The chunks a client asked for, by index, of the content the hash list
+    /// with the same seq was made from
+    Chunks {
+        file_id: String,
+        chunks: BTreeMap<usize, String>,
+    },
+
+    /// The source file's permissions or modification time changed while its
+    /// content did not; only broadcast by servers watching metadata
+    MetadataChanged {
+        file_id: String,
+        /// Unix permission bits, `None` on platforms without them
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mtime: Option<SystemTime>,
+    },
 }
 
 impl FileChange {
+    /// Names of the variants as they appear in JSON, to tell a change of a kind
+    /// added in a later version apart from a corrupt one
+    pub const KINDS: [&'static str; 6] = ["FullContent", "Diff", "ValidationError", "ChunkHashes", "Chunks", "MetadataChanged"];
+
     /// Creates an efficient diff between two strings
     pub fn create_diff(file_id: &str, old_content: &str, new_content: &str) -> Vec<Self> {
-        let mut changes = Vec::new();
-        let mut i = 0;
-        let mut j = 0;
-        let old_chars: Vec<char> = old_content.chars().collect();
-        let new_chars: Vec<char> = new_content.chars().collect();
-        while i < old_chars.len() && j < new_chars.len() {
-            if old_chars[i] == new_chars[j] {
-                i += 1;
-                j += 1;
-            } else {
-                let start = i;
-                while i < old_chars.len() && (j >= new_chars.len() || old_chars[i] != new_chars[j]) {
-                    i += 1;
-                }
-                let delete_count = i - start;
-                let mut insert_end = j;
-                while insert_end < new_chars.len() && i < old_chars.len() && old_chars[i] != new_chars[insert_end] {
-                    insert_end += 1;
+        CharDiff.diff(file_id, old_content, new_content)
+    }
+    
+    /// The file this change belongs to
+    pub fn file_id(&self) -> &str {
+        match self {
+            FileChange::FullContent { file_id, .. }
+            | FileChange::Diff { file_id, .. }
+            | FileChange::ValidationError { file_id, .. }
+            | FileChange::ChunkHashes { file_id, .. }
+            | FileChange::Chunks { file_id, .. }
+            | FileChange::MetadataChanged { file_id, .. } => file_id,
+        }
+    }
+
+    /// The part of the previous content a diff replaces, in the diff's unit;
+    /// `None` when the whole file is affected or nothing is
+    pub fn affected_range(&self) -> Option<Range<usize>> {
+        match self {
+            FileChange::Diff { position, delete_count, .. } => Some(*position..position + delete_count),
+            _ => None,
+        }
+    }
+
+    /// Whether this is a diff that neither deletes nor inserts anything
+    pub fn is_noop(&self) -> bool {
+        matches!(self, FileChange::Diff { delete_count: 0, insert_text, .. } if insert_text.is_empty())
+    }
+
+    /// The text a diff appends to content of `len` chars; `None` for any
+    /// change that does more than add text at the end, and for diffs that do
+    /// not count chars
+    pub fn appended_text(&self, len: usize) -> Option<&str> {
+        match self {
+            FileChange::Diff { position, delete_count: 0, insert_text, unit: PositionUnit::Chars, .. } if *position == len => {
+                Some(insert_text)
+            }
+            _ => None,
+        }
+    }
+
+    /// The char diff with its position and delete count counted in `unit`
+    /// over `base`, the content it applies to (see [`utf16`]); `None` for
+    /// other changes and for diffs that count the same either way
+    pub fn to_unit(&self, base: &str, unit: PositionUnit) -> Option<FileChange> {
+        let FileChange::Diff { file_id, position, delete_count, insert_text, unit: PositionUnit::Chars } = self else {
+            return None;
+        };
+        let mut chars = base.chars();
+        let unit_position: usize = chars.by_ref().take(*position).map(|c| unit.width(c)).sum();
+        let unit_delete_count: usize = chars.take(*delete_count).map(|c| unit.width(c)).sum();
+        if unit_position == *position && unit_delete_count == *delete_count {
+            return None;
+        }
+        Some(FileChange::Diff {
+            file_id: file_id.clone(),
+            position: unit_position,
+            delete_count: unit_delete_count,
+            insert_text: insert_text.clone(),
+            unit,
+        })
+    }
+
+    /// Translates the change for a client that only holds the chars in `range`
+    /// of the file, with positions relative to the start of the range.
+    ///
+    /// `range` is moved to cover the same text after the change. Returns `None`
+    /// when the change does not touch the range, e.g. an edit further down.
+    pub fn narrow_to(&self, range: &mut Range<usize>) -> Option<FileChange> {
+        match self {
+            FileChange::FullContent { file_id, content, last_modified } => {
+                let len = content.chars().count();
+                *range = range.start.min(len)..range.end.min(len);
+                Some(FileChange::FullContent {
+                    file_id: file_id.clone(),
+                    content: char_slice(content, range.clone()),
+                    last_modified: *last_modified,
+                })
+            }
+            FileChange::Diff { file_id, position, delete_count, insert_text, .. } => {
+                let (start, end) = (*position, position + delete_count);
+                let inserted = insert_text.chars().count();
+                if end <= range.start {
+                    // entirely before the range: it just shifts
+                    range.start = range.start - delete_count + inserted;
+                    range.end = range.end - delete_count + inserted;
+                    return None;
                 }
-                let insert_text: String = new_chars[j..insert_end].iter().collect();
-                if !insert_text.is_empty() || delete_count > 0 {
-                    changes.push(FileChange::Diff {
-                        file_id: file_id.to_string(),
-                        position: start,
-                        delete_count,
-                        insert_text,
-                    });
+                if start >= range.end {
+                    return None;
                 }
-                j = insert_end;
+                let local_start = start.max(range.start);
+                let local_end = end.min(range.end);
+                let narrowed = FileChange::Diff {
+                    file_id: file_id.clone(),
+                    position: local_start - range.start,
+                    delete_count: local_end - local_start,
+                    insert_text: insert_text.clone(),
+                    unit: PositionUnit::Chars,
+                };
+                // text deleted outside the range was never sent, so the range
+                // now starts at the edit and ends after whatever it kept
+                *range = range.start.min(start)..start + inserted + range.end.saturating_sub(end);
+                Some(narrowed)
             }
+            // sent to one connection, never broadcast, or about the file rather than its content
+            FileChange::ValidationError { .. }
+            | FileChange::ChunkHashes { .. }
+            | FileChange::Chunks { .. }
+            | FileChange::MetadataChanged { .. } => Some(self.clone()),
         }
-        if i < old_chars.len() {
-            changes.push(FileChange::Diff {
-                file_id: file_id.to_string(),
-                position: i,
-                delete_count: old_chars.len() - i,
-                insert_text: String::new(),
-            });
-        } else if j < new_chars.len() {
-            let insert_text: String = new_chars[j..].iter().collect();
-            changes.push(FileChange::Diff {
-                file_id: file_id.to_string(),
-                position: old_chars.len(),
-                delete_count: 0,
-                insert_text,
-            });
+    }
+
+    /// Translates the change for a client that only holds the chars from
+    /// `offset` to the end of the file, with positions relative to `offset`.
+    ///
+    /// `offset` is moved to where the same text starts after the change; text
+    /// inserted at the offset is part of the tail. Returns `None` when the
+    /// change is entirely before the offset.
+    pub fn narrow_from(&self, offset: &mut usize) -> Option<FileChange> {
+        match self {
+            FileChange::FullContent { file_id, content, last_modified } => {
+                *offset = (*offset).min(content.chars().count());
+                Some(FileChange::FullContent {
+                    file_id: file_id.clone(),
+                    content: content.chars().skip(*offset).collect(),
+                    last_modified: *last_modified,
+                })
+            }
+            FileChange::Diff { file_id, position, delete_count, insert_text, .. } => {
+                let (start, end) = (*position, position + delete_count);
+                if start < *offset && end <= *offset {
+                    // entirely before the tail: it just shifts
+                    *offset = *offset - delete_count + insert_text.chars().count();
+                    return None;
+                }
+                // a diff that starts before the offset deletes the head of the
+                // tail, and the tail then starts with its text
+                let local_start = start.saturating_sub(*offset);
+                let narrowed = FileChange::Diff {
+                    file_id: file_id.clone(),
+                    position: local_start,
+                    delete_count: end - start.max(*offset),
+                    insert_text: insert_text.clone(),
+                    unit: PositionUnit::Chars,
+                };
+                *offset = (*offset).min(start);
+                Some(narrowed)
+            }
+            FileChange::ValidationError { .. }
+            | FileChange::ChunkHashes { .. }
+            | FileChange::Chunks { .. }
+            | FileChange::MetadataChanged { .. } => Some(self.clone()),
         }
-        changes
     }
-    
-    /// Applies the change to a string in-place
+
+    /// Applies the change to a string in-place, ignoring changes that do not fit the content
     pub fn apply(&self, content: &mut String) {
+        let _ = self.try_apply(content);
+    }
+
+    /// Applies the change to a string in-place, leaving it untouched if the change does not fit.
+    /// Diff positions count in the diff's unit, chars unless it says otherwise; a
+    /// position inside a char, e.g. between the bytes of one, does not fit.
+    pub fn try_apply(&self, content: &mut String) -> Result<(), ApplyError> {
         match self {
             FileChange::FullContent { content: new_content, .. } => {
                 *content = new_content.clone();
             }
-            FileChange::Diff { position, delete_count, insert_text, .. } => {
-                if *position <= content.len() {
-                    let end = (*position + *delete_count).min(content.len());
-                    content.replace_range(*position..end, insert_text);
-                }
+            FileChange::Diff { position, delete_count, insert_text, unit, .. } => {
+                let out_of_range = || ApplyError::OutOfRange {
+                    position: *position,
+                    delete_count: *delete_count,
+                    len: unit.len(content),
+                    unit: *unit,
+                };
+                let start = byte_offset(content, 0, *position, *unit).ok_or_else(out_of_range)?;
+                let end = byte_offset(content, start, *delete_count, *unit).ok_or_else(out_of_range)?;
+                content.replace_range(start..end, insert_text);
             }
+            // chunks only make content together with the client's own copy
+            FileChange::ValidationError { .. }
+            | FileChange::ChunkHashes { .. }
+            | FileChange::Chunks { .. }
+            | FileChange::MetadataChanged { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// A change as sent to clients, numbered in broadcast order across all files
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Sequenced {
+    pub seq: u64,
+    /// When the server detected the change, never earlier than the change
+    /// before it; clients measure how long mirroring took from it. `None`
+    /// for content sent to one client, such as its initial content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detected_at: Option<SystemTime>,
+    #[serde(flatten)]
+    pub change: FileChange,
+}
+
+impl Sequenced {
+    /// Fields of the JSON besides the change itself, which is one field named after its kind
+    pub const ENVELOPE_FIELDS: [&'static str; 2] = ["seq", "detected_at"];
+
+    /// A change that was not detected as such, e.g. a client's initial content
+    pub fn new(seq: u64, change: FileChange) -> Self {
+        Self { seq, detected_at: None, change }
+    }
+}
+
+/// Brackets the content a client is sent for a file when it connects, so it
+/// can show progress and tell when it has caught up. Not a change: it carries
+/// the seq of the content it brackets and is not acked. Only sent by servers
+/// run with `--sync-report`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum SyncProgress {
+    /// The content of `file_id` as of `seq` follows, `total_bytes` of UTF-8
+    /// (as full content, or as chunks of it the client may partly hold already)
+    SyncStart { file_id: String, total_bytes: usize, seq: u64 },
+    /// The client holds `file_id` as of `seq`
+    SyncComplete { file_id: String, seq: u64 },
+}
+
+/// A change as a client receives it. A change of a kind added in a later
+/// version of the protocol is told apart from a corrupt message, so an older
+/// client can skip it (acking its seq) instead of giving up on the connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Received {
+    Change(Sequenced),
+    /// A progress report around a file's initial content
+    Sync(SyncProgress),
+    /// A change of a kind not in [`FileChange::KINDS`]
+    Unknown { kind: String, seq: Option<u64> },
+}
+
+impl Received {
+    /// Parses a change; JSON that fails to parse only because of its kind is `Unknown`
+    pub fn parse(text: &str) -> Result<Self, serde_json::Error> {
+        match serde_json::from_str(text) {
+            Ok(message) => Ok(Received::Change(message)),
+            Err(error) => match serde_json::from_str(text) {
+                Ok(progress) => Ok(Received::Sync(progress)),
+                Err(_) => serde_json::from_str(text).ok().and_then(Self::unknown).ok_or(error),
+            },
+        }
+    }
+
+    /// Like [`Received::parse`], for a change that is part of a larger document,
+    /// e.g. a long-poll response
+    pub fn from_value(value: serde_json::Value) -> Result<Self, serde_json::Error> {
+        match serde_json::from_value(value.clone()) {
+            Ok(message) => Ok(Received::Change(message)),
+            Err(error) => match serde_json::from_value(value.clone()) {
+                Ok(progress) => Ok(Received::Sync(progress)),
+                Err(_) => Self::unknown(value).ok_or(error),
+            },
+        }
+    }
+
+    /// The kind and seq of a change whose only field besides the envelope is a kind this version does not know
+    fn unknown(value: serde_json::Value) -> Option<Self> {
+        let serde_json::Value::Object(fields) = value else {
+            return None;
+        };
+        let seq = fields.get("seq").and_then(serde_json::Value::as_u64);
+        let mut kinds = fields.keys().filter(|key| !Sequenced::ENVELOPE_FIELDS.contains(&key.as_str()));
+        match (kinds.next(), kinds.next()) {
+            (Some(kind), None) if !FileChange::KINDS.contains(&kind.as_str()) => Some(Received::Unknown { kind: kind.clone(), seq }),
+            _ => None,
+        }
+    }
+}
+
+/// Messages a client sends to the server
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ClientMessage {
+    /// Every change up to and including `seq` has been applied
+    Ack { seq: u64 },
+    /// Only mirror chars `start..end` of the file from now on
+    SubscribeRange { file_id: String, start: usize, end: usize },
+    /// Only mirror the file from char `offset` to its end from now on, e.g. a
+    /// viewer that already shows everything before it
+    SubscribeFrom { file_id: String, offset: usize },
+    /// The client's copy of the file is broken, send it again in full
+    Resync { file_id: String },
+    /// Send the diffs that turn `content` into the current content of the file,
+    /// e.g. after a long disconnect, instead of the whole file
+    RequestDiffFromContent { file_id: String, content: String },
+    /// Send these chunks of the file the last [`FileChange::ChunkHashes`] was made from
+    RequestChunks { file_id: String, indices: Vec<usize> },
+    /// Send this connection's messages compressed (see [`compression`]) or plain from now on
+    SetCompression { enabled: bool },
+    /// Controls the server rather than this connection
+    Control(Control),
+}
+
+/// Server-wide commands a client can send
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Control {
+    /// Stop broadcasting changes, e.g. during a bulk operation on the watched files
+    Pause,
+    /// Broadcast the current content of every file that changed while paused, then continue
+    Resume,
+    /// Ask for a [`ServerStatus`], answered with [`ControlReply::Status`]
+    Status,
+}
+
+/// The server's answer to a [`Control`] command that asks for one, or with
+/// the server's `--echo` to any client message
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ControlReply {
+    Status(ServerStatus),
+    /// A client message as the server parsed it and as it applied it, after
+    /// whatever the server sent in answer to it; `applied` is `None` when the
+    /// message was ignored, e.g. a subscription to a file that is not watched
+    Echo { received: ClientMessage, applied: Option<ClientMessage> },
+}
+
+/// A snapshot of the server's state, for scripts and monitoring
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ServerStatus {
+    /// Seq of the last broadcast change
+    pub seq: u64,
+    pub paused: bool,
+    /// Connected clients, including the one asking
+    pub connections: usize,
+    pub files: Vec<FileStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FileStatus {
+    pub file_id: String,
+    /// Size in bytes of the content as of `seq`, if it is known yet
+    pub bytes: Option<usize>,
+    /// [`digest`] of that content
+    pub digest: Option<String>,
+}
+
+/// FNV-1a 64-bit hash of the content, as 16 hex digits; cheap to compute and
+/// enough to tell whether two copies of a file differ, not a cryptographic hash
+pub fn digest(content: &str) -> String {
+    let hash = content.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{hash:016x}")
+}
+
+/// Byte offset `count` positions in `unit` after byte offset `from`, which must
+/// be on a char boundary; `None` past the end of `content` or inside a char
+fn byte_offset(content: &str, from: usize, count: usize, unit: PositionUnit) -> Option<usize> {
+    let mut counted = 0;
+    for (offset, c) in content[from..].char_indices() {
+        if counted >= count {
+            return (counted == count).then_some(from + offset);
         }
+        counted += unit.width(c);
     }
+    (counted == count).then_some(content.len())
+}
+
+/// The chars of `content` in `range`, clamped to its length
+pub fn char_slice(content: &str, range: Range<usize>) -> String {
+    content.chars().skip(range.start).take(range.end.saturating_sub(range.start)).collect()
+}
+
+/// Why a change could not be applied to some content
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ApplyError {
+    #[error("diff at position {position} deleting {delete_count} is out of range for content of {len} {unit}")]
+    OutOfRange {
+        position: usize,
+        delete_count: usize,
+        len: usize,
+        unit: PositionUnit,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -110,4 +493,57 @@ impl Default for FileState {
     }
 }
 
-pub type FileRegistry = HashMap<String, FileState>;
\ No newline at end of file
+pub type FileRegistry = HashMap<String, FileState>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(name: &str) -> String {
+        (0..40).map(|i| format!("{name} line {i} of the document.\n")).collect()
+    }
+
+    /// Narrows the line diff from `old` to `new`, returning what a range subscriber is sent
+    fn narrowed(old: &str, new: &str, range: &mut Range<usize>) -> Vec<FileChange> {
+        LineDiff.diff("doc.md", old, new).iter().filter_map(|change| change.narrow_to(range)).collect()
+    }
+
+    #[test]
+    fn only_edits_inside_the_range_are_forwarded() {
+        let (head, middle, tail) = (lines("Head"), lines("Middle"), lines("Tail"));
+        let start = head.chars().count();
+        let mut range = start..start + middle.chars().count();
+        let full = FileChange::FullContent { file_id: "doc.md".to_string(), content: format!("{head}{middle}{tail}"), last_modified: None };
+        let Some(FileChange::FullContent { mut content, .. }) = full.narrow_to(&mut range) else {
+            panic!("expected the content of the range");
+        };
+        assert_eq!(content, middle);
+
+        // before the range it only moves, after it nothing is mirrored
+        let edited_head = head.replace("Head line 3 ", "Head line three ");
+        assert_eq!(narrowed(&format!("{head}{middle}{tail}"), &format!("{edited_head}{middle}{tail}"), &mut range), []);
+        assert_eq!(range, edited_head.chars().count()..edited_head.chars().count() + middle.chars().count());
+        let edited_tail = tail.replace("Tail line 5 ", "Tail line five ");
+        assert_eq!(narrowed(&format!("{edited_head}{middle}{tail}"), &format!("{edited_head}{middle}{edited_tail}"), &mut range), []);
+
+        let edited = middle.replace("Middle line 7 ", "Middle line seven ");
+        let changes = narrowed(&format!("{edited_head}{middle}{edited_tail}"), &format!("{edited_head}{edited}{edited_tail}"), &mut range);
+        assert!(!changes.is_empty());
+        for change in &changes {
+            change.try_apply(&mut content).expect("diff fits the range");
+        }
+        assert_eq!(content, edited);
+        assert_eq!(range.end - range.start, edited.chars().count());
+    }
+
+    #[test]
+    fn an_edit_across_the_start_of_the_range_is_clipped_to_it() {
+        let mut range = 4..8;
+        let change = FileChange::Diff { file_id: "doc.md".to_string(), position: 2, delete_count: 4, insert_text: "xy".to_string(), unit: PositionUnit::Chars };
+        let narrowed = change.narrow_to(&mut range);
+        assert_eq!(
+            narrowed,
+            Some(FileChange::Diff { file_id: "doc.md".to_string(), position: 0, delete_count: 2, insert_text: "xy".to_string(), unit: PositionUnit::Chars })
+        );
+        assert_eq!(range, 2..6);
+    }
+}
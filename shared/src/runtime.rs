@@ -0,0 +1,32 @@
+//! The multi-threaded tokio runtime the server and client run on, sized by
+//! their `--worker-threads` option.
+
+use std::{io, num::NonZeroUsize};
+use tokio::runtime::{Builder, Runtime};
+
+/// A runtime with `worker_threads` workers, or one per available core when
+/// `None`, with IO and timers enabled
+pub fn multi_thread(worker_threads: Option<NonZeroUsize>) -> io::Result<Runtime> {
+    let worker_threads = worker_threads
+        .or_else(|| std::thread::available_parallelism().ok())
+        .map_or(1, NonZeroUsize::get);
+    Builder::new_multi_thread().worker_threads(worker_threads).enable_all().build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_has_the_configured_workers() {
+        let runtime = multi_thread(NonZeroUsize::new(3)).expect("runtime");
+        assert_eq!(runtime.metrics().num_workers(), 3);
+    }
+
+    #[test]
+    fn runtime_defaults_to_the_available_parallelism() {
+        let expected = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let runtime = multi_thread(None).expect("runtime");
+        assert_eq!(runtime.metrics().num_workers(), expected);
+    }
+}
@@ -0,0 +1,138 @@
+//! Sequence numbers of changes: the server hands them out in broadcast order,
+//! one past the last across all files, and clients follow the ones they see.
+//! Seq 0 stands for the state before any change was broadcast.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Hands out increasing seqs, starting from 1
+#[derive(Debug, Default)]
+pub struct SeqGen(AtomicU64);
+
+impl SeqGen {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The seq after the last one handed out
+    pub fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// The last seq handed out, 0 before the first
+    pub fn current(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// How an observed seq relates to the ones observed before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GapInfo {
+    /// The first seq observed, or the one right after the highest so far
+    InOrder,
+    /// Past the one after the highest so far, skipping `missed` seqs
+    Gap { missed: u64 },
+    /// At or below the highest so far, e.g. a change replayed after it was applied
+    Duplicate,
+}
+
+/// Follows the seqs of one stream of changes, e.g. a connection or a file,
+/// keeping the highest one seen
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SeqTracker {
+    last: Option<u64>,
+}
+
+impl SeqTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records `seq` and tells how it follows the highest seq seen so far;
+    /// a duplicate leaves that unchanged
+    pub fn observe(&mut self, seq: u64) -> GapInfo {
+        let info = match self.last {
+            None => GapInfo::InOrder,
+            Some(last) if seq <= last => return GapInfo::Duplicate,
+            Some(last) if seq == last + 1 => GapInfo::InOrder,
+            Some(last) => GapInfo::Gap { missed: seq - last - 1 },
+        };
+        self.last = Some(seq);
+        info
+    }
+
+    /// The highest seq seen, `None` before the first or after a reset
+    pub fn last(&self) -> Option<u64> {
+        self.last
+    }
+
+    /// Forgets every seq seen, e.g. to start over from full content
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+    use super::*;
+
+    #[test]
+    fn generator_counts_up_from_one() {
+        let seqs = SeqGen::new();
+        assert_eq!(seqs.current(), 0);
+        assert_eq!([seqs.next(), seqs.next(), seqs.next()], [1, 2, 3]);
+        assert_eq!(seqs.current(), 3);
+    }
+
+    #[test]
+    fn generator_never_hands_out_a_seq_twice_across_threads() {
+        let seqs = Arc::new(SeqGen::new());
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let seqs = Arc::clone(&seqs);
+                thread::spawn(move || (0..1000).map(|_| seqs.next()).collect::<Vec<_>>())
+            })
+            .collect();
+        let mut all: Vec<u64> = handles.into_iter().flat_map(|handle| handle.join().expect("thread")).collect();
+        all.sort_unstable();
+        assert_eq!(all, (1..=4000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn consecutive_seqs_are_in_order() {
+        let mut tracker = SeqTracker::new();
+        // the first seq is in order wherever it starts, e.g. when resuming
+        assert_eq!(tracker.observe(7), GapInfo::InOrder);
+        assert_eq!(tracker.observe(8), GapInfo::InOrder);
+        assert_eq!(tracker.last(), Some(8));
+    }
+
+    #[test]
+    fn skipped_seqs_are_reported_as_a_gap() {
+        let mut tracker = SeqTracker::new();
+        tracker.observe(1);
+        assert_eq!(tracker.observe(5), GapInfo::Gap { missed: 3 });
+        assert_eq!(tracker.last(), Some(5));
+        assert_eq!(tracker.observe(6), GapInfo::InOrder);
+    }
+
+    #[test]
+    fn repeated_and_older_seqs_are_duplicates_and_keep_the_highest() {
+        let mut tracker = SeqTracker::new();
+        tracker.observe(3);
+        tracker.observe(4);
+        assert_eq!(tracker.observe(4), GapInfo::Duplicate);
+        assert_eq!(tracker.observe(2), GapInfo::Duplicate);
+        assert_eq!(tracker.last(), Some(4));
+        assert_eq!(tracker.observe(5), GapInfo::InOrder);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut tracker = SeqTracker::new();
+        tracker.observe(9);
+        tracker.reset();
+        assert_eq!(tracker.last(), None);
+        assert_eq!(tracker.observe(2), GapInfo::InOrder);
+    }
+}
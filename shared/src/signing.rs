@@ -0,0 +1,97 @@
+use std::borrow::Cow;
+use ed25519_dalek::{Signature, Signer as _, SigningKey, Verifier as _, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+const PREFIX: &str = "{\"Signed\":";
+
+/// A signed JSON message: `{"Signed":{"message":"<json>","signature":"<base64>"}}`,
+/// the signature covering the exact text of `message`
+#[derive(Serialize, Deserialize)]
+enum Envelope {
+    Signed { message: String, signature: String },
+}
+
+/// Why a key could not be loaded or a message could not be verified
+#[derive(Debug, thiserror::Error)]
+pub enum SigningError {
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+    #[error("message is not signed")]
+    Unsigned,
+    #[error("message signature is invalid")]
+    BadSignature,
+}
+
+/// Signs the messages the server sends with an Ed25519 key, so clients can
+/// tell they were not tampered with, e.g. by a relay they don't trust
+pub struct Signer(SigningKey);
+
+/// Checks the messages a [`Signer`] signed against its public key
+#[derive(Debug, Clone)]
+pub struct Verifier(VerifyingKey);
+
+impl Signer {
+    /// From the base64 of a 32-byte Ed25519 secret key
+    pub fn from_base64(key: &str) -> Result<Self, SigningError> {
+        Ok(Self(SigningKey::from_bytes(&decode_key(key)?)))
+    }
+
+    /// The public key clients verify with, in base64
+    pub fn public_key(&self) -> String {
+        data_encoding::BASE64.encode(self.0.verifying_key().as_bytes())
+    }
+
+    /// Wraps a JSON message in a signed envelope
+    pub fn sign(&self, message: &str) -> String {
+        let signature = data_encoding::BASE64.encode(&self.0.sign(message.as_bytes()).to_bytes());
+        let envelope = Envelope::Signed { message: message.to_string(), signature };
+        // two strings always serialize
+        serde_json::to_string(&envelope).expect("serialize envelope")
+    }
+}
+
+impl Verifier {
+    /// From the base64 of a 32-byte Ed25519 public key, see [`Signer::public_key`]
+    pub fn from_base64(key: &str) -> Result<Self, SigningError> {
+        VerifyingKey::from_bytes(&decode_key(key)?)
+            .map(Self)
+            .map_err(|e| SigningError::InvalidKey(e.to_string()))
+    }
+
+    /// The JSON message in a signed envelope; unsigned messages and messages
+    /// whose signature does not match are rejected
+    pub fn verify(&self, text: &str) -> Result<String, SigningError> {
+        let Ok(Envelope::Signed { message, signature }) = serde_json::from_str(text) else {
+            return Err(SigningError::Unsigned);
+        };
+        let signature = data_encoding::BASE64
+            .decode(signature.as_bytes())
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(SigningError::BadSignature)?;
+        self.0.verify(message.as_bytes(), &signature).map_err(|_| SigningError::BadSignature)?;
+        Ok(message)
+    }
+}
+
+/// The JSON message carried by `text`, signed or not, without checking the
+/// signature; for clients that were not given a key
+pub fn strip(text: &str) -> Cow<'_, str> {
+    // only envelopes are parsed twice
+    if !text.starts_with(PREFIX) {
+        return Cow::Borrowed(text);
+    }
+    match serde_json::from_str(text) {
+        Ok(Envelope::Signed { message, .. }) => Cow::Owned(message),
+        Err(_) => Cow::Borrowed(text),
+    }
+}
+
+fn decode_key(key: &str) -> Result<[u8; 32], SigningError> {
+    let bytes = data_encoding::BASE64
+        .decode(key.trim().as_bytes())
+        .map_err(|e| SigningError::InvalidKey(e.to_string()))?;
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| SigningError::InvalidKey(format!("expected 32 bytes, got {}", bytes.len())))
+}
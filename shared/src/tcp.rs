@@ -0,0 +1,82 @@
+//! Socket options for the TCP connections between the server and clients:
+//! `TCP_NODELAY` sends small diffs right away instead of waiting to batch
+//! them, and keepalive probes notice a peer that went away without closing
+//! the connection, e.g. a laptop that went to sleep, long before a write fails.
+
+use std::{io, time::Duration};
+use socket2::{SockRef, TcpKeepalive};
+
+/// Options set on every connection once it is established
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// Disable Nagle's algorithm
+    pub nodelay: bool,
+    /// Idle time before the first keepalive probe; `None` disables keepalive
+    pub keepalive: Option<Duration>,
+    /// Time between unanswered keepalive probes
+    pub keepalive_interval: Duration,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(60)),
+            keepalive_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl TcpOptions {
+    /// Sets the options on `socket`, e.g. a `tokio::net::TcpStream`
+    pub fn apply<'a>(&self, socket: impl Into<SockRef<'a>>) -> io::Result<()> {
+        let socket = socket.into();
+        socket.set_tcp_nodelay(self.nodelay)?;
+        match self.keepalive {
+            Some(time) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time).with_interval(self.keepalive_interval)),
+            None => socket.set_keepalive(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use super::*;
+
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let client = TcpStream::connect(listener.local_addr().expect("local address")).expect("connect");
+        let (server, _) = listener.accept().expect("accept");
+        (client, server)
+    }
+
+    #[test]
+    fn options_are_set_on_the_socket() {
+        let (_client, server) = connected_pair();
+        let options = TcpOptions {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(30)),
+            keepalive_interval: Duration::from_secs(5),
+        };
+        options.apply(&server).expect("apply");
+        let socket = SockRef::from(&server);
+        assert!(socket.tcp_nodelay().expect("nodelay"));
+        assert!(socket.keepalive().expect("keepalive"));
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+        assert_eq!(socket.tcp_keepalive_time().expect("keepalive time"), Duration::from_secs(30));
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        assert_eq!(socket.tcp_keepalive_interval().expect("keepalive interval"), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn options_can_be_turned_off() {
+        let (client, _server) = connected_pair();
+        TcpOptions::default().apply(&client).expect("apply defaults");
+        let options = TcpOptions { nodelay: false, keepalive: None, ..TcpOptions::default() };
+        options.apply(&client).expect("apply");
+        let socket = SockRef::from(&client);
+        assert!(!socket.tcp_nodelay().expect("nodelay"));
+        assert!(!socket.keepalive().expect("keepalive"));
+    }
+}
@@ -0,0 +1,91 @@
+//! Positions counted in UTF-16 code units, the way JavaScript indexes strings.
+//!
+//! Diff positions and delete counts normally count chars (Unicode scalar
+//! values). `String.prototype.length`, `slice`, `substring` and a
+//! `<textarea>`'s `selectionStart` count UTF-16 code units instead, in which a
+//! char outside the Basic Multilingual Plane, e.g. most emoji, takes two. The
+//! two counts agree up to the first such char, so only text after one needs
+//! converting: char position `p` of `text` is UTF-16 offset
+//! `text.chars().take(p).map(char::len_utf16).sum()`.
+//!
+//! A connection asks for UTF-16 positions with [`PositionUnit::Utf16`], or
+//! for UTF-8 byte offsets with [`PositionUnit::Bytes`]; a diff is then
+//! converted against the content it applies to with
+//! [`FileChange::to_unit`](crate::FileChange::to_unit), which labels it with
+//! the unit so [`FileChange::try_apply`](crate::FileChange::try_apply) counts
+//! the same way.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// What diff positions and delete counts sent to a connection count
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PositionUnit {
+    /// Unicode scalar values, Rust's `char`
+    #[default]
+    Chars,
+    /// UTF-16 code units, JavaScript's string indices
+    Utf16,
+    /// UTF-8 bytes, offsets into the raw file
+    Bytes,
+}
+
+impl PositionUnit {
+    /// Whether this is the unit diffs count when they say nothing else
+    pub fn is_chars(&self) -> bool {
+        *self == PositionUnit::Chars
+    }
+
+    /// How many positions `c` takes
+    pub fn width(self, c: char) -> usize {
+        match self {
+            PositionUnit::Chars => 1,
+            PositionUnit::Utf16 => c.len_utf16(),
+            PositionUnit::Bytes => c.len_utf8(),
+        }
+    }
+
+    /// Length of `text` in this unit
+    pub fn len(self, text: &str) -> usize {
+        match self {
+            PositionUnit::Chars => text.chars().count(),
+            PositionUnit::Utf16 => len(text),
+            PositionUnit::Bytes => text.len(),
+        }
+    }
+}
+
+impl fmt::Display for PositionUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PositionUnit::Chars => "chars",
+            PositionUnit::Utf16 => "UTF-16 code units",
+            PositionUnit::Bytes => "bytes",
+        })
+    }
+}
+
+impl FromStr for PositionUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chars" => Ok(PositionUnit::Chars),
+            "utf16" => Ok(PositionUnit::Utf16),
+            "bytes" => Ok(PositionUnit::Bytes),
+            _ => Err(format!("unknown position unit {s:?}, expected chars, utf16 or bytes")),
+        }
+    }
+}
+
+/// The UTF-16 offset of char position `char_position` in `text`, clamped to its end
+pub fn offset(text: &str, char_position: usize) -> usize {
+    text.chars().take(char_position).map(char::len_utf16).sum()
+}
+
+/// Length of `text` in UTF-16 code units, JavaScript's `length`
+pub fn len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
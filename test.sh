@@ -9,7 +9,9 @@ NC='\033[0m' # No Color
 SERVER_BINARY="./target/release/server"
 CLIENT_BINARY="./target/release/client"
 TEST_FILE="server/README.md"
-NUM_CLIENTS=3
+NUM_CLIENTS=4
+# The last client long-polls over HTTP instead of using WebSocket
+LONG_POLL_ADDR="127.0.0.1:3031"
 TIMEOUT=30
 
 # Initialize counters and arrays
@@ -39,7 +41,7 @@ cargo build --release
 echo "Starting server..."
 START_TIME=$(date +%s.%N)
 # Disable turbo mode to ensure events are not filtered
-TURBO_MODE="" ULTRA_TURBO_MODE="" $SERVER_BINARY "$TEST_FILE" > server.log 2>&1 &
+TURBO_MODE="" ULTRA_TURBO_MODE="" $SERVER_BINARY "$TEST_FILE" --long-poll "$LONG_POLL_ADDR" > server.log 2>&1 &
 SERVER_PID=$!
 
 # Wait for server to be ready
@@ -66,9 +68,15 @@ echo ""
 
 echo "Starting multiple clients..."
 for i in $(seq 1 $NUM_CLIENTS); do
-    echo "Starting Client $i..."
+    CLIENT_ARGS=()
+    if [ "$i" -eq "$NUM_CLIENTS" ]; then
+        echo "Starting Client $i (long-poll)..."
+        CLIENT_ARGS=(--long-poll --long-poll-url "http://$LONG_POLL_ADDR")
+    else
+        echo "Starting Client $i..."
+    fi
     START_TIME=$(date +%s.%N)
-    CLIENT_ID="client${i}" OUTPUT_DIR="client" $CLIENT_BINARY "$i" > "client${i}.log" 2>&1 &
+    CLIENT_ID="client${i}" OUTPUT_DIR="client" $CLIENT_BINARY "$i" "${CLIENT_ARGS[@]}" > "client${i}.log" 2>&1 &
     CLIENT_PIDS[$i]=$!
 
     # Verify client started